@@ -0,0 +1,2 @@
+-- JSON storage keys of the thumbnails of the map file, null until generated.
+ALTER TABLE smaps ADD COLUMN thumbnails TEXT;
@@ -0,0 +1,3 @@
+-- Width and height of the map image in pixels, null for other formats.
+ALTER TABLE smaps ADD COLUMN width BIGINT;
+ALTER TABLE smaps ADD COLUMN height BIGINT;
@@ -0,0 +1,2 @@
+-- Media type of the map file as uploaded, null unless it was transcoded.
+ALTER TABLE smaps ADD COLUMN original_content_type TEXT;
@@ -0,0 +1,2 @@
+-- RFC 3339 time after which the map is removed, null for maps kept indefinitely.
+ALTER TABLE smaps ADD COLUMN expires_at TEXT;
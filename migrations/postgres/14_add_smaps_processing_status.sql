@@ -0,0 +1,2 @@
+-- JSON status of the processing of the map after upload, null if it needs none.
+ALTER TABLE smaps ADD COLUMN processing_status TEXT;
@@ -0,0 +1,10 @@
+-- Registration order is kept in the serial id.
+CREATE TABLE IF NOT EXISTS smaps (
+    id BIGSERIAL NOT NULL,
+    uuid TEXT PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL,
+    key TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    stored_size BIGINT NOT NULL
+);
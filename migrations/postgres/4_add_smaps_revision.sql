@@ -0,0 +1,2 @@
+-- Incremented on every update, for optimistic concurrency control.
+ALTER TABLE smaps ADD COLUMN revision BIGINT NOT NULL DEFAULT 1;
@@ -0,0 +1,4 @@
+-- Free-form description, null when unset.
+ALTER TABLE smaps ADD COLUMN description TEXT;
+-- JSON array of tags.
+ALTER TABLE smaps ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';
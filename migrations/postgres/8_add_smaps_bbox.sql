@@ -0,0 +1,2 @@
+-- JSON array [min_x, min_y, max_x, max_y] of the map footprint, null when unknown.
+ALTER TABLE smaps ADD COLUMN bbox TEXT;
@@ -0,0 +1,2 @@
+-- JSON cache policy of the map file, overriding the deployment one, null if unset.
+ALTER TABLE smaps ADD COLUMN cache_control TEXT;
@@ -0,0 +1,9 @@
+-- Registration order is kept in the implicit rowid.
+CREATE TABLE IF NOT EXISTS smaps (
+    uuid TEXT PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL,
+    key TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    stored_size INTEGER NOT NULL
+);
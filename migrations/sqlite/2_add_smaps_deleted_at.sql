@@ -0,0 +1,2 @@
+-- RFC 3339 time the map was moved to the trash, null for active maps.
+ALTER TABLE smaps ADD COLUMN deleted_at TEXT;
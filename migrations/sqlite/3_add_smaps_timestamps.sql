@@ -0,0 +1,3 @@
+-- RFC 3339 creation and last modification times, the epoch for maps registered before.
+ALTER TABLE smaps ADD COLUMN created_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00.000Z';
+ALTER TABLE smaps ADD COLUMN updated_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00.000Z';
@@ -0,0 +1,2 @@
+-- Media type of the map file, unknown for maps stored before it was recorded.
+ALTER TABLE smaps ADD COLUMN content_type TEXT NOT NULL DEFAULT 'application/octet-stream';
@@ -0,0 +1,2 @@
+-- Owner of the API key the map was uploaded with, null for anonymous uploads.
+ALTER TABLE smaps ADD COLUMN owner TEXT;
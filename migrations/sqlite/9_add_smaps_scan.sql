@@ -0,0 +1,2 @@
+-- JSON antivirus scan of the map file on upload, null when not scanned.
+ALTER TABLE smaps ADD COLUMN scan TEXT;
@@ -0,0 +1,311 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, StreamBody},
+    extract::{BodyStream, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use hyper::{header::CONTENT_TYPE, Request, StatusCode};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    config::Config,
+    gc::{self, GcError},
+    rescan::{self, RescanError},
+    smap::{self, Caller, SMap, SMapError, Store},
+    storage::{StorageBackend, StorageError},
+};
+
+/// Refuse requests to the admin routes without an administrator API key with 401.
+pub(super) async fn require_admin(
+    State(config): State<Arc<Config>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    match smap::caller(&config, request.headers()) {
+        Caller::Admin => next.run(request).await,
+        Caller::Anonymous | Caller::Owner(_) => (
+            StatusCode::UNAUTHORIZED,
+            Json(SMapError::Unauthorized(
+                "admin routes require an admin api key".to_string(),
+            )),
+        )
+            .into_response(),
+    }
+}
+
+/// Response to a failed storage backend operation.
+fn storage_error(err: StorageError) -> (StatusCode, Json<SMapError>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(SMapError::Storage(err.to_string())),
+    )
+}
+
+/// Storage consumption of the service.
+#[derive(Serialize, ToSchema)]
+pub(super) struct StorageUsage {
+    /// Bytes held by the storage backend.
+    #[schema(example = 1048576)]
+    used_bytes: u64,
+    /// Number of registered maps.
+    #[schema(example = 2)]
+    maps: usize,
+    /// Free bytes on the backing volume, when the backend can report it.
+    #[schema(example = 53687091200u64)]
+    free_bytes: Option<u64>,
+    /// File size of every registered map.
+    sizes: Vec<MapSize>,
+}
+
+/// File size of a single map.
+#[derive(Serialize, ToSchema)]
+pub(super) struct MapSize {
+    uuid: String,
+    #[schema(example = 524288)]
+    size: u64,
+}
+
+/// Report storage usage
+///
+/// Report total and per-map storage usage plus free space on the backing volume.
+#[utoipa::path(
+    get,
+    path = "/admin/storage",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Storage usage reported successfully", body = StorageUsage),
+        (status = 401, description = "Missing or non-admin api key", body = SMapError),
+        (status = 500, description = "Metadata store or storage unavailable", body = SMapError)
+    )
+)]
+pub(super) async fn storage_usage(
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+) -> Result<Json<StorageUsage>, (StatusCode, Json<SMapError>)> {
+    let sizes: Vec<MapSize> = store
+        .list()
+        .await
+        .map_err(smap::database_error)?
+        .into_iter()
+        .map(|smap| MapSize {
+            uuid: smap.uuid,
+            size: smap.size,
+        })
+        .collect();
+
+    Ok(Json(StorageUsage {
+        used_bytes: store.usage(),
+        maps: sizes.len(),
+        free_bytes: storage.free_space().await.map_err(storage_error)?,
+        sizes,
+    }))
+}
+
+/// Outcome of a garbage collection run.
+#[derive(Serialize, ToSchema)]
+pub(super) struct GarbageReport {
+    /// Keys of the deleted orphaned files.
+    removed: Vec<String>,
+}
+
+/// Collect garbage
+///
+/// Delete stored files that are not referenced by any static map.
+#[utoipa::path(
+    post,
+    path = "/admin/gc",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Orphaned files deleted successfully", body = GarbageReport),
+        (status = 401, description = "Missing or non-admin api key", body = SMapError),
+        (status = 500, description = "Metadata store or storage unavailable", body = SMapError)
+    )
+)]
+pub(super) async fn collect_garbage(
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+) -> Result<Json<GarbageReport>, (StatusCode, Json<SMapError>)> {
+    let removed = gc::collect(&store, storage.as_ref())
+        .await
+        .map_err(|err| match err {
+            GcError::Storage(err) => storage_error(err),
+            GcError::Database(err) => smap::database_error(err),
+        })?;
+    Ok(Json(GarbageReport { removed }))
+}
+
+/// Outcome of a rescan.
+#[derive(Serialize, ToSchema)]
+pub(super) struct RescanReport {
+    /// Uuids of the maps registered from their sidecar records.
+    restored: Vec<String>,
+}
+
+/// Rescan storage
+///
+/// Register static maps whose files and sidecar records are stored but which are
+/// missing from the metadata store.
+#[utoipa::path(
+    post,
+    path = "/admin/rescan",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Missing static maps registered successfully", body = RescanReport),
+        (status = 401, description = "Missing or non-admin api key", body = SMapError),
+        (status = 500, description = "Metadata store or storage unavailable", body = SMapError)
+    )
+)]
+pub(super) async fn rescan_storage(
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+) -> Result<Json<RescanReport>, (StatusCode, Json<SMapError>)> {
+    let restored = rescan::rescan(&store, storage.as_ref())
+        .await
+        .map_err(|err| match err {
+            RescanError::Storage(err) => storage_error(err),
+            RescanError::Database(err) => smap::database_error(err),
+        })?;
+    Ok(Json(RescanReport { restored }))
+}
+
+/// Export catalog
+///
+/// Stream every static map record, trashed ones included, as newline-delimited JSON.
+#[utoipa::path(
+    get,
+    path = "/admin/export",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Catalog exported successfully", content_type = "application/x-ndjson", body = SMap),
+        (status = 401, description = "Missing or non-admin api key", body = SMapError),
+        (status = 500, description = "Metadata store unavailable", body = SMapError)
+    )
+)]
+pub(super) async fn export_catalog(State(store): State<Arc<Store>>) -> impl IntoResponse {
+    let smaps = match store.list_all().await {
+        Ok(smaps) => smaps,
+        Err(err) => return smap::database_error(err).into_response(),
+    };
+    let lines = stream::iter(smaps).map(|smap| {
+        let mut line = serde_json::to_vec(&smap)?;
+        line.push(b'\n');
+        Ok::<_, serde_json::Error>(Bytes::from(line))
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(lines),
+    )
+        .into_response()
+}
+
+/// Outcome of a catalog import.
+#[derive(Serialize, ToSchema)]
+pub(super) struct ImportReport {
+    /// Uuids of the registered maps.
+    imported: Vec<String>,
+    /// Uuids of the maps skipped because they were already registered.
+    skipped: Vec<String>,
+}
+
+/// Import catalog
+///
+/// Register the static map records of a newline-delimited JSON export. Records
+/// whose uuid is already registered are skipped; the referenced files must be
+/// copied to the storage backend separately.
+#[utoipa::path(
+    post,
+    path = "/admin/import",
+    request_body(content = SMap, content_type = "application/x-ndjson"),
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Catalog imported successfully", body = ImportReport),
+        (status = 400, description = "Malformed record", body = SMapError),
+        (status = 401, description = "Missing or non-admin api key", body = SMapError),
+        (status = 500, description = "Metadata store unavailable", body = SMapError)
+    )
+)]
+pub(super) async fn import_catalog(
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    mut body: BodyStream,
+) -> Result<Json<ImportReport>, (StatusCode, Json<SMapError>)> {
+    let bad_request = |msg: String| (StatusCode::BAD_REQUEST, Json(SMapError::BadRequest(msg)));
+
+    // Parse the whole file first, so a malformed record imports nothing.
+    let mut smaps: Vec<SMap> = Vec::new();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut line_number = 0;
+    loop {
+        let chunk = body.next().await;
+        match &chunk {
+            Some(Ok(bytes)) => buffer.extend_from_slice(bytes),
+            Some(Err(err)) => return Err(bad_request(err.to_string())),
+            None => buffer.push(b'\n'),
+        }
+        while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            line_number += 1;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let smap = serde_json::from_slice(&line)
+                .map_err(|err| bad_request(format!("line {line_number}: {err}")))?;
+            smaps.push(smap);
+        }
+        if chunk.is_none() {
+            break;
+        }
+    }
+
+    let mut report = ImportReport {
+        imported: Vec::new(),
+        skipped: Vec::new(),
+    };
+    for smap in smaps {
+        if store
+            .adopt(smap.clone())
+            .await
+            .map_err(smap::database_error)?
+        {
+            rescan::record(storage.as_ref(), &smap).await;
+            report.imported.push(smap.uuid);
+        } else {
+            report.skipped.push(smap.uuid);
+        }
+    }
+    Ok(Json(report))
+}
+
+/// Schema of the metadata database.
+#[derive(Serialize, ToSchema)]
+pub(super) struct SchemaVersion {
+    /// Version of the last applied migration, null when the repository has no schema.
+    #[schema(example = 1)]
+    version: Option<i64>,
+}
+
+/// Report schema version
+///
+/// Report the version of the last migration applied to the metadata database.
+#[utoipa::path(
+    get,
+    path = "/admin/schema",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Schema version reported successfully", body = SchemaVersion),
+        (status = 401, description = "Missing or non-admin api key", body = SMapError),
+        (status = 500, description = "Metadata store unavailable", body = SMapError)
+    )
+)]
+pub(super) async fn schema_version(
+    State(store): State<Arc<Store>>,
+) -> Result<Json<SchemaVersion>, (StatusCode, Json<SMapError>)> {
+    let version = store.schema_version().await.map_err(smap::database_error)?;
+    Ok(Json(SchemaVersion { version }))
+}
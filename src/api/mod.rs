@@ -0,0 +1,28 @@
+//! HTTP API. Each version is nested under `/api/<version>` with its own OpenAPI
+//! document, so a new version can be served next to the ones clients rely on.
+
+use axum::{middleware, Router};
+use utoipa::OpenApi;
+use utoipa_swagger_ui::{SwaggerUi, Url};
+
+use crate::{ratelimit, state::AppState};
+
+mod v1;
+
+/// Routes of every API version, rate limited.
+pub(crate) fn router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .nest("/api/v1", v1::router(state))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            ratelimit::limit_rate,
+        ))
+}
+
+/// Swagger UI at `/docs`, offering the OpenAPI document of every API version.
+pub(crate) fn docs() -> SwaggerUi {
+    SwaggerUi::new("/docs").urls(vec![(
+        Url::new("v1", "/api-docs/v1/openapi.json"),
+        v1::ApiDoc::openapi(),
+    )])
+}
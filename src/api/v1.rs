@@ -0,0 +1,230 @@
+//! Version 1 of the HTTP API.
+
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{self, MethodRouter},
+    Router,
+};
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        Content, PathItemType, Ref,
+    },
+    Modify, OpenApi,
+};
+
+use crate::{
+    admin, archive, cache, feed, health, jobs, presign, quota, scan, sessions, smap,
+    state::AppState, thumbnail, tus,
+};
+
+/// OpenAPI document of the v1 API.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        smap::list_smaps,
+        smap::search_smaps,
+        smap::list_tags,
+        feed::atom_feed,
+        smap::get_smap,
+        smap::upload_smap,
+        smap::upload_smap_multipart,
+        smap::upload_smap_from_url,
+        smap::download_smap_file,
+        smap::download_smap_thumbnail,
+        smap::head_smap_file,
+        smap::checksum_smap_file,
+        smap::replace_smap_file,
+        smap::update_smap,
+        smap::get_smaps,
+        archive::export_smaps,
+        archive::import_smaps,
+        tus::upload_options,
+        tus::create_upload,
+        tus::head_upload,
+        tus::patch_upload,
+        sessions::open_session,
+        sessions::upload_part,
+        sessions::complete_session,
+        sessions::session_progress,
+        presign::presign_upload,
+        presign::put_presigned,
+        presign::finalize_presigned,
+        smap::delete_smaps,
+        smap::delete_smap,
+        smap::restore_smap,
+        smap::copy_smap,
+        admin::storage_usage,
+        admin::collect_garbage,
+        admin::rescan_storage,
+        admin::export_catalog,
+        admin::import_catalog,
+        admin::schema_version,
+        health::readiness,
+    ),
+    components(
+        schemas(smap::SMap, smap::SMapPatch, smap::Links, smap::BatchReport, smap::TagCount, smap::Checksums, smap::BulkDeleteReport, smap::BulkFailure, smap::SMapError, smap::Violation, scan::Scan, thumbnail::Thumbnails, thumbnail::Size, jobs::ProcessingStatus, cache::CachePolicy, smap::NewSMap, smap::RemoteSMap, smap::CopySMap, admin::StorageUsage, admin::MapSize, admin::GarbageReport, admin::RescanReport, admin::ImportReport, admin::SchemaVersion, health::Readiness, smap::SortField, smap::SortOrder, sessions::NewUploadSession, sessions::UploadSession, sessions::UploadPart, sessions::UploadProgress, presign::NewPresignedUpload, presign::PresignedUpload, presign::Target)
+    ),
+    servers((url = "/api/v1")),
+    modifiers(&SecurityAddon, &UploadAddon),
+    tags(
+        (name = "static map", description = "Static Map items management API"),
+        (name = "admin", description = "Service administration API"),
+        (name = "health", description = "Service health probes")
+    )
+)]
+pub(super) struct ApiDoc;
+
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("smap_apikey"))),
+            )
+        }
+    }
+}
+
+/// Documents the multipart body of `POST /smap` next to the JSON one, which
+/// `utoipa::path` cannot declare together.
+struct UploadAddon;
+
+impl Modify for UploadAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let body = openapi
+            .paths
+            .paths
+            .get_mut("/smap")
+            .and_then(|path| path.operations.get_mut(&PathItemType::Post))
+            .and_then(|operation| operation.request_body.as_mut());
+        if let Some(body) = body {
+            body.content.insert(
+                "multipart/form-data".to_string(),
+                Content::new(Ref::from_schema_name("NewSMap")),
+            );
+        }
+    }
+}
+
+/// Routes of the v1 API, relative to its prefix.
+pub(super) fn router(state: &AppState) -> Router<AppState> {
+    // Routes receiving map files share the upload slots, and the bytes they
+    // receive count towards the upload quotas of API keys.
+    let receiving = |route: MethodRouter<AppState>| {
+        route
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                quota::limit_quota,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                smap::limit_concurrent_uploads,
+            ))
+    };
+    // Those routes also accept bodies up to the upload limit, others the
+    // default limit of extractors.
+    let upload =
+        |route: MethodRouter<AppState>| {
+            receiving(route).layer(DefaultBodyLimit::disable()).layer(
+                middleware::from_fn_with_state(state.config.clone(), smap::limit_upload),
+            )
+        };
+    Router::new()
+        .route(
+            "/smap",
+            upload(routing::get(smap::list_smaps).post(smap::upload_smap)),
+        )
+        .route("/smap/search", routing::get(smap::search_smaps))
+        .route("/smap/feed.atom", routing::get(feed::atom_feed))
+        .route("/smap/batch", routing::post(smap::get_smaps))
+        .route("/smap/delete", routing::post(smap::delete_smaps))
+        .route("/smap/export", routing::get(archive::export_smaps))
+        .route("/smap/import", upload(routing::post(archive::import_smaps)))
+        .route("/smap/from-url", routing::post(smap::upload_smap_from_url))
+        // Kept for one release after the move of uploads to `POST /smap`.
+        .route(
+            "/upload",
+            #[allow(deprecated)]
+            upload(routing::post(smap::upload_smap_multipart)),
+        )
+        .route("/upload/sessions", routing::post(sessions::open_session))
+        .route(
+            "/upload/sessions/:id/parts/:n",
+            upload(routing::put(sessions::upload_part)),
+        )
+        .route(
+            "/upload/sessions/:id/complete",
+            routing::post(sessions::complete_session),
+        )
+        .route(
+            "/upload/sessions/:id/progress",
+            routing::get(sessions::session_progress),
+        )
+        .route("/upload/presign", routing::post(presign::presign_upload))
+        .route(
+            "/upload/presign/:id",
+            upload(routing::put(presign::put_presigned)),
+        )
+        .route(
+            "/upload/presign/:id/finalize",
+            routing::post(presign::finalize_presigned),
+        )
+        .route(
+            "/uploads",
+            receiving(routing::options(tus::upload_options).post(tus::create_upload)),
+        )
+        .route(
+            "/uploads/:id",
+            receiving(
+                routing::head(tus::head_upload)
+                    .patch(tus::patch_upload)
+                    .options(tus::upload_options),
+            ),
+        )
+        .route(
+            "/smap/:uuid",
+            routing::get(smap::get_smap)
+                .patch(smap::update_smap)
+                .delete(smap::delete_smap),
+        )
+        .route("/smap/:uuid/restore", routing::post(smap::restore_smap))
+        .route("/smap/:uuid/copy", routing::post(smap::copy_smap))
+        .route(
+            "/smap/:uuid/file",
+            upload(
+                routing::get(smap::download_smap_file)
+                    .head(smap::head_smap_file)
+                    .put(smap::replace_smap_file),
+            ),
+        )
+        .route(
+            "/smap/:uuid/thumbnails/:size",
+            routing::get(smap::download_smap_thumbnail),
+        )
+        .route(
+            "/smap/:uuid/checksum",
+            routing::get(smap::checksum_smap_file),
+        )
+        .route("/tags", routing::get(smap::list_tags))
+        .route("/ready", routing::get(health::readiness))
+        .merge(admin_router(state))
+}
+
+/// Routes of the administration API, restricted to administrator API keys.
+fn admin_router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/admin/storage", routing::get(admin::storage_usage))
+        .route("/admin/gc", routing::post(admin::collect_garbage))
+        .route("/admin/rescan", routing::post(admin::rescan_storage))
+        .route("/admin/export", routing::get(admin::export_catalog))
+        .route("/admin/import", routing::post(admin::import_catalog))
+        .route("/admin/schema", routing::get(admin::schema_version))
+        .route_layer(middleware::from_fn_with_state(
+            state.config.clone(),
+            admin::require_admin,
+        ))
+}
@@ -0,0 +1,489 @@
+//! ZIP archives of maps, to hand collections over offline.
+//!
+//! An archive holds `manifest.json`, the array of the archived map records, and
+//! the file of each map under `files/<uuid>`. Archives of plain folders of map
+//! files can be imported too.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use axum::{
+    body::StreamBody,
+    extract::{BodyStream, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use hyper::{
+    header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderMap, StatusCode,
+};
+use serde::Deserialize;
+use tokio::{io::AsyncWriteExt, sync::mpsc, task};
+use tokio_util::io::ReaderStream;
+use utoipa::IntoParams;
+use uuid::Uuid;
+use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    config::Config,
+    smap::{self, BBox, SMap, SMapError, Store},
+    storage::{StorageBackend, StorageError},
+};
+
+/// Name of the archive entry listing the archived maps.
+const MANIFEST: &str = "manifest.json";
+
+/// Archive errors.
+#[derive(Debug)]
+pub(crate) enum ArchiveError {
+    /// Map files could not be read from storage.
+    Storage(StorageError),
+    /// Archive could not be written to or read from disk.
+    Io(io::Error),
+    /// Archive is not a valid ZIP file.
+    Zip(ZipError),
+    /// Manifest could not be encoded or decoded.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Storage(err) => write!(f, "{err}"),
+            Self::Io(err) => write!(f, "archive i/o error: {err}"),
+            Self::Zip(err) => write!(f, "invalid archive: {err}"),
+            Self::Json(err) => write!(f, "invalid manifest: {err}"),
+        }
+    }
+}
+
+impl From<StorageError> for ArchiveError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ZipError> for ArchiveError {
+    fn from(err: ZipError) -> Self {
+        Self::Zip(err)
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+/// Archive export query parameters.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct ExportQuery {
+    /// Comma-separated uuids of the maps to export, every active map if unset.
+    uuids: Option<String>,
+}
+
+/// Export Static maps
+///
+/// Download a ZIP archive of static maps: their files under `files/<uuid>` and
+/// their records in `manifest.json`.
+#[utoipa::path(
+    get,
+    path = "/smap/export",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "Archive of the static maps", content_type = "application/zip", body = Vec<u8>),
+        (status = 404, description = "Some static maps were not found", body = SMapError),
+        (status = 500, description = "Metadata store or storage unavailable", body = SMapError)
+    )
+)]
+pub(super) async fn export_smaps(
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let smaps = match query.uuids {
+        None => store.list().await,
+        Some(uuids) => {
+            let uuids: Vec<String> = uuids
+                .split(',')
+                .map(str::trim)
+                .filter(|uuid| !uuid.is_empty())
+                .map(str::to_string)
+                .collect();
+            match store.get_many(&uuids).await {
+                Ok(found) => {
+                    let missing: Vec<&str> = uuids
+                        .iter()
+                        .zip(&found)
+                        .filter(|(_, smap)| smap.is_none())
+                        .map(|(uuid, _)| uuid.as_str())
+                        .collect();
+                    if !missing.is_empty() {
+                        return (
+                            StatusCode::NOT_FOUND,
+                            Json(SMapError::NotFound(format!("uuid = {}", missing.join(",")))),
+                        )
+                            .into_response();
+                    }
+                    Ok(found.into_iter().flatten().collect())
+                }
+                Err(err) => Err(err),
+            }
+        }
+    };
+    let smaps = match smaps {
+        Ok(smaps) => smaps,
+        Err(err) => return smap::database_error(err).into_response(),
+    };
+
+    let archive = match write(smaps, storage.as_ref()).await {
+        Ok(archive) => archive,
+        Err(err) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(err.to_string())),
+            )
+                .into_response()
+        }
+    };
+    let length = match archive.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(err) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(err.to_string())),
+            )
+                .into_response()
+        }
+    };
+    (
+        [
+            (CONTENT_TYPE, "application/zip".to_string()),
+            (CONTENT_LENGTH, length.to_string()),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"smaps.zip\"".to_string(),
+            ),
+        ],
+        StreamBody::new(ReaderStream::new(archive)),
+    )
+        .into_response()
+}
+
+/// Part of an archive sent to its writer.
+enum Part {
+    /// Start of the file of a map, with its size.
+    File(String, u64),
+    /// Next bytes of the current file.
+    Chunk(Bytes),
+}
+
+/// Write the archive of `smaps` to an anonymous temporary file, returned
+/// rewound.
+///
+/// ZIP headers are patched once each file is written, so the archive is built
+/// on disk before it is sent.
+async fn write(
+    smaps: Vec<SMap>,
+    storage: &dyn StorageBackend,
+) -> Result<tokio::fs::File, ArchiveError> {
+    let manifest = serde_json::to_vec_pretty(&smaps)?;
+    let (sender, mut receiver) = mpsc::channel(16);
+    let writer = task::spawn_blocking(move || {
+        let mut zip = ZipWriter::new(temporary_file()?);
+        zip.start_file(MANIFEST, FileOptions::default())?;
+        zip.write_all(&manifest)?;
+        while let Some(part) = receiver.blocking_recv() {
+            match part {
+                Part::File(name, size) => {
+                    // Map files are mostly compressed images already.
+                    let options = FileOptions::default()
+                        .compression_method(CompressionMethod::Stored)
+                        .large_file(size >= u64::from(u32::MAX));
+                    zip.start_file(name, options)?;
+                }
+                Part::Chunk(bytes) => zip.write_all(&bytes)?,
+            }
+        }
+        let mut file = zip.finish()?;
+        file.rewind()?;
+        Ok::<_, ArchiveError>(file)
+    });
+
+    // Sending only fails once the writer gave up, which then reports why.
+    let sent = async {
+        for smap in &smaps {
+            let name = format!("files/{}", smap.uuid);
+            if sender.send(Part::File(name, smap.size)).await.is_err() {
+                break;
+            }
+            let mut chunks = storage.get(&smap.key).await?;
+            while let Some(chunk) = chunks.try_next().await? {
+                if sender.send(Part::Chunk(chunk)).await.is_err() {
+                    break;
+                }
+            }
+        }
+        Ok::<_, ArchiveError>(())
+    }
+    .await;
+    drop(sender);
+
+    let written = writer.await.map_err(io::Error::other)?;
+    sent?;
+    Ok(tokio::fs::File::from_std(written?))
+}
+
+/// Import Static maps
+///
+/// Register a static map for every file of a ZIP archive, as exported or of a
+/// plain folder of map files. Maps get new uuids and take their title,
+/// description and tags from the manifest, or their title from the file name
+/// without a manifest. They are registered together or not at all.
+#[utoipa::path(
+    post,
+    path = "/smap/import",
+    request_body(content = Vec<u8>, content_type = "application/zip"),
+    responses(
+        (status = 201, description = "Static maps imported successfully", body = [SMap]),
+        (status = 400, description = "Body is not a ZIP archive of map files", body = SMapError),
+        (status = 409, description = "Static map duplicates an existing one", body = SMapError),
+        (status = 413, description = "A map image exceeds the pixel limits", body = SMapError),
+        (status = 415, description = "A map file has a content type that is not accepted", body = SMapError),
+        (status = 422, description = "A map file name has a blocked or disguised extension", body = SMapError),
+        (status = 500, description = "Static map files or metadata could not be stored", body = SMapError),
+        (status = 507, description = "Storage quota exhausted", body = SMapError)
+    )
+)]
+pub(super) async fn import_smaps(
+    State(config): State<Arc<Config>>,
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Response {
+    let archive = match receive(body).await {
+        Ok(archive) => archive,
+        Err(err) => return archive_error(err),
+    };
+
+    let (sender, mut receiver) = mpsc::channel(4);
+    let reader = task::spawn_blocking(move || read(archive, sender));
+    let mut smaps = Vec::new();
+    let mut failure = None;
+    while let Some(import) = receiver.recv().await {
+        let Import {
+            file_name,
+            title,
+            description,
+            tags,
+            bbox,
+            content_type,
+            bytes,
+        } = import;
+        let content_type = content_type.as_deref();
+        let checked = file_name
+            .map_or(Ok(()), |name| smap::accept_file_name(&config, &name))
+            .and_then(|_| smap::accept_content_type(&config, content_type))
+            .and_then(|_| smap::sniff_content_type(&config, &bytes));
+        let content_type = match checked {
+            Ok(content_type) => Some(content_type),
+            Err(err) => {
+                failure = Some(err.into_response());
+                break;
+            }
+        };
+        match smap::store_file(&config, &store, storage.as_ref(), bytes, content_type).await {
+            Ok(file) => {
+                let mut smap = SMap::new(Uuid::new_v4().to_string(), title, file);
+                smap.description = description;
+                smap.tags = tags;
+                smap.bbox = bbox;
+                smap.owner = smap::caller(&config, &headers).owner();
+                smaps.push(smap);
+            }
+            Err(err) => {
+                failure = Some(err.into_response());
+                break;
+            }
+        }
+    }
+    drop(receiver);
+
+    let read = reader.await.map_err(io::Error::other);
+    let failure = match (failure, read) {
+        (Some(failure), _) => Some(failure),
+        (None, Ok(Ok(()))) if smaps.is_empty() => Some(
+            (
+                StatusCode::BAD_REQUEST,
+                Json(SMapError::BadRequest(
+                    "archive holds no map files".to_string(),
+                )),
+            )
+                .into_response(),
+        ),
+        (None, Ok(Ok(()))) => None,
+        (None, Ok(Err(err))) => Some(archive_error(err)),
+        (None, Err(err)) => Some(archive_error(err.into())),
+    };
+    if let Some(failure) = failure {
+        for smap in &smaps {
+            store.release(&smap.key).await;
+        }
+        return failure;
+    }
+
+    match smap::register_new(&config, &store, storage.as_ref(), smaps).await {
+        Ok(smaps) => smap::created(smaps),
+        Err(response) => response,
+    }
+}
+
+/// Map file read from an archive, with the metadata it is imported with.
+struct Import {
+    /// Name of the file in an archive without manifest.
+    file_name: Option<String>,
+    title: String,
+    description: Option<String>,
+    tags: Vec<String>,
+    bbox: Option<BBox>,
+    content_type: Option<String>,
+    bytes: Bytes,
+}
+
+/// Spool an uploaded archive to a temporary file, returned rewound.
+async fn receive(mut body: BodyStream) -> Result<File, ArchiveError> {
+    let mut file = tokio::fs::File::from_std(temporary_file()?);
+    while let Some(chunk) = body.try_next().await.map_err(io::Error::other)? {
+        file.write_all(&chunk).await?;
+    }
+    let mut file = file.into_std().await;
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Send every map file of `archive` to `sender`, until it is closed.
+fn read(archive: File, sender: mpsc::Sender<Import>) -> Result<(), ArchiveError> {
+    let mut zip = ZipArchive::new(archive)?;
+    let manifest: HashMap<String, SMap> = match zip.by_name(MANIFEST) {
+        Ok(entry) => serde_json::from_reader::<_, Vec<SMap>>(entry)?
+            .into_iter()
+            .map(|smap| (format!("files/{}", smap.uuid), smap))
+            .collect(),
+        Err(ZipError::FileNotFound) => HashMap::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        let name = entry.name().to_string();
+        let file_name = Path::new(&name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // Skip folders, the manifest and hidden files, e.g. `__MACOSX/._map.png`.
+        if entry.is_dir()
+            || name == MANIFEST
+            || name.starts_with("__MACOSX/")
+            || file_name.starts_with('.')
+        {
+            continue;
+        }
+
+        let mut bytes = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut bytes)?;
+        let import = match manifest.get(&name) {
+            Some(smap) => Import {
+                file_name: None,
+                title: smap.title.clone(),
+                description: smap.description.clone(),
+                tags: smap.tags.clone(),
+                bbox: smap.bbox,
+                content_type: Some(smap.content_type.clone()),
+                bytes: Bytes::from(bytes),
+            },
+            None => Import {
+                title: title_of(&file_name).unwrap_or_else(|| file_name.clone()),
+                file_name: Some(file_name.clone()),
+                description: None,
+                tags: Vec::new(),
+                bbox: None,
+                content_type: content_type_of(&file_name).map(str::to_string),
+                bytes: Bytes::from(bytes),
+            },
+        };
+        if sender.blocking_send(import).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Response for an archive that could not be imported.
+fn archive_error(err: ArchiveError) -> Response {
+    match err {
+        ArchiveError::Zip(_) | ArchiveError::Json(_) => (
+            StatusCode::BAD_REQUEST,
+            Json(SMapError::BadRequest(err.to_string())),
+        )
+            .into_response(),
+        err => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(SMapError::Storage(err.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+/// Title for a map file named `file_name` by a client: its stem, without any
+/// directories (`/` or `\` separated) or control characters.
+pub(crate) fn title_of(file_name: &str) -> Option<String> {
+    let base = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let stem = Path::new(base)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let title: String = stem.chars().filter(|c| !c.is_control()).collect();
+    let title = title.trim();
+    (!title.is_empty() && title != "..").then(|| title.to_string())
+}
+
+/// Media type of a map file named `file_name`, guessed from its extension.
+fn content_type_of(file_name: &str) -> Option<&'static str> {
+    let extension = Path::new(file_name).extension()?.to_str()?;
+    match extension.to_ascii_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        "tif" | "tiff" => Some("image/tiff"),
+        "pdf" => Some("application/pdf"),
+        _ => None,
+    }
+}
+
+/// Temporary file deleted once closed.
+pub(crate) fn temporary_file() -> io::Result<File> {
+    let path = std::env::temp_dir().join(format!("smu-{}", Uuid::new_v4()));
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
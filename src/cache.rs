@@ -0,0 +1,72 @@
+//! `Cache-Control` of served map files and thumbnails, set for the deployment
+//! and optionally overridden per map.
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Cache settings of the deployment, applied to maps without their own policy.
+#[derive(Args, Debug)]
+pub(crate) struct CacheConfig {
+    /// Seconds clients and proxies may cache served map files and thumbnails.
+    /// No `Cache-Control` is sent if unset, unless another cache flag is given.
+    #[arg(long = "cache-max-age", env = "SMU_CACHE_MAX_AGE")]
+    pub(crate) max_age: Option<u64>,
+
+    /// Mark served files immutable, sparing revalidation while fresh. Only fit
+    /// for deployments where map files are never replaced.
+    #[arg(long = "cache-immutable", env = "SMU_CACHE_IMMUTABLE")]
+    pub(crate) immutable: bool,
+
+    /// Forbid caching served files at all, overriding the other cache flags.
+    #[arg(long = "cache-no-store", env = "SMU_CACHE_NO_STORE")]
+    pub(crate) no_store: bool,
+}
+
+impl CacheConfig {
+    /// Policy of maps without their own.
+    pub(crate) fn policy(&self) -> CachePolicy {
+        CachePolicy {
+            max_age: self.max_age,
+            immutable: self.immutable,
+            no_store: self.no_store,
+        }
+    }
+}
+
+/// Caching allowed of the file and thumbnails of a map.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub(crate) struct CachePolicy {
+    /// Seconds the file may be cached.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 86400)]
+    pub(crate) max_age: Option<u64>,
+    /// Whether the file never changes while fresh, sparing revalidation.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) immutable: bool,
+    /// Whether the file must not be cached at all, overriding the other fields.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) no_store: bool,
+}
+
+impl CachePolicy {
+    /// Whether the policy sets nothing.
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// `Cache-Control` value of the policy, none for an empty one.
+    pub(crate) fn header(&self) -> Option<String> {
+        if self.no_store {
+            return Some("no-store".to_string());
+        }
+        let mut directives = Vec::new();
+        if let Some(max_age) = self.max_age {
+            directives.push(format!("public, max-age={max_age}"));
+        }
+        if self.immutable {
+            directives.push("immutable".to_string());
+        }
+        (!directives.is_empty()).then(|| directives.join(", "))
+    }
+}
@@ -0,0 +1,28 @@
+//! Conditional requests of map files, sparing clients holding a current copy
+//! from downloading it again.
+
+use chrono::{DateTime, Utc};
+
+/// Whether a client sending `If-None-Match` header `if_none_match` or
+/// `If-Modified-Since` header `if_modified_since` holds the current version of
+/// a file with entity tag `etag`, last modified at `modified`.
+///
+/// `If-Modified-Since` is only considered without `If-None-Match`, and ignored
+/// when it is not a valid HTTP date.
+pub(crate) fn not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    modified: DateTime<Utc>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        // Entity tags are compared weakly: a weak tag of the same content matches.
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    }
+    if_modified_since
+        .and_then(|since| DateTime::parse_from_rfc2822(since.trim()).ok())
+        .is_some_and(|since| modified.timestamp() <= since.timestamp())
+}
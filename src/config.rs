@@ -0,0 +1,330 @@
+use std::{path::PathBuf, str::FromStr};
+
+use clap::{Parser, ValueEnum};
+
+use crate::cache::CacheConfig;
+use crate::db::{DatabaseConfig, RedisConfig};
+use crate::filename::FilenameConfig;
+use crate::ingest::IngestConfig;
+use crate::jobs::JobConfig;
+use crate::normalize::NormalizeConfig;
+use crate::presign::PresignConfig;
+use crate::quota::QuotaConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::scan::ScanConfig;
+use crate::storage::{
+    AzureConfig, CompressionConfig, EncryptionConfig, GcsConfig, ReplicationConfig, S3Config,
+    SftpConfig, WebDavConfig,
+};
+use crate::strip::StripConfig;
+use crate::sync::SyncConfig;
+use crate::thumbnail::ThumbnailConfig;
+use crate::tus::TusConfig;
+use crate::webhook::WebhookConfig;
+
+/// Static map service configuration, read from flags or environment.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub(crate) struct Config {
+    /// Directory holding service data, created on startup if missing.
+    #[arg(long, env = "SMU_DATA_DIR", default_value = "data")]
+    pub(crate) data_dir: PathBuf,
+
+    /// Storage backend holding uploaded map files.
+    #[arg(long, env = "SMU_STORAGE", value_enum, default_value_t = StorageKind::Fs)]
+    pub(crate) storage: StorageKind,
+
+    /// Where map metadata is kept.
+    #[arg(long, env = "SMU_METADATA", value_enum, default_value_t = MetadataKind::Sqlite)]
+    pub(crate) metadata: MetadataKind,
+
+    /// Maximum total bytes held by the storage backend, unlimited if unset.
+    #[arg(long, env = "SMU_MAX_STORAGE_BYTES")]
+    pub(crate) max_storage_bytes: Option<u64>,
+
+    /// Largest request body accepted by upload routes, in bytes; larger ones
+    /// are refused with 413.
+    #[arg(
+        long = "max-upload-bytes",
+        env = "SMU_MAX_UPLOAD_BYTES",
+        default_value_t = 1024 * 1024 * 1024
+    )]
+    pub(crate) max_upload_bytes: u64,
+
+    /// Uploads handled at once, unlimited if unset. Further ones wait for a
+    /// slot up to `--upload-queue-secs`, then are refused with 503.
+    #[arg(long = "max-concurrent-uploads", env = "SMU_MAX_CONCURRENT_UPLOADS")]
+    pub(crate) max_concurrent_uploads: Option<usize>,
+
+    /// Seconds an upload waits for a slot when all are taken, 0 to refuse it
+    /// at once.
+    #[arg(
+        long = "upload-queue-secs",
+        env = "SMU_UPLOAD_QUEUE_SECS",
+        default_value_t = 0
+    )]
+    pub(crate) upload_queue_secs: u64,
+
+    /// Media types accepted for uploaded map files, comma-separated; others
+    /// are refused with 415.
+    #[arg(
+        long = "upload-content-types",
+        env = "SMU_UPLOAD_CONTENT_TYPES",
+        value_delimiter = ',',
+        default_value = "image/png,image/jpeg,image/webp,image/tiff,application/pdf"
+    )]
+    pub(crate) upload_content_types: Vec<String>,
+
+    /// Pixels, width times height, an uploaded image may hold; larger ones are
+    /// refused with 413 before being decoded.
+    #[arg(
+        long = "max-image-pixels",
+        env = "SMU_MAX_IMAGE_PIXELS",
+        default_value_t = 256 * 1024 * 1024
+    )]
+    pub(crate) max_image_pixels: u64,
+
+    /// Pixels an uploaded image may span on either side; larger ones are
+    /// refused with 413 before being decoded.
+    #[arg(
+        long = "max-image-side",
+        env = "SMU_MAX_IMAGE_SIDE",
+        default_value_t = 65_535
+    )]
+    pub(crate) max_image_side: u32,
+
+    /// Public base URL of the service, e.g. `https://maps.example.org`, for links
+    /// in responses. Taken from the `Host` header of each request if unset.
+    #[arg(long, env = "SMU_PUBLIC_URL")]
+    pub(crate) public_url: Option<String>,
+
+    /// Which uploads count as duplicates of an active map. By default, those
+    /// whose content hashes like that of a map, whatever their title.
+    #[arg(long, env = "SMU_DUPLICATES", value_enum, default_value_t = DuplicatePolicy::Content)]
+    pub(crate) duplicates: DuplicatePolicy,
+
+    /// What happens to uploads duplicating an active map. By default, the map
+    /// is returned in their place rather than storing a second copy.
+    #[arg(long, env = "SMU_ON_DUPLICATE", value_enum, default_value_t = CollisionPolicy::Existing)]
+    pub(crate) on_duplicate: CollisionPolicy,
+
+    /// API keys of clients trusted to choose the uuid of their uploads,
+    /// comma-separated. Without any, client-supplied uuids are refused.
+    #[arg(
+        long = "upsert-api-keys",
+        env = "SMU_UPSERT_API_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub(crate) upsert_api_keys: Vec<String>,
+
+    /// API keys attributing uploads to an owner, as comma-separated `owner:key`
+    /// pairs. Listings requested with one of them only show the owner's maps.
+    #[arg(
+        long = "api-keys",
+        env = "SMU_API_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub(crate) api_keys: Vec<OwnerKey>,
+
+    /// API keys of administrators, whose listings show every map and who alone
+    /// may use the `/admin` routes, comma-separated.
+    #[arg(
+        long = "admin-api-keys",
+        env = "SMU_ADMIN_API_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub(crate) admin_api_keys: Vec<String>,
+
+    /// Seconds an upload `Idempotency-Key` is remembered after its first use.
+    #[arg(
+        long = "idempotency-window-secs",
+        env = "SMU_IDEMPOTENCY_WINDOW_SECS",
+        default_value_t = 86400
+    )]
+    pub(crate) idempotency_window: u64,
+
+    /// Seconds between garbage collection runs of orphaned files, 0 disables them.
+    #[arg(
+        long = "gc-interval-secs",
+        env = "SMU_GC_INTERVAL_SECS",
+        default_value_t = 3600
+    )]
+    pub(crate) gc_interval: u64,
+
+    /// Seconds between JSON snapshots of the catalog, 0 only snapshots on shutdown.
+    #[arg(
+        long = "snapshot-interval-secs",
+        env = "SMU_SNAPSHOT_INTERVAL_SECS",
+        default_value_t = 60
+    )]
+    pub(crate) snapshot_interval: u64,
+
+    /// Days trashed maps are kept before being purged, 0 keeps them forever.
+    #[arg(long, env = "SMU_TRASH_RETENTION_DAYS", default_value_t = 30)]
+    pub(crate) trash_retention_days: u64,
+
+    /// Register maps missing from the catalog from their sidecar records on startup.
+    #[arg(long, env = "SMU_RESCAN")]
+    pub(crate) rescan: bool,
+
+    #[command(flatten)]
+    pub(crate) database: DatabaseConfig,
+
+    #[command(flatten)]
+    pub(crate) redis: RedisConfig,
+
+    #[command(flatten)]
+    pub(crate) sync: SyncConfig,
+
+    #[command(flatten)]
+    pub(crate) ingest: IngestConfig,
+
+    #[command(flatten)]
+    pub(crate) tus: TusConfig,
+
+    #[command(flatten)]
+    pub(crate) filename: FilenameConfig,
+
+    #[command(flatten)]
+    pub(crate) scan: ScanConfig,
+
+    #[command(flatten)]
+    pub(crate) strip: StripConfig,
+
+    #[command(flatten)]
+    pub(crate) normalize: NormalizeConfig,
+
+    #[command(flatten)]
+    pub(crate) thumbnail: ThumbnailConfig,
+
+    #[command(flatten)]
+    pub(crate) job: JobConfig,
+
+    #[command(flatten)]
+    pub(crate) webhook: WebhookConfig,
+
+    #[command(flatten)]
+    pub(crate) cache: CacheConfig,
+
+    #[command(flatten)]
+    pub(crate) presign: PresignConfig,
+
+    #[command(flatten)]
+    pub(crate) quota: QuotaConfig,
+
+    #[command(flatten)]
+    pub(crate) rate_limit: RateLimitConfig,
+
+    #[command(flatten)]
+    pub(crate) replication: ReplicationConfig,
+
+    #[command(flatten)]
+    pub(crate) compression: CompressionConfig,
+
+    #[command(flatten)]
+    pub(crate) encryption: EncryptionConfig,
+
+    #[command(flatten)]
+    pub(crate) s3: S3Config,
+
+    #[command(flatten)]
+    pub(crate) azure: AzureConfig,
+
+    #[command(flatten)]
+    pub(crate) gcs: GcsConfig,
+
+    #[command(flatten)]
+    pub(crate) webdav: WebDavConfig,
+
+    #[command(flatten)]
+    pub(crate) sftp: SftpConfig,
+}
+
+/// API key attributing the uploads made with it to an owner.
+#[derive(Clone, Debug)]
+pub(crate) struct OwnerKey {
+    pub(crate) owner: String,
+    pub(crate) key: String,
+}
+
+impl FromStr for OwnerKey {
+    type Err = String;
+
+    fn from_str(pair: &str) -> Result<Self, Self::Err> {
+        match pair.split_once(':') {
+            Some((owner, key)) if !owner.is_empty() && !key.is_empty() => Ok(Self {
+                owner: owner.to_string(),
+                key: key.to_string(),
+            }),
+            _ => Err("expected owner:key".to_string()),
+        }
+    }
+}
+
+/// Available storage backends.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum StorageKind {
+    /// Local filesystem.
+    Fs,
+    /// Process memory, for tests and ephemeral deployments.
+    Memory,
+    /// Amazon S3 bucket.
+    S3,
+    /// Azure Blob Storage container.
+    Azure,
+    /// Google Cloud Storage bucket.
+    Gcs,
+    /// WebDAV collection.
+    #[value(name = "webdav")]
+    WebDav,
+    /// Directory on an SFTP server.
+    Sftp,
+}
+
+/// Uploads rejected with 409 for duplicating an active map.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum DuplicatePolicy {
+    /// Accept every upload; identical files still share a stored blob.
+    Allow,
+    /// Reject uploads with both the title and the content of a map.
+    Exact,
+    /// Reject uploads with the title of a map.
+    Title,
+    /// Reject uploads with the content of a map.
+    Content,
+}
+
+/// Handling of uploads found duplicate under the [`DuplicatePolicy`].
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum CollisionPolicy {
+    /// Reject the upload with 409.
+    Reject,
+    /// Register the upload with a numbered title suffix, e.g. `Floods (2)`; uploads
+    /// duplicating by content only are still rejected.
+    Rename,
+    /// Replace the title and file of the duplicated map, keeping its uuid.
+    Overwrite,
+    /// Register nothing and respond with the duplicated map, flagged with
+    /// `duplicate: true`.
+    Existing,
+}
+
+/// Available map metadata stores.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum MetadataKind {
+    /// Process memory only, the catalog is lost on restart.
+    Memory,
+    /// Process memory, snapshotted to a JSON file in the data directory.
+    Json,
+    /// SQLite database file in the data directory.
+    Sqlite,
+    /// PostgreSQL database, which several instances can share.
+    Postgres,
+    /// Embedded sled database in the data directory.
+    Sled,
+    /// Redis server, which several instances can share.
+    Redis,
+}
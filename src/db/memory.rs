@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+
+use axum::async_trait;
+use indexmap::IndexMap;
+use tokio::sync::{Mutex, RwLock};
+
+use super::{MetadataError, SMapRepository};
+use crate::{
+    smap::SMap,
+    snapshot,
+    wal::{Mutation, Wal},
+};
+
+/// Registered maps keyed by uuid, in registration order.
+pub(crate) type Catalog = IndexMap<String, SMap>;
+
+/// Keeps maps in process memory.
+///
+/// When opened on a snapshot, mutations go to a write-ahead log first and
+/// [`SMapRepository::checkpoint`] writes the snapshot and empties the log.
+#[derive(Default)]
+pub(crate) struct MemoryRepository {
+    /// Read-locked by lookups, so listings never wait on each other.
+    smaps: RwLock<Catalog>,
+    /// Log of mutations since the last snapshot, with the snapshot path.
+    persistence: Mutex<Option<(Wal, PathBuf)>>,
+}
+
+impl MemoryRepository {
+    /// Load the snapshot at `snapshot_path` and replay the log at `wal_path` on top of it.
+    pub(crate) async fn open(snapshot_path: &Path, wal_path: &Path) -> Result<Self, MetadataError> {
+        let mut smaps: Catalog = snapshot::load(snapshot_path)
+            .await?
+            .into_iter()
+            .map(|smap| (smap.uuid.clone(), smap))
+            .collect();
+        let (wal, mutations) = Wal::open(wal_path).await?;
+        for mutation in mutations {
+            mutation.apply(&mut smaps);
+        }
+        Ok(Self {
+            smaps: RwLock::new(smaps),
+            persistence: Mutex::new(Some((wal, snapshot_path.to_path_buf()))),
+        })
+    }
+
+    /// Log `mutation` to the write-ahead log, if any, then apply it, returning
+    /// whether it changed anything.
+    async fn apply(&self, mutation: Mutation) -> Result<bool, MetadataError> {
+        Ok(self.apply_all(vec![mutation], |_, _| true).await?[0])
+    }
+
+    /// Apply each of `mutations` for which `precondition`, given its index, holds,
+    /// without other mutations interleaving, returning for each whether it was applied.
+    async fn apply_all(
+        &self,
+        mutations: Vec<Mutation>,
+        mut precondition: impl FnMut(&Catalog, usize) -> bool,
+    ) -> Result<Vec<bool>, MetadataError> {
+        let mut persistence = self.persistence.lock().await;
+        let mut smaps = self.smaps.write().await;
+        let mut applied = Vec::with_capacity(mutations.len());
+        for (index, mutation) in mutations.into_iter().enumerate() {
+            if !precondition(&smaps, index) || !mutation.applies_to(&smaps) {
+                applied.push(false);
+                continue;
+            }
+            if let Some((wal, _)) = persistence.as_mut() {
+                wal.append(&mutation).await?;
+            }
+            applied.push(mutation.apply(&mut smaps));
+        }
+        Ok(applied)
+    }
+}
+
+#[async_trait]
+impl SMapRepository for MemoryRepository {
+    async fn list(&self) -> Result<Vec<SMap>, MetadataError> {
+        Ok(self.smaps.read().await.values().cloned().collect())
+    }
+
+    async fn get(&self, uuid: &str) -> Result<Option<SMap>, MetadataError> {
+        Ok(self.smaps.read().await.get(uuid).cloned())
+    }
+
+    async fn get_many(&self, uuids: &[String]) -> Result<Vec<Option<SMap>>, MetadataError> {
+        let smaps = self.smaps.read().await;
+        Ok(uuids.iter().map(|uuid| smaps.get(uuid).cloned()).collect())
+    }
+
+    async fn find_by_key(&self, key: &str) -> Result<Option<SMap>, MetadataError> {
+        Ok(self
+            .smaps
+            .read()
+            .await
+            .values()
+            .find(|smap| smap.key == key)
+            .cloned())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>, MetadataError> {
+        Ok(self
+            .smaps
+            .read()
+            .await
+            .values()
+            .map(|smap| smap.key.clone())
+            .collect())
+    }
+
+    async fn insert(&self, smap: &SMap) -> Result<(), MetadataError> {
+        self.apply(Mutation::Insert { smap: smap.clone() }).await?;
+        Ok(())
+    }
+
+    async fn insert_many(&self, smaps: &[SMap]) -> Result<(), MetadataError> {
+        let mutations = smaps
+            .iter()
+            .map(|smap| Mutation::Insert { smap: smap.clone() })
+            .collect();
+        self.apply_all(mutations, |_, _| true).await?;
+        Ok(())
+    }
+
+    async fn update(&self, smap: &SMap, revision: u64) -> Result<bool, MetadataError> {
+        Ok(self.update_many(&[(smap.clone(), revision)]).await?[0])
+    }
+
+    async fn delete(&self, uuid: &str) -> Result<bool, MetadataError> {
+        self.apply(Mutation::Delete {
+            uuid: uuid.to_string(),
+        })
+        .await
+    }
+
+    async fn update_many(&self, updates: &[(SMap, u64)]) -> Result<Vec<bool>, MetadataError> {
+        let mutations = updates
+            .iter()
+            .map(|(smap, _)| Mutation::Update { smap: smap.clone() })
+            .collect();
+        self.apply_all(mutations, |smaps, index| {
+            let (smap, revision) = &updates[index];
+            smaps
+                .get(&smap.uuid)
+                .is_some_and(|existing| existing.revision == *revision)
+        })
+        .await
+    }
+
+    async fn delete_many(&self, uuids: &[String]) -> Result<Vec<bool>, MetadataError> {
+        let mutations = uuids
+            .iter()
+            .map(|uuid| Mutation::Delete { uuid: uuid.clone() })
+            .collect();
+        self.apply_all(mutations, |_, _| true).await
+    }
+
+    async fn checkpoint(&self) -> Result<(), MetadataError> {
+        // Hold the log so no mutation slips between the snapshot and the truncation.
+        let mut persistence = self.persistence.lock().await;
+        if let Some((wal, path)) = persistence.as_mut() {
+            let smaps: Vec<SMap> = self.smaps.read().await.values().cloned().collect();
+            snapshot::write(&smaps, path).await?;
+            wal.truncate().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Persistence of map metadata, so the catalog survives restarts and can be
+//! shared between instances.
+
+use std::{fmt, io};
+
+use axum::async_trait;
+
+use crate::{
+    config::{Config, MetadataKind},
+    query::Filter,
+    smap::{SMap, SortField, SortOrder},
+    snapshot,
+};
+
+mod memory;
+mod redis;
+mod sled;
+mod sql;
+
+pub(crate) use self::redis::{RedisConfig, RedisDatabase};
+pub(crate) use self::sled::SledDatabase;
+pub(crate) use memory::{Catalog, MemoryRepository};
+pub(crate) use sql::{DatabaseConfig, SqlDatabase};
+
+/// Metadata store errors.
+#[derive(Debug)]
+pub(crate) enum MetadataError {
+    /// Backend misconfiguration detected at startup.
+    Config(String),
+    /// Local file failure.
+    Io(io::Error),
+    /// SQL database failure.
+    Sql(sqlx::Error),
+    /// Redis failure.
+    Redis(::redis::RedisError),
+    /// sled database failure.
+    Sled(::sled::Error),
+    /// Stored record could not be decoded.
+    Corrupt(String),
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config(msg) => write!(f, "invalid metadata store configuration: {msg}"),
+            Self::Io(err) => write!(f, "metadata i/o error: {err}"),
+            Self::Sql(err) => write!(f, "database error: {err}"),
+            Self::Redis(err) => write!(f, "redis error: {err}"),
+            Self::Sled(err) => write!(f, "sled error: {err}"),
+            Self::Corrupt(msg) => write!(f, "corrupt metadata record: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+impl From<io::Error> for MetadataError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<sqlx::Error> for MetadataError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Sql(err)
+    }
+}
+
+impl From<::redis::RedisError> for MetadataError {
+    fn from(err: ::redis::RedisError) -> Self {
+        Self::Redis(err)
+    }
+}
+
+impl From<::sled::Error> for MetadataError {
+    fn from(err: ::sled::Error) -> Self {
+        Self::Sled(err)
+    }
+}
+
+impl From<serde_json::Error> for MetadataError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Corrupt(err.to_string())
+    }
+}
+
+/// Catalog of registered maps.
+#[allow(dead_code)]
+#[async_trait]
+pub(crate) trait SMapRepository: Send + Sync {
+    /// Every stored map, in registration order.
+    async fn list(&self) -> Result<Vec<SMap>, MetadataError>;
+
+    /// Every stored map sorted by `sort` in `order`, ties broken by uuid.
+    ///
+    /// Repositories without indexes sort the maps of [`SMapRepository::list`].
+    async fn list_sorted(
+        &self,
+        sort: SortField,
+        order: SortOrder,
+    ) -> Result<Vec<SMap>, MetadataError> {
+        let mut smaps = self.list().await?;
+        smaps.sort_by(|a, b| sort.compare(a, b));
+        if order == SortOrder::Desc {
+            smaps.reverse();
+        }
+        Ok(smaps)
+    }
+
+    /// Every stored map passing `filter`, sorted by `sort` in its order if
+    /// given, else in registration order.
+    ///
+    /// Repositories without queries match the maps of [`SMapRepository::list`]
+    /// or [`SMapRepository::list_sorted`] in memory.
+    async fn list_filtered(
+        &self,
+        filter: &Filter,
+        sort: Option<(SortField, SortOrder)>,
+    ) -> Result<Vec<SMap>, MetadataError> {
+        let mut smaps = match sort {
+            Some((sort, order)) => self.list_sorted(sort, order).await?,
+            None => self.list().await?,
+        };
+        smaps.retain(|smap| filter.matches(smap));
+        Ok(smaps)
+    }
+
+    /// Map registered under `uuid`.
+    async fn get(&self, uuid: &str) -> Result<Option<SMap>, MetadataError>;
+
+    /// First registered map whose file is stored under `key`.
+    async fn find_by_key(&self, key: &str) -> Result<Option<SMap>, MetadataError>;
+
+    /// Distinct storage keys of every stored map.
+    async fn keys(&self) -> Result<Vec<String>, MetadataError>;
+
+    /// Persist a newly registered map.
+    async fn insert(&self, smap: &SMap) -> Result<(), MetadataError>;
+
+    /// Replace the map registered under `smap.uuid` if it is still at `revision`,
+    /// returning whether it was replaced.
+    async fn update(&self, smap: &SMap, revision: u64) -> Result<bool, MetadataError>;
+
+    /// Remove the map registered under `uuid`, returning whether there was one.
+    async fn delete(&self, uuid: &str) -> Result<bool, MetadataError>;
+
+    /// [`SMapRepository::get`] the map of each of `uuids`, in order.
+    ///
+    /// Repositories without batched reads fetch them one by one.
+    async fn get_many(&self, uuids: &[String]) -> Result<Vec<Option<SMap>>, MetadataError> {
+        let mut smaps = Vec::with_capacity(uuids.len());
+        for uuid in uuids {
+            smaps.push(self.get(uuid).await?);
+        }
+        Ok(smaps)
+    }
+
+    /// [`SMapRepository::insert`] every map of `smaps` in one transaction.
+    ///
+    /// Repositories without transactions insert them one by one.
+    async fn insert_many(&self, smaps: &[SMap]) -> Result<(), MetadataError> {
+        for smap in smaps {
+            self.insert(smap).await?;
+        }
+        Ok(())
+    }
+
+    /// [`SMapRepository::update`] every map with its paired revision in one
+    /// transaction, returning for each whether it was replaced.
+    ///
+    /// Repositories without transactions replace them one by one.
+    async fn update_many(&self, updates: &[(SMap, u64)]) -> Result<Vec<bool>, MetadataError> {
+        let mut replaced = Vec::with_capacity(updates.len());
+        for (smap, revision) in updates {
+            replaced.push(self.update(smap, *revision).await?);
+        }
+        Ok(replaced)
+    }
+
+    /// [`SMapRepository::delete`] every map of `uuids` in one transaction,
+    /// returning for each whether there was one.
+    ///
+    /// Repositories without transactions remove them one by one.
+    async fn delete_many(&self, uuids: &[String]) -> Result<Vec<bool>, MetadataError> {
+        let mut deleted = Vec::with_capacity(uuids.len());
+        for uuid in uuids {
+            deleted.push(self.delete(uuid).await?);
+        }
+        Ok(deleted)
+    }
+
+    /// Version of the last applied schema migration, for repositories with a schema.
+    async fn schema_version(&self) -> Result<Option<i64>, MetadataError> {
+        Ok(None)
+    }
+
+    /// Flush state the repository persists in batches.
+    async fn checkpoint(&self) -> Result<(), MetadataError> {
+        Ok(())
+    }
+}
+
+/// Open the map repository selected in `config`.
+pub(crate) async fn from_config(config: &Config) -> Result<Box<dyn SMapRepository>, MetadataError> {
+    Ok(match config.metadata {
+        MetadataKind::Memory => Box::new(MemoryRepository::default()),
+        MetadataKind::Json => Box::new(
+            MemoryRepository::open(&snapshot::path(config), &config.data_dir.join("smaps.wal"))
+                .await?,
+        ),
+        MetadataKind::Sqlite => {
+            Box::new(SqlDatabase::sqlite(&config.data_dir.join("smu.db"), &config.database).await?)
+        }
+        MetadataKind::Postgres => Box::new(SqlDatabase::postgres(&config.database).await?),
+        MetadataKind::Sled => Box::new(SledDatabase::open(&config.data_dir.join("smu.sled"))?),
+        MetadataKind::Redis => Box::new(RedisDatabase::connect(&config.redis).await?),
+    })
+}
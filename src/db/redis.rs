@@ -0,0 +1,190 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::async_trait;
+use clap::Args;
+use redis::{aio::ConnectionManager, AsyncCommands};
+
+use super::{MetadataError, SMapRepository};
+use crate::smap::SMap;
+
+/// Redis settings.
+#[derive(Args, Debug)]
+pub(crate) struct RedisConfig {
+    /// URL of the Redis server, e.g. `redis://cache:6379/0`.
+    #[arg(
+        id = "redis_url",
+        long = "redis-url",
+        env = "SMU_REDIS_URL",
+        hide_env_values = true
+    )]
+    pub(crate) url: Option<String>,
+
+    /// Prefix of every key written to Redis.
+    #[arg(
+        id = "redis_prefix",
+        long = "redis-prefix",
+        env = "SMU_REDIS_PREFIX",
+        default_value = "smu"
+    )]
+    pub(crate) prefix: String,
+}
+
+/// Replace a map record if it is still at the expected revision, moving its uuid
+/// to the index of its new storage key.
+///
+/// Keys: record, new key index. Arguments: record, revision, uuid, key index prefix.
+const UPDATE_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if not current then
+    return 0
+end
+current = cjson.decode(current)
+if (current.revision or 1) ~= tonumber(ARGV[2]) then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1])
+redis.call('SREM', ARGV[4] .. current.key, ARGV[3])
+redis.call('SADD', KEYS[2], ARGV[3])
+return 1
+"#;
+
+/// Keeps maps in Redis, so stateless instances can share them.
+///
+/// Each map is a JSON string under `<prefix>:smap:<uuid>`. The uuids are listed
+/// in the `<prefix>:smaps` sorted set, scored by registration time, and the
+/// `<prefix>:key:<key>` set holds the uuids of the maps stored under a storage key.
+pub(crate) struct RedisDatabase {
+    connection: ConnectionManager,
+    prefix: String,
+}
+
+impl RedisDatabase {
+    pub(crate) async fn connect(config: &RedisConfig) -> Result<Self, MetadataError> {
+        let url = config
+            .url
+            .as_deref()
+            .ok_or_else(|| MetadataError::Config("missing Redis URL".to_string()))?;
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            connection: ConnectionManager::new(client).await?,
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    fn smap_key(&self, uuid: &str) -> String {
+        format!("{}:smap:{uuid}", self.prefix)
+    }
+
+    fn index_key(&self, key: &str) -> String {
+        format!("{}:key:{key}", self.prefix)
+    }
+}
+
+#[async_trait]
+impl SMapRepository for RedisDatabase {
+    async fn list(&self) -> Result<Vec<SMap>, MetadataError> {
+        let mut connection = self.connection.clone();
+        let uuids: Vec<String> = connection
+            .zrange(format!("{}:smaps", self.prefix), 0, -1)
+            .await?;
+        if uuids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let keys: Vec<String> = uuids.iter().map(|uuid| self.smap_key(uuid)).collect();
+        let records: Vec<Option<String>> = connection.mget(keys).await?;
+        records
+            .into_iter()
+            .flatten()
+            .map(|record| Ok(serde_json::from_str(&record)?))
+            .collect()
+    }
+
+    async fn get(&self, uuid: &str) -> Result<Option<SMap>, MetadataError> {
+        let record: Option<String> = self.connection.clone().get(self.smap_key(uuid)).await?;
+        Ok(record
+            .map(|record| serde_json::from_str(&record))
+            .transpose()?)
+    }
+
+    async fn get_many(&self, uuids: &[String]) -> Result<Vec<Option<SMap>>, MetadataError> {
+        if uuids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<String> = uuids.iter().map(|uuid| self.smap_key(uuid)).collect();
+        let records: Vec<Option<String>> = self.connection.clone().mget(keys).await?;
+        records
+            .into_iter()
+            .map(|record| {
+                Ok(record
+                    .map(|record| serde_json::from_str(&record))
+                    .transpose()?)
+            })
+            .collect()
+    }
+
+    async fn find_by_key(&self, key: &str) -> Result<Option<SMap>, MetadataError> {
+        let uuid: Option<String> = self
+            .connection
+            .clone()
+            .srandmember(self.index_key(key))
+            .await?;
+        match uuid {
+            Some(uuid) => self.get(&uuid).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn keys(&self) -> Result<Vec<String>, MetadataError> {
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .map(|smap| smap.key)
+            .collect())
+    }
+
+    async fn insert(&self, smap: &SMap) -> Result<(), MetadataError> {
+        let registered_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as f64;
+
+        redis::pipe()
+            .atomic()
+            .set(self.smap_key(&smap.uuid), serde_json::to_string(smap)?)
+            .zadd(format!("{}:smaps", self.prefix), &smap.uuid, registered_at)
+            .sadd(self.index_key(&smap.key), &smap.uuid)
+            .query_async::<()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn update(&self, smap: &SMap, revision: u64) -> Result<bool, MetadataError> {
+        let replaced: bool = redis::Script::new(UPDATE_SCRIPT)
+            .key(self.smap_key(&smap.uuid))
+            .key(self.index_key(&smap.key))
+            .arg(serde_json::to_string(smap)?)
+            .arg(revision)
+            .arg(&smap.uuid)
+            .arg(format!("{}:key:", self.prefix))
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(replaced)
+    }
+
+    async fn delete(&self, uuid: &str) -> Result<bool, MetadataError> {
+        let Some(existing) = self.get(uuid).await? else {
+            return Ok(false);
+        };
+
+        redis::pipe()
+            .atomic()
+            .del(self.smap_key(uuid))
+            .zrem(format!("{}:smaps", self.prefix), uuid)
+            .srem(self.index_key(&existing.key), uuid)
+            .query_async::<()>(&mut self.connection.clone())
+            .await?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,184 @@
+use std::path::Path;
+
+use axum::async_trait;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Db, Transactional, Tree,
+};
+
+use super::{MetadataError, SMapRepository};
+use crate::smap::SMap;
+
+/// Keeps maps in an embedded sled database, for durability without a database server.
+///
+/// The `smaps` tree holds each map as JSON under its big-endian registration
+/// sequence number, so iterating it yields maps in registration order. The
+/// `uuids` tree maps each uuid to that sequence number.
+pub(crate) struct SledDatabase {
+    db: Db,
+    smaps: Tree,
+    uuids: Tree,
+}
+
+impl SledDatabase {
+    pub(crate) fn open(path: &Path) -> Result<Self, MetadataError> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            smaps: db.open_tree("smaps")?,
+            uuids: db.open_tree("uuids")?,
+            db,
+        })
+    }
+
+    /// Decode every stored map, in registration order.
+    fn iter(&self) -> impl Iterator<Item = Result<SMap, MetadataError>> {
+        self.smaps
+            .iter()
+            .values()
+            .map(|record| Ok(serde_json::from_slice(&record?)?))
+    }
+}
+
+fn transaction_error<E: Into<MetadataError>>(err: TransactionError<E>) -> MetadataError {
+    match err {
+        TransactionError::Abort(err) => err.into(),
+        TransactionError::Storage(err) => err.into(),
+    }
+}
+
+#[async_trait]
+impl SMapRepository for SledDatabase {
+    async fn list(&self) -> Result<Vec<SMap>, MetadataError> {
+        self.iter().collect()
+    }
+
+    async fn get(&self, uuid: &str) -> Result<Option<SMap>, MetadataError> {
+        let Some(seq) = self.uuids.get(uuid)? else {
+            return Ok(None);
+        };
+        Ok(self
+            .smaps
+            .get(seq)?
+            .map(|record| serde_json::from_slice(&record))
+            .transpose()?)
+    }
+
+    async fn find_by_key(&self, key: &str) -> Result<Option<SMap>, MetadataError> {
+        for smap in self.iter() {
+            let smap = smap?;
+            if smap.key == key {
+                return Ok(Some(smap));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn keys(&self) -> Result<Vec<String>, MetadataError> {
+        self.iter().map(|smap| Ok(smap?.key)).collect()
+    }
+
+    async fn insert(&self, smap: &SMap) -> Result<(), MetadataError> {
+        self.insert_many(std::slice::from_ref(smap)).await
+    }
+
+    async fn insert_many(&self, smaps: &[SMap]) -> Result<(), MetadataError> {
+        let mut records = Vec::with_capacity(smaps.len());
+        for smap in smaps {
+            let seq = self.db.generate_id()?.to_be_bytes();
+            records.push((seq, serde_json::to_vec(smap)?));
+        }
+        (&self.smaps, &self.uuids)
+            .transaction(|(tree, uuids)| {
+                for (smap, (seq, record)) in smaps.iter().zip(&records) {
+                    tree.insert(&seq[..], record.as_slice())?;
+                    uuids.insert(smap.uuid.as_bytes(), &seq[..])?;
+                }
+                Ok::<_, ConflictableTransactionError>(())
+            })
+            .map_err(transaction_error)
+    }
+
+    async fn update(&self, smap: &SMap, revision: u64) -> Result<bool, MetadataError> {
+        let Some(seq) = self.uuids.get(&smap.uuid)? else {
+            return Ok(false);
+        };
+        let Some(current) = self.smaps.get(&seq)? else {
+            return Ok(false);
+        };
+        let existing: SMap = serde_json::from_slice(&current)?;
+        if existing.revision != revision {
+            return Ok(false);
+        }
+        // Swapping against the decoded record catches updates racing with this one.
+        Ok(self
+            .smaps
+            .compare_and_swap(seq, Some(current), Some(serde_json::to_vec(smap)?))?
+            .is_ok())
+    }
+
+    async fn delete(&self, uuid: &str) -> Result<bool, MetadataError> {
+        (&self.smaps, &self.uuids)
+            .transaction(|(smaps, uuids)| {
+                let Some(seq) = uuids.remove(uuid.as_bytes())? else {
+                    return Ok(false);
+                };
+                smaps.remove(seq)?;
+                Ok::<_, ConflictableTransactionError>(true)
+            })
+            .map_err(transaction_error)
+    }
+
+    async fn update_many(&self, updates: &[(SMap, u64)]) -> Result<Vec<bool>, MetadataError> {
+        let records = updates
+            .iter()
+            .map(|(smap, _)| serde_json::to_vec(smap))
+            .collect::<Result<Vec<_>, _>>()?;
+        (&self.smaps, &self.uuids)
+            .transaction(|(smaps, uuids)| {
+                let mut replaced = Vec::with_capacity(updates.len());
+                for ((smap, revision), record) in updates.iter().zip(&records) {
+                    let Some(seq) = uuids.get(smap.uuid.as_bytes())? else {
+                        replaced.push(false);
+                        continue;
+                    };
+                    let Some(current) = smaps.get(&seq)? else {
+                        replaced.push(false);
+                        continue;
+                    };
+                    let existing: SMap = serde_json::from_slice(&current).map_err(|err| {
+                        ConflictableTransactionError::Abort(MetadataError::from(err))
+                    })?;
+                    if existing.revision != *revision {
+                        replaced.push(false);
+                        continue;
+                    }
+                    smaps.insert(seq, record.as_slice())?;
+                    replaced.push(true);
+                }
+                Ok(replaced)
+            })
+            .map_err(transaction_error)
+    }
+
+    async fn delete_many(&self, uuids: &[String]) -> Result<Vec<bool>, MetadataError> {
+        (&self.smaps, &self.uuids)
+            .transaction(|(smaps, index)| {
+                let mut deleted = Vec::with_capacity(uuids.len());
+                for uuid in uuids {
+                    let Some(seq) = index.remove(uuid.as_bytes())? else {
+                        deleted.push(false);
+                        continue;
+                    };
+                    smaps.remove(seq)?;
+                    deleted.push(true);
+                }
+                Ok::<_, ConflictableTransactionError>(deleted)
+            })
+            .map_err(transaction_error)
+    }
+
+    async fn checkpoint(&self) -> Result<(), MetadataError> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,588 @@
+use std::path::Path;
+
+use axum::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::Args;
+use sqlx::{
+    any::{AnyArguments, AnyPoolOptions, AnyRow},
+    migrate::Migrator,
+    query::Query,
+    Any, AnyPool, AssertSqlSafe, Row,
+};
+
+use super::{MetadataError, SMapRepository};
+use crate::{
+    query::{Field, Filter, Op, Value},
+    smap::{SMap, SortField, SortOrder},
+};
+
+/// Database settings.
+#[derive(Args, Debug)]
+pub(crate) struct DatabaseConfig {
+    /// URL of the PostgreSQL database, e.g. `postgres://smu:secret@db/smu`.
+    #[arg(
+        id = "database_url",
+        long = "database-url",
+        env = "SMU_DATABASE_URL",
+        hide_env_values = true
+    )]
+    pub(crate) url: Option<String>,
+
+    /// Maximum number of pooled database connections.
+    #[arg(
+        id = "database_max_connections",
+        long = "database-max-connections",
+        env = "SMU_DATABASE_MAX_CONNECTIONS",
+        default_value_t = 10
+    )]
+    pub(crate) max_connections: u32,
+}
+
+/// Schema and statements that differ between SQL databases.
+struct Dialect {
+    /// Versioned schema migrations, applied on startup.
+    migrator: Migrator,
+    /// Every map, in registration order.
+    ///
+    /// Like `list_sorted`, a single `ORDER BY` follows `FROM smaps`, so that
+    /// filters insert their `WHERE` clause before it.
+    list: &'static str,
+    /// First registered map with a given key.
+    find_by_key: &'static str,
+    /// Every map sorted by a field, then by uuid.
+    list_sorted: fn(SortField, SortOrder) -> &'static str,
+    /// Collation appended to timestamp comparisons, to compare them bytewise.
+    byte_order: &'static str,
+}
+
+/// SQLite keeps the registration order in the implicit rowid.
+static SQLITE: Dialect = Dialect {
+    migrator: sqlx::migrate!("migrations/sqlite"),
+    list: "SELECT * FROM smaps ORDER BY rowid",
+    find_by_key: "SELECT * FROM smaps
+        WHERE key = $1 ORDER BY rowid LIMIT 1",
+    list_sorted: |sort, order| match (sort, order) {
+        (SortField::Title, SortOrder::Asc) => "SELECT * FROM smaps ORDER BY title, uuid",
+        (SortField::Title, SortOrder::Desc) => "SELECT * FROM smaps ORDER BY title DESC, uuid DESC",
+        (SortField::CreatedAt, SortOrder::Asc) => "SELECT * FROM smaps ORDER BY created_at, uuid",
+        (SortField::CreatedAt, SortOrder::Desc) => {
+            "SELECT * FROM smaps ORDER BY created_at DESC, uuid DESC"
+        }
+        (SortField::UpdatedAt, SortOrder::Asc) => "SELECT * FROM smaps ORDER BY updated_at, uuid",
+        (SortField::UpdatedAt, SortOrder::Desc) => {
+            "SELECT * FROM smaps ORDER BY updated_at DESC, uuid DESC"
+        }
+        (SortField::Size, SortOrder::Asc) => "SELECT * FROM smaps ORDER BY size, uuid",
+        (SortField::Size, SortOrder::Desc) => "SELECT * FROM smaps ORDER BY size DESC, uuid DESC",
+    },
+    byte_order: "",
+};
+
+/// PostgreSQL keeps the registration order in a serial `id` column.
+static POSTGRES: Dialect = Dialect {
+    migrator: sqlx::migrate!("migrations/postgres"),
+    list: "SELECT * FROM smaps ORDER BY id",
+    find_by_key: "SELECT * FROM smaps
+        WHERE key = $1 ORDER BY id LIMIT 1",
+    // Byte order, as in SQLite and in memory, rather than the database locale.
+    list_sorted: |sort, order| match (sort, order) {
+        (SortField::Title, SortOrder::Asc) => {
+            r#"SELECT * FROM smaps ORDER BY title COLLATE "C", uuid COLLATE "C""#
+        }
+        (SortField::Title, SortOrder::Desc) => {
+            r#"SELECT * FROM smaps ORDER BY title COLLATE "C" DESC, uuid COLLATE "C" DESC"#
+        }
+        (SortField::CreatedAt, SortOrder::Asc) => {
+            r#"SELECT * FROM smaps ORDER BY created_at COLLATE "C", uuid COLLATE "C""#
+        }
+        (SortField::CreatedAt, SortOrder::Desc) => {
+            r#"SELECT * FROM smaps ORDER BY created_at COLLATE "C" DESC, uuid COLLATE "C" DESC"#
+        }
+        (SortField::UpdatedAt, SortOrder::Asc) => {
+            r#"SELECT * FROM smaps ORDER BY updated_at COLLATE "C", uuid COLLATE "C""#
+        }
+        (SortField::UpdatedAt, SortOrder::Desc) => {
+            r#"SELECT * FROM smaps ORDER BY updated_at COLLATE "C" DESC, uuid COLLATE "C" DESC"#
+        }
+        (SortField::Size, SortOrder::Asc) => {
+            r#"SELECT * FROM smaps ORDER BY size, uuid COLLATE "C""#
+        }
+        (SortField::Size, SortOrder::Desc) => {
+            r#"SELECT * FROM smaps ORDER BY size DESC, uuid COLLATE "C" DESC"#
+        }
+    },
+    byte_order: r#" COLLATE "C""#,
+};
+
+/// Pool of connections to a database holding one row per registered map.
+pub(crate) struct SqlDatabase {
+    pool: AnyPool,
+    dialect: &'static Dialect,
+}
+
+impl SqlDatabase {
+    /// Open the SQLite database at `path`, creating the file if missing and migrating its schema.
+    pub(crate) async fn sqlite(
+        path: &Path,
+        config: &DatabaseConfig,
+    ) -> Result<Self, MetadataError> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        Self::connect(&url, config, &SQLITE).await
+    }
+
+    /// Connect to the configured PostgreSQL database and migrate its schema.
+    pub(crate) async fn postgres(config: &DatabaseConfig) -> Result<Self, MetadataError> {
+        let url = config
+            .url
+            .as_deref()
+            .ok_or_else(|| MetadataError::Config("missing database URL".to_string()))?;
+        Self::connect(url, config, &POSTGRES).await
+    }
+
+    async fn connect(
+        url: &str,
+        config: &DatabaseConfig,
+        dialect: &'static Dialect,
+    ) -> Result<Self, MetadataError> {
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect(url)
+            .await?;
+        dialect
+            .migrator
+            .run(&pool)
+            .await
+            .map_err(sqlx::Error::from)?;
+        Ok(Self { pool, dialect })
+    }
+}
+
+#[async_trait]
+impl SMapRepository for SqlDatabase {
+    async fn list(&self) -> Result<Vec<SMap>, MetadataError> {
+        sqlx::query(self.dialect.list)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(from_row)
+            .collect()
+    }
+
+    async fn list_sorted(
+        &self,
+        sort: SortField,
+        order: SortOrder,
+    ) -> Result<Vec<SMap>, MetadataError> {
+        sqlx::query((self.dialect.list_sorted)(sort, order))
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(from_row)
+            .collect()
+    }
+
+    async fn list_filtered(
+        &self,
+        filter: &Filter,
+        sort: Option<(SortField, SortOrder)>,
+    ) -> Result<Vec<SMap>, MetadataError> {
+        let statement = match sort {
+            Some((sort, order)) => (self.dialect.list_sorted)(sort, order),
+            None => self.dialect.list,
+        };
+        let mut binds = Vec::new();
+        let statement = match condition(filter, self.dialect, &mut binds) {
+            Some(condition) => {
+                statement.replacen(" ORDER BY ", &format!(" WHERE {condition} ORDER BY "), 1)
+            }
+            None => statement.to_string(),
+        };
+        // Values are bound: the statement only joins static fragments.
+        let mut query = sqlx::query(AssertSqlSafe(statement));
+        for bind in binds {
+            query = match bind {
+                Bind::Text(text) => query.bind(text),
+                Bind::Integer(number) => query.bind(number),
+            };
+        }
+        let mut smaps = query
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(from_row)
+            .collect::<Result<Vec<_>, _>>()?;
+        // The condition may select more maps than the filter, never fewer.
+        smaps.retain(|smap| filter.matches(smap));
+        Ok(smaps)
+    }
+
+    async fn get(&self, uuid: &str) -> Result<Option<SMap>, MetadataError> {
+        sqlx::query("SELECT * FROM smaps WHERE uuid = $1")
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await?
+            .as_ref()
+            .map(from_row)
+            .transpose()
+    }
+
+    async fn find_by_key(&self, key: &str) -> Result<Option<SMap>, MetadataError> {
+        sqlx::query(self.dialect.find_by_key)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?
+            .as_ref()
+            .map(from_row)
+            .transpose()
+    }
+
+    async fn keys(&self) -> Result<Vec<String>, MetadataError> {
+        sqlx::query("SELECT DISTINCT key FROM smaps")
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| Ok(row.try_get("key")?))
+            .collect()
+    }
+
+    async fn insert(&self, smap: &SMap) -> Result<(), MetadataError> {
+        insert_query(smap)?.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn insert_many(&self, smaps: &[SMap]) -> Result<(), MetadataError> {
+        let mut transaction = self.pool.begin().await?;
+        for smap in smaps {
+            insert_query(smap)?.execute(&mut *transaction).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn update(&self, smap: &SMap, revision: u64) -> Result<bool, MetadataError> {
+        let result = update_query(smap, revision)?.execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete(&self, uuid: &str) -> Result<bool, MetadataError> {
+        let result = delete_query(uuid).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn update_many(&self, updates: &[(SMap, u64)]) -> Result<Vec<bool>, MetadataError> {
+        let mut transaction = self.pool.begin().await?;
+        let mut replaced = Vec::with_capacity(updates.len());
+        for (smap, revision) in updates {
+            let result = update_query(smap, *revision)?
+                .execute(&mut *transaction)
+                .await?;
+            replaced.push(result.rows_affected() > 0);
+        }
+        transaction.commit().await?;
+        Ok(replaced)
+    }
+
+    async fn delete_many(&self, uuids: &[String]) -> Result<Vec<bool>, MetadataError> {
+        let mut transaction = self.pool.begin().await?;
+        let mut deleted = Vec::with_capacity(uuids.len());
+        for uuid in uuids {
+            let result = delete_query(uuid).execute(&mut *transaction).await?;
+            deleted.push(result.rows_affected() > 0);
+        }
+        transaction.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn schema_version(&self) -> Result<Option<i64>, MetadataError> {
+        let row = sqlx::query("SELECT MAX(version) AS version FROM _sqlx_migrations WHERE success")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.try_get("version")?)
+    }
+}
+
+fn insert_query(smap: &SMap) -> Result<Query<'_, Any, AnyArguments>, MetadataError> {
+    let bbox = smap.bbox.as_ref().map(serde_json::to_string).transpose()?;
+    let scan = smap.scan.as_ref().map(serde_json::to_string).transpose()?;
+    let thumbnails = smap
+        .thumbnails
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let processing_status = smap
+        .processing_status
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let cache_control = smap
+        .cache_control
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    Ok(sqlx::query(
+        "INSERT INTO smaps
+         (uuid, title, key, hash, size, stored_size, deleted_at, created_at, updated_at,
+          revision, description, tags, content_type, owner, bbox, scan, thumbnails, width,
+          height, original_content_type, expires_at, processing_status, cache_control)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+                 $19, $20, $21, $22, $23)",
+    )
+    .bind(&smap.uuid)
+    .bind(&smap.title)
+    .bind(&smap.key)
+    .bind(&smap.hash)
+    .bind(smap.size as i64)
+    .bind(smap.stored_size as i64)
+    .bind(smap.deleted_at.map(encode_timestamp))
+    .bind(encode_timestamp(smap.created_at))
+    .bind(encode_timestamp(smap.updated_at))
+    .bind(smap.revision as i64)
+    .bind(&smap.description)
+    .bind(serde_json::to_string(&smap.tags)?)
+    .bind(&smap.content_type)
+    .bind(&smap.owner)
+    .bind(bbox)
+    .bind(scan)
+    .bind(thumbnails)
+    .bind(smap.width.map(i64::from))
+    .bind(smap.height.map(i64::from))
+    .bind(&smap.original_content_type)
+    .bind(smap.expires_at.map(encode_timestamp))
+    .bind(processing_status)
+    .bind(cache_control))
+}
+
+/// Replace the row of `smap` if it is still at `revision`.
+fn update_query(smap: &SMap, revision: u64) -> Result<Query<'_, Any, AnyArguments>, MetadataError> {
+    let bbox = smap.bbox.as_ref().map(serde_json::to_string).transpose()?;
+    let scan = smap.scan.as_ref().map(serde_json::to_string).transpose()?;
+    let thumbnails = smap
+        .thumbnails
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let processing_status = smap
+        .processing_status
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let cache_control = smap
+        .cache_control
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    Ok(sqlx::query(
+        "UPDATE smaps
+         SET title = $2, key = $3, hash = $4, size = $5, stored_size = $6, deleted_at = $7,
+             created_at = $8, updated_at = $9, revision = $10, description = $11, tags = $12,
+             content_type = $13, owner = $14, bbox = $15, scan = $16, thumbnails = $17,
+             width = $18, height = $19, original_content_type = $20, expires_at = $21,
+             processing_status = $22, cache_control = $23
+         WHERE uuid = $1 AND revision = $24",
+    )
+    .bind(&smap.uuid)
+    .bind(&smap.title)
+    .bind(&smap.key)
+    .bind(&smap.hash)
+    .bind(smap.size as i64)
+    .bind(smap.stored_size as i64)
+    .bind(smap.deleted_at.map(encode_timestamp))
+    .bind(encode_timestamp(smap.created_at))
+    .bind(encode_timestamp(smap.updated_at))
+    .bind(smap.revision as i64)
+    .bind(&smap.description)
+    .bind(serde_json::to_string(&smap.tags)?)
+    .bind(&smap.content_type)
+    .bind(&smap.owner)
+    .bind(bbox)
+    .bind(scan)
+    .bind(thumbnails)
+    .bind(smap.width.map(i64::from))
+    .bind(smap.height.map(i64::from))
+    .bind(&smap.original_content_type)
+    .bind(smap.expires_at.map(encode_timestamp))
+    .bind(processing_status)
+    .bind(cache_control)
+    .bind(revision as i64))
+}
+
+fn delete_query(uuid: &str) -> Query<'_, Any, AnyArguments> {
+    sqlx::query("DELETE FROM smaps WHERE uuid = $1").bind(uuid)
+}
+
+fn from_row(row: &AnyRow) -> Result<SMap, MetadataError> {
+    Ok(SMap {
+        uuid: row.try_get("uuid")?,
+        title: row.try_get("title")?,
+        key: row.try_get("key")?,
+        hash: row.try_get("hash")?,
+        size: row.try_get::<i64, _>("size")? as u64,
+        stored_size: row.try_get::<i64, _>("stored_size")? as u64,
+        content_type: row.try_get("content_type")?,
+        original_content_type: row.try_get("original_content_type")?,
+        width: row
+            .try_get::<Option<i64>, _>("width")?
+            .map(|width| width as u32),
+        height: row
+            .try_get::<Option<i64>, _>("height")?
+            .map(|height| height as u32),
+        deleted_at: row
+            .try_get::<Option<String>, _>("deleted_at")?
+            .as_deref()
+            .map(decode_timestamp)
+            .transpose()?,
+        created_at: decode_timestamp(&row.try_get::<String, _>("created_at")?)?,
+        updated_at: decode_timestamp(&row.try_get::<String, _>("updated_at")?)?,
+        expires_at: row
+            .try_get::<Option<String>, _>("expires_at")?
+            .as_deref()
+            .map(decode_timestamp)
+            .transpose()?,
+        revision: row.try_get::<i64, _>("revision")? as u64,
+        description: row.try_get("description")?,
+        tags: serde_json::from_str(&row.try_get::<String, _>("tags")?)?,
+        owner: row.try_get("owner")?,
+        bbox: row
+            .try_get::<Option<String>, _>("bbox")?
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?,
+        scan: row
+            .try_get::<Option<String>, _>("scan")?
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?,
+        thumbnails: row
+            .try_get::<Option<String>, _>("thumbnails")?
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?,
+        processing_status: row
+            .try_get::<Option<String>, _>("processing_status")?
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?,
+        cache_control: row
+            .try_get::<Option<String>, _>("cache_control")?
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?,
+        links: None,
+        duplicate: false,
+    })
+}
+
+/// Value bound to a placeholder of a filter condition.
+enum Bind {
+    Text(String),
+    Integer(i64),
+}
+
+/// Condition selecting at least the maps passing `filter`, its values pushed
+/// onto `binds`, if any part of it translates to SQL.
+///
+/// Operands of `AND` that do not translate are left out, to be matched in
+/// memory. Contains comparisons and tags are never translated, as `LOWER` and
+/// `LIKE` fold case differently than Rust and tags are stored as JSON.
+fn condition(filter: &Filter, dialect: &Dialect, binds: &mut Vec<Bind>) -> Option<String> {
+    match filter {
+        Filter::And(left, right) => match (
+            condition(left, dialect, binds),
+            condition(right, dialect, binds),
+        ) {
+            (Some(left), Some(right)) => Some(format!("({left} AND {right})")),
+            (left, right) => left.or(right),
+        },
+        filter => exact_condition(filter, dialect, binds),
+    }
+}
+
+/// Condition selecting exactly the maps passing `filter`, its values pushed
+/// onto `binds`, or none, leaving `binds` untouched, if some part of it does
+/// not translate.
+fn exact_condition(filter: &Filter, dialect: &Dialect, binds: &mut Vec<Bind>) -> Option<String> {
+    let pushed = binds.len();
+    let condition = match filter {
+        Filter::And(left, right) => exact_condition(left, dialect, binds)
+            .zip(exact_condition(right, dialect, binds))
+            .map(|(left, right)| format!("({left} AND {right})")),
+        Filter::Or(left, right) => exact_condition(left, dialect, binds)
+            .zip(exact_condition(right, dialect, binds))
+            .map(|(left, right)| format!("({left} OR {right})")),
+        Filter::Not(filter) => {
+            exact_condition(filter, dialect, binds).map(|filter| format!("NOT {filter}"))
+        }
+        Filter::Compare(field, op, value) => comparison(*field, *op, value, dialect, binds),
+    };
+    if condition.is_none() {
+        binds.truncate(pushed);
+    }
+    condition
+}
+
+fn comparison(
+    field: Field,
+    op: Op,
+    value: &Value,
+    dialect: &Dialect,
+    binds: &mut Vec<Bind>,
+) -> Option<String> {
+    let column = match field {
+        Field::Uuid => "uuid",
+        Field::Title => "title",
+        Field::Description => "description",
+        Field::ContentType => "content_type",
+        Field::Owner => "owner",
+        Field::Size => "size",
+        Field::Revision => "revision",
+        Field::CreatedAt => "created_at",
+        Field::UpdatedAt => "updated_at",
+        Field::Tags => return None,
+    };
+    let operator = match op {
+        Op::Eq => "=",
+        Op::Ne => "<>",
+        Op::Lt => "<",
+        Op::Le => "<=",
+        Op::Gt => ">",
+        Op::Ge => ">=",
+        Op::Contains => return None,
+    };
+    let (bind, collation) = match value {
+        Value::Text(text) => (Bind::Text(text.clone()), ""),
+        Value::Number(number) => (Bind::Integer(i64::try_from(*number).ok()?), ""),
+        Value::Time(time) => {
+            // Stored timestamps have millisecond precision: finer times would
+            // compare differently once truncated.
+            let encoded = encode_timestamp(*time);
+            if decode_timestamp(&encoded).ok()? != *time {
+                return None;
+            }
+            (Bind::Text(encoded), dialect.byte_order)
+        }
+    };
+    binds.push(bind);
+    let placeholder = format!("${}", binds.len());
+    Some(match field {
+        // Maps without the field only differ from values.
+        Field::Description | Field::Owner if op == Op::Ne => {
+            format!("({column} IS NULL OR {column} <> {placeholder})")
+        }
+        _ => format!("{column}{collation} {operator} {placeholder}"),
+    })
+}
+
+/// Timestamps are stored as RFC 3339 text, which sorts chronologically.
+fn encode_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn decode_timestamp(text: &str) -> Result<DateTime<Utc>, MetadataError> {
+    DateTime::parse_from_rfc3339(text)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|err| MetadataError::Corrupt(format!("invalid timestamp {text:?}: {err}")))
+}
@@ -0,0 +1,65 @@
+//! Removal of maps past their expiry, such as situational snapshots that go
+//! stale within days.
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use tokio::time::{self, Instant};
+
+use crate::{
+    db::MetadataError,
+    smap::{self, Modified, Store},
+    storage::StorageBackend,
+};
+
+/// Seconds between sweeps.
+const SWEEP_INTERVAL: u64 = 60;
+
+/// Permanently remove maps past their expiry, trashed or not, returning their uuids.
+///
+/// Their sidecars are deleted along with their files, unless other maps share them.
+pub(crate) async fn sweep(
+    store: &Store,
+    storage: &dyn StorageBackend,
+) -> Result<Vec<String>, MetadataError> {
+    let now = Utc::now();
+
+    let mut removed = Vec::new();
+    let expired = store
+        .list_all()
+        .await?
+        .into_iter()
+        .filter(|smap| smap.expires_at.is_some_and(|expires_at| expires_at <= now));
+    for smap in expired {
+        // Maps changed since listing are checked again next sweep.
+        let Modified::Updated(smap) = store.take(&smap.uuid, Some(smap.revision)).await? else {
+            continue;
+        };
+        if let Err(err) = smap::discard_removed(store, storage, &smap).await {
+            eprintln!(
+                "discarding file of expired map {} failed: {err:?}",
+                smap.uuid
+            );
+        }
+        removed.push(smap.uuid);
+    }
+    Ok(removed)
+}
+
+/// Run [`sweep`] every minute in the background.
+pub(crate) fn spawn(store: Arc<Store>, storage: Arc<dyn StorageBackend>) {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(SWEEP_INTERVAL);
+        let mut ticker = time::interval_at(Instant::now(), period);
+        loop {
+            ticker.tick().await;
+            match sweep(&store, storage.as_ref()).await {
+                Ok(removed) if !removed.is_empty() => {
+                    println!("expiry sweep removed {} maps", removed.len())
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("expiry sweep failed: {err}"),
+            }
+        }
+    });
+}
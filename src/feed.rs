@@ -0,0 +1,145 @@
+//! Atom feed of the newest maps, for partners to subscribe to new releases.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::header::CONTENT_TYPE;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::smap::{self, BaseUrl, SMap, SortField, SortOrder, Store};
+
+/// Entries in a feed unless requested otherwise.
+fn default_limit() -> usize {
+    50
+}
+
+/// Feed parameters.
+#[derive(Deserialize, IntoParams)]
+pub(super) struct FeedQuery {
+    /// Newest maps to include, at most 1000.
+    #[serde(default = "default_limit")]
+    #[param(default = 50, maximum = 1000, minimum = 1)]
+    limit: usize,
+}
+
+/// Feed of Static maps
+///
+/// Atom feed of the newest static maps, with their titles, timestamps, tags and
+/// download links.
+#[utoipa::path(
+    get,
+    path = "/smap/feed.atom",
+    params(FeedQuery),
+    responses(
+        (status = 200, description = "Atom feed of the newest maps", content_type = "application/atom+xml", body = String),
+        (status = 500, description = "Metadata store unavailable", body = SMapError)
+    )
+)]
+pub(super) async fn atom_feed(
+    State(store): State<Arc<Store>>,
+    Query(query): Query<FeedQuery>,
+    base: BaseUrl,
+) -> impl IntoResponse {
+    let mut smaps = match store
+        .list_sorted(SortField::CreatedAt, SortOrder::Desc)
+        .await
+    {
+        Ok(smaps) => smaps,
+        Err(err) => return smap::database_error(err).into_response(),
+    };
+    smaps.truncate(query.limit.clamp(1, smap::MAX_LIMIT));
+
+    (
+        [(CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        atom(&base, &smaps),
+    )
+        .into_response()
+}
+
+/// Atom document listing `smaps`.
+fn atom(base: &BaseUrl, smaps: &[SMap]) -> String {
+    let updated = smaps
+        .iter()
+        .map(|smap| smap.updated_at)
+        .max()
+        .unwrap_or_else(Utc::now);
+    let feed = base.join("/smap/feed.atom");
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <id>{}</id>\n", escape(&feed)));
+    xml.push_str("  <title>Static maps</title>\n");
+    // Atom requires an author for entries without their own, e.g. anonymous uploads.
+    xml.push_str("  <author><name>Static map service</name></author>\n");
+    xml.push_str(&format!("  <updated>{}</updated>\n", timestamp(updated)));
+    xml.push_str(&format!(
+        "  <link rel=\"self\" type=\"application/atom+xml\" href=\"{}\"/>\n",
+        escape(&feed)
+    ));
+    for smap in smaps {
+        let this = base.join(&format!("/smap/{}", smap.uuid));
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <id>urn:uuid:{}</id>\n", escape(&smap.uuid)));
+        xml.push_str(&format!("    <title>{}</title>\n", escape(&smap.title)));
+        xml.push_str(&format!(
+            "    <published>{}</published>\n",
+            timestamp(smap.created_at)
+        ));
+        xml.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            timestamp(smap.updated_at)
+        ));
+        if let Some(owner) = &smap.owner {
+            xml.push_str(&format!(
+                "    <author><name>{}</name></author>\n",
+                escape(owner)
+            ));
+        }
+        xml.push_str(&format!(
+            "    <link rel=\"enclosure\" type=\"{}\" length=\"{}\" href=\"{}/file\"/>\n",
+            escape(&smap.content_type),
+            smap.size,
+            escape(&this)
+        ));
+        xml.push_str(&format!(
+            "    <link rel=\"alternate\" type=\"application/json\" href=\"{}\"/>\n",
+            escape(&this)
+        ));
+        for tag in &smap.tags {
+            xml.push_str(&format!("    <category term=\"{}\"/>\n", escape(tag)));
+        }
+        if let Some(description) = &smap.description {
+            xml.push_str(&format!("    <summary>{}</summary>\n", escape(description)));
+        }
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// `text` escaped for XML element content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters other than tab and newlines are not allowed in XML.
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,152 @@
+//! Checks of the names of uploaded files, refusing extensions that browsers or
+//! web servers may run, and names disguising one behind a map extension; and
+//! names given to downloaded files.
+
+use clap::Args;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Extensions of map files.
+const MAP_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "tif", "tiff", "webp", "pdf"];
+
+/// Characters kept as is in `filename*` parameters, as RFC 8187 `attr-char`.
+const ATTR_CHARS: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Longest stem of download file names, in characters.
+const MAX_STEM_LEN: usize = 100;
+
+/// File name settings.
+#[derive(Args, Debug)]
+pub(crate) struct FilenameConfig {
+    /// Comma-separated extensions of the file names of uploads to refuse.
+    #[arg(
+        long = "blocked-extensions",
+        env = "SMU_BLOCKED_EXTENSIONS",
+        value_delimiter = ',',
+        default_value = "exe,com,bat,cmd,scr,msi,dll,jar,js,vbs,ps1,sh,php,htm,html,xhtml,svg"
+    )]
+    pub(crate) blocked_extensions: Vec<String>,
+
+    /// Accept file names with several extensions, such as `map.png.exe`, as
+    /// long as the last one is not blocked.
+    #[arg(long = "allow-double-extensions", env = "SMU_ALLOW_DOUBLE_EXTENSIONS")]
+    pub(crate) allow_double_extensions: bool,
+}
+
+impl FilenameConfig {
+    fn blocked(&self, extension: &str) -> bool {
+        self.blocked_extensions.iter().any(|blocked| {
+            blocked
+                .trim()
+                .trim_start_matches('.')
+                .eq_ignore_ascii_case(extension)
+        })
+    }
+
+    /// Why an upload of a file named `name` by the client is refused, if it is.
+    pub(crate) fn check(&self, name: &str) -> Result<(), String> {
+        // Without directories, nor the trailing dots and spaces Windows ignores.
+        let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+        let base = base.trim_end_matches(['.', ' ']);
+        let mut extensions: Vec<String> = base
+            .split('.')
+            .skip(1)
+            .map(|extension| extension.trim().to_ascii_lowercase())
+            .collect();
+        let Some(last) = extensions.pop() else {
+            return Ok(());
+        };
+
+        if self.blocked(&last) {
+            return Err(format!("files with extension .{last} are not accepted"));
+        }
+        if self.allow_double_extensions {
+            return Ok(());
+        }
+        if let Some(inner) = extensions.iter().find(|extension| self.blocked(extension)) {
+            return Err(format!("file name {name:?} hides extension .{inner}"));
+        }
+        match extensions.last() {
+            Some(previous)
+                if MAP_EXTENSIONS.contains(&previous.as_str())
+                    && !MAP_EXTENSIONS.contains(&last.as_str()) =>
+            {
+                Err(format!(
+                    "file name {name:?} disguises extension .{last} as .{previous}"
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Extension of map files of media type `content_type`.
+fn extension_of(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "image/png" => Some("png"),
+        "image/jpeg" => Some("jpg"),
+        "image/gif" => Some("gif"),
+        "image/tiff" => Some("tif"),
+        "image/webp" => Some("webp"),
+        "application/pdf" => Some("pdf"),
+        _ => None,
+    }
+}
+
+/// Safe file name of a map titled `title` with a file of type `content_type`.
+///
+/// Letters and digits are kept, other characters collapse into underscores, so
+/// names hold no path separators, quotes or control characters.
+fn download_name(title: &str, content_type: &str) -> String {
+    let mut stem = String::new();
+    for c in title.chars() {
+        if c.is_alphanumeric() || c == '-' || c == '.' {
+            stem.push(c);
+        } else if !stem.ends_with('_') {
+            stem.push('_');
+        }
+    }
+    let stem: String = stem
+        .trim_matches(['_', '.'])
+        .chars()
+        .take(MAX_STEM_LEN)
+        .collect();
+    let stem = match stem.trim_end_matches(['_', '.']) {
+        "" => "map",
+        stem => stem,
+    };
+    match extension_of(content_type) {
+        Some(extension) => format!("{stem}.{extension}"),
+        None => stem.to_string(),
+    }
+}
+
+/// `Content-Disposition` of the file of a map titled `title`, of type
+/// `content_type`: an attachment when `download` is set, shown inline otherwise.
+///
+/// Names beyond ASCII are sent as `filename*`, with an ASCII `filename` for
+/// clients not supporting it.
+pub(crate) fn content_disposition(title: &str, content_type: &str, download: bool) -> String {
+    let disposition = if download { "attachment" } else { "inline" };
+    let name = download_name(title, content_type);
+    if name.is_ascii() {
+        return format!("{disposition}; filename=\"{name}\"");
+    }
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let encoded = utf8_percent_encode(&name, ATTR_CHARS);
+    format!("{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
@@ -0,0 +1,99 @@
+//! Garbage collection of stored files no map refers to anymore.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use tokio::time::{self, Instant};
+
+use crate::{
+    db::MetadataError,
+    presign::STAGING_PREFIX,
+    rescan,
+    smap::Store,
+    storage::{StorageBackend, StorageError},
+    thumbnail::Thumbnails,
+};
+
+/// Garbage collection errors.
+#[derive(Debug)]
+pub(crate) enum GcError {
+    /// Stored objects could not be listed or deleted.
+    Storage(StorageError),
+    /// Referenced keys could not be read from the metadata store.
+    Database(MetadataError),
+}
+
+impl fmt::Display for GcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Storage(err) => write!(f, "{err}"),
+            Self::Database(err) => write!(f, "metadata store failed: {err}"),
+        }
+    }
+}
+
+impl From<StorageError> for GcError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<MetadataError> for GcError {
+    fn from(err: MetadataError) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Delete every stored object not referenced by a map, returning the removed keys.
+pub(crate) async fn collect(
+    store: &Store,
+    storage: &dyn StorageBackend,
+) -> Result<Vec<String>, GcError> {
+    let keys = storage.list().await?;
+    let mut referenced = store.referenced_keys().await?;
+    // Read after listing: maps record their sidecar and thumbnails once registered.
+    for smap in store.list_all().await? {
+        referenced.insert(rescan::sidecar_key(&smap.uuid));
+        // Thumbnails are stored before being recorded in their map.
+        let thumbnails = Thumbnails::of(&smap.hash);
+        referenced.extend(thumbnails.keys().map(str::to_string));
+    }
+
+    let mut removed = Vec::new();
+    for key in keys {
+        // Direct uploads are discarded once expired, if not finalized before.
+        if referenced.contains(&key) || key.starts_with(STAGING_PREFIX) {
+            continue;
+        }
+        match storage.delete(&key).await {
+            Ok(()) | Err(StorageError::NotFound(_)) => {
+                store.collected(&key).await;
+                removed.push(key);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    store.forget_registered().await?;
+    Ok(removed)
+}
+
+/// Run [`collect`] every `interval` seconds in the background, unless it is 0.
+pub(crate) fn spawn(store: Arc<Store>, storage: Arc<dyn StorageBackend>, interval: u64) {
+    if interval == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let period = Duration::from_secs(interval);
+        let mut ticker = time::interval_at(Instant::now() + period, period);
+        loop {
+            ticker.tick().await;
+            match collect(&store, storage.as_ref()).await {
+                Ok(removed) if !removed.is_empty() => {
+                    println!("garbage collection removed {} files", removed.len())
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("garbage collection failed: {err}"),
+            }
+        }
+    });
+}
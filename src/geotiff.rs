@@ -0,0 +1,149 @@
+//! Footprints of GeoTIFF maps, read from their georeferencing tags by
+//! background jobs, so that uploads need not give their bbox.
+//!
+//! Only images placed by a tie point and a pixel scale, in WGS 84 (EPSG:4326)
+//! or Web Mercator (EPSG:3857), are understood; other maps keep no footprint.
+
+use std::{
+    fmt,
+    io::{self, Cursor},
+};
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use tiff::{
+    decoder::{ifd::Value, Decoder},
+    tags::Tag,
+    TiffError,
+};
+use tokio::task;
+
+use crate::{
+    smap::{BBox, SMap},
+    storage::{StorageBackend, StorageError},
+};
+
+/// GeoKey of the model type: 1 for projected, 2 for geographic coordinates.
+const MODEL_TYPE_KEY: u16 = 1024;
+
+/// GeoKey of the EPSG code of geographic coordinates.
+const GEOGRAPHIC_TYPE_KEY: u16 = 2048;
+
+/// GeoKey of the EPSG code of projected coordinates.
+const PROJECTED_TYPE_KEY: u16 = 3072;
+
+const WGS_84: u16 = 4326;
+
+const WEB_MERCATOR: u16 = 3857;
+
+/// Radius of the sphere of Web Mercator, in meters.
+const EARTH_RADIUS: f64 = 6_378_137.0;
+
+/// Footprint extraction errors.
+#[derive(Debug)]
+pub(crate) enum GeoError {
+    /// Map file could not be read.
+    Storage(StorageError),
+    /// Map file is not a readable TIFF.
+    Tiff(TiffError),
+}
+
+impl fmt::Display for GeoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Storage(err) => write!(f, "{err}"),
+            Self::Tiff(err) => write!(f, "tiff error: {err}"),
+        }
+    }
+}
+
+impl From<StorageError> for GeoError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<TiffError> for GeoError {
+    fn from(err: TiffError) -> Self {
+        Self::Tiff(err)
+    }
+}
+
+/// Whether `smap` is a TIFF without footprint, which its tags may give.
+pub(crate) fn needs_footprint(smap: &SMap) -> bool {
+    smap.content_type == "image/tiff" && smap.bbox.is_none()
+}
+
+/// Footprint of the file of `smap`, if it is georeferenced in a known way.
+pub(crate) async fn footprint(
+    storage: &dyn StorageBackend,
+    smap: &SMap,
+) -> Result<Option<BBox>, GeoError> {
+    let bytes: Vec<Bytes> = storage.get(&smap.key).await?.try_collect().await?;
+    task::spawn_blocking(move || Ok(read_footprint(&bytes.concat())?))
+        .await
+        .map_err(|err| GeoError::Tiff(TiffError::IoError(io::Error::other(err))))?
+}
+
+fn read_footprint(bytes: &[u8]) -> Result<Option<BBox>, TiffError> {
+    let mut decoder = Decoder::new(Cursor::new(bytes))?;
+    let (width, height) = decoder.dimensions()?;
+    let tiepoint = decoder
+        .find_tag(Tag::ModelTiepointTag)?
+        .map(Value::into_f64_vec)
+        .transpose()?;
+    let scale = decoder
+        .find_tag(Tag::ModelPixelScaleTag)?
+        .map(Value::into_f64_vec)
+        .transpose()?;
+    let keys = decoder.find_tag_unsigned_vec::<u16>(Tag::GeoKeyDirectoryTag)?;
+    let (Some(tiepoint), Some(scale), Some(keys)) = (tiepoint, scale, keys) else {
+        return Ok(None);
+    };
+    // Raster point (i, j) lies at model point (x, y); rows go south.
+    let (&[i, j, _, x, y, ..], &[scale_x, scale_y, ..]) = (&tiepoint[..], &scale[..]) else {
+        return Ok(None);
+    };
+    let (min_x, max_y) = (x - i * scale_x, y + j * scale_y);
+    let (max_x, min_y) = (
+        min_x + f64::from(width) * scale_x,
+        max_y - f64::from(height) * scale_y,
+    );
+
+    let coordinates = match crs(&keys) {
+        Some(WGS_84) => [min_x, min_y, max_x, max_y],
+        Some(WEB_MERCATOR) => {
+            let (west, south) = degrees(min_x, min_y);
+            let (east, north) = degrees(max_x, max_y);
+            [west, south, east, north]
+        }
+        _ => return Ok(None),
+    };
+    Ok(BBox::new(&coordinates).ok())
+}
+
+/// EPSG code of the coordinates declared in GeoKey directory `keys`.
+fn crs(keys: &[u16]) -> Option<u16> {
+    // A header of four values, then each key, where its value is stored, how
+    // many values it has and its value, stored inline when the location is 0.
+    let entries = keys.get(4..)?.chunks_exact(4);
+    let key = |id: u16| {
+        entries
+            .clone()
+            .find(|entry| entry[0] == id && entry[1] == 0)
+            .map(|entry| entry[3])
+    };
+    match key(MODEL_TYPE_KEY)? {
+        1 => key(PROJECTED_TYPE_KEY),
+        2 => key(GEOGRAPHIC_TYPE_KEY),
+        _ => None,
+    }
+}
+
+/// Longitude and latitude of Web Mercator point (`x`, `y`).
+fn degrees(x: f64, y: f64) -> (f64, f64) {
+    (
+        (x / EARTH_RADIUS).to_degrees(),
+        (y / EARTH_RADIUS).sinh().atan().to_degrees(),
+    )
+}
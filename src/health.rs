@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use axum::{extract::State, response::IntoResponse, Json};
+use hyper::StatusCode;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::storage::StorageBackend;
+
+/// Readiness of the service to handle requests.
+#[derive(Serialize, ToSchema)]
+pub(super) struct Readiness {
+    #[schema(example = "ready")]
+    status: &'static str,
+    /// Reason the service is not ready.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "storage i/o error: No space left on device")]
+    error: Option<String>,
+}
+
+/// Readiness probe
+///
+/// Check that the storage backend can write, read and delete objects.
+#[utoipa::path(
+    get,
+    path = "/ready",
+    responses(
+        (status = 200, description = "Service is ready", body = Readiness),
+        (status = 503, description = "Storage backend is failing", body = Readiness)
+    )
+)]
+pub(super) async fn readiness(State(storage): State<Arc<dyn StorageBackend>>) -> impl IntoResponse {
+    match storage.health_check().await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(Readiness {
+                status: "ready",
+                error: None,
+            }),
+        ),
+        Err(err) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Readiness {
+                status: "unavailable",
+                error: Some(err.to_string()),
+            }),
+        ),
+    }
+}
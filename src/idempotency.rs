@@ -0,0 +1,99 @@
+//! Replay of uploads retried with the same `Idempotency-Key` header, so clients
+//! can safely retry after a timeout without registering maps twice.
+//!
+//! Keys live in process memory: instances behind a load balancer do not share them.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::smap::SMap;
+
+/// State of an idempotency key handed out by [`IdempotencyKeys::claim`].
+pub(crate) enum Claim<'a> {
+    /// First use of the key, now held by the caller.
+    New(Pending<'a>),
+    /// Another request with the key is still running.
+    InProgress,
+    /// A request with the key already registered these maps.
+    Done(Vec<SMap>),
+}
+
+/// Use of an idempotency key.
+struct Entry {
+    used_at: Instant,
+    /// Maps registered under the key, none while the first request runs.
+    smaps: Option<Vec<SMap>>,
+}
+
+/// Idempotency keys seen within the expiry window.
+pub(crate) struct IdempotencyKeys {
+    window: Duration,
+    keys: Mutex<HashMap<String, Entry>>,
+}
+
+impl IdempotencyKeys {
+    /// Remember keys for `window` after their first use.
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            keys: Mutex::default(),
+        }
+    }
+
+    /// Look `key` up, claiming it for the caller if it is unknown or expired.
+    pub(crate) fn claim(&self, key: &str) -> Claim<'_> {
+        let mut keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+        keys.retain(|_, entry| now.duration_since(entry.used_at) < self.window);
+
+        match keys.get(key) {
+            Some(Entry {
+                smaps: Some(smaps), ..
+            }) => Claim::Done(smaps.clone()),
+            Some(Entry { smaps: None, .. }) => Claim::InProgress,
+            None => {
+                let entry = Entry {
+                    used_at: now,
+                    smaps: None,
+                };
+                keys.insert(key.to_string(), entry);
+                Claim::New(Pending {
+                    keys: self,
+                    key: key.to_string(),
+                    completed: false,
+                })
+            }
+        }
+    }
+}
+
+/// Idempotency key claimed by a running request.
+///
+/// Dropping it without [`Pending::complete`], e.g. when the request fails or the
+/// client disconnects, releases the key so the upload can be retried.
+pub(crate) struct Pending<'a> {
+    keys: &'a IdempotencyKeys,
+    key: String,
+    completed: bool,
+}
+
+impl Pending<'_> {
+    /// Record the maps registered by the request.
+    pub(crate) fn complete(mut self, smaps: &[SMap]) {
+        if let Some(entry) = self.keys.keys.lock().unwrap().get_mut(&self.key) {
+            entry.smaps = Some(smaps.to_vec());
+        }
+        self.completed = true;
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.keys.keys.lock().unwrap().remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Download of map files published elsewhere, to register them as new maps.
+
+use std::{fmt, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use clap::Args;
+use futures::TryStreamExt;
+use percent_encoding::percent_decode_str;
+use reqwest::{header::CONTENT_TYPE, Url};
+
+/// Remote ingestion settings.
+#[derive(Args, Debug)]
+pub(crate) struct IngestConfig {
+    /// Largest remote file downloaded by `POST /smap/from-url`, in bytes.
+    #[arg(
+        long = "ingest-max-bytes",
+        env = "SMU_INGEST_MAX_BYTES",
+        default_value_t = 100 * 1024 * 1024
+    )]
+    pub(crate) max_bytes: u64,
+
+    /// Content types accepted from remote servers, comma-separated.
+    #[arg(
+        long = "ingest-content-types",
+        env = "SMU_INGEST_CONTENT_TYPES",
+        value_delimiter = ',',
+        default_value = "image/png,image/jpeg,image/webp,image/tiff,application/pdf"
+    )]
+    pub(crate) content_types: Vec<String>,
+
+    /// Seconds allowed for a remote download.
+    #[arg(
+        long = "ingest-timeout-secs",
+        env = "SMU_INGEST_TIMEOUT_SECS",
+        default_value_t = 60
+    )]
+    pub(crate) timeout: u64,
+}
+
+/// Remote download errors.
+#[derive(Debug)]
+pub(crate) enum IngestError {
+    /// URL is not an absolute HTTP(S) URL.
+    InvalidUrl(String),
+    /// Remote request failed or answered with an error status.
+    Http(reqwest::Error),
+    /// Remote file has a content type outside the accepted ones.
+    ContentType(String),
+    /// Remote file is larger than the configured limit.
+    TooLarge(u64),
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(url) => write!(f, "not an http(s) url: {url}"),
+            Self::Http(err) => write!(f, "remote request failed: {err}"),
+            Self::ContentType(content_type) => {
+                write!(f, "content type {content_type:?} is not accepted")
+            }
+            Self::TooLarge(limit) => write!(f, "remote file exceeds {limit} bytes"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for IngestError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+/// Name of the file at `url`, the last segment of its path, if any.
+pub(crate) fn file_name(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let segment = url.path_segments()?.next_back()?;
+    let name = percent_decode_str(segment).decode_utf8_lossy();
+    (!name.is_empty()).then(|| name.into_owned())
+}
+
+/// Download the file at `url`, enforcing the limits of `config`.
+pub(crate) async fn fetch(config: &IngestConfig, url: &str) -> Result<Bytes, IngestError> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(IngestError::InvalidUrl(url.to_string()));
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout))
+        .build()?;
+    let response = client.get(url).send().await?.error_for_status()?;
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    // Compare the media type only, without parameters such as the charset.
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if !config
+        .content_types
+        .iter()
+        .any(|accepted| accepted.eq_ignore_ascii_case(&media_type))
+    {
+        return Err(IngestError::ContentType(content_type.to_string()));
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length > config.max_bytes)
+    {
+        return Err(IngestError::TooLarge(config.max_bytes));
+    }
+
+    // The announced length may be missing or wrong: count while downloading.
+    let mut body = response.bytes_stream();
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.try_next().await? {
+        if (bytes.len() + chunk.len()) as u64 > config.max_bytes {
+            return Err(IngestError::TooLarge(config.max_bytes));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes.freeze())
+}
@@ -0,0 +1,562 @@
+//! Background jobs processing maps after upload: verifying their stored file,
+//! generating their thumbnails and reading the footprint of GeoTIFFs, then
+//! notifying webhooks.
+//!
+//! Jobs are queued below `<data dir>/jobs`, one file each, so that pending jobs
+//! survive restarts. Failing jobs are retried with exponential backoff, up to
+//! `--job-max-attempts` times; maps record the outcome as their
+//! `processing_status`, and webhooks are notified once every processing job of
+//! a map ended.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use clap::Args;
+use futures::TryStreamExt;
+use image::Limits;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, sync::Notify, time};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    db::MetadataError,
+    geotiff::{self, GeoError},
+    rescan,
+    smap::{Modified, SMap, Store},
+    sniff,
+    storage::{StorageBackend, StorageError},
+    thumbnail::{self, ThumbnailError},
+    webhook,
+};
+
+/// Delay before the first retry of a failed job, doubled on each further one.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Longest delay before retrying a failed job.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Background job settings.
+#[derive(Args, Debug)]
+pub(crate) struct JobConfig {
+    /// Attempts at a failing processing job before giving up on it.
+    #[arg(
+        long = "job-max-attempts",
+        env = "SMU_JOB_MAX_ATTEMPTS",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub(crate) max_attempts: u32,
+}
+
+/// Progress of the processing of a map after upload.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProcessingStatus {
+    /// Jobs are queued or running.
+    Pending,
+    /// Every processing job succeeded.
+    Done,
+    /// A job failed for good.
+    Failed,
+}
+
+/// Work of a job.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Task {
+    /// Check that the stored file of map `uuid` still hashes to `hash`.
+    Verify { uuid: String, hash: String },
+    /// Generate the thumbnails of map `uuid`, while its file hashes to `hash`.
+    Thumbnails { uuid: String, hash: String },
+    /// Read the footprint of map `uuid` from its GeoTIFF tags, while its file
+    /// hashes to `hash`.
+    Footprint { uuid: String, hash: String },
+    /// Send processed map `uuid` to webhook `url`, while its file hashes to `hash`.
+    Notify {
+        uuid: String,
+        hash: String,
+        url: String,
+    },
+}
+
+impl Task {
+    /// Map processed, and the digest of its file when the task was queued.
+    fn target(&self) -> (&str, &str) {
+        match self {
+            Self::Verify { uuid, hash }
+            | Self::Thumbnails { uuid, hash }
+            | Self::Footprint { uuid, hash }
+            | Self::Notify { uuid, hash, .. } => (uuid, hash),
+        }
+    }
+
+    /// Whether the task processes the map, rather than notifying of it.
+    fn processes(&self) -> bool {
+        !matches!(self, Self::Notify { .. })
+    }
+}
+
+/// Processing settings, from the configuration.
+struct Settings {
+    /// Whether thumbnails are generated.
+    thumbnails: bool,
+    /// Decoding limits of map images.
+    limits: Limits,
+    /// Attempts at a failing job before giving up on it.
+    max_attempts: u32,
+    /// URLs notified of processed maps.
+    webhooks: Vec<String>,
+    /// Client delivering webhooks.
+    client: reqwest::Client,
+}
+
+/// Queued job.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Job {
+    id: String,
+    task: Task,
+    /// Failed attempts so far.
+    attempts: u32,
+    /// Time before which the job is not run.
+    run_after: DateTime<Utc>,
+    /// Error of the last failed attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Job errors.
+#[derive(Debug)]
+pub(crate) enum JobError {
+    /// Jobs could not be read or written.
+    Queue(io::Error),
+    /// Maps could not be read or updated.
+    Database(MetadataError),
+    /// Map files could not be read.
+    Storage(StorageError),
+    /// Stored file no longer hashes to the digest recorded at upload.
+    Corrupt(String),
+    /// Thumbnails could not be generated.
+    Thumbnail(ThumbnailError),
+    /// Footprint could not be read.
+    Footprint(GeoError),
+    /// Webhook could not be delivered.
+    Webhook(reqwest::Error),
+}
+
+impl JobError {
+    /// Whether running the job again may succeed, unlike decoding the same file.
+    fn retryable(&self) -> bool {
+        !matches!(
+            self,
+            Self::Corrupt(_)
+                | Self::Thumbnail(ThumbnailError::Image(_))
+                | Self::Footprint(GeoError::Tiff(_))
+        )
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Queue(err) => write!(f, "job queue failed: {err}"),
+            Self::Database(err) => write!(f, "metadata store failed: {err}"),
+            Self::Storage(err) => write!(f, "{err}"),
+            Self::Corrupt(msg) => write!(f, "corrupt map file: {msg}"),
+            Self::Thumbnail(err) => write!(f, "thumbnail generation failed: {err}"),
+            Self::Footprint(err) => write!(f, "footprint extraction failed: {err}"),
+            Self::Webhook(err) => write!(f, "webhook delivery failed: {err}"),
+        }
+    }
+}
+
+impl From<io::Error> for JobError {
+    fn from(err: io::Error) -> Self {
+        Self::Queue(err)
+    }
+}
+
+impl From<MetadataError> for JobError {
+    fn from(err: MetadataError) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl From<StorageError> for JobError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<ThumbnailError> for JobError {
+    fn from(err: ThumbnailError) -> Self {
+        Self::Thumbnail(err)
+    }
+}
+
+impl From<GeoError> for JobError {
+    fn from(err: GeoError) -> Self {
+        Self::Footprint(err)
+    }
+}
+
+impl From<reqwest::Error> for JobError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Webhook(err)
+    }
+}
+
+/// Queued jobs, one file each.
+pub(crate) struct Queue {
+    dir: PathBuf,
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Notified when jobs are queued.
+    queued: Notify,
+}
+
+impl Queue {
+    /// Keep jobs in `dir`, creating it if missing, and load those left queued.
+    pub(crate) fn open(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut jobs = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                let job: Job = serde_json::from_slice(&std::fs::read(&path)?)?;
+                jobs.insert(job.id.clone(), job);
+            }
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            jobs: Mutex::new(jobs),
+            queued: Notify::new(),
+        })
+    }
+
+    fn job_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// Whether processing jobs on `target` are queued, besides job `except`.
+    fn processing(&self, target: (&str, &str), except: Option<&str>) -> bool {
+        self.jobs.lock().unwrap().values().any(|job| {
+            job.task.processes() && job.task.target() == target && Some(job.id.as_str()) != except
+        })
+    }
+
+    /// Job to run first, if any.
+    fn next(&self) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.values().min_by_key(|job| job.run_after).cloned()
+    }
+
+    /// Queue `tasks` to run at once, none before all of them are queued.
+    async fn push(&self, tasks: Vec<Task>) -> io::Result<()> {
+        let mut jobs = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            let job = Job {
+                id: Uuid::new_v4().to_string(),
+                task,
+                attempts: 0,
+                run_after: Utc::now(),
+                error: None,
+            };
+            fs::write(self.job_path(&job.id), serde_json::to_vec(&job)?).await?;
+            jobs.push(job);
+        }
+        self.jobs
+            .lock()
+            .unwrap()
+            .extend(jobs.into_iter().map(|job| (job.id.clone(), job)));
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    /// Write `job`, queued or updated.
+    async fn save(&self, job: Job) -> io::Result<()> {
+        fs::write(self.job_path(&job.id), serde_json::to_vec(&job)?).await?;
+        self.jobs.lock().unwrap().insert(job.id.clone(), job);
+        Ok(())
+    }
+
+    /// Take job `id` off the queue.
+    async fn remove(&self, id: &str) -> io::Result<()> {
+        self.jobs.lock().unwrap().remove(id);
+        match fs::remove_file(self.job_path(id)).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Apply `change` to map `uuid` and record its sidecar, unless its file no
+/// longer hashes to `hash`.
+async fn annotate(
+    store: &Store,
+    storage: &dyn StorageBackend,
+    (uuid, hash): (&str, &str),
+    mut change: impl FnMut(&mut SMap),
+) -> Result<(), MetadataError> {
+    let recorded = store
+        .annotate(uuid, |smap| {
+            if smap.hash != hash {
+                return false;
+            }
+            change(smap);
+            true
+        })
+        .await?;
+    if let Modified::Updated(smap) = recorded {
+        rescan::record(storage, &smap).await;
+    }
+    Ok(())
+}
+
+/// Processing tasks of `smap` under `settings`.
+fn tasks(smap: &SMap, settings: &Settings) -> Vec<Task> {
+    let (uuid, hash) = (smap.uuid.clone(), smap.hash.clone());
+    let mut tasks = vec![Task::Verify {
+        uuid: uuid.clone(),
+        hash: hash.clone(),
+    }];
+    if settings.thumbnails && thumbnail::needs_thumbnails(smap) {
+        tasks.push(Task::Thumbnails {
+            uuid: uuid.clone(),
+            hash: hash.clone(),
+        });
+    }
+    if geotiff::needs_footprint(smap) {
+        tasks.push(Task::Footprint { uuid, hash });
+    }
+    tasks
+}
+
+/// Queue jobs for the active maps not yet processed, or whose jobs were lost,
+/// marking them pending, and return how many were queued.
+async fn schedule(
+    queue: &Queue,
+    store: &Store,
+    storage: &dyn StorageBackend,
+    settings: &Settings,
+) -> Result<usize, JobError> {
+    let mut queued = 0;
+    for smap in store.list().await? {
+        let target = (smap.uuid.as_str(), smap.hash.as_str());
+        match smap.processing_status {
+            None => {}
+            Some(ProcessingStatus::Pending) if !queue.processing(target, None) => {}
+            Some(_) => continue,
+        }
+        // Marked first: annotations of running jobs must not be overwritten.
+        annotate(store, storage, target, |smap| {
+            smap.processing_status = Some(ProcessingStatus::Pending);
+        })
+        .await?;
+        let tasks = tasks(&smap, settings);
+        queued += tasks.len();
+        queue.push(tasks).await?;
+    }
+    Ok(queued)
+}
+
+/// Check that the stored file of `smap` still hashes to its digest.
+async fn verify(storage: &dyn StorageBackend, smap: &SMap) -> Result<(), JobError> {
+    let mut stream = storage.get(&smap.key).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.try_next().await? {
+        hasher.update(&chunk);
+    }
+    let digest = format!("{:x}", hasher.finalize());
+    if digest != smap.hash {
+        return Err(JobError::Corrupt(format!(
+            "file of map {} has sha-256 {digest}, not {} as uploaded",
+            smap.uuid, smap.hash
+        )));
+    }
+    Ok(())
+}
+
+/// Run `task` under `settings`.
+///
+/// Tasks on maps since removed, or given another file, have nothing left to do.
+async fn run(
+    task: &Task,
+    store: &Store,
+    storage: &dyn StorageBackend,
+    settings: &Settings,
+) -> Result<(), JobError> {
+    let (uuid, hash) = task.target();
+    let Some(smap) = store.get(uuid).await?.filter(|smap| smap.hash == hash) else {
+        return Ok(());
+    };
+    match task {
+        Task::Verify { .. } => verify(storage, &smap).await?,
+        Task::Thumbnails { .. } => {
+            let thumbnails = thumbnail::generate(storage, &smap, settings.limits.clone()).await?;
+            annotate(store, storage, task.target(), |smap| {
+                smap.thumbnails = Some(thumbnails.clone());
+            })
+            .await?;
+        }
+        Task::Footprint { .. } => {
+            if let Some(bbox) = geotiff::footprint(storage, &smap).await? {
+                // A footprint given meanwhile wins over the tags.
+                annotate(store, storage, task.target(), |smap| {
+                    smap.bbox.get_or_insert(bbox);
+                })
+                .await?;
+            }
+        }
+        Task::Notify { url, .. } => webhook::notify(&settings.client, url, &smap).await?,
+    }
+    Ok(())
+}
+
+/// Delay before retrying a job failed `attempts` times.
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_DELAY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Run `job` once, then take it off the queue or schedule its retry.
+///
+/// Once the last processing job of a map ends, the map is marked done unless
+/// one failed, and its webhook notifications are queued.
+async fn attempt(
+    mut job: Job,
+    queue: &Queue,
+    store: &Store,
+    storage: &dyn StorageBackend,
+    settings: &Settings,
+) -> Result<(), JobError> {
+    let failure = match run(&job.task, store, storage, settings).await {
+        Ok(()) => None,
+        Err(err) => {
+            job.attempts += 1;
+            if err.retryable() && job.attempts < settings.max_attempts {
+                let delay =
+                    chrono::Duration::from_std(retry_delay(job.attempts)).unwrap_or_default();
+                job.run_after = Utc::now() + delay;
+                job.error = Some(err.to_string());
+                return Ok(queue.save(job).await?);
+            }
+            Some(err)
+        }
+    };
+
+    let (uuid, hash) = job.task.target();
+    if let Task::Notify { url, .. } = &job.task {
+        if let Some(err) = failure {
+            eprintln!(
+                "giving up notifying {url} of map {uuid} after {} attempts: {err}",
+                job.attempts
+            );
+        }
+        return Ok(queue.remove(&job.id).await?);
+    }
+
+    if let Some(err) = failure {
+        eprintln!(
+            "giving up processing of map {uuid} after {} attempts: {err}",
+            job.attempts
+        );
+        annotate(store, storage, (uuid, hash), |smap| {
+            smap.processing_status = Some(ProcessingStatus::Failed);
+        })
+        .await?;
+    }
+    // Marked before the job leaves the queue, so that it is not scheduled again.
+    if !queue.processing((uuid, hash), Some(&job.id)) {
+        annotate(store, storage, (uuid, hash), |smap| {
+            if smap.processing_status != Some(ProcessingStatus::Failed) {
+                smap.processing_status = Some(ProcessingStatus::Done);
+            }
+        })
+        .await?;
+        let notifications = settings.webhooks.iter().map(|url| Task::Notify {
+            uuid: uuid.to_string(),
+            hash: hash.to_string(),
+            url: url.clone(),
+        });
+        queue.push(notifications.collect()).await?;
+    }
+    queue.remove(&job.id).await?;
+    Ok(())
+}
+
+/// Queue jobs whenever maps change and run them in the background.
+pub(crate) fn spawn(
+    queue: Arc<Queue>,
+    store: Arc<Store>,
+    storage: Arc<dyn StorageBackend>,
+    config: &Config,
+) -> reqwest::Result<()> {
+    let settings = Arc::new(Settings {
+        thumbnails: !config.thumbnail.disabled,
+        limits: sniff::limits(config),
+        max_attempts: config.job.max_attempts,
+        webhooks: config.webhook.urls.clone(),
+        client: webhook::client(&config.webhook)?,
+    });
+
+    let (scheduler_queue, scheduler_store, scheduler_storage, scheduler_settings) = (
+        queue.clone(),
+        store.clone(),
+        storage.clone(),
+        settings.clone(),
+    );
+    tokio::spawn(async move {
+        loop {
+            let scheduled = schedule(
+                &scheduler_queue,
+                &scheduler_store,
+                scheduler_storage.as_ref(),
+                &scheduler_settings,
+            )
+            .await;
+            if let Err(err) = scheduled {
+                eprintln!("scheduling processing jobs failed: {err}");
+            }
+            scheduler_store.changed().await;
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let Some(job) = queue.next() else {
+                queue.queued.notified().await;
+                continue;
+            };
+            let wait = (job.run_after - Utc::now()).to_std().unwrap_or_default();
+            if !wait.is_zero() {
+                tokio::select! {
+                    _ = time::sleep(wait) => {}
+                    _ = queue.queued.notified() => {}
+                }
+                continue;
+            }
+
+            let id = job.id.clone();
+            let outcome = attempt(job, &queue, &store, storage.as_ref(), &settings);
+            if let Err(err) = outcome.await {
+                eprintln!("processing job {id} failed: {err}");
+                // Keep a broken queue from spinning.
+                time::sleep(RETRY_DELAY).await;
+            }
+        }
+    });
+    Ok(())
+}
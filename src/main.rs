@@ -1,109 +1,1343 @@
 use std::{
+    error::Error,
     net::{Ipv4Addr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 
 use axum::{routing, Router, Server};
 
-use hyper::Error;
-use utoipa::{
-    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
-    Modify, OpenApi,
-};
-use utoipa_swagger_ui::SwaggerUi;
-
-use crate::smap::Store;
+use clap::Parser;
+use tokio::sync::Semaphore;
 
-use axum::extract::DefaultBodyLimit;
+use crate::{
+    config::Config, idempotency::IdempotencyKeys, presign::PresignedUploads, quota::Quotas,
+    sessions::Sessions, smap::Store, state::AppState, tus::Uploads,
+};
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
-    #[derive(OpenApi)]
-    #[openapi(
-        paths(
-            smap::list_smaps,
-            smap::upload_smap_multipart,
-        ),
-        components(
-            schemas(smap::SMap, smap::SMapError, smap::NewSMap)
-        ),
-        modifiers(&SecurityAddon),
-        tags(
-            (name = "static map", description = "Static Map items management API")
-        )
-    )]
-    struct ApiDoc;
-
-    struct SecurityAddon;
+async fn main() -> Result<(), Box<dyn Error>> {
+    let config = Arc::new(Config::parse());
 
-    impl Modify for SecurityAddon {
-        fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
-            if let Some(components) = openapi.components.as_mut() {
-                components.add_security_scheme(
-                    "api_key",
-                    SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("smap_apikey"))),
-                )
-            }
-        }
+    let store = Arc::new(Store::open(&config).await?);
+    let storage = storage::from_config(&config)?;
+    if config.rescan {
+        let restored = rescan::rescan(&store, storage.as_ref()).await?;
+        println!("rescan registered {} maps", restored.len());
     }
-
-    let store = Arc::new(Store::default());
+    gc::spawn(store.clone(), storage.clone(), config.gc_interval);
+    snapshot::spawn(store.clone(), &config);
+    trash::spawn(store.clone(), config.trash_retention_days);
+    expiry::spawn(store.clone(), storage.clone());
+    let jobs = Arc::new(jobs::Queue::open(&config.data_dir.join("jobs"))?);
+    jobs::spawn(jobs, store.clone(), storage.clone(), &config)?;
+    let presigned = Arc::new(PresignedUploads::open(
+        &config.data_dir.join("presigned"),
+        &config.presign,
+    )?);
+    presign::spawn(presigned.clone(), storage.clone());
+    let state = AppState {
+        config: config.clone(),
+        store: store.clone(),
+        storage,
+        idempotency: Arc::new(IdempotencyKeys::new(Duration::from_secs(
+            config.idempotency_window,
+        ))),
+        uploads: Arc::new(Uploads::open(&config.data_dir.join("uploads"))?),
+        sessions: Arc::new(Sessions::open(&config.data_dir.join("sessions"))?),
+        presigned: presigned.clone(),
+        quotas: Arc::new(Quotas::open(&config.data_dir.join("quotas.json")).await?),
+        rate_limiter: Arc::default(),
+        upload_slots: Arc::new(Semaphore::new(
+            config
+                .max_concurrent_uploads
+                .unwrap_or(Semaphore::MAX_PERMITS),
+        )),
+    };
+    sync::spawn(state.clone());
     let app = Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-docs/openapi.json", ApiDoc::openapi()))
-        .route("/smap", routing::get(smap::list_smaps))
-        .route("/upload", routing::post(smap::upload_smap_multipart))
-        .layer(DefaultBodyLimit::disable())
-        .layer(DefaultBodyLimit::max(1024))
-        .with_state(store);
+        .merge(api::docs())
+        .merge(api::router(&state))
+        // Probes stay outside the versioned API, for deployments to keep them.
+        .route("/ready", routing::get(health::readiness))
+        .with_state(state);
 
     let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080));
-    Server::bind(&address).serve(app.into_make_service()).await
+    Server::bind(&address)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    store.checkpoint().await?;
+
+    Ok(())
+}
+
+/// Resolve on Ctrl+C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        if let Ok(mut signal) =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        {
+            signal.recv().await;
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
 
+mod admin;
+mod api;
+mod archive;
+mod cache;
+mod conditional;
+mod config;
+mod db;
+mod expiry;
+mod feed;
+mod filename;
+mod gc;
+mod geotiff;
+mod health;
+mod idempotency;
+mod ingest;
+mod jobs;
+mod normalize;
+mod presign;
+mod query;
+mod quota;
+mod range;
+mod ratelimit;
+mod rescan;
+mod scan;
+mod search;
+mod sessions;
+mod snapshot;
+mod sniff;
+mod state;
+mod storage;
+mod strip;
+mod sync;
+mod thumbnail;
+mod trash;
+mod tus;
+mod wal;
+mod webhook;
+
 mod smap {
     use axum::{
-        extract::{Multipart, Path, Query, State},
-        response::IntoResponse,
+        async_trait,
+        body::{Body, StreamBody},
+        extract::{
+            multipart::{Field, MultipartError, MultipartRejection},
+            FromRef, FromRequest, FromRequestParts, Multipart, OriginalUri, Path, Query, State,
+        },
+        http::request::Parts,
+        middleware::Next,
+        response::{IntoResponse, Response},
         Json,
     };
-    use hyper::{HeaderMap, StatusCode};
+    use base64::{
+        engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL},
+        Engine,
+    };
+    use bytes::Bytes;
+    use chrono::{DateTime, Utc};
+    use futures::{future, stream, StreamExt, TryStreamExt};
+    use hyper::{
+        header::{
+            HeaderName, HeaderValue, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION,
+            CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MATCH, IF_MODIFIED_SINCE,
+            IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LINK, RANGE, RETRY_AFTER,
+        },
+        HeaderMap, Method, Request, StatusCode, Uri,
+    };
+    use image::ImageError;
     use serde::{Deserialize, Serialize};
-    use std::sync::Arc;
-    use tokio::fs::File;
-    use tokio::io::AsyncWriteExt;
-    use tokio::sync::Mutex;
-    use utoipa::{IntoParams, ToSchema};
+    use sha2::{Digest, Sha256};
+    use std::{
+        collections::{HashMap, HashSet},
+        convert::Infallible,
+        future::Future,
+        io,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt},
+        sync::{Mutex, Notify, Semaphore},
+        task,
+    };
+    use tokio_util::io::ReaderStream;
+    use utoipa::{
+        openapi::{schema::Schema, RefOr},
+        IntoParams, ToSchema,
+    };
     use uuid::Uuid;
 
-    use utoipa::openapi::schema::KnownFormat;
+    use crate::{
+        archive,
+        cache::{CacheConfig, CachePolicy},
+        conditional,
+        config::{CollisionPolicy, Config, DuplicatePolicy},
+        db::{self, MetadataError, SMapRepository},
+        filename,
+        idempotency::{Claim, IdempotencyKeys},
+        ingest::{self, IngestError},
+        jobs::ProcessingStatus,
+        normalize,
+        query::Filter,
+        range::{self, Requested},
+        rescan,
+        scan::{self, Scan, Verdict},
+        search::SearchIndex,
+        sniff::{self, SNIFF_LEN},
+        storage::{ByteStream, StorageBackend, StorageError},
+        strip,
+        thumbnail::{Size, Thumbnails},
+    };
+
+    /// Static map store: the catalog repository plus storage bookkeeping.
+    pub(super) struct Store {
+        repository: Box<dyn SMapRepository>,
+        /// Bytes currently held by the storage backend.
+        usage: AtomicU64,
+        /// Storage keys written by in-flight uploads, with their writer count.
+        pending: Mutex<HashMap<String, usize>>,
+        /// Stored size of files written by uploads no map referred to yet.
+        written: Mutex<HashMap<String, u64>>,
+        /// Full-text index of active maps.
+        search: SearchIndex,
+        /// Woken when maps are registered or modified.
+        changed: Notify,
+    }
+
+    impl Store {
+        /// Open the map repository selected in `config`, accounting for persisted maps.
+        pub(super) async fn open(config: &Config) -> Result<Self, MetadataError> {
+            let repository = db::from_config(config).await?;
+            let smaps = repository.list().await?;
+            let usage = stored_usage(&smaps);
+
+            Ok(Self {
+                repository,
+                usage: AtomicU64::new(usage),
+                pending: Mutex::default(),
+                written: Mutex::default(),
+                search: SearchIndex::build(&smaps),
+                changed: Notify::new(),
+            })
+        }
+
+        /// Snapshot of every registered map not in the trash.
+        pub(super) async fn list(&self) -> Result<Vec<SMap>, MetadataError> {
+            let mut smaps = self.repository.list().await?;
+            smaps.retain(|smap| smap.deleted_at.is_none());
+            Ok(smaps)
+        }
+
+        /// Active maps sorted by `sort` in `order`.
+        pub(super) async fn list_sorted(
+            &self,
+            sort: SortField,
+            order: SortOrder,
+        ) -> Result<Vec<SMap>, MetadataError> {
+            let mut smaps = self.repository.list_sorted(sort, order).await?;
+            smaps.retain(|smap| smap.deleted_at.is_none());
+            Ok(smaps)
+        }
+
+        /// Active maps passing `filter`, sorted by `sort` in its order if given.
+        pub(super) async fn list_filtered(
+            &self,
+            filter: &Filter,
+            sort: Option<(SortField, SortOrder)>,
+        ) -> Result<Vec<SMap>, MetadataError> {
+            let mut smaps = self.repository.list_filtered(filter, sort).await?;
+            smaps.retain(|smap| smap.deleted_at.is_none());
+            Ok(smaps)
+        }
+
+        /// Snapshot of every registered map, trashed ones included.
+        pub(super) async fn list_all(&self) -> Result<Vec<SMap>, MetadataError> {
+            self.repository.list().await
+        }
+
+        /// Active maps whose title, description or tags match every word of
+        /// `query`, best first.
+        pub(super) async fn search(&self, query: &str) -> Result<Vec<SMap>, MetadataError> {
+            let uuids = self.search.search(query);
+            let smaps = self.get_many(&uuids).await?;
+            Ok(smaps.into_iter().flatten().collect())
+        }
+
+        /// Bytes currently held by the storage backend.
+        pub(super) fn usage(&self) -> u64 {
+            self.usage.load(Ordering::SeqCst)
+        }
+
+        /// Registered map with the given `uuid`, unless it is in the trash.
+        pub(super) async fn get(&self, uuid: &str) -> Result<Option<SMap>, MetadataError> {
+            let smap = self.repository.get(uuid).await?;
+            Ok(smap.filter(|smap| smap.deleted_at.is_none()))
+        }
+
+        /// Registered maps with the given `uuids`, in order, none for those unknown
+        /// or in the trash.
+        pub(super) async fn get_many(
+            &self,
+            uuids: &[String],
+        ) -> Result<Vec<Option<SMap>>, MetadataError> {
+            let mut smaps = self.repository.get_many(uuids).await?;
+            for smap in &mut smaps {
+                *smap = smap.take().filter(|smap| smap.deleted_at.is_none());
+            }
+            Ok(smaps)
+        }
+
+        /// Move the map registered under `uuid` to the trash, returning whether it was active.
+        pub(super) async fn trash(
+            &self,
+            uuid: &str,
+            revision: Option<u64>,
+        ) -> Result<Modified, MetadataError> {
+            self.modify(uuid, revision, |smap| {
+                if smap.deleted_at.is_some() {
+                    return false;
+                }
+                smap.deleted_at = Some(Utc::now());
+                true
+            })
+            .await
+        }
+
+        /// Take the map registered under `uuid` out of the trash.
+        pub(super) async fn restore(
+            &self,
+            uuid: &str,
+            revision: Option<u64>,
+        ) -> Result<Modified, MetadataError> {
+            self.modify(uuid, revision, |smap| smap.deleted_at.take().is_some())
+                .await
+        }
+
+        /// Apply `patch` to the active map registered under `uuid`.
+        pub(super) async fn edit(
+            &self,
+            uuid: &str,
+            revision: Option<u64>,
+            patch: &SMapPatch,
+        ) -> Result<Modified, MetadataError> {
+            self.modify(uuid, revision, |smap| {
+                if smap.deleted_at.is_some() {
+                    return false;
+                }
+                patch.apply(smap);
+                true
+            })
+            .await
+        }
+
+        /// Apply `change` to the map registered under `uuid` and bump its revision.
+        ///
+        /// `change` returns false when it does not apply to the map, which is then
+        /// reported as not found. With a `revision`, the map must still be at it;
+        /// without one, concurrent updates are retried on the latest revision.
+        pub(super) async fn modify(
+            &self,
+            uuid: &str,
+            revision: Option<u64>,
+            mut change: impl FnMut(&mut SMap) -> bool,
+        ) -> Result<Modified, MetadataError> {
+            loop {
+                let Some(mut smap) = self.repository.get(uuid).await? else {
+                    return Ok(Modified::NotFound);
+                };
+                let current = smap.revision;
+                if revision.is_some_and(|revision| revision != current) {
+                    return Ok(Modified::Stale(smap));
+                }
+                if !change(&mut smap) {
+                    return Ok(Modified::NotFound);
+                }
+
+                smap.revision += 1;
+                smap.updated_at = Utc::now();
+                if self.repository.update(&smap, current).await? {
+                    self.search.index(&smap);
+                    self.changed.notify_one();
+                    return Ok(Modified::Updated(smap));
+                }
+            }
+        }
+
+        /// Apply `change`, which records data derived from the map file, to the
+        /// map registered under `uuid`, leaving its revision as is.
+        ///
+        /// `change` returns false when it does not apply to the map, which is then
+        /// reported as not found.
+        pub(super) async fn annotate(
+            &self,
+            uuid: &str,
+            mut change: impl FnMut(&mut SMap) -> bool,
+        ) -> Result<Modified, MetadataError> {
+            loop {
+                let Some(mut smap) = self.repository.get(uuid).await? else {
+                    return Ok(Modified::NotFound);
+                };
+                if !change(&mut smap) {
+                    return Ok(Modified::NotFound);
+                }
+                if self.repository.update(&smap, smap.revision).await? {
+                    return Ok(Modified::Updated(smap));
+                }
+            }
+        }
+
+        /// Wait until maps are registered or modified, returning at once if they
+        /// were since the last call.
+        pub(super) async fn changed(&self) {
+            self.changed.notified().await
+        }
+
+        /// Permanently remove the map registered under `uuid`, leaving its file to
+        /// garbage collection.
+        pub(super) async fn remove(&self, uuid: &str) -> Result<bool, MetadataError> {
+            let removed = self.repository.delete(uuid).await?;
+            self.search.remove(uuid);
+            Ok(removed)
+        }
+
+        /// Permanently remove the map registered under `uuid`, trashed or not.
+        ///
+        /// With a `revision`, the map must be at it. The removed map is returned as
+        /// [`Modified::Updated`].
+        pub(super) async fn take(
+            &self,
+            uuid: &str,
+            revision: Option<u64>,
+        ) -> Result<Modified, MetadataError> {
+            let Some(smap) = self.repository.get(uuid).await? else {
+                return Ok(Modified::NotFound);
+            };
+            if revision.is_some_and(|revision| revision != smap.revision) {
+                return Ok(Modified::Stale(smap));
+            }
+            Ok(match self.repository.delete(uuid).await? {
+                true => {
+                    self.search.remove(uuid);
+                    Modified::Updated(smap)
+                }
+                false => Modified::NotFound,
+            })
+        }
+
+        /// Move the active maps registered under `uuids` to the trash in one
+        /// repository transaction.
+        pub(super) async fn trash_many(
+            &self,
+            uuids: &[String],
+        ) -> Result<Vec<Modified>, MetadataError> {
+            let mut outcomes: Vec<Modified> = uuids.iter().map(|_| Modified::NotFound).collect();
+            let mut updates = Vec::new();
+            let mut positions = Vec::new();
+            let mut seen = HashSet::new();
+            for (position, uuid) in uuids.iter().enumerate() {
+                // A repeated uuid is no longer active once its first occurrence applies.
+                if !seen.insert(uuid) {
+                    continue;
+                }
+                if let Some(mut smap) = self.repository.get(uuid).await? {
+                    if smap.deleted_at.is_none() {
+                        let current = smap.revision;
+                        smap.deleted_at = Some(Utc::now());
+                        smap.revision += 1;
+                        smap.updated_at = Utc::now();
+                        updates.push((smap, current));
+                        positions.push(position);
+                    }
+                }
+            }
+
+            let replaced = self.repository.update_many(&updates).await?;
+            for ((position, replaced), (smap, _)) in
+                positions.into_iter().zip(replaced).zip(updates)
+            {
+                outcomes[position] = if replaced {
+                    self.search.remove(&smap.uuid);
+                    Modified::Updated(smap)
+                } else {
+                    // Changed since it was read: report its current state.
+                    match self.repository.get(&smap.uuid).await? {
+                        Some(current) => Modified::Stale(current),
+                        None => Modified::NotFound,
+                    }
+                };
+            }
+            Ok(outcomes)
+        }
+
+        /// Permanently remove the maps registered under `uuids`, trashed or not, in
+        /// one repository transaction. Removed maps are returned as [`Modified::Updated`].
+        pub(super) async fn take_many(
+            &self,
+            uuids: &[String],
+        ) -> Result<Vec<Modified>, MetadataError> {
+            let mut smaps = Vec::with_capacity(uuids.len());
+            for uuid in uuids {
+                smaps.push(self.repository.get(uuid).await?);
+            }
+            let deleted = self.repository.delete_many(uuids).await?;
+            Ok(smaps
+                .into_iter()
+                .zip(deleted)
+                .map(|(smap, deleted)| match smap {
+                    Some(smap) if deleted => {
+                        self.search.remove(&smap.uuid);
+                        Modified::Updated(smap)
+                    }
+                    _ => Modified::NotFound,
+                })
+                .collect())
+        }
+
+        /// Delete the stored file of a removed `smap`, unless another map or an
+        /// upload still uses it, returning whether it was deleted.
+        pub(super) async fn discard_file(
+            &self,
+            smap: &SMap,
+            storage: &dyn StorageBackend,
+        ) -> Result<bool, SMapError> {
+            let referenced = self
+                .referenced_keys()
+                .await
+                .map_err(|err| SMapError::Database(err.to_string()))?;
+            if referenced.contains(&smap.key) {
+                return Ok(false);
+            }
+            match storage.delete(&smap.key).await {
+                Ok(()) | Err(StorageError::NotFound(_)) => {}
+                Err(err) => return Err(SMapError::Storage(err.to_string())),
+            }
+            self.written.lock().await.remove(&smap.key);
+            self.unreserve(smap.stored_size);
+            Ok(true)
+        }
+
+        /// Give back the bytes of a file garbage collection deleted, if written
+        /// by an upload that no map came to refer to, as when it was rejected.
+        pub(super) async fn collected(&self, key: &str) {
+            if let Some(size) = self.written.lock().await.remove(key) {
+                self.unreserve(size);
+            }
+        }
+
+        /// Stop tracking the files written by uploads that maps now refer to.
+        pub(super) async fn forget_registered(&self) -> Result<(), MetadataError> {
+            let registered: HashSet<String> = self.repository.keys().await?.into_iter().collect();
+            self.written
+                .lock()
+                .await
+                .retain(|key, _| !registered.contains(key));
+            Ok(())
+        }
+
+        /// First map of `smaps` duplicating under `policy` an active map, returned
+        /// along with it, or an earlier map of `smaps`.
+        pub(super) async fn find_duplicate<'a>(
+            &self,
+            smaps: &'a [SMap],
+            policy: DuplicatePolicy,
+        ) -> Result<Option<(&'a SMap, Option<SMap>)>, MetadataError> {
+            if policy == DuplicatePolicy::Allow {
+                return Ok(None);
+            }
+            let active = self.list().await?;
+            for (index, smap) in smaps.iter().enumerate() {
+                if let Some(existing) = active.iter().find(|other| smap.duplicates(other, policy)) {
+                    return Ok(Some((smap, Some(existing.clone()))));
+                }
+                if smaps[..index]
+                    .iter()
+                    .any(|other| smap.duplicates(other, policy))
+                {
+                    return Ok(Some((smap, None)));
+                }
+            }
+            Ok(None)
+        }
+
+        /// Suffix the title of each map of `smaps` duplicating under `policy` an
+        /// active map or an earlier map of `smaps`, until it no longer does.
+        ///
+        /// Under [`DuplicatePolicy::Content`] no title resolves a duplicate, so
+        /// titles are left unchanged.
+        pub(super) async fn rename_duplicates(
+            &self,
+            smaps: &mut [SMap],
+            policy: DuplicatePolicy,
+        ) -> Result<(), MetadataError> {
+            if !matches!(policy, DuplicatePolicy::Exact | DuplicatePolicy::Title) {
+                return Ok(());
+            }
+            let active = self.list().await?;
+            for index in 0..smaps.len() {
+                let (earlier, rest) = smaps.split_at_mut(index);
+                let smap = &mut rest[0];
+                let title = smap.title.clone();
+                let mut number = 1;
+                while active
+                    .iter()
+                    .chain(earlier.iter())
+                    .any(|other| smap.duplicates(other, policy))
+                {
+                    number += 1;
+                    smap.title = format!("{title} ({number})");
+                }
+            }
+            Ok(())
+        }
+
+        /// Give the active map registered under `uuid` the title and file of the
+        /// uploaded `smap`, whose storage key stays held.
+        ///
+        /// The map is returned at its new revision along with its previous state.
+        pub(super) async fn overwrite(
+            &self,
+            uuid: &str,
+            smap: &SMap,
+        ) -> Result<Option<(SMap, SMap)>, MetadataError> {
+            let mut previous = None;
+            let outcome = self
+                .modify(uuid, None, |current| {
+                    if current.deleted_at.is_some() {
+                        return false;
+                    }
+                    previous = Some(current.clone());
+                    current.title = smap.title.clone();
+                    current.key = smap.key.clone();
+                    current.hash = smap.hash.clone();
+                    current.size = smap.size;
+                    current.stored_size = smap.stored_size;
+                    current.content_type = smap.content_type.clone();
+                    current.original_content_type = smap.original_content_type.clone();
+                    current.width = smap.width;
+                    current.height = smap.height;
+                    current.thumbnails = smap.thumbnails.clone();
+                    current.processing_status = smap.processing_status;
+                    if smap.expires_at.is_some() {
+                        current.expires_at = smap.expires_at;
+                    }
+                    true
+                })
+                .await;
+            match (outcome?, previous) {
+                (Modified::Updated(updated), Some(previous)) => Ok(Some((updated, previous))),
+                _ => Ok(None),
+            }
+        }
+
+        /// Whether a map, trashed or not, is registered under `uuid`.
+        pub(super) async fn contains(&self, uuid: &str) -> Result<bool, MetadataError> {
+            Ok(self.repository.get(uuid).await?.is_some())
+        }
+
+        /// Register `smap`, releasing its file from garbage collection protection.
+        pub(super) async fn register(&self, smap: SMap) -> Result<(), MetadataError> {
+            self.register_all(std::slice::from_ref(&smap)).await
+        }
+
+        /// [`Store::register`] every map of `smaps` in one repository transaction.
+        pub(super) async fn register_all(&self, smaps: &[SMap]) -> Result<(), MetadataError> {
+            let registered = self.repository.insert_many(smaps).await;
+            for smap in smaps {
+                if registered.is_ok() {
+                    self.search.index(smap);
+                }
+                self.release(&smap.key).await;
+            }
+            if registered.is_ok() {
+                self.changed.notify_one();
+            }
+            registered
+        }
+
+        /// Version of the last applied schema migration, if the repository has a schema.
+        pub(super) async fn schema_version(&self) -> Result<Option<i64>, MetadataError> {
+            self.repository.schema_version().await
+        }
+
+        /// Flush repository state persisted in batches, e.g. the JSON snapshot.
+        pub(super) async fn checkpoint(&self) -> Result<(), MetadataError> {
+            self.repository.checkpoint().await
+        }
+
+        /// Register `smap`, recovered from outside the catalog, unless its uuid is
+        /// taken, returning whether it was registered.
+        pub(super) async fn adopt(&self, smap: SMap) -> Result<bool, MetadataError> {
+            if self.repository.get(&smap.uuid).await?.is_some() {
+                return Ok(false);
+            }
+            if self.find_by_key(&smap.key).await?.is_none() {
+                self.usage.fetch_add(smap.stored_size, Ordering::SeqCst);
+            }
+            self.repository.insert(&smap).await?;
+            self.search.index(&smap);
+            self.changed.notify_one();
+            Ok(true)
+        }
+
+        /// First registered map whose file is stored under `key`.
+        async fn find_by_key(&self, key: &str) -> Result<Option<SMap>, MetadataError> {
+            self.repository.find_by_key(key).await
+        }
+
+        /// Storage keys referenced by registered maps or in-flight uploads.
+        pub(super) async fn referenced_keys(&self) -> Result<HashSet<String>, MetadataError> {
+            // Read pending keys first: uploads register their map before releasing them.
+            let mut keys: HashSet<String> = self.pending.lock().await.keys().cloned().collect();
+            keys.extend(self.repository.keys().await?);
+            Ok(keys)
+        }
+
+        /// Protect `key` from garbage collection while an upload writes it.
+        async fn hold(&self, key: &str) {
+            *self
+                .pending
+                .lock()
+                .await
+                .entry(key.to_string())
+                .or_default() += 1;
+        }
+
+        /// Release a key protected by [`Store::hold`].
+        pub(super) async fn release(&self, key: &str) {
+            let mut pending = self.pending.lock().await;
+            if let Some(count) = pending.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    pending.remove(key);
+                }
+            }
+        }
+
+        /// Release the keys of `files` stored for an upload that was abandoned.
+        async fn release_all(&self, files: &[StoredFile]) {
+            for file in files {
+                self.release(&file.key).await;
+            }
+        }
+
+        /// Account for `size` new bytes, unless that would exceed `limit`.
+        fn reserve(&self, size: u64, limit: Option<u64>) -> bool {
+            self.usage
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                    let total = used.checked_add(size)?;
+                    match limit {
+                        Some(limit) if total > limit => None,
+                        _ => Some(total),
+                    }
+                })
+                .is_ok()
+        }
+
+        /// Give back bytes accounted by [`Store::reserve`].
+        fn unreserve(&self, size: u64) {
+            self.usage.fetch_sub(size, Ordering::SeqCst);
+        }
+
+        /// Turn `reserved` bytes accounted by [`Store::reserve`] into the `stored`
+        /// bytes a file ended up occupying, as compression or encryption change it.
+        fn settle(&self, reserved: u64, stored: u64) {
+            if stored >= reserved {
+                self.usage.fetch_add(stored - reserved, Ordering::SeqCst);
+            } else {
+                self.unreserve(reserved - stored);
+            }
+        }
+    }
+
+    /// Bytes held by the stored files of `smaps`; maps sharing a key share a single file.
+    fn stored_usage(smaps: &[SMap]) -> u64 {
+        let mut keys = HashSet::new();
+        smaps
+            .iter()
+            .filter(|smap| keys.insert(&smap.key))
+            .map(|smap| smap.stored_size)
+            .sum()
+    }
 
-    /// In-memory static map store.
-    pub(super) type Store = Mutex<Vec<SMap>>;
+    /// Outcome of [`Store::modify`].
+    pub(super) enum Modified {
+        /// The map was changed, here at its new revision.
+        Updated(SMap),
+        /// No map applies under the uuid.
+        NotFound,
+        /// The map is no longer at the expected revision, here at its current one.
+        Stale(SMap),
+    }
 
-    #[derive(ToSchema)]
+    /// New static map, as multipart form or as JSON with a base64-encoded file.
+    #[derive(Deserialize, ToSchema)]
     pub(super) struct NewSMap {
         #[schema(example = "Tropical Cyclone exposed population")]
         title: String,
-        file: Vec<u8>,
+        #[schema(value_type = String, format = Byte)]
+        file: String,
+        /// Media type of the file for JSON uploads; multipart uploads declare it
+        /// on the file part.
+        #[schema(example = "image/png")]
+        content_type: Option<String>,
+        /// Hex-encoded SHA-256 digest of the file, checked against the received
+        /// bytes; multipart uploads give one per file, in the same order.
+        #[schema(example = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08")]
+        sha256: Option<String>,
+        /// Seconds after which the map and its file are removed, kept indefinitely if unset.
+        #[schema(example = 604800)]
+        expires_in: Option<u64>,
+    }
+
+    /// Static map to download from a remote server.
+    #[derive(Deserialize, ToSchema)]
+    pub(super) struct RemoteSMap {
+        #[schema(example = "Tropical Cyclone exposed population")]
+        title: String,
+        /// HTTP(S) URL of the map file.
+        #[schema(example = "https://example.org/maps/cyclone.png")]
+        url: String,
+        /// Seconds after which the map and its file are removed, kept indefinitely if unset.
+        #[schema(example = 604800)]
+        expires_in: Option<u64>,
+    }
+
+    /// Copy of a static map.
+    #[derive(Deserialize, ToSchema)]
+    pub(super) struct CopySMap {
+        /// Title of the copy.
+        #[schema(example = "Tropical Cyclone exposed population, draft")]
+        title: Option<String>,
     }
 
     /// Item to do.
     #[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
     pub(super) struct SMap {
-        uuid: String,
+        pub(super) uuid: String,
         #[schema(example = "Tropical Cyclone exposed population")]
-        title: String,
-        path: String,
+        pub(super) title: String,
+        /// Backend-agnostic key of the stored map file.
+        pub(super) key: String,
+        /// Hex-encoded SHA-256 digest of the map file.
+        #[schema(example = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08")]
+        pub(super) hash: String,
+        /// Size of the map file in bytes.
+        #[schema(example = 524288)]
+        pub(super) size: u64,
+        /// Bytes the map file occupies in storage, after compression.
+        #[schema(example = 262144)]
+        pub(super) stored_size: u64,
+        /// Media type of the map file, sniffed from its content on upload.
+        #[serde(default = "unknown_media_type")]
+        #[schema(example = "image/png")]
+        pub(super) content_type: String,
+        /// Media type of the map file as uploaded, when it was transcoded to `content_type`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(example = "image/jpeg")]
+        pub(super) original_content_type: Option<String>,
+        /// Width of the map image in pixels, absent for PDFs and unreadable images.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(example = 3508)]
+        pub(super) width: Option<u32>,
+        /// Height of the map image in pixels, absent for PDFs and unreadable images.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(example = 2480)]
+        pub(super) height: Option<u32>,
+        /// When the map was moved to the trash, absent for active maps.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(example = "2023-05-20T10:00:00Z")]
+        pub(super) deleted_at: Option<DateTime<Utc>>,
+        /// When the map was registered.
+        #[serde(default = "unknown_time")]
+        #[schema(example = "2023-05-20T10:00:00Z")]
+        pub(super) created_at: DateTime<Utc>,
+        /// When the map was last modified.
+        #[serde(default = "unknown_time")]
+        #[schema(example = "2023-05-20T10:00:00Z")]
+        pub(super) updated_at: DateTime<Utc>,
+        /// When the map and its file are removed, absent for maps kept indefinitely.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(example = "2023-05-27T10:00:00Z")]
+        pub(super) expires_at: Option<DateTime<Utc>>,
+        /// Incremented on every update, also returned as the `ETag` header.
+        #[serde(default = "first_revision")]
+        #[schema(example = 1)]
+        pub(super) revision: u64,
+        /// Free-form description of the map.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(example = "Population exposed to winds above 120 km/h")]
+        pub(super) description: Option<String>,
+        /// Labels to group maps by.
+        #[serde(default)]
+        #[schema(example = json!(["cyclone", "exposure"]))]
+        pub(super) tags: Vec<String>,
+        /// Owner of the API key the map was uploaded with, absent for anonymous uploads.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(example = "mapaction")]
+        pub(super) owner: Option<String>,
+        /// Footprint of the map as `[min_x, min_y, max_x, max_y]`, in WGS 84 degrees.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(value_type = Option<Vec<f64>>, example = json!([32.0, -26.9, 40.9, -10.4]))]
+        pub(super) bbox: Option<BBox>,
+        /// Antivirus scan of the map file on upload, absent if not scanned.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) scan: Option<Scan>,
+        /// Thumbnails of map images, absent until generated after upload.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) thumbnails: Option<Thumbnails>,
+        /// Processing of the map after upload, absent until it is scheduled.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) processing_status: Option<ProcessingStatus>,
+        /// Caching allowed of the map file and thumbnails, overriding that of the
+        /// deployment; absent if not overridden.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) cache_control: Option<CachePolicy>,
+        /// URLs of the map and its resources, in listing and detail responses.
+        #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+        pub(super) links: Option<Links>,
+        /// Set in upload responses returning this existing map in place of an
+        /// upload duplicating it, which was not registered.
+        #[serde(
+            default,
+            skip_deserializing,
+            skip_serializing_if = "std::ops::Not::not"
+        )]
+        pub(super) duplicate: bool,
+    }
+
+    /// Bounding box as `[min_x, min_y, max_x, max_y]`.
+    #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+    #[serde(transparent)]
+    pub(super) struct BBox([f64; 4]);
+
+    impl BBox {
+        /// Box of the four `coordinates`, if they are finite and ordered.
+        pub(super) fn new(coordinates: &[f64]) -> Result<Self, String> {
+            let Ok([min_x, min_y, max_x, max_y]) = <[f64; 4]>::try_from(coordinates) else {
+                return Err(format!(
+                    "bbox needs 4 coordinates, got {}",
+                    coordinates.len()
+                ));
+            };
+            if !coordinates.iter().all(|coordinate| coordinate.is_finite()) {
+                return Err("bbox coordinates must be finite".to_string());
+            }
+            if min_x > max_x || min_y > max_y {
+                return Err("bbox minimums must not exceed its maximums".to_string());
+            }
+            Ok(Self([min_x, min_y, max_x, max_y]))
+        }
+
+        /// Box written as comma-separated `min_x,min_y,max_x,max_y`.
+        fn parse(text: &str) -> Result<Self, String> {
+            let coordinates = text
+                .split(',')
+                .map(|coordinate| coordinate.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|err| format!("invalid bbox {text:?}: {err}"))?;
+            Self::new(&coordinates)
+        }
+
+        /// Whether the boxes share a point, edges included.
+        fn intersects(&self, other: &BBox) -> bool {
+            let [min_x, min_y, max_x, max_y] = self.0;
+            let [other_min_x, other_min_y, other_max_x, other_max_y] = other.0;
+            min_x <= other_max_x
+                && other_min_x <= max_x
+                && min_y <= other_max_y
+                && other_min_y <= max_y
+        }
+    }
+
+    /// URLs of a static map and its resources.
+    #[derive(Serialize, ToSchema, Clone, Debug)]
+    pub(super) struct Links {
+        /// The map itself.
+        #[serde(rename = "self")]
+        #[schema(
+            example = "https://maps.example.org/api/v1/smap/1e7ab3f0-4b8e-4c5e-9f0a-2b8d1c3e4f5a"
+        )]
+        this: String,
+        /// Map file download.
+        #[schema(
+            example = "https://maps.example.org/api/v1/smap/1e7ab3f0-4b8e-4c5e-9f0a-2b8d1c3e4f5a/file"
+        )]
+        file: String,
+        /// Small thumbnail download, once one is generated.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[schema(
+            example = "https://maps.example.org/api/v1/smap/1e7ab3f0-4b8e-4c5e-9f0a-2b8d1c3e4f5a/thumbnails/small"
+        )]
+        thumbnail: Option<String>,
+        /// Target of `DELETE` to trash the map.
+        #[schema(
+            example = "https://maps.example.org/api/v1/smap/1e7ab3f0-4b8e-4c5e-9f0a-2b8d1c3e4f5a"
+        )]
+        delete: String,
+    }
+
+    /// Base URL of the v1 API as seen by the client, to link resources in responses.
+    pub(super) struct BaseUrl(String);
+
+    #[async_trait]
+    impl<S> FromRequestParts<S> for BaseUrl
+    where
+        Arc<Config>: FromRef<S>,
+        S: Send + Sync,
+    {
+        type Rejection = Infallible;
+
+        async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+            let config = Arc::<Config>::from_ref(state);
+            let header = |name: &str| {
+                parts
+                    .headers
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            };
+            let origin = match &config.public_url {
+                Some(url) => url.trim_end_matches('/').to_string(),
+                None => {
+                    // Behind a TLS-terminating proxy, the scheme is forwarded.
+                    let scheme = header("x-forwarded-proto").unwrap_or_else(|| "http".to_string());
+                    let host = header("host").unwrap_or_else(|| "localhost".to_string());
+                    format!("{scheme}://{host}")
+                }
+            };
+            Ok(Self(format!("{origin}/api/v1")))
+        }
+    }
+
+    impl BaseUrl {
+        /// Absolute URL of the API `path`.
+        pub(super) fn join(&self, path: &str) -> String {
+            format!("{}{path}", self.0)
+        }
+
+        /// `smap` with the links to its resources.
+        pub(super) fn link(&self, mut smap: SMap) -> SMap {
+            let this = format!("{}/smap/{}", self.0, smap.uuid);
+            smap.links = Some(Links {
+                file: format!("{this}/file"),
+                thumbnail: smap
+                    .thumbnails
+                    .as_ref()
+                    .map(|_| format!("{this}/thumbnails/small")),
+                delete: this.clone(),
+                this,
+            });
+            smap
+        }
+    }
+
+    /// Partial update of a static map, unset fields are left unchanged.
+    #[derive(Deserialize, ToSchema, Clone)]
+    pub(super) struct SMapPatch {
+        /// New title, must not be blank.
+        #[schema(example = "Tropical Cyclone exposed population")]
+        title: Option<String>,
+        /// New description, an empty one clears it.
+        #[schema(example = "Population exposed to winds above 120 km/h")]
+        description: Option<String>,
+        /// New tags, replacing the current ones.
+        #[schema(example = json!(["cyclone", "exposure"]))]
+        tags: Option<Vec<String>>,
+        /// New footprint as `[min_x, min_y, max_x, max_y]`, an empty one clears it.
+        #[schema(example = json!([32.0, -26.9, 40.9, -10.4]))]
+        bbox: Option<Vec<f64>>,
+        /// New caching policy of the map file and thumbnails, an empty one falls
+        /// back to that of the deployment.
+        cache_control: Option<CachePolicy>,
+    }
+
+    impl SMapPatch {
+        /// Reject patches that would leave the map invalid.
+        fn validate(&self) -> Result<(), SMapError> {
+            if self
+                .title
+                .as_deref()
+                .is_some_and(|title| title.trim().is_empty())
+            {
+                return Err(SMapError::BadRequest("title must not be empty".to_string()));
+            }
+            if self
+                .title
+                .as_deref()
+                .is_some_and(|title| title.chars().count() > MAX_TITLE_LEN)
+            {
+                return Err(SMapError::BadRequest(format!(
+                    "title must be at most {MAX_TITLE_LEN} characters"
+                )));
+            }
+            if let Some(bbox) = self.bbox.as_deref().filter(|bbox| !bbox.is_empty()) {
+                BBox::new(bbox).map_err(SMapError::BadRequest)?;
+            }
+            Ok(())
+        }
+
+        fn apply(&self, smap: &mut SMap) {
+            if let Some(title) = &self.title {
+                smap.title = title.clone();
+            }
+            if let Some(description) = &self.description {
+                smap.description = Some(description.clone()).filter(|text| !text.is_empty());
+            }
+            if let Some(tags) = &self.tags {
+                smap.tags = tags.clone();
+            }
+            if let Some(bbox) = &self.bbox {
+                smap.bbox = BBox::new(bbox).ok();
+            }
+            if let Some(cache_control) = self.cache_control {
+                smap.cache_control = Some(cache_control).filter(|policy| !policy.is_empty());
+            }
+        }
+    }
+
+    fn first_revision() -> u64 {
+        1
+    }
+
+    /// Media type given to files uploaded without one, or before types were recorded.
+    fn unknown_media_type() -> String {
+        "application/octet-stream".to_string()
+    }
+
+    /// Media type of a declared `Content-Type`, lowercase and without parameters
+    /// such as the charset.
+    pub(super) fn media_type(content_type: Option<&str>) -> String {
+        let media_type = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        match media_type.is_empty() {
+            true => unknown_media_type(),
+            false => media_type,
+        }
+    }
+
+    /// 415 error unless the media type of `content_type` is accepted for uploads.
+    pub(super) fn accept_content_type(
+        config: &Config,
+        content_type: Option<&str>,
+    ) -> Result<(), (StatusCode, Json<SMapError>)> {
+        let media_type = media_type(content_type);
+        if config
+            .upload_content_types
+            .iter()
+            .any(|accepted| accepted.trim().eq_ignore_ascii_case(&media_type))
+        {
+            return Ok(());
+        }
+        Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(SMapError::UnsupportedMediaType(format!(
+                "content type {media_type:?} is not accepted"
+            ))),
+        ))
+    }
+
+    /// 422 error if the client named the uploaded file `name` with a blocked or
+    /// disguised extension.
+    pub(super) fn accept_file_name(
+        config: &Config,
+        name: &str,
+    ) -> Result<(), (StatusCode, Json<SMapError>)> {
+        config.filename.check(name).map_err(|message| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(SMapError::Invalid(vec![Violation::new("file", message)])),
+            )
+        })
+    }
+
+    /// Media type sniffed from the leading bytes `head` of an uploaded file, or
+    /// a 415 error unless it is of a known format accepted for uploads.
+    pub(super) fn sniff_content_type(
+        config: &Config,
+        head: &[u8],
+    ) -> Result<&'static str, (StatusCode, Json<SMapError>)> {
+        let Some(media_type) = sniff::sniff(head) else {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                Json(SMapError::UnsupportedMediaType(
+                    "file is not in a known map format".to_string(),
+                )),
+            ));
+        };
+        accept_content_type(config, Some(media_type))?;
+        Ok(media_type)
+    }
+
+    /// Append to `head` the start of `chunk` it lacks to be sniffed, telling
+    /// whether it is now long enough.
+    fn fill_head(head: &mut Vec<u8>, chunk: &[u8]) -> bool {
+        let missing = SNIFF_LEN.saturating_sub(head.len());
+        head.extend_from_slice(&chunk[..missing.min(chunk.len())]);
+        head.len() == SNIFF_LEN
+    }
+
+    /// Time given to records written before timestamps were tracked.
+    fn unknown_time() -> DateTime<Utc> {
+        DateTime::UNIX_EPOCH
     }
 
     impl SMap {
-        fn new(uuid: String, title: String, path: String) -> Self {
-            Self { uuid, title, path }
+        pub(super) fn new(uuid: String, title: String, file: StoredFile) -> Self {
+            let now = Utc::now();
+            Self {
+                uuid,
+                title,
+                key: file.key,
+                hash: file.hash,
+                size: file.size,
+                stored_size: file.stored_size,
+                content_type: file.content_type,
+                original_content_type: file.original_content_type,
+                width: file.dimensions.map(|(width, _)| width),
+                height: file.dimensions.map(|(_, height)| height),
+                deleted_at: None,
+                created_at: now,
+                updated_at: now,
+                expires_at: None,
+                revision: first_revision(),
+                description: None,
+                tags: Vec::new(),
+                owner: None,
+                bbox: None,
+                scan: file.scan,
+                thumbnails: None,
+                processing_status: None,
+                cache_control: None,
+                links: None,
+                duplicate: false,
+            }
+        }
+
+        /// Whether the map counts as a duplicate of `other` under `policy`.
+        fn duplicates(&self, other: &SMap, policy: DuplicatePolicy) -> bool {
+            let title = self.title == other.title;
+            let content = self.hash == other.hash;
+            match policy {
+                DuplicatePolicy::Allow => false,
+                DuplicatePolicy::Exact => title && content,
+                DuplicatePolicy::Title => title,
+                DuplicatePolicy::Content => content,
+            }
+        }
+
+        /// Strong entity tag of the map revision.
+        fn etag(&self) -> String {
+            format!("\"{}\"", self.revision)
+        }
+
+        /// Entity tag of the map file, changing only with its content.
+        fn file_etag(&self) -> String {
+            format!("\"{}\"", self.hash)
+        }
+
+        /// HTTP date of the last change of the map.
+        fn last_modified(&self) -> String {
+            self.updated_at
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string()
+        }
+
+        /// Headers describing the map file, sent with and without its content, to
+        /// be saved rather than shown if `download` is set.
+        fn file_headers(&self, download: bool) -> [(HeaderName, String); 6] {
+            [
+                (CONTENT_LENGTH, self.size.to_string()),
+                (CONTENT_TYPE, self.content_type.clone()),
+                (ETAG, self.file_etag()),
+                (LAST_MODIFIED, self.last_modified()),
+                (ACCEPT_RANGES, "bytes".to_string()),
+                (
+                    CONTENT_DISPOSITION,
+                    filename::content_disposition(&self.title, &self.content_type, download),
+                ),
+            ]
+        }
+
+        /// `Cache-Control` of the map file and thumbnails, from the policy of the
+        /// map or else that of the deployment.
+        fn cache_headers(&self, cache: &CacheConfig) -> Option<[(HeaderName, String); 1]> {
+            let policy = self.cache_control.unwrap_or_else(|| cache.policy());
+            policy.header().map(|value| [(CACHE_CONTROL, value)])
+        }
+
+        /// Whether the client sending `headers` already holds the current map file.
+        fn file_unchanged(&self, headers: &HeaderMap) -> bool {
+            let header = |name| {
+                headers
+                    .get(name)
+                    .and_then(|value: &HeaderValue| value.to_str().ok())
+            };
+            conditional::not_modified(
+                header(IF_NONE_MATCH),
+                header(IF_MODIFIED_SINCE),
+                &self.file_etag(),
+                self.updated_at,
+            )
+        }
+
+        /// Response telling a client its copy of the map file is current.
+        fn file_not_modified(&self, cache: &CacheConfig) -> Response {
+            (
+                StatusCode::NOT_MODIFIED,
+                [
+                    (ETAG, self.file_etag()),
+                    (LAST_MODIFIED, self.last_modified()),
+                ],
+                self.cache_headers(cache),
+                (),
+            )
+                .into_response()
         }
     }
 
+    /// Map file written to the storage backend, not yet registered in the store.
+    pub(super) struct StoredFile {
+        key: String,
+        hash: String,
+        size: u64,
+        stored_size: u64,
+        content_type: String,
+        /// Media type of the file as uploaded, if transcoded to `content_type`.
+        original_content_type: Option<String>,
+        scan: Option<Scan>,
+        /// Width and height of the image in pixels.
+        dimensions: Option<(u32, u32)>,
+        /// Hex-encoded SHA-256 digest of the file as uploaded, before its
+        /// metadata was stripped.
+        received: String,
+    }
+
     /// Static maps operation errors
-    #[derive(Serialize, Deserialize, ToSchema)]
+    #[derive(Serialize, Deserialize, ToSchema, Debug)]
     pub(super) enum SMapError {
         /// SMap already exists conflict.
         #[schema(example = "Static map already exists")]
@@ -114,60 +1348,2873 @@ mod smap {
         /// SMap operation unauthorized
         #[schema(example = "missing api key")]
         Unauthorized(String),
+        /// Storage quota exhausted.
+        #[schema(example = "storage quota of 1073741824 bytes exceeded")]
+        InsufficientStorage(String),
+        /// SMap file could not be written to storage.
+        #[schema(example = "storage i/o error: No space left on device")]
+        Storage(String),
+        /// SMap metadata could not be persisted.
+        #[schema(example = "error returned from database: database is locked")]
+        Database(String),
+        /// Request body could not be parsed.
+        #[schema(example = "line 3: expected value at line 1 column 1")]
+        BadRequest(String),
+        /// SMap changed since the revision given in `If-Match`.
+        #[schema(example = "revision 3 does not match current revision 4")]
+        PreconditionFailed(String),
+        /// SMap file or request body exceeds the accepted size.
+        #[schema(example = "remote file exceeds 104857600 bytes")]
+        PayloadTooLarge(String),
+        /// SMap file does not match the checksum given by the client.
+        #[schema(
+            example = "file has sha256 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08, not 2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        )]
+        ChecksumMismatch(String),
+        /// SMap file has a content type that is not accepted.
+        #[schema(example = "content type \"text/html\" is not accepted")]
+        UnsupportedMediaType(String),
+        /// Request fields violate the constraints listed.
+        #[schema(example = json!([{"field": "title", "message": "must not be empty"}]))]
+        Invalid(Vec<Violation>),
+        /// SMap file is infected, with the signature found by the antivirus.
+        #[schema(example = "file is infected with Eicar-Test-Signature")]
+        Infected(String),
+        /// Client sent too many requests or bytes, and should retry later.
+        #[schema(example = "daily upload quota exhausted")]
+        TooManyRequests(String),
+        /// Service is too busy to handle the request, which should be retried later.
+        #[schema(example = "too many uploads in progress")]
+        Unavailable(String),
+        /// Remote server hosting the SMap file failed.
+        #[schema(example = "remote request failed: HTTP status server error (404 Not Found)")]
+        BadGateway(String),
     }
 
-    /// List all Smap items
-    ///
-    /// List all Smap items from in-memory storage.
-    #[utoipa::path(
-        get,
-        path = "/smap",
-        responses(
-            (status = 200, description = "List all static maps successfully", body = [SMap])
-        )
-    )]
-    pub(super) async fn list_smaps(State(store): State<Arc<Store>>) -> Json<Vec<SMap>> {
-        let smaps = store.lock().await.clone();
-        Json(smaps)
+    /// Constraint of a request field violated by the client.
+    #[derive(Serialize, Deserialize, ToSchema, Debug)]
+    pub(super) struct Violation {
+        #[schema(example = "title")]
+        field: String,
+        #[schema(example = "must not be empty")]
+        message: String,
     }
 
-    /// Uppload Static map
-    ///
-    /// Tries to upload a new SMap item to in-memory storage or fails with 409 conflict if already exists.
-    #[utoipa::path(
-        post,
-        path = "/upload",
-        request_body(content=NewSMap, content_type = "multipart/form-data")
-    )]
-    pub(super) async fn upload_smap_multipart(mut multipart: Multipart) -> impl IntoResponse {
-        let mut title: Option<String> = None;
-        let mut path: Option<String> = None;
+    impl Violation {
+        fn new(field: &str, message: String) -> Self {
+            Self {
+                field: field.to_string(),
+                message,
+            }
+        }
+    }
 
-        let uuid = Uuid::new_v4().to_string();
+    /// Longest title of a map, in characters.
+    pub(super) const MAX_TITLE_LEN: usize = 256;
 
-        while let Some(field) = multipart.next_field().await.unwrap() {
-            let name = field.name().unwrap().to_string();
+    /// Header carrying the client key of a retriable upload.
+    const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
 
-            if name == "title" {
-                title = Some(field.text().await.unwrap());
-                continue;
-            }
-            let file_name = field.file_name().unwrap().to_owned();
+    /// Header carrying the uuid chosen by a trusted client for its upload.
+    const SMAP_UUID: HeaderName = HeaderName::from_static("smap-uuid");
+
+    /// Header carrying digests of the request body, as in RFC 9530.
+    const CONTENT_DIGEST: HeaderName = HeaderName::from_static("content-digest");
+
+    /// Header carrying the API key of the client.
+    pub(super) const API_KEY: HeaderName = HeaderName::from_static("smap_apikey");
 
-            let bytes = field.bytes().await.unwrap();
+    /// Header flagging responses of deprecated routes.
+    const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
 
-            let file_path = format!("/tmp/{file_name}");
-            let mut file = File::create(&file_path).await.unwrap();
+    /// Header flagging the replay of an upload already done under the same key.
+    const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
 
-            file.write_all(&bytes).await.unwrap();
+    /// Listing query parameters.
+    #[derive(Deserialize, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub(super) struct ListQuery {
+        /// Field to sort by, then by uuid, registration order if unset.
+        #[param(inline)]
+        sort: Option<SortField>,
+        /// Sort direction.
+        #[serde(default)]
+        #[param(inline)]
+        order: SortOrder,
+        /// Only list maps modified at or after this RFC 3339 time.
+        #[param(value_type = Option<String>, example = "2023-05-20T10:00:00Z")]
+        updated_after: Option<DateTime<Utc>>,
+        /// Only list maps registered at or after this RFC 3339 time.
+        #[param(value_type = Option<String>, example = "2024-01-01T00:00:00Z")]
+        created_after: Option<DateTime<Utc>>,
+        /// Only list maps registered before this RFC 3339 time.
+        #[param(value_type = Option<String>, example = "2024-02-01T00:00:00Z")]
+        created_before: Option<DateTime<Utc>>,
+        /// Only list maps whose title contains this text, ignoring case.
+        #[param(example = "harbour")]
+        title: Option<String>,
+        /// Only list maps matching this filter expression: comparisons of a field
+        /// with `=`, `!=`, `~` (contains), `<`, `<=`, `>` or `>=`, combined with
+        /// `NOT`, `AND`, `OR` and parentheses.
+        #[param(example = "title~\"cyclone\" AND created_at>2024-01-01")]
+        q: Option<String>,
+        /// Only list maps uploaded by this owner. Listings requested with the API key
+        /// of an owner only show their maps.
+        #[param(example = "mapaction")]
+        owner: Option<String>,
+        /// Only list maps whose footprint intersects this `min_x,min_y,max_x,max_y` box.
+        #[param(example = "30,-30,45,-10")]
+        bbox: Option<String>,
+        /// Only list maps of this media type.
+        #[param(example = "image/png")]
+        content_type: Option<String>,
+        /// Most maps to return, at most 1000.
+        #[serde(default = "default_limit")]
+        #[param(default = 100, maximum = 1000, minimum = 1)]
+        limit: usize,
+        /// Maps to skip before the first one returned.
+        #[serde(default)]
+        offset: usize,
+        /// Comma-separated fields to return for each map, all of them if unset.
+        #[param(example = "uuid,title")]
+        fields: Option<String>,
+        /// Resume a listing sorted by creation time after the page whose
+        /// `X-Next-Cursor` header gave this token. Other parameters must be repeated.
+        cursor: Option<String>,
+    }
+
+    /// Fields of a serialized [`SMap`], as documented in its schema, so that
+    /// every field added to it can be selected.
+    fn smap_fields() -> Vec<String> {
+        match SMap::schema().1 {
+            RefOr::T(Schema::Object(object)) => object.properties.into_keys().collect(),
+            _ => Vec::new(),
+        }
+    }
 
-            path = Some(file_path);
-            //println!("Length of `{}` is {} bytes", name, data.len());
+    /// Fields selected by a comma-separated `list`, or the unknown field named.
+    fn parse_fields(list: &str) -> Result<Vec<&str>, String> {
+        let known = smap_fields();
+        let mut fields = Vec::new();
+        for field in list
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            if !known.iter().any(|known| known == field) {
+                return Err(format!("unknown field {field:?}"));
+            }
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
         }
+        Ok(fields)
+    }
+
+    /// Map serialized with the selected fields only, those it has.
+    struct Sparse<'a> {
+        smap: &'a SMap,
+        fields: &'a [&'a str],
+    }
 
-        let smap = SMap::new(uuid, title.unwrap(), path.unwrap());
-        println!("{:?}", smap);
+    impl Serialize for Sparse<'_> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::{Error, SerializeMap};
 
-        (StatusCode::CREATED, Json(smap)).into_response()
+            let serde_json::Value::Object(mut smap) =
+                serde_json::to_value(self.smap).map_err(S::Error::custom)?
+            else {
+                return Err(S::Error::custom("map is not serialized as an object"));
+            };
+            let mut map = serializer.serialize_map(None)?;
+            for field in self.fields {
+                if let Some(value) = smap.remove(*field) {
+                    map.serialize_entry(field, &value)?;
+                }
+            }
+            map.end()
+        }
+    }
+
+    /// Position in a listing sorted by creation time, then uuid for maps created
+    /// at the same time, handed to clients as an opaque token.
+    struct Cursor {
+        created_at: DateTime<Utc>,
+        uuid: String,
+    }
+
+    impl Cursor {
+        fn after(smap: &SMap) -> Self {
+            Self {
+                created_at: smap.created_at,
+                uuid: smap.uuid.clone(),
+            }
+        }
+
+        fn encode(&self) -> String {
+            let position = format!("{} {}", self.created_at.to_rfc3339(), self.uuid);
+            BASE64_URL.encode(position)
+        }
+
+        fn decode(token: &str) -> Option<Self> {
+            let position = String::from_utf8(BASE64_URL.decode(token).ok()?).ok()?;
+            let (created_at, uuid) = position.split_once(' ')?;
+            Some(Self {
+                created_at: DateTime::parse_from_rfc3339(created_at).ok()?.into(),
+                uuid: uuid.to_string(),
+            })
+        }
+
+        /// Listing position of `smap`, compared with cursors.
+        fn key(smap: &SMap) -> (DateTime<Utc>, &str) {
+            (smap.created_at, &smap.uuid)
+        }
+    }
+
+    /// Page size of listings without `limit`.
+    fn default_limit() -> usize {
+        100
+    }
+
+    /// Largest page size of listings.
+    pub(super) const MAX_LIMIT: usize = 1000;
+
+    /// Header carrying the number of maps matching a listing, across pages.
+    const TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+    /// Header carrying the cursor of the next page of a listing.
+    pub(super) const NEXT_CURSOR: HeaderName = HeaderName::from_static("x-next-cursor");
+
+    /// Deletion query parameters.
+    #[derive(Deserialize, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub(super) struct DeleteQuery {
+        /// Remove the map and its file right away instead of moving it to the trash.
+        #[serde(default)]
+        permanent: bool,
+    }
+
+    /// Query of map file downloads.
+    #[derive(Deserialize, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub(super) struct DownloadQuery {
+        /// Have browsers save the file, named after the map title, rather than
+        /// show it.
+        #[serde(default)]
+        download: bool,
+    }
+
+    /// Field static maps can be sorted by.
+    #[derive(Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, Debug)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum SortField {
+        Title,
+        CreatedAt,
+        UpdatedAt,
+        Size,
+    }
+
+    impl SortField {
+        /// Ascending order of `a` and `b` by the field, then by uuid.
+        pub(super) fn compare(self, a: &SMap, b: &SMap) -> std::cmp::Ordering {
+            let by_field = match self {
+                Self::Title => a.title.cmp(&b.title),
+                Self::CreatedAt => a.created_at.cmp(&b.created_at),
+                Self::UpdatedAt => a.updated_at.cmp(&b.updated_at),
+                Self::Size => a.size.cmp(&b.size),
+            };
+            by_field.then_with(|| a.uuid.cmp(&b.uuid))
+        }
+    }
+
+    /// Sort direction.
+    #[derive(Deserialize, ToSchema, Default, Clone, Copy, PartialEq, Eq, Debug)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum SortOrder {
+        #[default]
+        Asc,
+        Desc,
+    }
+
+    /// List all Smap items
+    ///
+    /// List all Smap items from the metadata store, optionally filtered by title, tags,
+    /// media type, footprint, creation or modification time and sorted. Maps must
+    /// carry every `tag` given.
+    /// Maps are returned a page at a time, with the number of matching maps in `X-Total-Count`.
+    ///
+    /// Offsets skip or repeat maps registered while paging. Listings sorted by
+    /// `created_at` instead give the cursor of their next page in `X-Next-Cursor`,
+    /// to pass as `cursor` and go through the catalog consistently.
+    ///
+    /// The `Link` header gives the URLs of the first, previous, next and last pages
+    /// by offset, or of the first and next pages when paging with a cursor.
+    #[utoipa::path(
+        get,
+        path = "/smap",
+        params(
+            ListQuery,
+            ("tag" = Option<Vec<String>>, Query, description = "Only list maps with this tag, repeatable", example = "cyclone")
+        ),
+        responses(
+            (status = 200, description = "List all static maps successfully", body = [SMap],
+                headers(
+                    ("X-Total-Count" = usize, description = "Number of maps matching the query, across pages"),
+                    ("X-Next-Cursor" = String, description = "Cursor of the next page, for listings sorted by creation time"),
+                    ("Link" = String, description = "URLs of neighbouring pages, as `first`, `prev`, `next` and `last` relations")
+                )),
+            (status = 400, description = "Limit is out of range, filter or bbox is invalid, a field is unknown, or cursor is invalid or combined with an offset or another sort", body = SMapError),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn list_smaps(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        headers: HeaderMap,
+        Query(query): Query<ListQuery>,
+        OriginalUri(uri): OriginalUri,
+        base: BaseUrl,
+    ) -> impl IntoResponse {
+        // Repeated keys do not fit `ListQuery`: read them from the raw query.
+        let tags: Vec<String> = form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .filter(|(key, _)| key == "tag")
+            .map(|(_, tag)| tag.into_owned())
+            .collect();
+        if !(1..=MAX_LIMIT).contains(&query.limit) {
+            return bad_request(format!("limit must be between 1 and {MAX_LIMIT}")).into_response();
+        }
+        let filter = query.q.as_deref().filter(|q| !q.trim().is_empty());
+        let filter = match filter.map(Filter::parse).transpose() {
+            Ok(filter) => filter,
+            Err(err) => return bad_request(format!("invalid filter: {err}")).into_response(),
+        };
+        let bbox = match query.bbox.as_deref().map(BBox::parse).transpose() {
+            Ok(bbox) => bbox,
+            Err(message) => return bad_request(message).into_response(),
+        };
+        let fields = query
+            .fields
+            .as_deref()
+            .filter(|list| !list.trim().is_empty());
+        let fields = match fields.map(parse_fields).transpose() {
+            Ok(fields) => fields,
+            Err(message) => return bad_request(message).into_response(),
+        };
+        let cursor = match query.cursor.as_deref().map(Cursor::decode) {
+            None => None,
+            Some(None) => return bad_request("invalid cursor".to_string()).into_response(),
+            Some(Some(_)) if query.offset > 0 => {
+                return bad_request("cursor and offset exclude each other".to_string())
+                    .into_response()
+            }
+            Some(Some(_)) if query.sort.is_some_and(|sort| sort != SortField::CreatedAt) => {
+                return bad_request("cursors follow the created_at order".to_string())
+                    .into_response()
+            }
+            Some(Some(cursor)) => Some(cursor),
+        };
+        let sort = match cursor {
+            Some(_) => Some(SortField::CreatedAt),
+            None => query.sort,
+        };
+        let by_cursor = sort == Some(SortField::CreatedAt);
+        let descending = matches!(query.order, SortOrder::Desc);
+        let smaps = match (&filter, sort) {
+            (Some(filter), sort) => store
+                .list_filtered(filter, sort.map(|sort| (sort, query.order)))
+                .await
+                .map(|mut smaps| {
+                    if sort.is_none() && descending {
+                        smaps.reverse();
+                    }
+                    smaps
+                }),
+            (None, Some(sort)) => store.list_sorted(sort, query.order).await,
+            (None, None) => store.list().await.map(|mut smaps| {
+                if descending {
+                    smaps.reverse();
+                }
+                smaps
+            }),
+        };
+        let mut smaps = match smaps {
+            Ok(smaps) => smaps,
+            Err(err) => return database_error(err).into_response(),
+        };
+
+        if let Some(updated_after) = query.updated_after {
+            smaps.retain(|smap| smap.updated_at >= updated_after);
+        }
+        if let Some(created_after) = query.created_after {
+            smaps.retain(|smap| smap.created_at >= created_after);
+        }
+        if let Some(created_before) = query.created_before {
+            smaps.retain(|smap| smap.created_at < created_before);
+        }
+        if let Some(title) = query.title.filter(|title| !title.is_empty()) {
+            let title = title.to_lowercase();
+            smaps.retain(|smap| smap.title.to_lowercase().contains(&title));
+        }
+        let mine = caller(&config, &headers).owner();
+        for owner in [mine, query.owner].into_iter().flatten() {
+            smaps.retain(|smap| smap.owner.as_ref() == Some(&owner));
+        }
+        if let Some(content_type) = query.content_type.as_deref() {
+            let content_type = media_type(Some(content_type));
+            smaps.retain(|smap| smap.content_type == content_type);
+        }
+        if !tags.is_empty() {
+            smaps.retain(|smap| tags.iter().all(|tag| smap.tags.contains(tag)));
+        }
+        if let Some(bbox) = bbox {
+            smaps.retain(|smap| {
+                smap.bbox
+                    .is_some_and(|footprint| footprint.intersects(&bbox))
+            });
+        }
+
+        let total = smaps.len();
+        if let Some(cursor) = cursor {
+            let position = (cursor.created_at, cursor.uuid.as_str());
+            smaps.retain(|smap| match descending {
+                false => Cursor::key(smap) > position,
+                true => Cursor::key(smap) < position,
+            });
+        }
+        let more = smaps.len() > query.offset + query.limit;
+        let page: Vec<SMap> = smaps
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit)
+            .map(|smap| base.link(smap))
+            .collect();
+
+        let next_cursor = page
+            .last()
+            .filter(|_| by_cursor && more)
+            .map(|last| Cursor::after(last).encode());
+
+        let mut links = vec![("first", page_url(&uri, &[]))];
+        if query.cursor.is_some() {
+            if let Some(next) = &next_cursor {
+                links.push(("next", page_url(&uri, &[("cursor", next.clone())])));
+            }
+        } else {
+            let offset = |offset: usize| page_url(&uri, &[("offset", offset.to_string())]);
+            if query.offset > 0 {
+                links.push(("prev", offset(query.offset.saturating_sub(query.limit))));
+            }
+            if query.offset + query.limit < total {
+                links.push(("next", offset(query.offset + query.limit)));
+            }
+            links.push((
+                "last",
+                offset(total.saturating_sub(1) / query.limit * query.limit),
+            ));
+        }
+        let links: Vec<String> = links
+            .into_iter()
+            .map(|(rel, url)| format!("<{url}>; rel=\"{rel}\""))
+            .collect();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(TOTAL_COUNT, HeaderValue::from(total));
+        if let Some(next) = next_cursor.and_then(|next| HeaderValue::from_str(&next).ok()) {
+            headers.insert(NEXT_CURSOR, next);
+        }
+        if let Ok(links) = HeaderValue::from_str(&links.join(", ")) {
+            headers.insert(LINK, links);
+        }
+        match fields {
+            Some(fields) => {
+                let page: Vec<Sparse> = page
+                    .iter()
+                    .map(|smap| Sparse {
+                        smap,
+                        fields: &fields,
+                    })
+                    .collect();
+                (headers, Json(page)).into_response()
+            }
+            None => (headers, Json(page)).into_response(),
+        }
+    }
+
+    /// Number of active maps carrying a tag.
+    #[derive(Serialize, ToSchema)]
+    pub(super) struct TagCount {
+        #[schema(example = "cyclone")]
+        tag: String,
+        #[schema(example = 12)]
+        count: usize,
+    }
+
+    /// List tags
+    ///
+    /// List the tags of active static maps with the number of maps carrying each,
+    /// most used first.
+    #[utoipa::path(
+        get,
+        path = "/tags",
+        responses(
+            (status = 200, description = "Tags in use", body = [TagCount]),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn list_tags(State(store): State<Arc<Store>>) -> impl IntoResponse {
+        let smaps = match store.list().await {
+            Ok(smaps) => smaps,
+            Err(err) => return database_error(err).into_response(),
+        };
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for smap in smaps {
+            // A tag repeated on a map counts once.
+            let tags: HashSet<String> = smap.tags.into_iter().collect();
+            for tag in tags {
+                *counts.entry(tag).or_default() += 1;
+            }
+        }
+
+        let mut tags: Vec<TagCount> = counts
+            .into_iter()
+            .map(|(tag, count)| TagCount { tag, count })
+            .collect();
+        tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+        Json(tags).into_response()
+    }
+
+    /// URL of the listing requested at `uri` with its `offset` and `cursor`
+    /// replaced by the `page` parameters.
+    fn page_url(uri: &Uri, page: &[(&str, String)]) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        let pairs = form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes());
+        for (key, value) in pairs.filter(|(key, _)| key != "offset" && key != "cursor") {
+            query.append_pair(&key, &value);
+        }
+        for (key, value) in page {
+            query.append_pair(key, value);
+        }
+        match query.finish() {
+            query if query.is_empty() => uri.path().to_string(),
+            query => format!("{}?{query}", uri.path()),
+        }
+    }
+
+    /// Full-text search parameters.
+    #[derive(Deserialize, IntoParams)]
+    pub(super) struct SearchQuery {
+        /// Words to find in the title, description or tags; a word also matches
+        /// longer words it starts.
+        #[param(example = "cyclone pop")]
+        q: String,
+        /// Most maps to return, at most 1000.
+        #[serde(default = "default_limit")]
+        #[param(default = 100, maximum = 1000, minimum = 1)]
+        limit: usize,
+    }
+
+    /// Search Static maps
+    ///
+    /// Find the active maps matching every word of `q`, best matches first: title
+    /// words weigh more than tags, which weigh more than the description. The number
+    /// of matching maps is given in `X-Total-Count`.
+    #[utoipa::path(
+        get,
+        path = "/smap/search",
+        params(SearchQuery),
+        responses(
+            (status = 200, description = "Matching static maps", body = [SMap],
+                headers(("X-Total-Count" = usize, description = "Number of matching maps"))),
+            (status = 400, description = "Query has no words or limit is out of range", body = SMapError),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn search_smaps(
+        State(store): State<Arc<Store>>,
+        Query(query): Query<SearchQuery>,
+        base: BaseUrl,
+    ) -> impl IntoResponse {
+        if !(1..=MAX_LIMIT).contains(&query.limit) {
+            return bad_request(format!("limit must be between 1 and {MAX_LIMIT}")).into_response();
+        }
+        if !query.q.chars().any(char::is_alphanumeric) {
+            return bad_request("query has no words".to_string()).into_response();
+        }
+        let mut smaps = match store.search(&query.q).await {
+            Ok(smaps) => smaps,
+            Err(err) => return database_error(err).into_response(),
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(TOTAL_COUNT, HeaderValue::from(smaps.len()));
+        smaps.truncate(query.limit);
+        let smaps: Vec<SMap> = smaps.into_iter().map(|smap| base.link(smap)).collect();
+        (headers, Json(smaps)).into_response()
+    }
+
+    /// Upload Static map
+    ///
+    /// Tries to upload a new SMap item to the metadata store or fails with 409 conflict if already exists.
+    /// Uploads are rejected with 507 once the configured storage quota is reached.
+    ///
+    /// The map comes as a multipart form, or as JSON with the file content encoded
+    /// in base64 for clients that cannot build multipart bodies.
+    ///
+    /// Several maps can be uploaded at once by sending several file parts, the n-th
+    /// `title` part naming the n-th file. They are registered together or not at
+    /// all, and returned as an array instead of a single map.
+    ///
+    /// Titles must not be empty nor longer than 256 characters; each violated
+    /// constraint is listed in the 422 response.
+    ///
+    /// A retry sending the `Idempotency-Key` of an upload that succeeded gets the
+    /// maps it registered back instead of registering new ones.
+    ///
+    /// Files not matching the `sha256` checksum sent along, one per file in
+    /// multipart uploads, are refused.
+    ///
+    /// Clients trusted with an upsert API key may choose the uuid of a single map,
+    /// in a `uuid` part or the `SMap-Uuid` header, replacing the title and file of
+    /// the map already registered under it.
+    #[utoipa::path(
+        post,
+        path = "/smap",
+        params(
+            ("Idempotency-Key" = Option<String>, Header, description = "Client-chosen key identifying the upload across retries"),
+            ("SMap-Uuid" = Option<String>, Header, description = "Uuid of the map, created or replaced; trusted API keys only")
+        ),
+        request_body = NewSMap,
+        responses(
+            (status = 200, description = "Static map registered under the given uuid replaced successfully, or every file duplicated an existing one, returned flagged `duplicate: true` under the default `--on-duplicate existing`", body = SMap),
+            (status = 201, description = "Static map uploaded successfully, an array of them for several files", body = SMap),
+            (status = 400, description = "Malformed multipart body, file is not valid base64, or uuid is invalid", body = SMapError),
+            (status = 401, description = "API key is not trusted to choose the uuid", body = SMapError),
+            (status = 409, description = "Static map duplicates an existing one under `--on-duplicate reject` or `rename`, or an upload with the same idempotency key is running", body = SMapError),
+            (status = 413, description = "Image exceeds the pixel limits", body = SMapError),
+            (status = 415, description = "File has a content type that is not accepted", body = SMapError),
+            (status = 422, description = "Title or file missing, empty or too long, expiry not a positive number of seconds, file name has a blocked or disguised extension, or file does not match its sha256 checksum, is infected, or is too malformed to strip its metadata", body = SMapError),
+            (status = 500, description = "Static map file or metadata could not be stored", body = SMapError),
+            (status = 502, description = "Antivirus could not scan the file", body = SMapError),
+            (status = 507, description = "Storage quota exhausted", body = SMapError)
+        )
+    )]
+    pub(super) async fn upload_smap(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        State(idempotency): State<Arc<IdempotencyKeys>>,
+        headers: HeaderMap,
+        request: Request<Body>,
+    ) -> Response {
+        let multipart = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("multipart/form-data"));
+        if multipart {
+            let multipart = match Multipart::from_request(request, &()).await {
+                Ok(multipart) => multipart,
+                Err(rejection) => return bad_request(rejection.body_text()).into_response(),
+            };
+            let upload = upload_multipart(&config, &store, storage.as_ref(), &headers, multipart);
+            return idempotent(&idempotency, &headers, upload).await;
+        }
+
+        let Json(new) = match Json::<NewSMap>::from_request(request, &()).await {
+            Ok(new) => new,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let upload = async {
+            let uuid = client_uuid(&config, &headers, None).map_err(IntoResponse::into_response)?;
+            accept_content_type(&config, new.content_type.as_deref())
+                .map_err(IntoResponse::into_response)?;
+            let bytes = BASE64.decode(&new.file).map_err(|err| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(SMapError::BadRequest(format!(
+                        "file is not valid base64: {err}"
+                    ))),
+                )
+                    .into_response()
+            })?;
+            let content_type =
+                sniff_content_type(&config, &bytes).map_err(IntoResponse::into_response)?;
+            let (bytes, content_type) = (Bytes::from(bytes), Some(content_type));
+            let file = store_file(&config, &store, storage.as_ref(), bytes, content_type)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            if let Some(expected) = &new.sha256 {
+                if let Err(err) = verify_checksum(&file.received, expected) {
+                    store.release(&file.key).await;
+                    return Err(err.into_response());
+                }
+            }
+            let (titles, files) = (vec![new.title], vec![file]);
+            let options = UploadOptions {
+                uuid,
+                owner: caller(&config, &headers).owner(),
+                expires_in: new.expires_in,
+            };
+            register_upload(&config, &store, storage.as_ref(), titles, files, options).await
+        };
+        idempotent(&idempotency, &headers, upload).await
+    }
+
+    /// Uppload Static map
+    ///
+    /// Former multipart upload route, replaced by `POST /smap`.
+    #[utoipa::path(
+        post,
+        path = "/upload",
+        params(
+            ("Idempotency-Key" = Option<String>, Header, description = "Client-chosen key identifying the upload across retries"),
+            ("SMap-Uuid" = Option<String>, Header, description = "Uuid of the map, created or replaced; trusted API keys only")
+        ),
+        request_body(content=NewSMap, content_type = "multipart/form-data"),
+        responses(
+            (status = 200, description = "Static map registered under the given uuid replaced successfully, or every file duplicated an existing one, returned flagged `duplicate: true` under the default `--on-duplicate existing`", body = SMap),
+            (status = 201, description = "Static map uploaded successfully, an array of them for several files", body = SMap),
+            (status = 400, description = "Malformed multipart body, or uuid is invalid", body = SMapError),
+            (status = 401, description = "API key is not trusted to choose the uuid", body = SMapError),
+            (status = 409, description = "Static map duplicates an existing one under `--on-duplicate reject` or `rename`, or an upload with the same idempotency key is running", body = SMapError),
+            (status = 413, description = "Image exceeds the pixel limits", body = SMapError),
+            (status = 415, description = "File has a content type that is not accepted", body = SMapError),
+            (status = 422, description = "Title or file missing, empty or too long, expiry not a positive number of seconds, file name has a blocked or disguised extension, or file does not match its sha256 checksum, is infected, or is too malformed to strip its metadata", body = SMapError),
+            (status = 500, description = "Static map file or metadata could not be stored", body = SMapError),
+            (status = 502, description = "Antivirus could not scan the file", body = SMapError),
+            (status = 507, description = "Storage quota exhausted", body = SMapError)
+        )
+    )]
+    #[deprecated = "use `POST /smap`"]
+    pub(super) async fn upload_smap_multipart(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        State(idempotency): State<Arc<IdempotencyKeys>>,
+        headers: HeaderMap,
+        multipart: Result<Multipart, MultipartRejection>,
+    ) -> Response {
+        let multipart = match multipart {
+            Ok(multipart) => multipart,
+            Err(rejection) => return bad_request(rejection.body_text()).into_response(),
+        };
+        let upload = upload_multipart(&config, &store, storage.as_ref(), &headers, multipart);
+        let response = idempotent(&idempotency, &headers, upload).await;
+        (
+            [
+                (DEPRECATION, "true"),
+                (LINK, "</api/v1/smap>; rel=\"successor-version\""),
+            ],
+            response,
+        )
+            .into_response()
+    }
+
+    /// Upload Static map from a URL
+    ///
+    /// Download the map file from a remote server, e.g. to import an already
+    /// published map, and register it like an uploaded one.
+    #[utoipa::path(
+        post,
+        path = "/smap/from-url",
+        params(
+            ("Idempotency-Key" = Option<String>, Header, description = "Client-chosen key identifying the upload across retries"),
+            ("SMap-Uuid" = Option<String>, Header, description = "Uuid of the map, created or replaced; trusted API keys only")
+        ),
+        request_body = RemoteSMap,
+        responses(
+            (status = 200, description = "Static map registered under the given uuid replaced successfully, or the file duplicated an existing one, returned flagged `duplicate: true` under the default `--on-duplicate existing`", body = SMap),
+            (status = 201, description = "Static map downloaded and registered successfully", body = SMap),
+            (status = 400, description = "URL is not an http(s) URL, or uuid is invalid", body = SMapError),
+            (status = 401, description = "API key is not trusted to choose the uuid", body = SMapError),
+            (status = 409, description = "Static map duplicates an existing one under `--on-duplicate reject` or `rename`, or an upload with the same idempotency key is running", body = SMapError),
+            (status = 413, description = "Remote file exceeds the size limit, or image exceeds the pixel limits", body = SMapError),
+            (status = 415, description = "Remote file has a content type that is not accepted", body = SMapError),
+            (status = 422, description = "Title is empty or too long, expiry not a positive number of seconds, URL names a file with a blocked or disguised extension, or file is infected or too malformed to strip its metadata", body = SMapError),
+            (status = 500, description = "Static map file or metadata could not be stored", body = SMapError),
+            (status = 502, description = "Remote server could not be reached or answered with an error, or antivirus could not scan the file", body = SMapError),
+            (status = 507, description = "Storage quota exhausted", body = SMapError)
+        )
+    )]
+    pub(super) async fn upload_smap_from_url(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        State(idempotency): State<Arc<IdempotencyKeys>>,
+        headers: HeaderMap,
+        Json(remote): Json<RemoteSMap>,
+    ) -> impl IntoResponse {
+        let upload = async {
+            let uuid = client_uuid(&config, &headers, None).map_err(IntoResponse::into_response)?;
+            if let Some(name) = ingest::file_name(&remote.url) {
+                accept_file_name(&config, &name).map_err(IntoResponse::into_response)?;
+            }
+            let bytes = ingest::fetch(&config.ingest, &remote.url)
+                .await
+                .map_err(|err| ingest_error(err).into_response())?;
+            let content_type =
+                sniff_content_type(&config, &bytes).map_err(IntoResponse::into_response)?;
+            let file = store_file(&config, &store, storage.as_ref(), bytes, Some(content_type))
+                .await
+                .map_err(IntoResponse::into_response)?;
+            let (titles, files) = (vec![remote.title], vec![file]);
+            let options = UploadOptions {
+                uuid,
+                owner: caller(&config, &headers).owner(),
+                expires_in: remote.expires_in,
+            };
+            register_upload(&config, &store, storage.as_ref(), titles, files, options).await
+        };
+        idempotent(&idempotency, &headers, upload).await
+    }
+
+    fn ingest_error(err: IngestError) -> (StatusCode, Json<SMapError>) {
+        let message = err.to_string();
+        match err {
+            IngestError::InvalidUrl(_) => (
+                StatusCode::BAD_REQUEST,
+                Json(SMapError::BadRequest(message)),
+            ),
+            IngestError::Http(_) => (
+                StatusCode::BAD_GATEWAY,
+                Json(SMapError::BadGateway(message)),
+            ),
+            IngestError::ContentType(_) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                Json(SMapError::UnsupportedMediaType(message)),
+            ),
+            IngestError::TooLarge(_) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(SMapError::PayloadTooLarge(message)),
+            ),
+        }
+    }
+
+    /// Refuse request bodies larger than the configured upload limit with 413.
+    ///
+    /// Bodies announcing their length are refused before being read; others
+    /// once they exceed the limit, whatever the handler made of the failed read.
+    pub(super) async fn limit_upload(
+        State(config): State<Arc<Config>>,
+        request: Request<Body>,
+        next: Next<Body>,
+    ) -> Response {
+        let limit = config.max_upload_bytes;
+        let too_large = || {
+            (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(SMapError::PayloadTooLarge(format!(
+                    "request body exceeds {limit} bytes"
+                ))),
+            )
+                .into_response()
+        };
+        let length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<u64>().ok());
+        match length {
+            Some(length) if length > limit => return too_large(),
+            Some(_) => return next.run(request).await,
+            None => {}
+        }
+
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let (parts, body) = request.into_parts();
+        let mut read = 0;
+        let body = body.map_err(io::Error::other).and_then({
+            let exceeded = exceeded.clone();
+            move |chunk| {
+                read += chunk.len() as u64;
+                if read > limit {
+                    exceeded.store(true, Ordering::Relaxed);
+                    return future::ready(Err(io::Error::other("request body too large")));
+                }
+                future::ready(Ok(chunk))
+            }
+        });
+        let response = next
+            .run(Request::from_parts(parts, Body::wrap_stream(body)))
+            .await;
+        if exceeded.load(Ordering::Relaxed) {
+            return too_large();
+        }
+        response
+    }
+
+    /// Seconds clients are asked to wait before retrying an upload refused for
+    /// lack of a slot.
+    const UPLOAD_RETRY_AFTER: u64 = 5;
+
+    /// Handle at most `--max-concurrent-uploads` requests sending a body at once,
+    /// queuing others for a slot up to `--upload-queue-secs` and refusing them
+    /// with 503 after.
+    pub(super) async fn limit_concurrent_uploads(
+        State(config): State<Arc<Config>>,
+        State(slots): State<Arc<Semaphore>>,
+        request: Request<Body>,
+        next: Next<Body>,
+    ) -> Response {
+        if matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS
+        ) {
+            return next.run(request).await;
+        }
+        let wait = Duration::from_secs(config.upload_queue_secs);
+        let Ok(Ok(_permit)) = tokio::time::timeout(wait, slots.acquire()).await else {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, UPLOAD_RETRY_AFTER)],
+                Json(SMapError::Unavailable(
+                    "too many uploads in progress".to_string(),
+                )),
+            )
+                .into_response();
+        };
+        next.run(request).await
+    }
+
+    /// Run `upload` unless the `Idempotency-Key` of `headers` was already used,
+    /// answering with the maps it registered.
+    async fn idempotent(
+        idempotency: &IdempotencyKeys,
+        headers: &HeaderMap,
+        upload: impl Future<Output = Result<Vec<SMap>, Response>>,
+    ) -> Response {
+        let key = headers
+            .get(IDEMPOTENCY_KEY)
+            .and_then(|key| key.to_str().ok())
+            .map(str::to_string);
+        let pending = match &key {
+            None => None,
+            Some(key) => match idempotency.claim(key) {
+                Claim::New(pending) => Some(pending),
+                Claim::InProgress => {
+                    return (
+                        StatusCode::CONFLICT,
+                        Json(SMapError::Conflict(format!(
+                            "an upload with idempotency key {key:?} is in progress"
+                        ))),
+                    )
+                        .into_response()
+                }
+                Claim::Done(smaps) => {
+                    let mut response = created(smaps);
+                    response
+                        .headers_mut()
+                        .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+                    return response;
+                }
+            },
+        };
+
+        match upload.await {
+            Ok(smaps) => {
+                if let Some(pending) = pending {
+                    pending.complete(&smaps);
+                }
+                created(smaps)
+            }
+            Err(err) => err,
+        }
+    }
+
+    /// Response listing the maps of an upload, a single one without array: 201,
+    /// or 200 if the upload only replaced the files of existing maps.
+    pub(super) fn created(smaps: Vec<SMap>) -> Response {
+        let status = if smaps
+            .iter()
+            .any(|smap| !smap.duplicate && smap.revision == first_revision())
+        {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        };
+        match <[SMap; 1]>::try_from(smaps) {
+            Ok([smap]) => (status, [(ETAG, smap.etag())], Json(smap)).into_response(),
+            Err(smaps) => (status, Json(smaps)).into_response(),
+        }
+    }
+
+    /// Store the files of a multipart upload and register a map for each.
+    async fn upload_multipart(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        headers: &HeaderMap,
+        mut multipart: Multipart,
+    ) -> Result<Vec<SMap>, Response> {
+        let mut titles: Vec<String> = Vec::new();
+        let mut checksums: Vec<String> = Vec::new();
+        let mut files: Vec<StoredFile> = Vec::new();
+        let mut uuid = None;
+        let mut expires_in = None;
+
+        let read = async {
+            while let Some(field) = multipart.next_field().await.map_err(multipart_error)? {
+                let Some(name) = field.name().map(str::to_string) else {
+                    return Err(bad_request("multipart part without a name".to_string()));
+                };
+                match name.as_str() {
+                    "title" => titles.push(field.text().await.map_err(multipart_error)?),
+                    "uuid" => uuid = Some(field.text().await.map_err(multipart_error)?),
+                    "sha256" => checksums.push(field.text().await.map_err(multipart_error)?),
+                    "expires_in" => expires_in = Some(field.text().await.map_err(multipart_error)?),
+                    _ if field.file_name().is_none() => {
+                        return Err(bad_request(format!("part {name:?} is not a file")));
+                    }
+                    _ => files.push(store_field(config, store, storage, field).await?),
+                }
+            }
+            Ok(())
+        };
+        if let Err(err) = read.await {
+            store.release_all(&files).await;
+            return Err(err.into_response());
+        }
+
+        let verified = match checksums.len() {
+            0 => Ok(()),
+            count if count != files.len() => Err(bad_request(format!(
+                "got {count} sha256 checksums for {} files",
+                files.len()
+            ))),
+            _ => files
+                .iter()
+                .zip(&checksums)
+                .try_for_each(|(file, expected)| verify_checksum(&file.received, expected)),
+        };
+        if let Err(err) = verified {
+            store.release_all(&files).await;
+            return Err(err.into_response());
+        }
+
+        let expires_in = match expires_in
+            .map(|text| text.trim().parse::<u64>())
+            .transpose()
+        {
+            Ok(expires_in) => expires_in,
+            Err(err) => {
+                store.release_all(&files).await;
+                let violation = Violation::new("expires_in", format!("must be seconds: {err}"));
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(SMapError::Invalid(vec![violation])),
+                )
+                    .into_response());
+            }
+        };
+
+        let uuid = match client_uuid(config, headers, uuid) {
+            Ok(uuid) => uuid,
+            Err(err) => {
+                store.release_all(&files).await;
+                return Err(err.into_response());
+            }
+        };
+        let options = UploadOptions {
+            uuid,
+            owner: caller(config, headers).owner(),
+            expires_in,
+        };
+        register_upload(config, store, storage, titles, files, options).await
+    }
+
+    /// Settings an upload applies to the maps it registers.
+    struct UploadOptions {
+        /// Uuid chosen by the client for the single map of the upload.
+        uuid: Option<String>,
+        /// Owner of the API key the upload was made with.
+        owner: Option<String>,
+        /// Seconds after which the maps expire.
+        expires_in: Option<u64>,
+    }
+
+    /// Register a map for each of the uploaded `files`, named by `titles`.
+    ///
+    /// With a client-chosen uuid, the single file is upserted under it instead;
+    /// a replaced map keeps its owner, and its expiry unless a new one is given.
+    async fn register_upload(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        titles: Vec<String>,
+        files: Vec<StoredFile>,
+        options: UploadOptions,
+    ) -> Result<Vec<SMap>, Response> {
+        let UploadOptions {
+            uuid,
+            owner,
+            expires_in,
+        } = options;
+        if uuid.is_some() && files.len() > 1 {
+            store.release_all(&files).await;
+            return Err(
+                bad_request("a client-chosen uuid applies to a single file".to_string())
+                    .into_response(),
+            );
+        }
+        let violations = upload_violations(&titles, files.len(), expires_in);
+        if !violations.is_empty() {
+            store.release_all(&files).await;
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(SMapError::Invalid(violations)),
+            )
+                .into_response());
+        }
+
+        let mut smaps: Vec<SMap> = titles
+            .into_iter()
+            .zip(files)
+            .map(|(title, file)| {
+                let mut smap = SMap::new(Uuid::new_v4().to_string(), title, file);
+                smap.owner = owner.clone();
+                smap.expires_at = expires_in.and_then(expiry);
+                smap
+            })
+            .collect();
+        if let Some(uuid) = uuid {
+            let mut smap = smaps.remove(0);
+            smap.uuid = uuid;
+            return upsert(config, store, storage, smap).await;
+        }
+        register_new(config, store, storage, smaps).await
+    }
+
+    /// Time `seconds` from now, unless too far in the future to represent.
+    fn expiry(seconds: u64) -> Option<DateTime<Utc>> {
+        let seconds = chrono::Duration::try_seconds(i64::try_from(seconds).ok()?)?;
+        Utc::now().checked_add_signed(seconds)
+    }
+
+    /// Constraints violated by an upload of `files` files named by `titles`,
+    /// expiring after `expires_in` seconds.
+    fn upload_violations(
+        titles: &[String],
+        files: usize,
+        expires_in: Option<u64>,
+    ) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        match expires_in {
+            Some(0) => {
+                violations.push(Violation::new("expires_in", "must be positive".to_string()))
+            }
+            Some(seconds) if expiry(seconds).is_none() => violations.push(Violation::new(
+                "expires_in",
+                "is too far in the future".to_string(),
+            )),
+            _ => {}
+        }
+        if titles.is_empty() {
+            violations.push(Violation::new("title", "is required".to_string()));
+        }
+        if files == 0 {
+            violations.push(Violation::new("file", "is required".to_string()));
+        } else if !titles.is_empty() && files != titles.len() {
+            violations.push(Violation::new(
+                "file",
+                format!(
+                    "got {files} files for {} titles, each needs one",
+                    titles.len()
+                ),
+            ));
+        }
+        for (index, title) in titles.iter().enumerate() {
+            let field = match titles.len() {
+                1 => "title".to_string(),
+                _ => format!("title[{index}]"),
+            };
+            if title.trim().is_empty() {
+                violations.push(Violation::new(&field, "must not be empty".to_string()));
+            } else if title.chars().count() > MAX_TITLE_LEN {
+                violations.push(Violation::new(
+                    &field,
+                    format!("must be at most {MAX_TITLE_LEN} characters"),
+                ));
+            }
+        }
+        violations
+    }
+
+    /// Register `smaps` unless one is a duplicate, recording their sidecars.
+    ///
+    /// The storage keys of `smaps` must be held; they are released either way.
+    pub(super) async fn register_new(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        mut smaps: Vec<SMap>,
+    ) -> Result<Vec<SMap>, Response> {
+        match config.on_duplicate {
+            CollisionPolicy::Reject => {}
+            CollisionPolicy::Rename => {
+                if let Err(err) = store.rename_duplicates(&mut smaps, config.duplicates).await {
+                    for smap in &smaps {
+                        store.release(&smap.key).await;
+                    }
+                    return Err(database_error(err).into_response());
+                }
+            }
+            CollisionPolicy::Overwrite => {
+                return overwrite_duplicates(config, store, storage, smaps).await
+            }
+            CollisionPolicy::Existing => {
+                return reuse_duplicates(config, store, storage, smaps).await
+            }
+        }
+
+        let duplicate = store.find_duplicate(&smaps, config.duplicates).await;
+        if !matches!(duplicate, Ok(None)) {
+            for smap in &smaps {
+                store.release(&smap.key).await;
+            }
+        }
+        match duplicate {
+            Ok(None) => {}
+            Ok(Some((smap, existing))) => {
+                let original = match existing {
+                    Some(existing) => format!("map {}", existing.uuid),
+                    None => "another file of the upload".to_string(),
+                };
+                return Err(duplicate_error(smap, &original));
+            }
+            Err(err) => return Err(database_error(err).into_response()),
+        }
+        register(store, storage, smaps).await
+    }
+
+    /// Register `smaps`, whatever they duplicate, recording their sidecars.
+    ///
+    /// The storage keys of `smaps` must be held; they are released either way.
+    async fn register(
+        store: &Store,
+        storage: &dyn StorageBackend,
+        smaps: Vec<SMap>,
+    ) -> Result<Vec<SMap>, Response> {
+        if let Err(err) = store.register_all(&smaps).await {
+            return Err(database_error(err).into_response());
+        }
+        for smap in &smaps {
+            rescan::record(storage, smap).await;
+        }
+        Ok(smaps)
+    }
+
+    /// [`register_new`] under [`CollisionPolicy::Existing`]: maps duplicating an
+    /// active map, or an earlier map of `smaps`, are not registered, and that
+    /// map is returned in their place flagged as a duplicate.
+    async fn reuse_duplicates(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        smaps: Vec<SMap>,
+    ) -> Result<Vec<SMap>, Response> {
+        let policy = config.duplicates;
+        let active = if policy == DuplicatePolicy::Allow {
+            Ok(Vec::new())
+        } else {
+            store.list().await
+        };
+        let active = match active {
+            Ok(active) => active,
+            Err(err) => {
+                for smap in &smaps {
+                    store.release(&smap.key).await;
+                }
+                return Err(database_error(err).into_response());
+            }
+        };
+
+        let mut registered = Vec::with_capacity(smaps.len());
+        let mut fresh: Vec<SMap> = Vec::new();
+        for smap in smaps {
+            let existing = active
+                .iter()
+                .chain(&fresh)
+                .find(|other| smap.duplicates(other, policy))
+                .cloned();
+            match existing {
+                Some(existing) => {
+                    store.release(&smap.key).await;
+                    registered.push(SMap {
+                        duplicate: true,
+                        ..existing
+                    });
+                }
+                None => {
+                    fresh.push(smap.clone());
+                    registered.push(smap);
+                }
+            }
+        }
+
+        if let Err(err) = store.register_all(&fresh).await {
+            return Err(database_error(err).into_response());
+        }
+        for smap in &fresh {
+            rescan::record(storage, smap).await;
+        }
+        Ok(registered)
+    }
+
+    /// [`register_new`] under [`CollisionPolicy::Overwrite`]: maps duplicating an
+    /// active map replace it, the others are registered.
+    ///
+    /// Maps duplicating an earlier map of `smaps` are still rejected.
+    async fn overwrite_duplicates(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        smaps: Vec<SMap>,
+    ) -> Result<Vec<SMap>, Response> {
+        let policy = config.duplicates;
+        let active = if policy == DuplicatePolicy::Allow {
+            Ok(Vec::new())
+        } else {
+            store.list().await
+        };
+        let conflict = match &active {
+            Ok(_) => smaps
+                .iter()
+                .enumerate()
+                .find(|(index, smap)| {
+                    smaps[..*index]
+                        .iter()
+                        .any(|other| smap.duplicates(other, policy))
+                })
+                .map(|(_, smap)| duplicate_error(smap, "another file of the upload")),
+            Err(_) => None,
+        };
+        if active.is_err() || conflict.is_some() {
+            for smap in &smaps {
+                store.release(&smap.key).await;
+            }
+        }
+        let active = match active {
+            Ok(active) => active,
+            Err(err) => return Err(database_error(err).into_response()),
+        };
+        if let Some(conflict) = conflict {
+            return Err(conflict);
+        }
+
+        let mut registered = Vec::with_capacity(smaps.len());
+        let mut fresh = Vec::new();
+        let mut remaining = smaps.into_iter();
+        while let Some(smap) = remaining.next() {
+            let Some(existing) = active.iter().find(|other| smap.duplicates(other, policy)) else {
+                fresh.push(smap.clone());
+                registered.push(smap);
+                continue;
+            };
+            match store.overwrite(&existing.uuid, &smap).await {
+                Ok(Some((updated, previous))) => {
+                    store.release(&smap.key).await;
+                    overwritten(store, storage, &updated, &previous).await;
+                    registered.push(updated);
+                }
+                // The duplicated map was removed meanwhile: register the upload.
+                Ok(None) => {
+                    fresh.push(smap.clone());
+                    registered.push(smap);
+                }
+                Err(err) => {
+                    store.release(&smap.key).await;
+                    for smap in fresh.iter().chain(remaining.as_slice()) {
+                        store.release(&smap.key).await;
+                    }
+                    return Err(database_error(err).into_response());
+                }
+            }
+        }
+
+        if let Err(err) = store.register_all(&fresh).await {
+            return Err(database_error(err).into_response());
+        }
+        for smap in &fresh {
+            rescan::record(storage, smap).await;
+        }
+        Ok(registered)
+    }
+
+    /// Register `smap` under the uuid chosen by the client, or give the map
+    /// already registered under it the title and file of `smap`.
+    ///
+    /// Replacing a map skips the duplicate check: the client names the map.
+    async fn upsert(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        smap: SMap,
+    ) -> Result<Vec<SMap>, Response> {
+        let replaced = store.overwrite(&smap.uuid, &smap).await;
+        let trashed = match replaced {
+            Ok(None) => store.contains(&smap.uuid).await,
+            _ => Ok(false),
+        };
+        if !matches!((&replaced, &trashed), (Ok(None), Ok(false))) {
+            store.release(&smap.key).await;
+        }
+        match (replaced, trashed) {
+            (Ok(Some((updated, previous))), _) => {
+                overwritten(store, storage, &updated, &previous).await;
+                Ok(vec![updated])
+            }
+            (Ok(None), Ok(false)) => register_new(config, store, storage, vec![smap]).await,
+            (Ok(None), Ok(true)) => Err((
+                StatusCode::CONFLICT,
+                Json(SMapError::Conflict(format!(
+                    "map {} is in the trash",
+                    smap.uuid
+                ))),
+            )
+                .into_response()),
+            (Err(err), _) | (_, Err(err)) => Err(database_error(err).into_response()),
+        }
+    }
+
+    /// Record the sidecar of a map given a new file by an upload, discarding the
+    /// file of its `previous` state unless it is still used.
+    async fn overwritten(
+        store: &Store,
+        storage: &dyn StorageBackend,
+        smap: &SMap,
+        previous: &SMap,
+    ) {
+        rescan::record(storage, smap).await;
+        if previous.key != smap.key {
+            if let Err(err) = store.discard_file(previous, storage).await {
+                eprintln!(
+                    "discarding previous file of map {} failed: {err:?}",
+                    smap.uuid
+                );
+            }
+        }
+    }
+
+    /// Uuid of the uploaded map chosen by the client with the `SMap-Uuid` header
+    /// or the `field` of the upload, which trusted API keys only may do.
+    fn client_uuid(
+        config: &Config,
+        headers: &HeaderMap,
+        field: Option<String>,
+    ) -> Result<Option<String>, (StatusCode, Json<SMapError>)> {
+        let header = headers
+            .get(SMAP_UUID)
+            .map(|value| value.to_str().unwrap_or_default().to_string());
+        let uuid = match (header, field) {
+            (Some(header), Some(field)) if header != field => {
+                return Err(bad_request(format!(
+                    "uuid header {header:?} and field {field:?} differ"
+                )))
+            }
+            (Some(uuid), _) | (None, Some(uuid)) => uuid,
+            (None, None) => return Ok(None),
+        };
+
+        let trusted = headers
+            .get(API_KEY)
+            .and_then(|key| key.to_str().ok())
+            .is_some_and(|key| config.upsert_api_keys.iter().any(|trusted| trusted == key));
+        if !trusted {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Json(SMapError::Unauthorized(
+                    "choosing the uuid of a map requires a trusted api key".to_string(),
+                )),
+            ));
+        }
+        match Uuid::parse_str(uuid.trim()) {
+            Ok(uuid) => Ok(Some(uuid.hyphenated().to_string())),
+            Err(err) => Err(bad_request(format!("invalid uuid {uuid:?}: {err}"))),
+        }
+    }
+
+    /// Client identified by the API key of a request.
+    pub(super) enum Caller {
+        /// No API key, or an unknown one.
+        Anonymous,
+        /// Key attributed to an owner.
+        Owner(String),
+        /// Administrator key.
+        Admin,
+    }
+
+    impl Caller {
+        /// Owner the maps uploaded by the caller are attributed to.
+        pub(super) fn owner(&self) -> Option<String> {
+            match self {
+                Self::Owner(owner) => Some(owner.clone()),
+                Self::Anonymous | Self::Admin => None,
+            }
+        }
+    }
+
+    /// Whether `key` is one of the API keys listed in the configuration.
+    pub(super) fn is_listed_key(config: &Config, key: &str) -> bool {
+        config.api_keys.iter().any(|owned| owned.key == key)
+            || config.upsert_api_keys.iter().any(|trusted| trusted == key)
+            || config.admin_api_keys.iter().any(|admin| admin == key)
+    }
+
+    /// Caller identified by the `smap_apikey` header of `headers`.
+    pub(super) fn caller(config: &Config, headers: &HeaderMap) -> Caller {
+        let Some(key) = headers.get(API_KEY).and_then(|key| key.to_str().ok()) else {
+            return Caller::Anonymous;
+        };
+        if config.admin_api_keys.iter().any(|admin| admin == key) {
+            return Caller::Admin;
+        }
+        match config.api_keys.iter().find(|owned| owned.key == key) {
+            Some(owned) => Caller::Owner(owned.owner.clone()),
+            None => Caller::Anonymous,
+        }
+    }
+
+    /// 422 error unless the file hashing to `hash` matches the hex-encoded
+    /// SHA-256 digest `expected` given by the client.
+    pub(super) fn verify_checksum(
+        hash: &str,
+        expected: &str,
+    ) -> Result<(), (StatusCode, Json<SMapError>)> {
+        let expected = expected.trim();
+        if hash.eq_ignore_ascii_case(expected) {
+            return Ok(());
+        }
+        Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(SMapError::ChecksumMismatch(format!(
+                "file has sha256 {hash}, not {expected}"
+            ))),
+        ))
+    }
+
+    /// Hex-encoded SHA-256 digest of the `Content-Digest` header of `headers`,
+    /// if sent.
+    pub(super) fn content_digest(
+        headers: &HeaderMap,
+    ) -> Result<Option<String>, (StatusCode, Json<SMapError>)> {
+        let Some(header) = headers.get(CONTENT_DIGEST) else {
+            return Ok(None);
+        };
+        // Comma-separated `algorithm=:base64:` entries, in any order.
+        let digest = header.to_str().ok().and_then(|header| {
+            header.split(',').find_map(|entry| {
+                let (algorithm, digest) = entry.split_once('=')?;
+                if !algorithm.trim().eq_ignore_ascii_case("sha-256") {
+                    return None;
+                }
+                let digest = digest.trim().strip_prefix(':')?.strip_suffix(':')?;
+                BASE64.decode(digest).ok()
+            })
+        });
+        match digest {
+            Some(digest) if digest.len() == 32 => Ok(Some(
+                digest.iter().map(|byte| format!("{byte:02x}")).collect(),
+            )),
+            _ => Err(bad_request(
+                "Content-Digest holds no valid sha-256 digest".to_string(),
+            )),
+        }
+    }
+
+    fn bad_request(message: String) -> (StatusCode, Json<SMapError>) {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(SMapError::BadRequest(message)),
+        )
+    }
+
+    /// 413 response for a multipart body over the size limit, 400 otherwise.
+    fn multipart_error(err: MultipartError) -> (StatusCode, Json<SMapError>) {
+        match err.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(SMapError::PayloadTooLarge(err.body_text())),
+            ),
+            _ => bad_request(err.body_text()),
+        }
+    }
+
+    /// 409 response for an uploaded map duplicating `original`.
+    fn duplicate_error(smap: &SMap, original: &str) -> Response {
+        (
+            StatusCode::CONFLICT,
+            Json(SMapError::Conflict(format!(
+                "{:?} duplicates {original}",
+                smap.title
+            ))),
+        )
+            .into_response()
+    }
+
+    /// Get Static map
+    ///
+    /// Fetch a single static map by uuid, with its revision as `ETag`.
+    #[utoipa::path(
+        get,
+        path = "/smap/{uuid}",
+        params(("uuid" = String, Path, description = "Static map uuid")),
+        responses(
+            (status = 200, description = "Static map found", body = SMap),
+            (status = 404, description = "Static map not found", body = SMapError),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn get_smap(
+        State(store): State<Arc<Store>>,
+        Path(uuid): Path<String>,
+        base: BaseUrl,
+    ) -> impl IntoResponse {
+        match store.get(&uuid).await {
+            Ok(Some(smap)) => ([(ETAG, smap.etag())], Json(base.link(smap))).into_response(),
+            Ok(None) => (
+                StatusCode::NOT_FOUND,
+                Json(SMapError::NotFound(format!("uuid = {uuid}"))),
+            )
+                .into_response(),
+            Err(err) => database_error(err).into_response(),
+        }
+    }
+
+    /// Download Static map file
+    ///
+    /// Stream the file of a static map from the storage backend, with its media
+    /// type and size. A single byte range may be requested with `Range`, made
+    /// conditional on the file being unchanged with `If-Range`. Clients holding
+    /// a copy get `304 Not Modified` while it is current, as told by
+    /// `If-None-Match` with its strong `ETag`, the SHA-256 digest of the file,
+    /// or `If-Modified-Since`. Browsers show the file inline, or save it under a
+    /// name derived from the map title with `download=true`.
+    #[utoipa::path(
+        get,
+        path = "/smap/{uuid}/file",
+        params(
+            ("uuid" = String, Path, description = "Static map uuid"),
+            ("Range" = Option<String>, Header, description = "Single byte range of the file to send, such as `bytes=0-1023`; others get the whole file"),
+            ("If-Range" = Option<String>, Header, description = "Entity tag or modification time of the file the range was asked of; the whole file is sent if it changed"),
+            ("If-None-Match" = Option<String>, Header, description = "Entity tags of copies of the file held by the client"),
+            ("If-Modified-Since" = Option<String>, Header, description = "Modification time of the copy of the file held by the client; ignored with `If-None-Match`"),
+            DownloadQuery
+        ),
+        responses(
+            (status = 200, description = "Static map file streamed successfully", content_type = "application/octet-stream",
+                headers(
+                    ("Content-Type" = String, description = "Media type of the map file, as in its `content_type`"),
+                    ("Content-Length" = u64, description = "Size of the map file in bytes"),
+                    ("Accept-Ranges" = String, description = "`bytes`, as byte ranges may be requested"),
+                    ("ETag" = String, description = "Strong entity tag of the file, its quoted SHA-256 digest"),
+                    ("Last-Modified" = String, description = "Time the map last changed"),
+                    ("Cache-Control" = String, description = "Caching allowed of the file, as set for the map or else the deployment; absent if neither sets any"),
+                    ("Content-Disposition" = String, description = "`inline`, or `attachment` with `download`, and a file name derived from the map title")
+                )),
+            (status = 206, description = "Requested range of the static map file streamed successfully", content_type = "application/octet-stream",
+                headers(
+                    ("Content-Range" = String, description = "Range sent and size of the map file, such as `bytes 0-1023/4096`"),
+                    ("Content-Length" = u64, description = "Size of the range in bytes")
+                )),
+            (status = 304, description = "Static map file unchanged since the copy held by the client"),
+            (status = 404, description = "Static map not found", body = SMapError),
+            (status = 416, description = "Requested range starts past the end of the file",
+                headers(
+                    ("Content-Range" = String, description = "Size of the map file, such as `bytes */4096`")
+                )),
+            (status = 500, description = "Static map could not be read", body = SMapError)
+        )
+    )]
+    pub(super) async fn download_smap_file(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Path(uuid): Path<String>,
+        Query(query): Query<DownloadQuery>,
+        headers: HeaderMap,
+    ) -> impl IntoResponse {
+        let smap = match store.get(&uuid).await {
+            Ok(Some(smap)) => smap,
+            Ok(None) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(SMapError::NotFound(format!("uuid = {uuid}"))),
+                )
+                    .into_response()
+            }
+            Err(err) => return database_error(err).into_response(),
+        };
+        if smap.file_unchanged(&headers) {
+            return smap.file_not_modified(&config.cache);
+        }
+        let cache_headers = smap.cache_headers(&config.cache);
+
+        let requested = match headers.get(RANGE).and_then(|value| value.to_str().ok()) {
+            Some(_)
+                if headers
+                    .get(IF_RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| {
+                        !range::if_range_matches(value, &smap.file_etag(), &smap.last_modified())
+                    }) =>
+            {
+                Requested::Full
+            }
+            Some(value) => range::parse(value, smap.size),
+            None => Requested::Full,
+        };
+
+        let streamed = match requested {
+            Requested::Full => storage.get(&smap.key).await.map(|stream| {
+                let headers = smap.file_headers(query.download);
+                (headers, cache_headers, StreamBody::new(stream)).into_response()
+            }),
+            Requested::Partial(range) => storage
+                .get_range(&smap.key, range.offset, range.length)
+                .await
+                .map(|stream| {
+                    (
+                        StatusCode::PARTIAL_CONTENT,
+                        smap.file_headers(query.download),
+                        cache_headers,
+                        [
+                            (CONTENT_LENGTH, range.length.to_string()),
+                            (CONTENT_RANGE, range.content_range(smap.size)),
+                        ],
+                        StreamBody::new(stream),
+                    )
+                        .into_response()
+                }),
+            Requested::Unsatisfiable => {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [
+                        (ACCEPT_RANGES, "bytes".to_string()),
+                        (CONTENT_RANGE, format!("bytes */{}", smap.size)),
+                    ],
+                )
+                    .into_response()
+            }
+        };
+        match streamed {
+            Ok(response) => response,
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(err.to_string())),
+            )
+                .into_response(),
+        }
+    }
+
+    /// Download Static map thumbnail
+    ///
+    /// Stream a JPEG thumbnail of the map image, small or medium. Thumbnails are
+    /// generated in the background after upload: until then, and for maps that
+    /// are not images, this returns 404.
+    #[utoipa::path(
+        get,
+        path = "/smap/{uuid}/thumbnails/{size}",
+        params(
+            ("uuid" = String, Path, description = "Static map uuid"),
+            ("size" = Size, Path, description = "Thumbnail size")
+        ),
+        responses(
+            (status = 200, description = "Thumbnail streamed successfully", content_type = "image/jpeg",
+                headers(
+                    ("Cache-Control" = String, description = "Caching allowed of the thumbnail, as set for the map or else the deployment; absent if neither sets any")
+                )),
+            (status = 404, description = "Static map or thumbnail not found", body = SMapError),
+            (status = 500, description = "Thumbnail could not be read", body = SMapError)
+        )
+    )]
+    pub(super) async fn download_smap_thumbnail(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Path((uuid, size)): Path<(String, Size)>,
+    ) -> impl IntoResponse {
+        let smap = match store.get(&uuid).await {
+            Ok(smap) => smap,
+            Err(err) => return database_error(err).into_response(),
+        };
+        let Some((thumbnails, cache_headers)) = smap.and_then(|smap| {
+            let cache_headers = smap.cache_headers(&config.cache);
+            smap.thumbnails
+                .map(|thumbnails| (thumbnails, cache_headers))
+        }) else {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(SMapError::NotFound(format!("thumbnail of uuid = {uuid}"))),
+            )
+                .into_response();
+        };
+
+        match storage.get(thumbnails.key(size)).await {
+            Ok(stream) => (
+                [(CONTENT_TYPE, "image/jpeg")],
+                cache_headers,
+                StreamBody::new(stream),
+            )
+                .into_response(),
+            Err(StorageError::NotFound(_)) => (
+                StatusCode::NOT_FOUND,
+                Json(SMapError::NotFound(format!("thumbnail of uuid = {uuid}"))),
+            )
+                .into_response(),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(err.to_string())),
+            )
+                .into_response(),
+        }
+    }
+
+    /// Replace Static map file
+    ///
+    /// Replace the file of a static map with the request body, keeping its uuid
+    /// and title. The previous file is deleted unless another map shares it.
+    /// A file not matching the SHA-256 digest of its `Content-Digest` header is
+    /// refused.
+    #[utoipa::path(
+        put,
+        path = "/smap/{uuid}/file",
+        params(
+            ("uuid" = String, Path, description = "Static map uuid"),
+            ("If-Match" = Option<String>, Header, description = "Only replace the file of the map at this revision"),
+            ("Content-Digest" = Option<String>, Header, description = "SHA-256 digest of the file, as `sha-256=:<base64>:`")
+        ),
+        request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+        responses(
+            (status = 200, description = "Static map file replaced successfully", body = SMap),
+            (status = 404, description = "Static map not found", body = SMapError),
+            (status = 412, description = "Static map changed since the given revision", body = SMapError),
+            (status = 413, description = "Image exceeds the pixel limits", body = SMapError),
+            (status = 415, description = "File has a content type that is not accepted", body = SMapError),
+            (status = 422, description = "File does not match its Content-Digest, is infected, or is too malformed to strip its metadata", body = SMapError),
+            (status = 500, description = "Static map could not be stored", body = SMapError),
+            (status = 502, description = "Antivirus could not scan the file", body = SMapError),
+            (status = 507, description = "Storage quota exceeded", body = SMapError)
+        )
+    )]
+    pub(super) async fn replace_smap_file(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Path(uuid): Path<String>,
+        headers: HeaderMap,
+        bytes: Bytes,
+    ) -> impl IntoResponse {
+        let revision = match if_match(&headers) {
+            Ok(revision) => revision,
+            Err(err) => return err.into_response(),
+        };
+        let digest = match content_digest(&headers) {
+            Ok(digest) => digest,
+            Err(err) => return err.into_response(),
+        };
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        if let Err(err) = accept_content_type(&config, content_type) {
+            return err.into_response();
+        }
+        let content_type = match sniff_content_type(&config, &bytes) {
+            Ok(content_type) => Some(content_type),
+            Err(err) => return err.into_response(),
+        };
+        let file = match store_file(&config, &store, storage.as_ref(), bytes, content_type).await {
+            Ok(file) => file,
+            Err(err) => return err.into_response(),
+        };
+        if let Some(expected) = digest {
+            if let Err(err) = verify_checksum(&file.received, &expected) {
+                store.release(&file.key).await;
+                return err.into_response();
+            }
+        }
+
+        let mut previous = None;
+        let outcome = store
+            .modify(&uuid, revision, |smap| {
+                if smap.deleted_at.is_some() {
+                    return false;
+                }
+                previous = Some(smap.clone());
+                smap.key = file.key.clone();
+                smap.hash = file.hash.clone();
+                smap.size = file.size;
+                smap.stored_size = file.stored_size;
+                smap.content_type = file.content_type.clone();
+                smap.original_content_type = file.original_content_type.clone();
+                smap.width = file.dimensions.map(|(width, _)| width);
+                smap.height = file.dimensions.map(|(_, height)| height);
+                smap.scan = file.scan.clone();
+                smap.thumbnails = None;
+                smap.processing_status = None;
+                true
+            })
+            .await;
+        store.release(&file.key).await;
+
+        match outcome {
+            Ok(Modified::Updated(smap)) => {
+                rescan::record(storage.as_ref(), &smap).await;
+                if let Some(previous) = previous.filter(|previous| previous.key != smap.key) {
+                    if let Err(err) = store.discard_file(&previous, storage.as_ref()).await {
+                        eprintln!("discarding previous file of map {uuid} failed: {err:?}");
+                    }
+                }
+                ([(ETAG, smap.etag())], Json(smap)).into_response()
+            }
+            Ok(outcome) => modify_error(&uuid, revision, outcome).into_response(),
+            Err(err) => database_error(err).into_response(),
+        }
+    }
+
+    /// Check Static map file
+    ///
+    /// Report the size, type, entity tag and modification time of a static map
+    /// file without downloading it.
+    #[utoipa::path(
+        head,
+        path = "/smap/{uuid}/file",
+        params(
+            ("uuid" = String, Path, description = "Static map uuid"),
+            ("If-None-Match" = Option<String>, Header, description = "Entity tags of copies of the file held by the client"),
+            ("If-Modified-Since" = Option<String>, Header, description = "Modification time of the copy of the file held by the client; ignored with `If-None-Match`"),
+            DownloadQuery
+        ),
+        responses(
+            (status = 200, description = "Static map file exists"),
+            (status = 304, description = "Static map file unchanged since the copy held by the client"),
+            (status = 404, description = "Static map not found"),
+            (status = 500, description = "Metadata store unavailable")
+        )
+    )]
+    pub(super) async fn head_smap_file(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        Path(uuid): Path<String>,
+        Query(query): Query<DownloadQuery>,
+        headers: HeaderMap,
+    ) -> impl IntoResponse {
+        match store.get(&uuid).await {
+            Ok(Some(smap)) if smap.file_unchanged(&headers) => {
+                smap.file_not_modified(&config.cache)
+            }
+            Ok(Some(smap)) => {
+                let headers = smap.file_headers(query.download);
+                (headers, smap.cache_headers(&config.cache), ()).into_response()
+            }
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(err) => database_error(err).into_response(),
+        }
+    }
+
+    /// Digests of a static map file.
+    #[derive(Serialize, ToSchema)]
+    pub(super) struct Checksums {
+        /// Hex-encoded SHA-256 digest.
+        #[schema(example = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")]
+        sha256: String,
+        /// Hex-encoded MD5 digest.
+        #[schema(example = "5d41402abc4b2a76b9719d911017c592")]
+        md5: String,
+    }
+
+    /// Checksum Static map file
+    ///
+    /// Compute the digests of a static map file as stored, so downloads can be
+    /// verified. A file no longer matching the SHA-256 recorded at upload fails.
+    #[utoipa::path(
+        get,
+        path = "/smap/{uuid}/checksum",
+        params(("uuid" = String, Path, description = "Static map uuid")),
+        responses(
+            (status = 200, description = "Digests of the static map file", body = Checksums),
+            (status = 404, description = "Static map not found", body = SMapError),
+            (status = 500, description = "Static map file unreadable or corrupted", body = SMapError)
+        )
+    )]
+    pub(super) async fn checksum_smap_file(
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Path(uuid): Path<String>,
+    ) -> impl IntoResponse {
+        let smap = match store.get(&uuid).await {
+            Ok(Some(smap)) => smap,
+            Ok(None) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(SMapError::NotFound(format!("uuid = {uuid}"))),
+                )
+                    .into_response()
+            }
+            Err(err) => return database_error(err).into_response(),
+        };
+        let storage_error = |err: StorageError| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(err.to_string())),
+            )
+                .into_response()
+        };
+
+        let mut stream = match storage.get(&smap.key).await {
+            Ok(stream) => stream,
+            Err(err) => return storage_error(err),
+        };
+        let mut sha256 = Sha256::new();
+        let mut md5 = md5::Context::new();
+        loop {
+            match stream.try_next().await {
+                Ok(Some(chunk)) => {
+                    sha256.update(&chunk);
+                    md5.consume(&chunk);
+                }
+                Ok(None) => break,
+                Err(err) => return storage_error(err),
+            }
+        }
+
+        let checksums = Checksums {
+            sha256: format!("{:x}", sha256.finalize()),
+            md5: format!("{:x}", md5.finalize()),
+        };
+        if checksums.sha256 != smap.hash {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(format!(
+                    "file of map {uuid} has sha-256 {}, not {} as uploaded",
+                    checksums.sha256, smap.hash
+                ))),
+            )
+                .into_response();
+        }
+        Json(checksums).into_response()
+    }
+
+    /// Delete Static map
+    ///
+    /// Move a static map to the trash. Trashed maps are hidden from listings and
+    /// permanently removed after the configured retention period.
+    ///
+    /// With `permanent`, the map, trashed or not, is removed right away along with
+    /// its file, unless another map shares it.
+    #[utoipa::path(
+        delete,
+        path = "/smap/{uuid}",
+        params(
+            ("uuid" = String, Path, description = "Static map uuid"),
+            ("If-Match" = Option<String>, Header, description = "Only delete the map at this revision"),
+            DeleteQuery
+        ),
+        responses(
+            (status = 204, description = "Static map deleted"),
+            (status = 404, description = "Static map not found", body = SMapError),
+            (status = 412, description = "Static map changed since the given revision", body = SMapError),
+            (status = 500, description = "Metadata store or storage unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn delete_smap(
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Path(uuid): Path<String>,
+        Query(query): Query<DeleteQuery>,
+        headers: HeaderMap,
+    ) -> impl IntoResponse {
+        let revision = match if_match(&headers) {
+            Ok(revision) => revision,
+            Err(err) => return err.into_response(),
+        };
+        if query.permanent {
+            return match store.take(&uuid, revision).await {
+                Ok(Modified::Updated(smap)) => {
+                    match discard_removed(&store, storage.as_ref(), &smap).await {
+                        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response(),
+                    }
+                }
+                Ok(outcome) => modify_error(&uuid, revision, outcome).into_response(),
+                Err(err) => database_error(err).into_response(),
+            };
+        }
+        match store.trash(&uuid, revision).await {
+            Ok(Modified::Updated(smap)) => {
+                rescan::record(storage.as_ref(), &smap).await;
+                (StatusCode::NO_CONTENT, [(ETAG, smap.etag())]).into_response()
+            }
+            Ok(outcome) => modify_error(&uuid, revision, outcome).into_response(),
+            Err(err) => database_error(err).into_response(),
+        }
+    }
+
+    /// Update Static map
+    ///
+    /// Change the title, description or tags of a static map.
+    #[utoipa::path(
+        patch,
+        path = "/smap/{uuid}",
+        params(
+            ("uuid" = String, Path, description = "Static map uuid"),
+            ("If-Match" = Option<String>, Header, description = "Only update the map at this revision")
+        ),
+        request_body = SMapPatch,
+        responses(
+            (status = 200, description = "Static map updated successfully", body = SMap),
+            (status = 400, description = "Invalid update", body = SMapError),
+            (status = 404, description = "Static map not found", body = SMapError),
+            (status = 412, description = "Static map changed since the given revision", body = SMapError),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn update_smap(
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Path(uuid): Path<String>,
+        headers: HeaderMap,
+        Json(patch): Json<SMapPatch>,
+    ) -> impl IntoResponse {
+        let revision = match if_match(&headers) {
+            Ok(revision) => revision,
+            Err(err) => return err.into_response(),
+        };
+        if let Err(err) = patch.validate() {
+            return (StatusCode::BAD_REQUEST, Json(err)).into_response();
+        }
+        match store.edit(&uuid, revision, &patch).await {
+            Ok(Modified::Updated(smap)) => {
+                rescan::record(storage.as_ref(), &smap).await;
+                ([(ETAG, smap.etag())], Json(smap)).into_response()
+            }
+            Ok(outcome) => modify_error(&uuid, revision, outcome).into_response(),
+            Err(err) => database_error(err).into_response(),
+        }
+    }
+
+    /// Delete the sidecar and, unless still in use, the file of a permanently removed `smap`.
+    pub(super) async fn discard_removed(
+        store: &Store,
+        storage: &dyn StorageBackend,
+        smap: &SMap,
+    ) -> Result<(), SMapError> {
+        match storage.delete(&rescan::sidecar_key(&smap.uuid)).await {
+            Ok(()) | Err(StorageError::NotFound(_)) => {}
+            Err(err) => eprintln!("deleting sidecar of map {} failed: {err}", smap.uuid),
+        }
+        store.discard_file(smap, storage).await?;
+        Ok(())
+    }
+
+    /// Outcome of a bulk deletion.
+    #[derive(Serialize, ToSchema)]
+    pub(super) struct BulkDeleteReport {
+        /// Uuids of the deleted maps.
+        deleted: Vec<String>,
+        /// Maps that could not be deleted, with the reason.
+        failed: Vec<BulkFailure>,
+    }
+
+    /// Map a bulk operation could not apply to.
+    #[derive(Serialize, ToSchema)]
+    pub(super) struct BulkFailure {
+        uuid: String,
+        error: SMapError,
+    }
+
+    /// Maps found by a batch lookup.
+    #[derive(Serialize, ToSchema)]
+    pub(super) struct BatchReport {
+        /// Maps found, in the requested order.
+        smaps: Vec<SMap>,
+        /// Uuids not matching an active map.
+        missing: Vec<String>,
+    }
+
+    /// Get Static maps
+    ///
+    /// Fetch the static maps of the listed uuids in one request.
+    #[utoipa::path(
+        post,
+        path = "/smap/batch",
+        request_body(content = Vec<String>, example = json!(["1e7ab3f0-4b8e-4c5e-9f0a-2b8d1c3e4f5a"])),
+        responses(
+            (status = 200, description = "Maps found and uuids missing", body = BatchReport),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn get_smaps(
+        State(store): State<Arc<Store>>,
+        base: BaseUrl,
+        Json(uuids): Json<Vec<String>>,
+    ) -> impl IntoResponse {
+        let found = match store.get_many(&uuids).await {
+            Ok(found) => found,
+            Err(err) => return database_error(err).into_response(),
+        };
+        let mut report = BatchReport {
+            smaps: Vec::new(),
+            missing: Vec::new(),
+        };
+        for (uuid, smap) in uuids.into_iter().zip(found) {
+            match smap {
+                Some(smap) => report.smaps.push(base.link(smap)),
+                None => report.missing.push(uuid),
+            }
+        }
+        Json(report).into_response()
+    }
+
+    /// Delete Static maps
+    ///
+    /// Move the static maps of the listed uuids to the trash, or with `permanent`
+    /// remove them along with their files, in a single metadata store transaction.
+    #[utoipa::path(
+        post,
+        path = "/smap/delete",
+        params(DeleteQuery),
+        request_body(content = Vec<String>, example = json!(["1e7ab3f0-4b8e-4c5e-9f0a-2b8d1c3e4f5a"])),
+        responses(
+            (status = 200, description = "Deletion report", body = BulkDeleteReport),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn delete_smaps(
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Query(query): Query<DeleteQuery>,
+        Json(uuids): Json<Vec<String>>,
+    ) -> impl IntoResponse {
+        let outcomes = match query.permanent {
+            true => store.take_many(&uuids).await,
+            false => store.trash_many(&uuids).await,
+        };
+        let outcomes = match outcomes {
+            Ok(outcomes) => outcomes,
+            Err(err) => return database_error(err).into_response(),
+        };
+
+        let mut report = BulkDeleteReport {
+            deleted: Vec::new(),
+            failed: Vec::new(),
+        };
+        for (uuid, outcome) in uuids.into_iter().zip(outcomes) {
+            let error = match outcome {
+                Modified::Updated(smap) => {
+                    if query.permanent {
+                        if let Err(err) = discard_removed(&store, storage.as_ref(), &smap).await {
+                            eprintln!("discarding files of map {uuid} failed: {err:?}");
+                        }
+                    } else {
+                        rescan::record(storage.as_ref(), &smap).await;
+                    }
+                    report.deleted.push(uuid);
+                    continue;
+                }
+                Modified::NotFound => SMapError::NotFound(format!("uuid = {uuid}")),
+                Modified::Stale(current) => SMapError::Conflict(format!(
+                    "map changed during deletion, now at revision {}",
+                    current.revision
+                )),
+            };
+            report.failed.push(BulkFailure { uuid, error });
+        }
+        Json(report).into_response()
+    }
+
+    /// Copy Static map
+    ///
+    /// Register a copy of an active static map under a new uuid, sharing its file
+    /// and carrying its description and tags, so it can be edited separately.
+    #[utoipa::path(
+        post,
+        path = "/smap/{uuid}/copy",
+        params(("uuid" = String, Path, description = "Static map uuid")),
+        request_body(content = Option<CopySMap>, description = "Title of the copy, the original one with a \"(copy)\" suffix if unset"),
+        responses(
+            (status = 201, description = "Static map copied successfully", body = SMap),
+            (status = 404, description = "Static map not found", body = SMapError),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn copy_smap(
+        State(config): State<Arc<Config>>,
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Path(uuid): Path<String>,
+        headers: HeaderMap,
+        copy: Option<Json<CopySMap>>,
+    ) -> impl IntoResponse {
+        let not_found = || {
+            (
+                StatusCode::NOT_FOUND,
+                Json(SMapError::NotFound(format!("uuid = {uuid}"))),
+            )
+                .into_response()
+        };
+        let source = match store.get(&uuid).await {
+            Ok(Some(source)) => source,
+            Ok(None) => return not_found(),
+            Err(err) => return database_error(err).into_response(),
+        };
+
+        // Keep the shared file until the copy references it, in case the source
+        // is deleted meanwhile.
+        store.hold(&source.key).await;
+        match store.get(&uuid).await {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                store.release(&source.key).await;
+                return not_found();
+            }
+            Err(err) => {
+                store.release(&source.key).await;
+                return database_error(err).into_response();
+            }
+        }
+
+        let title = copy
+            .and_then(|Json(copy)| copy.title)
+            .unwrap_or_else(|| format!("{} (copy)", source.title));
+        let file = StoredFile {
+            key: source.key.clone(),
+            hash: source.hash.clone(),
+            size: source.size,
+            stored_size: source.stored_size,
+            content_type: source.content_type.clone(),
+            original_content_type: source.original_content_type.clone(),
+            scan: source.scan.clone(),
+            dimensions: source.width.zip(source.height),
+            received: source.hash.clone(),
+        };
+        let mut smap = SMap::new(Uuid::new_v4().to_string(), title, file);
+        smap.description = source.description;
+        smap.tags = source.tags;
+        smap.bbox = source.bbox;
+        smap.thumbnails = source.thumbnails;
+        smap.processing_status = source.processing_status;
+        smap.cache_control = source.cache_control;
+        smap.owner = caller(&config, &headers).owner();
+
+        // A copy shares the file of its source, so it never counts as a duplicate.
+        match register(&store, storage.as_ref(), vec![smap]).await {
+            Ok(smaps) => created(smaps),
+            Err(response) => response,
+        }
+    }
+
+    /// Restore Static map
+    ///
+    /// Take a static map out of the trash.
+    #[utoipa::path(
+        post,
+        path = "/smap/{uuid}/restore",
+        params(
+            ("uuid" = String, Path, description = "Static map uuid"),
+            ("If-Match" = Option<String>, Header, description = "Only restore the map at this revision")
+        ),
+        responses(
+            (status = 200, description = "Static map restored successfully", body = SMap),
+            (status = 404, description = "Static map not found in the trash", body = SMapError),
+            (status = 412, description = "Static map changed since the given revision", body = SMapError),
+            (status = 500, description = "Metadata store unavailable", body = SMapError)
+        )
+    )]
+    pub(super) async fn restore_smap(
+        State(store): State<Arc<Store>>,
+        State(storage): State<Arc<dyn StorageBackend>>,
+        Path(uuid): Path<String>,
+        headers: HeaderMap,
+    ) -> impl IntoResponse {
+        let revision = match if_match(&headers) {
+            Ok(revision) => revision,
+            Err(err) => return err.into_response(),
+        };
+        match store.restore(&uuid, revision).await {
+            Ok(Modified::Updated(smap)) => {
+                rescan::record(storage.as_ref(), &smap).await;
+                ([(ETAG, smap.etag())], Json(smap)).into_response()
+            }
+            Ok(outcome) => modify_error(&uuid, revision, outcome).into_response(),
+            Err(err) => database_error(err).into_response(),
+        }
+    }
+
+    /// Revision required by the `If-Match` header, none if it is absent or `*`.
+    pub(super) fn if_match(
+        headers: &HeaderMap,
+    ) -> Result<Option<u64>, (StatusCode, Json<SMapError>)> {
+        let Some(value) = headers.get(IF_MATCH) else {
+            return Ok(None);
+        };
+        let value = value.to_str().unwrap_or_default().trim();
+        if value == "*" {
+            return Ok(None);
+        }
+        value
+            .trim_start_matches("W/")
+            .trim_matches('"')
+            .parse()
+            .map(Some)
+            .map_err(|_| {
+                (
+                    StatusCode::PRECONDITION_FAILED,
+                    Json(SMapError::PreconditionFailed(format!(
+                        "invalid If-Match {value:?}"
+                    ))),
+                )
+            })
+    }
+
+    /// Error response for a [`Store::modify`] outcome other than an update.
+    pub(super) fn modify_error(
+        uuid: &str,
+        revision: Option<u64>,
+        outcome: Modified,
+    ) -> (StatusCode, Json<SMapError>) {
+        match outcome {
+            Modified::Stale(smap) => (
+                StatusCode::PRECONDITION_FAILED,
+                Json(SMapError::PreconditionFailed(format!(
+                    "revision {} does not match current revision {}",
+                    revision.unwrap_or_default(),
+                    smap.revision
+                ))),
+            ),
+            Modified::NotFound | Modified::Updated(_) => (
+                StatusCode::NOT_FOUND,
+                Json(SMapError::NotFound(format!("uuid = {uuid}"))),
+            ),
+        }
+    }
+
+    /// Store `bytes` content-addressed, reusing the blob of an identical registered map.
+    /// The file is recorded with the media type of its declared `content_type`.
+    ///
+    /// The stored key stays protected from garbage collection until the map is
+    /// passed to [`Store::register`].
+    pub(super) async fn store_file(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        bytes: Bytes,
+        content_type: Option<&str>,
+    ) -> Result<StoredFile, (StatusCode, Json<SMapError>)> {
+        let received = format!("{:x}", Sha256::digest(&bytes));
+        let dimensions = sniff::sniff(&bytes)
+            .and_then(|content_type| sniff::dimensions(content_type, io::Cursor::new(&bytes)));
+        check_dimensions(config, dimensions)?;
+        let (bytes, stripped) = strip_metadata(config, bytes)?;
+        let (bytes, original_content_type) = normalize_format(config, bytes).await?;
+        let content_type = match original_content_type {
+            Some(_) => config.normalize.format.map(|format| format.media_type()),
+            None => content_type,
+        };
+        // Content-addressed: identical files share a single stored blob.
+        let hash = match (&stripped, original_content_type) {
+            (None, None) => received.clone(),
+            _ => format!("{:x}", Sha256::digest(&bytes)),
+        };
+        let size = bytes.len() as u64;
+        let scan = scan_content(config, &hash, &mut io::Cursor::new(bytes.clone())).await?;
+        if let Some(entries) = stripped.filter(|_| config.strip.keep_stripped_metadata) {
+            let dir = config.data_dir.join("metadata");
+            strip::keep(&dir, &hash, &received, &entries)
+                .await
+                .map_err(|err| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(SMapError::Storage(StorageError::from(err).to_string())),
+                    )
+                })?;
+        }
+        let content = stream::iter([Ok(bytes)]).boxed();
+        let file = store_content(config, store, storage, hash, size, content, content_type).await?;
+        Ok(StoredFile {
+            original_content_type: original_content_type.map(str::to_string),
+            scan,
+            dimensions,
+            received,
+            ..file
+        })
+    }
+
+    /// Strip the metadata of `bytes` if configured, returning the stripped bytes
+    /// along with the stripped metadata, if any.
+    ///
+    /// Files that cannot be parsed are refused with 422, rather than stored with
+    /// their metadata.
+    #[allow(clippy::type_complexity)]
+    fn strip_metadata(
+        config: &Config,
+        bytes: Bytes,
+    ) -> Result<(Bytes, Option<Vec<strip::Entry>>), (StatusCode, Json<SMapError>)> {
+        let Some(content_type) = sniff::sniff(&bytes).filter(|_| config.strip.strip_metadata)
+        else {
+            return Ok((bytes, None));
+        };
+        match strip::strip(content_type, &bytes) {
+            Ok(Some(stripped)) => Ok((Bytes::from(stripped.bytes), Some(stripped.entries))),
+            Ok(None) => Ok((bytes, None)),
+            Err(err) => Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(SMapError::Invalid(vec![Violation::new(
+                    "file",
+                    err.to_string(),
+                )])),
+            )),
+        }
+    }
+
+    /// Transcode `bytes` to the canonical format if configured, returning the
+    /// transcoded bytes along with their original media type.
+    ///
+    /// Images that cannot be decoded are refused with 422.
+    async fn normalize_format(
+        config: &Config,
+        bytes: Bytes,
+    ) -> Result<(Bytes, Option<&'static str>), (StatusCode, Json<SMapError>)> {
+        let target = sniff::sniff(&bytes).and_then(|content_type| {
+            let format = config.normalize.target(content_type)?;
+            Some((content_type, format))
+        });
+        let Some((content_type, format)) = target else {
+            return Ok((bytes, None));
+        };
+        let transcoded = task::spawn_blocking({
+            let bytes = bytes.clone();
+            let limits = sniff::limits(config);
+            move || normalize::transcode(&bytes, format, limits)
+        })
+        .await
+        .map_err(|err| ImageError::IoError(io::Error::other(err)))
+        .and_then(|transcoded| transcoded);
+        match transcoded {
+            Ok(transcoded) => Ok((Bytes::from(transcoded), Some(content_type))),
+            Err(err) => Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(SMapError::Invalid(vec![Violation::new(
+                    "file",
+                    format!("image could not be normalized: {err}"),
+                )])),
+            )),
+        }
+    }
+
+    /// Refuse images of `dimensions` past the configured limits with 413, before
+    /// they are decoded.
+    fn check_dimensions(
+        config: &Config,
+        dimensions: Option<(u32, u32)>,
+    ) -> Result<(), (StatusCode, Json<SMapError>)> {
+        let Some((width, height)) = dimensions else {
+            return Ok(());
+        };
+        let side = width.max(height);
+        let pixels = u64::from(width) * u64::from(height);
+        if side <= config.max_image_side && pixels <= config.max_image_pixels {
+            return Ok(());
+        }
+        Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(SMapError::PayloadTooLarge(format!(
+                "image of {width}x{height} pixels exceeds the limits of {} pixels and {} pixels per side",
+                config.max_image_pixels, config.max_image_side
+            ))),
+        ))
+    }
+
+    /// Whether files of `content_type` are transformed before being stored,
+    /// which needs them in memory.
+    fn transformed(config: &Config, content_type: &str) -> bool {
+        (config.strip.strip_metadata && strip::applies(content_type))
+            || config.normalize.target(content_type).is_some()
+    }
+
+    /// Width and height of the image spooled in `file`, read from its header.
+    async fn spooled_dimensions(file: &tokio::fs::File, content_type: &str) -> Option<(u32, u32)> {
+        let mut file = file.try_clone().await.ok()?.into_std().await;
+        let content_type = content_type.to_string();
+        task::spawn_blocking(move || {
+            io::Seek::rewind(&mut file).ok()?;
+            sniff::dimensions(&content_type, io::BufReader::new(file))
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// [`store_file`] the spooled `file`, read in memory to be
+    /// [`transformed`].
+    async fn store_in_memory(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        mut file: tokio::fs::File,
+        content_type: &str,
+    ) -> Result<StoredFile, (StatusCode, Json<SMapError>)> {
+        let mut bytes = Vec::new();
+        let read = async {
+            file.rewind().await?;
+            file.read_to_end(&mut bytes).await
+        };
+        read.await.map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(StorageError::from(err).to_string())),
+            )
+        })?;
+        store_file(
+            config,
+            store,
+            storage,
+            Bytes::from(bytes),
+            Some(content_type),
+        )
+        .await
+    }
+
+    /// Store the file streamed by a multipart `field` like [`store_file`],
+    /// without holding it in memory.
+    ///
+    /// The file is spooled to a temporary file while hashing, then streamed
+    /// from there to the storage backend unless its blob is already stored. It
+    /// is recorded with the media type sniffed from its first bytes.
+    async fn store_field(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        mut field: Field<'_>,
+    ) -> Result<StoredFile, (StatusCode, Json<SMapError>)> {
+        let content_type = field.content_type().map(str::to_string);
+        accept_content_type(config, content_type.as_deref())?;
+        if let Some(name) = field.file_name() {
+            accept_file_name(config, name)?;
+        }
+        let storage_error = |err: io::Error| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(StorageError::from(err).to_string())),
+            )
+        };
+
+        let file = archive::temporary_file().map_err(storage_error)?;
+        let mut file = tokio::fs::File::from_std(file);
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        let mut sniffed = None;
+        while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+            // Refuse files of another format before spooling them whole.
+            if sniffed.is_none() && fill_head(&mut head, &chunk) {
+                sniffed = Some(sniff_content_type(config, &head)?);
+            }
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            file.write_all(&chunk).await.map_err(storage_error)?;
+        }
+        let content_type = match sniffed {
+            Some(content_type) => content_type,
+            None => sniff_content_type(config, &head)?,
+        };
+        file.flush().await.map_err(storage_error)?;
+        if transformed(config, content_type) {
+            return store_in_memory(config, store, storage, file, content_type).await;
+        }
+
+        let hash = format!("{:x}", hasher.finalize());
+        let dimensions = spooled_dimensions(&file, content_type).await;
+        check_dimensions(config, dimensions)?;
+        let scan = scan_content(config, &hash, &mut file).await?;
+        file.rewind().await.map_err(storage_error)?;
+        let content = ReaderStream::new(file).map_err(StorageError::from).boxed();
+        let file = store_content(
+            config,
+            store,
+            storage,
+            hash,
+            size,
+            content,
+            Some(content_type),
+        )
+        .await?;
+        Ok(StoredFile {
+            scan,
+            dimensions,
+            ..file
+        })
+    }
+
+    /// Store the complete file spooled in `file` like [`store_file`], streaming
+    /// it from disk. The file is recorded with the media type sniffed from its
+    /// content, which must be accepted for uploads.
+    pub(super) async fn store_spooled(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        mut file: tokio::fs::File,
+    ) -> Result<StoredFile, (StatusCode, Json<SMapError>)> {
+        let storage_error = |err: io::Error| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SMapError::Storage(StorageError::from(err).to_string())),
+            )
+        };
+
+        file.rewind().await.map_err(storage_error)?;
+        let mut chunks = ReaderStream::new(&mut file);
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        while let Some(chunk) = chunks.try_next().await.map_err(storage_error)? {
+            fill_head(&mut head, &chunk);
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+        }
+        let content_type = sniff_content_type(config, &head)?;
+        if transformed(config, content_type) {
+            return store_in_memory(config, store, storage, file, content_type).await;
+        }
+
+        let hash = format!("{:x}", hasher.finalize());
+        let dimensions = spooled_dimensions(&file, content_type).await;
+        check_dimensions(config, dimensions)?;
+        let scan = scan_content(config, &hash, &mut file).await?;
+        file.rewind().await.map_err(storage_error)?;
+        let content = ReaderStream::new(file).map_err(StorageError::from).boxed();
+        let file = store_content(
+            config,
+            store,
+            storage,
+            hash,
+            size,
+            content,
+            Some(content_type),
+        )
+        .await?;
+        Ok(StoredFile {
+            scan,
+            dimensions,
+            ..file
+        })
+    }
+
+    /// Scan `content`, hashing to `hash`, with the antivirus if one is
+    /// configured.
+    ///
+    /// Infected files are quarantined below `<data dir>/quarantine` and refused
+    /// with 422; the scan of clean ones is returned to be recorded.
+    async fn scan_content<R>(
+        config: &Config,
+        hash: &str,
+        content: &mut R,
+    ) -> Result<Option<Scan>, (StatusCode, Json<SMapError>)>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+    {
+        let Some(clamd) = &config.scan.clamd else {
+            return Ok(None);
+        };
+        let scan_error = |err: String| (StatusCode::BAD_GATEWAY, Json(SMapError::BadGateway(err)));
+
+        content
+            .rewind()
+            .await
+            .map_err(|err| scan_error(err.to_string()))?;
+        let verdict = scan::scan(clamd, content)
+            .await
+            .map_err(|err| scan_error(err.to_string()))?;
+        let signature = match verdict {
+            Verdict::Clean(scan) => return Ok(Some(scan)),
+            Verdict::Infected(signature) => signature,
+        };
+
+        let dir = config.data_dir.join("quarantine");
+        let quarantined = async {
+            content.rewind().await?;
+            scan::quarantine(&dir, hash, content, &signature).await
+        };
+        if let Err(err) = quarantined.await {
+            eprintln!("failed to quarantine infected file {hash}: {err}");
+        }
+        Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(SMapError::Infected(format!(
+                "file is infected with {signature}"
+            ))),
+        ))
+    }
+
+    /// Store `content`, of `size` bytes hashing to `hash`, unless an identical
+    /// registered map already holds it.
+    async fn store_content(
+        config: &Config,
+        store: &Store,
+        storage: &dyn StorageBackend,
+        hash: String,
+        size: u64,
+        content: ByteStream,
+        content_type: Option<&str>,
+    ) -> Result<StoredFile, (StatusCode, Json<SMapError>)> {
+        store.hold(&hash).await;
+
+        let existing = match store.find_by_key(&hash).await {
+            Ok(existing) => existing,
+            Err(err) => {
+                store.release(&hash).await;
+                return Err(database_error(err));
+            }
+        };
+        let stored_size = match existing {
+            Some(existing) => existing.stored_size,
+            None => {
+                if !store.reserve(size, config.max_storage_bytes) {
+                    store.release(&hash).await;
+                    let limit = config.max_storage_bytes.unwrap_or_default();
+                    return Err((
+                        StatusCode::INSUFFICIENT_STORAGE,
+                        Json(SMapError::InsufficientStorage(format!(
+                            "storage quota of {limit} bytes exceeded"
+                        ))),
+                    ));
+                }
+                // Usage counts stored bytes, known once written: reserve the
+                // file size meanwhile.
+                match storage.put_stream(&hash, content).await {
+                    Ok(stored_size) => {
+                        store.settle(size, stored_size);
+                        store.written.lock().await.insert(hash.clone(), stored_size);
+                        stored_size
+                    }
+                    Err(err) => {
+                        store.unreserve(size);
+                        store.release(&hash).await;
+                        return Err((
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Json(SMapError::Storage(err.to_string())),
+                        ));
+                    }
+                }
+            }
+        };
+
+        Ok(StoredFile {
+            key: hash.clone(),
+            hash: hash.clone(),
+            size,
+            stored_size,
+            content_type: media_type(content_type),
+            original_content_type: None,
+            scan: None,
+            dimensions: None,
+            received: hash,
+        })
+    }
+
+    /// 500 response for a failed metadata store operation.
+    pub(super) fn database_error(err: MetadataError) -> (StatusCode, Json<SMapError>) {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(SMapError::Database(err.to_string())),
+        )
     }
 }
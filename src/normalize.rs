@@ -0,0 +1,90 @@
+//! Transcoding of uploaded map images to a single canonical format, so that
+//! clients only ever have to handle one.
+//!
+//! Both canonical formats are lossless, but transcoding drops what the image
+//! crate does not carry over: metadata, and the georeferencing of GeoTIFFs.
+
+use clap::{Args, ValueEnum};
+use image::{
+    codecs::{png::PngEncoder, webp::WebPEncoder},
+    DynamicImage, ImageError, Limits,
+};
+
+use crate::sniff;
+
+/// Media types transcoded to the canonical format.
+const TRANSCODED_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/tiff",
+    "image/webp",
+];
+
+/// Format normalization settings.
+#[derive(Args, Debug)]
+pub(crate) struct NormalizeConfig {
+    /// Transcode uploaded images to this format before storing them, keeping
+    /// their original content type on record. PDFs are stored as uploaded.
+    #[arg(long = "normalize-format", env = "SMU_NORMALIZE_FORMAT", value_enum)]
+    pub(crate) format: Option<CanonicalFormat>,
+}
+
+/// Lossless formats uploads can be normalized to.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum CanonicalFormat {
+    /// PNG.
+    Png,
+    /// Lossless WebP, usually smaller than PNG but limited to 8 bits per channel.
+    Webp,
+}
+
+impl CanonicalFormat {
+    pub(crate) fn media_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+impl NormalizeConfig {
+    /// Format files of `content_type` are transcoded to, if any.
+    pub(crate) fn target(&self, content_type: &str) -> Option<CanonicalFormat> {
+        self.format.filter(|format| {
+            TRANSCODED_TYPES.contains(&content_type) && format.media_type() != content_type
+        })
+    }
+}
+
+/// Transcode the image `bytes`, decoded within `limits`, to `format`.
+pub(crate) fn transcode(
+    bytes: &[u8],
+    format: CanonicalFormat,
+    limits: Limits,
+) -> Result<Vec<u8>, ImageError> {
+    let image = sniff::decode(bytes, limits)?;
+
+    let mut transcoded = Vec::new();
+    match format {
+        CanonicalFormat::Png => {
+            // PNG holds up to 16 bits per channel, not floats.
+            let image = match image {
+                DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                    DynamicImage::ImageRgba16(image.to_rgba16())
+                }
+                image => image,
+            };
+            image.write_with_encoder(PngEncoder::new(&mut transcoded))?;
+        }
+        CanonicalFormat::Webp => {
+            // WebP holds 8-bit RGB, with or without alpha.
+            let image = match image.color().has_alpha() {
+                true => DynamicImage::ImageRgba8(image.to_rgba8()),
+                false => DynamicImage::ImageRgb8(image.to_rgb8()),
+            };
+            image.write_with_encoder(WebPEncoder::new_lossless(&mut transcoded))?;
+        }
+    }
+    Ok(transcoded)
+}
@@ -0,0 +1,525 @@
+//! Direct uploads to pre-signed URLs, keeping large transfers off the API
+//! process where the storage backend allows it.
+//!
+//! `POST /upload/presign` reserves an upload and returns the URL to `PUT` its
+//! file to: a pre-signed URL of the S3 bucket, or else a signed URL of this
+//! service writing the file straight to disk. `POST /upload/presign/{id}/finalize`
+//! then registers the map from the uploaded file. Reservations are kept below
+//! `<data dir>/presigned` and discarded once their URL expires unfinalized.
+
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{BodyStream, Path as UrlPath, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use futures::TryStreamExt;
+use hyper::{HeaderMap, StatusCode};
+use ring::{hmac, rand::SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, time};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    archive,
+    config::Config,
+    smap::{self, BaseUrl, SMap, SMapError, Store},
+    storage::{StorageBackend, StorageError},
+};
+
+/// Prefix of the storage keys direct uploads are written to before finalizing.
+pub(crate) const STAGING_PREFIX: &str = "presigned/";
+
+/// Seconds between sweeps of expired reservations.
+const SWEEP_INTERVAL: u64 = 60;
+
+/// Direct upload settings.
+#[derive(Args, Debug)]
+pub(crate) struct PresignConfig {
+    /// Seconds pre-signed upload URLs stay valid.
+    #[arg(long = "presign-ttl", env = "SMU_PRESIGN_TTL", default_value_t = 900)]
+    pub(crate) ttl: u64,
+
+    /// Key signing the upload URLs served by this service, random on each start
+    /// if unset, which invalidates pending URLs on restart.
+    #[arg(long = "presign-secret", env = "SMU_PRESIGN_SECRET")]
+    pub(crate) secret: Option<String>,
+}
+
+/// Direct upload to reserve.
+#[derive(Deserialize, ToSchema)]
+pub(super) struct NewPresignedUpload {
+    /// Title of the map registered on finalizing.
+    #[schema(example = "Tropical Cyclone exposed population")]
+    title: String,
+    /// Media type of the file.
+    #[schema(example = "image/tiff")]
+    content_type: Option<String>,
+}
+
+/// Where the file of a direct upload is sent.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Target {
+    /// The storage backend, bypassing this service.
+    Storage,
+    /// This service, writing it to disk.
+    Local,
+}
+
+/// Reserved direct upload.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(super) struct PresignedUpload {
+    #[schema(example = "5b1f0c7e-2f6e-4c1a-9d0b-6f3a8e2c4d17")]
+    id: String,
+    #[schema(example = "Tropical Cyclone exposed population")]
+    title: String,
+    #[schema(example = "image/tiff")]
+    content_type: Option<String>,
+    /// Owner the map is attributed to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    target: Target,
+    /// URL to `PUT` the file to.
+    #[schema(
+        example = "https://maps.example.org/api/v1/upload/presign/5b1f0c7e-2f6e-4c1a-9d0b-6f3a8e2c4d17?expires=1716200900&signature=q2Jx"
+    )]
+    url: String,
+    /// URL to `POST` to once the file is sent, registering the map.
+    #[schema(
+        example = "https://maps.example.org/api/v1/upload/presign/5b1f0c7e-2f6e-4c1a-9d0b-6f3a8e2c4d17/finalize"
+    )]
+    finalize_url: String,
+    /// When `url` stops accepting the file and the reservation is discarded.
+    #[schema(value_type = String, example = "2024-05-20T10:15:00Z")]
+    expires_at: DateTime<Utc>,
+}
+
+/// Signature of a direct upload URL served by this service.
+#[derive(Deserialize, IntoParams)]
+pub(super) struct Signature {
+    /// Unix time the URL expires at.
+    expires: i64,
+    /// Base64url-encoded HMAC-SHA256 of the upload id and `expires`.
+    signature: String,
+}
+
+/// Reserved direct uploads, one record each, with the files sent to this service.
+pub(crate) struct PresignedUploads {
+    dir: PathBuf,
+    key: hmac::Key,
+    ttl: Duration,
+    /// Uploads being finalized, so an upload is not registered twice.
+    finalizing: Mutex<HashSet<String>>,
+}
+
+impl PresignedUploads {
+    /// Keep reservations in `dir`, creating it if missing.
+    pub(crate) fn open(dir: &Path, config: &PresignConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let key = match &config.secret {
+            Some(secret) => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            None => hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .map_err(|_| io::Error::other("no randomness for the presign key"))?,
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            key,
+            ttl: Duration::from_secs(config.ttl),
+            finalizing: Mutex::default(),
+        })
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    fn file_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.upload"))
+    }
+
+    /// Upload reserved under `id`, if any.
+    async fn get(&self, id: &str) -> io::Result<Option<PresignedUpload>> {
+        // Ids are uuids: anything else must not reach the file system.
+        if Uuid::parse_str(id).is_err() {
+            return Ok(None);
+        }
+        match fs::read(self.record_path(id)).await {
+            Ok(upload) => Ok(Some(serde_json::from_slice(&upload)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn message(id: &str, expires: i64) -> String {
+        format!("{id}:{expires}")
+    }
+
+    /// Signature of the URL of upload `id` expiring at `expires`.
+    fn sign(&self, id: &str, expires: i64) -> String {
+        let tag = hmac::sign(&self.key, Self::message(id, expires).as_bytes());
+        URL_SAFE_NO_PAD.encode(tag.as_ref())
+    }
+
+    /// Whether `signature` is that of the URL of upload `id`, not expired yet.
+    fn verify(&self, id: &str, signature: &Signature) -> bool {
+        let Ok(tag) = URL_SAFE_NO_PAD.decode(&signature.signature) else {
+            return false;
+        };
+        let message = Self::message(id, signature.expires);
+        Utc::now().timestamp() < signature.expires
+            && hmac::verify(&self.key, message.as_bytes(), &tag).is_ok()
+    }
+
+    /// Delete the reservation of `upload` and its file, wherever it was sent.
+    async fn discard(&self, storage: &dyn StorageBackend, upload: &PresignedUpload) {
+        let removed = match upload.target {
+            Target::Storage => match storage.delete(&staging_key(&upload.id)).await {
+                Ok(()) | Err(StorageError::NotFound(_)) => Ok(()),
+                Err(err) => Err(err.to_string()),
+            },
+            Target::Local => remove_file(&self.file_path(&upload.id))
+                .await
+                .map_err(|err| err.to_string()),
+        };
+        let removed = removed.and(
+            remove_file(&self.record_path(&upload.id))
+                .await
+                .map_err(|err| err.to_string()),
+        );
+        if let Err(err) = removed {
+            eprintln!("failed to discard direct upload {}: {err}", upload.id);
+        }
+    }
+}
+
+/// Storage key the file of direct upload `id` is written to.
+fn staging_key(id: &str) -> String {
+    format!("{STAGING_PREFIX}{id}")
+}
+
+async fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn error(status: StatusCode, error: SMapError) -> (StatusCode, Json<SMapError>) {
+    (status, Json(error))
+}
+
+fn not_found(id: &str) -> (StatusCode, Json<SMapError>) {
+    error(
+        StatusCode::NOT_FOUND,
+        SMapError::NotFound(format!("direct upload = {id}")),
+    )
+}
+
+fn storage_error(err: impl std::fmt::Display) -> (StatusCode, Json<SMapError>) {
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        SMapError::Storage(format!("direct upload failed: {err}")),
+    )
+}
+
+/// Reserve direct upload
+///
+/// Reserve the upload of the file of a new map, returning the URL to `PUT` it
+/// to: a pre-signed URL of the S3 bucket, unless stored files are encrypted,
+/// compressed or replicated, or else a signed URL of this service. Finalize
+/// the upload once the file is sent.
+#[utoipa::path(
+    post,
+    path = "/upload/presign",
+    request_body = NewPresignedUpload,
+    responses(
+        (status = 201, description = "Upload reserved", body = PresignedUpload),
+        (status = 400, description = "Title is blank", body = SMapError),
+        (status = 415, description = "Content type is not accepted", body = SMapError),
+        (status = 500, description = "Upload could not be reserved", body = SMapError)
+    )
+)]
+pub(super) async fn presign_upload(
+    State(config): State<Arc<Config>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(uploads): State<Arc<PresignedUploads>>,
+    base: BaseUrl,
+    headers: HeaderMap,
+    Json(new): Json<NewPresignedUpload>,
+) -> Response {
+    if new.title.trim().is_empty() {
+        return error(
+            StatusCode::BAD_REQUEST,
+            SMapError::BadRequest("title must not be blank".to_string()),
+        )
+        .into_response();
+    }
+    if let Err(err) = smap::accept_content_type(&config, new.content_type.as_deref()) {
+        return err.into_response();
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let expires_at = Utc::now() + chrono::Duration::from_std(uploads.ttl).unwrap_or_default();
+    let (target, url) = match storage.presigned_put(&staging_key(&id), uploads.ttl).await {
+        Ok(Some(url)) => (Target::Storage, url),
+        Ok(None) => {
+            let expires = expires_at.timestamp();
+            let signature = uploads.sign(&id, expires);
+            let path = format!("/upload/presign/{id}?expires={expires}&signature={signature}");
+            (Target::Local, base.join(&path))
+        }
+        Err(err) => return storage_error(err).into_response(),
+    };
+    let upload = PresignedUpload {
+        finalize_url: base.join(&format!("/upload/presign/{id}/finalize")),
+        id,
+        title: new.title,
+        content_type: new.content_type,
+        owner: smap::caller(&config, &headers).owner(),
+        target,
+        url,
+        expires_at,
+    };
+
+    let record = serde_json::to_vec(&upload).map_err(io::Error::from);
+    let written = match record {
+        Ok(record) => fs::write(uploads.record_path(&upload.id), record).await,
+        Err(err) => Err(err),
+    };
+    match written {
+        Ok(()) => (StatusCode::CREATED, Json(upload)).into_response(),
+        Err(err) => storage_error(err).into_response(),
+    }
+}
+
+/// Send direct upload file
+///
+/// Target of the signed URLs of direct uploads served by this service, storing
+/// the file on disk until the upload is finalized. A file sent again replaces
+/// the previous one.
+#[utoipa::path(
+    put,
+    path = "/upload/presign/{id}",
+    params(
+        ("id" = String, Path, description = "Direct upload id"),
+        Signature
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "File stored"),
+        (status = 400, description = "Body interrupted", body = SMapError),
+        (status = 403, description = "Signature is invalid or expired", body = SMapError),
+        (status = 404, description = "Direct upload not found", body = SMapError),
+        (status = 500, description = "File could not be stored", body = SMapError)
+    )
+)]
+pub(super) async fn put_presigned(
+    State(uploads): State<Arc<PresignedUploads>>,
+    UrlPath(id): UrlPath<String>,
+    Query(signature): Query<Signature>,
+    body: BodyStream,
+) -> Response {
+    if !uploads.verify(&id, &signature) {
+        return error(
+            StatusCode::FORBIDDEN,
+            SMapError::Unauthorized("invalid or expired upload signature".to_string()),
+        )
+        .into_response();
+    }
+    match uploads.get(&id).await {
+        Ok(Some(upload)) if upload.target == Target::Local => {}
+        Ok(_) => return not_found(&id).into_response(),
+        Err(err) => return storage_error(err).into_response(),
+    }
+    match receive(&uploads, &id, body).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+async fn receive(
+    uploads: &PresignedUploads,
+    id: &str,
+    mut body: BodyStream,
+) -> Result<(), (StatusCode, Json<SMapError>)> {
+    // Written aside and renamed, so a file being resent never looks complete.
+    let temp_path = uploads.dir.join(format!(".{id}.{}.tmp", Uuid::new_v4()));
+    let mut file = fs::File::create(&temp_path).await.map_err(storage_error)?;
+    let written = async {
+        while let Some(chunk) = body.try_next().await.map_err(|err| {
+            error(
+                StatusCode::BAD_REQUEST,
+                SMapError::BadRequest(format!("file interrupted: {err}")),
+            )
+        })? {
+            file.write_all(&chunk).await.map_err(storage_error)?;
+        }
+        file.sync_all().await.map_err(storage_error)?;
+        fs::rename(&temp_path, uploads.file_path(id))
+            .await
+            .map_err(storage_error)
+    };
+    if let Err(err) = written.await {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Finalize direct upload
+///
+/// Register the map from the file sent to the URL of a direct upload, then
+/// discard the reservation.
+#[utoipa::path(
+    post,
+    path = "/upload/presign/{id}/finalize",
+    params(
+        ("id" = String, Path, description = "Direct upload id")
+    ),
+    responses(
+        (status = 201, description = "Static map registered from the uploaded file", body = SMap),
+        (status = 400, description = "File not sent yet", body = SMapError),
+        (status = 404, description = "Direct upload not found or expired", body = SMapError),
+        (status = 409, description = "Upload is already being finalized, or map duplicates an existing one", body = SMapError),
+        (status = 413, description = "Image exceeds the pixel limits", body = SMapError),
+        (status = 415, description = "File has a content type that is not accepted", body = SMapError),
+        (status = 422, description = "File is infected, or too malformed to strip its metadata", body = SMapError),
+        (status = 500, description = "Static map file or metadata could not be stored", body = SMapError),
+        (status = 502, description = "Antivirus could not scan the file", body = SMapError),
+        (status = 507, description = "Storage quota exhausted", body = SMapError)
+    )
+)]
+pub(super) async fn finalize_presigned(
+    State(config): State<Arc<Config>>,
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(uploads): State<Arc<PresignedUploads>>,
+    UrlPath(id): UrlPath<String>,
+) -> Response {
+    let upload = match uploads.get(&id).await {
+        Ok(Some(upload)) if upload.expires_at > Utc::now() => upload,
+        Ok(_) => return not_found(&id).into_response(),
+        Err(err) => return storage_error(err).into_response(),
+    };
+    if !uploads.finalizing.lock().unwrap().insert(id.clone()) {
+        return error(
+            StatusCode::CONFLICT,
+            SMapError::Conflict(format!("direct upload {id} is already being finalized")),
+        )
+        .into_response();
+    }
+    let finalized = finalize(&config, &store, storage.as_ref(), &uploads, upload).await;
+    uploads.finalizing.lock().unwrap().remove(&id);
+    match finalized {
+        Ok(smaps) => smap::created(smaps),
+        Err(response) => response,
+    }
+}
+
+async fn finalize(
+    config: &Config,
+    store: &Store,
+    storage: &dyn StorageBackend,
+    uploads: &PresignedUploads,
+    upload: PresignedUpload,
+) -> Result<Vec<SMap>, Response> {
+    let not_sent = || {
+        error(
+            StatusCode::BAD_REQUEST,
+            SMapError::BadRequest("file not sent yet".to_string()),
+        )
+        .into_response()
+    };
+    let file = match upload.target {
+        Target::Local => match fs::File::open(uploads.file_path(&upload.id)).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(not_sent()),
+            Err(err) => return Err(storage_error(err).into_response()),
+        },
+        // Spooled to disk, to be hashed and checked like other uploads.
+        Target::Storage => {
+            let spooled = async {
+                let mut stream = storage.get(&staging_key(&upload.id)).await?;
+                let mut file = fs::File::from_std(archive::temporary_file()?);
+                while let Some(chunk) = stream.try_next().await? {
+                    file.write_all(&chunk).await?;
+                }
+                file.flush().await?;
+                Ok::<_, StorageError>(file)
+            };
+            match spooled.await {
+                Ok(file) => file,
+                Err(StorageError::NotFound(_)) => return Err(not_sent()),
+                Err(err) => return Err(storage_error(err).into_response()),
+            }
+        }
+    };
+    let file = smap::store_spooled(config, store, storage, file)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let mut smap = SMap::new(Uuid::new_v4().to_string(), upload.title.clone(), file);
+    smap.owner = upload.owner.clone();
+    let smaps = smap::register_new(config, store, storage, vec![smap]).await?;
+    uploads.discard(storage, &upload).await;
+    Ok(smaps)
+}
+
+/// Discard the reservations whose URL expired unfinalized, returning their ids.
+pub(crate) async fn sweep(
+    uploads: &PresignedUploads,
+    storage: &dyn StorageBackend,
+) -> io::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    let mut entries = fs::read_dir(&uploads.dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(id) = name.strip_suffix(".json") {
+            ids.push(id.to_string());
+        }
+    }
+
+    let now = Utc::now();
+    let mut discarded = Vec::new();
+    for id in ids {
+        let Some(upload) = uploads.get(&id).await? else {
+            continue;
+        };
+        if upload.expires_at <= now && !uploads.finalizing.lock().unwrap().contains(&id) {
+            uploads.discard(storage, &upload).await;
+            discarded.push(id);
+        }
+    }
+    Ok(discarded)
+}
+
+/// Run [`sweep`] every minute in the background.
+pub(crate) fn spawn(uploads: Arc<PresignedUploads>, storage: Arc<dyn StorageBackend>) {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(SWEEP_INTERVAL);
+        let mut ticker = time::interval(period);
+        loop {
+            ticker.tick().await;
+            match sweep(&uploads, storage.as_ref()).await {
+                Ok(discarded) if !discarded.is_empty() => {
+                    println!("discarded {} expired direct uploads", discarded.len())
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("direct upload sweep failed: {err}"),
+            }
+        }
+    });
+}
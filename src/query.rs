@@ -0,0 +1,409 @@
+//! Filter expressions of the `q` listing parameter, such as
+//! `title~"cyclone" AND (tags=flood OR created_at>2024-01-01)`.
+//!
+//! A comparison is a field, an operator and a value, quoted when it holds
+//! spaces or parentheses. Comparisons combine with `NOT`, `AND` and `OR`, in
+//! that precedence, and parentheses. Operators are `=`, `!=`, `~` (contains,
+//! ignoring case) for text and tags, and `=`, `!=`, `<`, `<=`, `>`, `>=` for
+//! numbers and times, given in RFC 3339 or as `YYYY-MM-DD` dates in UTC.
+
+use std::{fmt, iter::Peekable, str::CharIndices};
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::smap::SMap;
+
+/// Parsed filter expression, matched against maps.
+#[derive(Debug)]
+pub(crate) enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Compare(Field, Op, Value),
+}
+
+/// Map field a comparison applies to.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Field {
+    Uuid,
+    Title,
+    Description,
+    Tags,
+    ContentType,
+    Owner,
+    Size,
+    Revision,
+    CreatedAt,
+    UpdatedAt,
+}
+
+/// Comparison operator.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Op {
+    Eq,
+    Ne,
+    Contains,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Value compared to a field, typed after it.
+#[derive(Debug)]
+pub(crate) enum Value {
+    Text(String),
+    Number(u64),
+    Time(DateTime<Utc>),
+}
+
+/// Invalid filter expression.
+#[derive(Debug)]
+pub(crate) struct QueryError {
+    /// Character offset of the error in the expression.
+    position: usize,
+    message: String,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at character {}", self.message, self.position)
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Eq => "=",
+            Self::Ne => "!=",
+            Self::Contains => "~",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        })
+    }
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "uuid" => Self::Uuid,
+            "title" => Self::Title,
+            "description" => Self::Description,
+            "tag" | "tags" => Self::Tags,
+            "content_type" => Self::ContentType,
+            "owner" => Self::Owner,
+            "size" => Self::Size,
+            "revision" => Self::Revision,
+            "created_at" => Self::CreatedAt,
+            "updated_at" => Self::UpdatedAt,
+            _ => return None,
+        })
+    }
+
+    /// Operators applying to the field.
+    fn accepts(self, op: Op) -> bool {
+        match self {
+            Self::Size | Self::Revision | Self::CreatedAt | Self::UpdatedAt => op != Op::Contains,
+            _ => matches!(op, Op::Eq | Op::Ne | Op::Contains),
+        }
+    }
+
+    /// Value of the field written as `text`.
+    fn value(self, text: String) -> Result<Value, String> {
+        match self {
+            Self::Size | Self::Revision => text
+                .parse()
+                .map(Value::Number)
+                .map_err(|_| format!("{text:?} is not a number")),
+            Self::CreatedAt | Self::UpdatedAt => parse_time(&text)
+                .map(Value::Time)
+                .ok_or_else(|| format!("{text:?} is not an RFC 3339 time or a date")),
+            _ => Ok(Value::Text(text)),
+        }
+    }
+}
+
+fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+impl Filter {
+    /// Parse the filter `expression`.
+    pub(crate) fn parse(expression: &str) -> Result<Self, QueryError> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?.into_iter().peekable(),
+            end: expression.chars().count(),
+        };
+        let filter = parser.or()?;
+        match parser.tokens.next() {
+            None => Ok(filter),
+            Some((position, token)) => Err(QueryError {
+                position,
+                message: format!("unexpected {token}"),
+            }),
+        }
+    }
+
+    /// Whether `smap` passes the filter.
+    pub(crate) fn matches(&self, smap: &SMap) -> bool {
+        match self {
+            Self::And(left, right) => left.matches(smap) && right.matches(smap),
+            Self::Or(left, right) => left.matches(smap) || right.matches(smap),
+            Self::Not(filter) => !filter.matches(smap),
+            Self::Compare(field, op, value) => compare(smap, *field, *op, value),
+        }
+    }
+}
+
+fn compare(smap: &SMap, field: Field, op: Op, value: &Value) -> bool {
+    match (field, value) {
+        (Field::Tags, Value::Text(text)) => match op {
+            // No tag equals the value, rather than some tag differing from it.
+            Op::Ne => !smap.tags.iter().any(|tag| tag == text),
+            _ => smap.tags.iter().any(|tag| text_matches(tag, op, text)),
+        },
+        (_, Value::Text(text)) => {
+            let field = match field {
+                Field::Uuid => Some(smap.uuid.as_str()),
+                Field::Title => Some(smap.title.as_str()),
+                Field::Description => smap.description.as_deref(),
+                Field::ContentType => Some(smap.content_type.as_str()),
+                Field::Owner => smap.owner.as_deref(),
+                _ => None,
+            };
+            match field {
+                Some(field) => text_matches(field, op, text),
+                None => op == Op::Ne,
+            }
+        }
+        (_, Value::Number(number)) => {
+            let field = match field {
+                Field::Size => smap.size,
+                _ => smap.revision,
+            };
+            ordered(field.cmp(number), op)
+        }
+        (_, Value::Time(time)) => {
+            let field = match field {
+                Field::CreatedAt => smap.created_at,
+                _ => smap.updated_at,
+            };
+            ordered(field.cmp(time), op)
+        }
+    }
+}
+
+fn text_matches(field: &str, op: Op, text: &str) -> bool {
+    match op {
+        Op::Eq => field == text,
+        Op::Ne => field != text,
+        _ => field.to_lowercase().contains(&text.to_lowercase()),
+    }
+}
+
+fn ordered(ordering: std::cmp::Ordering, op: Op) -> bool {
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+        Op::Contains => false,
+    }
+}
+
+#[derive(Debug)]
+enum Token {
+    LeftParen,
+    RightParen,
+    Op(Op),
+    /// Bare word: a field, keyword or value.
+    Word(String),
+    /// Quoted value.
+    Quoted(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LeftParen => write!(f, "'('"),
+            Self::RightParen => write!(f, "')'"),
+            Self::Op(op) => write!(f, "'{op}'"),
+            Self::Word(word) => write!(f, "{word:?}"),
+            Self::Quoted(text) => write!(f, "\"{text}\""),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<(usize, Token)>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars: Peekable<CharIndices> = expression.char_indices().peekable();
+    // Positions are reported in characters, not bytes.
+    let position = |byte: usize| expression[..byte].chars().count();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            _ if c.is_whitespace() => continue,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '~' => Token::Op(Op::Contains),
+            '=' => Token::Op(Op::Eq),
+            '!' | '<' | '>' => {
+                let equals = chars.next_if(|(_, next)| *next == '=').is_some();
+                Token::Op(match (c, equals) {
+                    ('!', true) => Op::Ne,
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    _ => {
+                        return Err(QueryError {
+                            position: position(start),
+                            message: "expected '!='".to_string(),
+                        })
+                    }
+                })
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => text.push(escaped),
+                            None => break,
+                        },
+                        Some((_, c)) => text.push(c),
+                        None => {
+                            return Err(QueryError {
+                                position: position(start),
+                                message: "unterminated quote".to_string(),
+                            })
+                        }
+                    }
+                }
+                Token::Quoted(text)
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| !c.is_whitespace() && !"()~=!<>\"".contains(*c))
+                {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push((position(start), token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<(usize, Token)>>,
+    /// Length of the expression, where running out of tokens is reported.
+    end: usize,
+}
+
+impl Parser {
+    fn keyword(&mut self, keyword: &str) -> bool {
+        self.tokens
+            .next_if(|(_, token)| {
+                matches!(token, Token::Word(word) if word.eq_ignore_ascii_case(keyword))
+            })
+            .is_some()
+    }
+
+    fn or(&mut self) -> Result<Filter, QueryError> {
+        let mut filter = self.and()?;
+        while self.keyword("OR") {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, QueryError> {
+        let mut filter = self.not()?;
+        while self.keyword("AND") {
+            filter = Filter::And(Box::new(filter), Box::new(self.not()?));
+        }
+        Ok(filter)
+    }
+
+    fn not(&mut self) -> Result<Filter, QueryError> {
+        if self.keyword("NOT") {
+            return Ok(Filter::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Filter, QueryError> {
+        let (position, token) = self.next("a comparison")?;
+        let name = match token {
+            Token::LeftParen => {
+                let filter = self.or()?;
+                return match self.next("')'")? {
+                    (_, Token::RightParen) => Ok(filter),
+                    (position, token) => Err(QueryError {
+                        position,
+                        message: format!("expected ')', found {token}"),
+                    }),
+                };
+            }
+            Token::Word(name) => name,
+            token => {
+                return Err(QueryError {
+                    position,
+                    message: format!("expected a field, found {token}"),
+                })
+            }
+        };
+        let Some(field) = Field::parse(&name) else {
+            return Err(QueryError {
+                position,
+                message: format!("unknown field {name:?}"),
+            });
+        };
+
+        let op = match self.next("an operator")? {
+            (position, Token::Op(op)) if !field.accepts(op) => {
+                return Err(QueryError {
+                    position,
+                    message: format!("'{op}' does not apply to {name}"),
+                })
+            }
+            (_, Token::Op(op)) => op,
+            (position, token) => {
+                return Err(QueryError {
+                    position,
+                    message: format!("expected an operator, found {token}"),
+                })
+            }
+        };
+        let value = match self.next("a value")? {
+            (position, Token::Word(text) | Token::Quoted(text)) => field
+                .value(text)
+                .map_err(|message| QueryError { position, message })?,
+            (position, token) => {
+                return Err(QueryError {
+                    position,
+                    message: format!("expected a value, found {token}"),
+                })
+            }
+        };
+        Ok(Filter::Compare(field, op, value))
+    }
+
+    fn next(&mut self, expected: &str) -> Result<(usize, Token), QueryError> {
+        self.tokens.next().ok_or_else(|| QueryError {
+            position: self.end,
+            message: format!("expected {expected}"),
+        })
+    }
+}
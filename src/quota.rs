@@ -0,0 +1,275 @@
+//! Upload quotas of API keys: bytes uploaded with a key are counted per UTC
+//! day and in total, and uploads past either limit are refused.
+//!
+//! Counts are kept in `<data dir>/quotas.json`, by SHA-256 digest of the key
+//! so the file does not disclose keys.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{Days, NaiveDate, Utc};
+use clap::Args;
+use futures::{future, TryStreamExt};
+use hyper::header::{CONTENT_LENGTH, RETRY_AFTER};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, sync::Mutex};
+
+use crate::{
+    config::Config,
+    smap::{self, SMapError},
+};
+
+/// Header carrying the bytes an API key may still upload today.
+const QUOTA_DAILY_REMAINING: HeaderName = HeaderName::from_static("x-quota-daily-remaining");
+
+/// Header carrying the bytes an API key may still upload in total.
+const QUOTA_TOTAL_REMAINING: HeaderName = HeaderName::from_static("x-quota-total-remaining");
+
+/// Upload quota settings.
+#[derive(Args, Debug)]
+pub(crate) struct QuotaConfig {
+    /// Bytes each API key may upload per UTC day, unlimited if unset. Uploads
+    /// past it are refused with 429.
+    #[arg(long = "key-daily-quota-bytes", env = "SMU_KEY_DAILY_QUOTA_BYTES")]
+    pub(crate) daily_bytes: Option<u64>,
+
+    /// Bytes each API key may upload in total, unlimited if unset. Uploads past
+    /// it are refused with 413.
+    #[arg(long = "key-total-quota-bytes", env = "SMU_KEY_TOTAL_QUOTA_BYTES")]
+    pub(crate) total_bytes: Option<u64>,
+}
+
+/// Bytes uploaded with an API key.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Usage {
+    total: u64,
+    /// UTC day counted by `today`.
+    day: NaiveDate,
+    today: u64,
+}
+
+impl Usage {
+    /// Bytes uploaded on `day`.
+    fn on(&self, day: NaiveDate) -> u64 {
+        if self.day == day {
+            self.today
+        } else {
+            0
+        }
+    }
+}
+
+/// Bytes an API key may still upload, `None` where unlimited.
+#[derive(Clone, Copy)]
+struct Remaining {
+    daily: Option<u64>,
+    total: Option<u64>,
+}
+
+impl Remaining {
+    /// Smallest of the remaining quotas.
+    fn least(&self) -> Option<u64> {
+        match (self.daily, self.total) {
+            (Some(daily), Some(total)) => Some(daily.min(total)),
+            (daily, total) => daily.or(total),
+        }
+    }
+
+    /// Refusal of an upload of `bytes` going past a quota: 413 past the total
+    /// one, which waiting does not restore, 429 past the daily one.
+    fn refusal(&self, bytes: u64) -> Option<Response> {
+        if self.total.is_some_and(|total| bytes > total) {
+            let error = SMapError::PayloadTooLarge("total upload quota exhausted".to_string());
+            let mut response = (StatusCode::PAYLOAD_TOO_LARGE, Json(error)).into_response();
+            self.describe(response.headers_mut());
+            return Some(response);
+        }
+        if self.daily.is_some_and(|daily| bytes > daily) {
+            let error = SMapError::TooManyRequests("daily upload quota exhausted".to_string());
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(error)).into_response();
+            self.describe(response.headers_mut());
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds_to_midnight()));
+            return Some(response);
+        }
+        None
+    }
+
+    /// Add the remaining quotas to `headers`.
+    fn describe(&self, headers: &mut HeaderMap) {
+        if let Some(daily) = self.daily {
+            headers.insert(QUOTA_DAILY_REMAINING, HeaderValue::from(daily));
+        }
+        if let Some(total) = self.total {
+            headers.insert(QUOTA_TOTAL_REMAINING, HeaderValue::from(total));
+        }
+    }
+}
+
+/// Seconds until the daily quotas reset, at the next UTC midnight.
+fn seconds_to_midnight() -> i64 {
+    let now = Utc::now();
+    let midnight = now
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|midnight| midnight.and_utc());
+    midnight.map_or(0, |midnight| (midnight - now).num_seconds().max(1))
+}
+
+/// Bytes uploaded with each API key.
+pub(crate) struct Quotas {
+    path: PathBuf,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl Quotas {
+    /// Load the counts saved at `path`, if any.
+    pub(crate) async fn open(path: &Path) -> io::Result<Self> {
+        let usage = match fs::read(path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            usage: Mutex::new(usage),
+        })
+    }
+
+    async fn remaining(&self, config: &QuotaConfig, key: &str) -> Remaining {
+        let usage = self.usage.lock().await.get(&digest(key)).copied();
+        let today = Utc::now().date_naive();
+        Remaining {
+            daily: config
+                .daily_bytes
+                .map(|daily| daily.saturating_sub(usage.map_or(0, |usage| usage.on(today)))),
+            total: config
+                .total_bytes
+                .map(|total| total.saturating_sub(usage.map_or(0, |usage| usage.total))),
+        }
+    }
+
+    /// Count `bytes` uploaded with `key`, saving the counts.
+    async fn record(&self, key: &str, bytes: u64) -> io::Result<()> {
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().await;
+        let entry = usage.entry(digest(key)).or_insert(Usage {
+            total: 0,
+            day: today,
+            today: 0,
+        });
+        *entry = Usage {
+            total: entry.total + bytes,
+            day: today,
+            today: entry.on(today) + bytes,
+        };
+
+        // Written aside and renamed, so a crash never leaves a truncated file.
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec(&*usage)?).await?;
+        fs::rename(&temp_path, &self.path).await
+    }
+}
+
+fn digest(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key))
+}
+
+/// API key of `headers` subject to quotas: any key listed in the configuration
+/// but administrator ones.
+fn quota_key(config: &Config, headers: &HeaderMap) -> Option<String> {
+    let key = headers.get(smap::API_KEY)?.to_str().ok()?;
+    let admin = config.admin_api_keys.iter().any(|admin| admin == key);
+    (smap::is_listed_key(config, key) && !admin).then(|| key.to_string())
+}
+
+/// Refuse uploads past the quotas of their API key, and count the bytes of the
+/// successful ones.
+///
+/// Bodies announcing their length are refused before being read; others once
+/// they go past the quota. Responses carry the remaining quotas.
+pub(crate) async fn limit_quota(
+    State(config): State<Arc<Config>>,
+    State(quotas): State<Arc<Quotas>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let quota = &config.quota;
+    let limited = quota.daily_bytes.is_some() || quota.total_bytes.is_some();
+    let bodiless = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    let key = match quota_key(&config, request.headers()) {
+        Some(key) if limited && !bodiless => key,
+        _ => return next.run(request).await,
+    };
+
+    let remaining = quotas.remaining(quota, &key).await;
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    // An exhausted quota refuses even empty bodies.
+    let announced = length.unwrap_or(1).max(1);
+    if let Some(refusal) = remaining.refusal(announced) {
+        return refusal;
+    }
+
+    let limit = remaining.least().unwrap_or(u64::MAX);
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let read = Arc::new(AtomicU64::new(0));
+    let (parts, body) = request.into_parts();
+    let body = body.map_err(io::Error::other).and_then({
+        let (exceeded, read) = (exceeded.clone(), read.clone());
+        move |chunk| {
+            let total = read.fetch_add(chunk.len() as u64, Ordering::Relaxed) + chunk.len() as u64;
+            if total > limit {
+                exceeded.store(true, Ordering::Relaxed);
+                return future::ready(Err(io::Error::other("upload quota exhausted")));
+            }
+            future::ready(Ok(chunk))
+        }
+    });
+    let mut response = next
+        .run(Request::from_parts(parts, Body::wrap_stream(body)))
+        .await;
+    let read = read.load(Ordering::Relaxed);
+    if exceeded.load(Ordering::Relaxed) {
+        if let Some(refusal) = remaining.refusal(read) {
+            return refusal;
+        }
+    }
+
+    if !response.status().is_success() || read == 0 {
+        remaining.describe(response.headers_mut());
+        return response;
+    }
+    if let Err(err) = quotas.record(&key, read).await {
+        eprintln!("failed to save upload quotas: {err}");
+    }
+    quotas
+        .remaining(quota, &key)
+        .await
+        .describe(response.headers_mut());
+    response
+}
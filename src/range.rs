@@ -0,0 +1,97 @@
+//! Byte ranges of map file downloads, as requested in `Range` headers, letting
+//! clients resume interrupted downloads or read parts of large maps.
+
+/// Bytes of a file to send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    /// Position of the first byte.
+    pub(crate) offset: u64,
+    /// Number of bytes, at least one.
+    pub(crate) length: u64,
+}
+
+impl ByteRange {
+    /// `Content-Range` of the range within a file of `size` bytes.
+    pub(crate) fn content_range(&self, size: u64) -> String {
+        format!(
+            "bytes {}-{}/{size}",
+            self.offset,
+            self.offset + self.length - 1
+        )
+    }
+}
+
+/// Part of a file a `Range` header asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Requested {
+    /// The whole file.
+    Full,
+    /// A single range within the file.
+    Partial(ByteRange),
+    /// A range starting past the end of the file.
+    Unsatisfiable,
+}
+
+/// Part of a file of `size` bytes asked for by `Range` header `value`.
+///
+/// Only single ranges are served: headers listing several, in other units or
+/// malformed, get the whole file, as they may be ignored.
+pub(crate) fn parse(value: &str, size: u64) -> Requested {
+    let Some((unit, spec)) = value.split_once('=') else {
+        return Requested::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return Requested::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Requested::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // The last `suffix` bytes.
+        return match last.parse::<u64>() {
+            Ok(0) => Requested::Unsatisfiable,
+            Ok(_) if size == 0 => Requested::Unsatisfiable,
+            Ok(suffix) => {
+                let length = suffix.min(size);
+                Requested::Partial(ByteRange {
+                    offset: size - length,
+                    length,
+                })
+            }
+            Err(_) => Requested::Full,
+        };
+    }
+
+    let Ok(offset) = first.parse::<u64>() else {
+        return Requested::Full;
+    };
+    let last = match last {
+        "" => u64::MAX,
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= offset => last,
+            _ => return Requested::Full,
+        },
+    };
+    if offset >= size {
+        return Requested::Unsatisfiable;
+    }
+    Requested::Partial(ByteRange {
+        offset,
+        length: last.min(size - 1) - offset + 1,
+    })
+}
+
+/// Whether `If-Range` header `value` still names the file with entity tag
+/// `etag` and modification time `last_modified`, so that a range of it may be
+/// sent rather than all of it.
+///
+/// Entity tags are compared strongly, weak ones never matching.
+pub(crate) fn if_range_matches(value: &str, etag: &str, last_modified: &str) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/") {
+        return value == etag;
+    }
+    value == last_modified
+}
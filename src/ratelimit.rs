@@ -0,0 +1,139 @@
+//! Token-bucket rate limiting of API requests, per client IP address or, for
+//! requests made with an API key listed in the configuration, per key.
+//!
+//! Buckets live in process memory: instances behind a load balancer each
+//! apply the limits on their own.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use clap::Args;
+use hyper::header::RETRY_AFTER;
+
+use crate::{
+    config::Config,
+    smap::{self, SMapError},
+};
+
+/// Buckets kept before full ones are forgotten.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Rate limiting settings.
+#[derive(Args, Debug)]
+pub(crate) struct RateLimitConfig {
+    /// Requests per second allowed to each client IP address, unlimited if
+    /// unset. Requests past it are refused with 429.
+    #[arg(long = "rate-limit-ip", env = "SMU_RATE_LIMIT_IP")]
+    pub(crate) ip: Option<f64>,
+
+    /// Requests per second allowed to each API key, unlimited if unset.
+    /// Requests made with a key are only counted against it, not their IP.
+    #[arg(long = "rate-limit-key", env = "SMU_RATE_LIMIT_KEY")]
+    pub(crate) key: Option<f64>,
+
+    /// Requests a client may make at once after being idle, above the rate.
+    #[arg(
+        long = "rate-limit-burst",
+        env = "SMU_RATE_LIMIT_BURST",
+        default_value_t = 20
+    )]
+    pub(crate) burst: u32,
+}
+
+/// Client a bucket is kept for.
+#[derive(PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Key(String),
+}
+
+/// Tokens left to a client, one taken per request.
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Buckets of the clients seen recently.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<Client, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Take a token from the bucket of `client`, refilled at `rate` per second
+    /// up to `burst`, or return the seconds until one is available.
+    fn take(&self, client: Client, rate: f64, burst: u32) -> Result<(), u64> {
+        let now = Instant::now();
+        let burst = f64::from(burst.max(1));
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            // Full buckets are as good as new ones.
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return Err(((1.0 - bucket.tokens) / rate).ceil() as u64);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Refuse requests past the rate of their API key or IP address with 429 and
+/// the seconds to wait in `Retry-After`.
+pub(crate) async fn limit_rate(
+    State(config): State<Arc<Config>>,
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let limits = &config.rate_limit;
+    let key = request
+        .headers()
+        .get(smap::API_KEY)
+        .and_then(|key| key.to_str().ok())
+        .filter(|key| smap::is_listed_key(&config, key));
+    let limited = match (key, limits.key, limits.ip) {
+        (Some(key), Some(rate), _) => Some((Client::Key(key.to_string()), rate)),
+        (Some(_), None, _) => None,
+        (None, _, Some(rate)) => Some((Client::Ip(address.ip()), rate)),
+        (None, _, None) => None,
+    };
+    let Some((client, rate)) = limited else {
+        return next.run(request).await;
+    };
+
+    match limiter.take(client, rate, limits.burst) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let error = SMapError::TooManyRequests(format!("rate limit of {rate} requests/s"));
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(error)).into_response();
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(wait.max(1)));
+            response
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Rebuilding of the catalog from sidecar records stored next to map files, so
+//! maps stay reachable when the metadata store is lost.
+
+use std::{collections::HashSet, fmt};
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+
+use crate::{
+    db::MetadataError,
+    smap::{SMap, Store},
+    storage::{StorageBackend, StorageError},
+};
+
+/// Suffix of the storage keys of sidecar records.
+const SIDECAR_SUFFIX: &str = ".smap.json";
+
+/// Rescan errors.
+#[derive(Debug)]
+pub(crate) enum RescanError {
+    /// Stored objects could not be listed or read.
+    Storage(StorageError),
+    /// Recovered maps could not be registered.
+    Database(MetadataError),
+}
+
+impl fmt::Display for RescanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Storage(err) => write!(f, "{err}"),
+            Self::Database(err) => write!(f, "metadata store failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for RescanError {}
+
+impl From<StorageError> for RescanError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<MetadataError> for RescanError {
+    fn from(err: MetadataError) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Storage key of the sidecar record of the map registered under `uuid`.
+pub(crate) fn sidecar_key(uuid: &str) -> String {
+    format!("{uuid}{SIDECAR_SUFFIX}")
+}
+
+/// Store the sidecar record of `smap`, replacing the previous one.
+///
+/// Failures are only logged: the catalog stays authoritative, the record merely
+/// lets [`rescan`] recover the map.
+pub(crate) async fn record(storage: &dyn StorageBackend, smap: &SMap) {
+    let bytes = match serde_json::to_vec(smap) {
+        Ok(bytes) => Bytes::from(bytes),
+        Err(err) => return eprintln!("encoding sidecar of map {} failed: {err}", smap.uuid),
+    };
+    if let Err(err) = storage.put(&sidecar_key(&smap.uuid), bytes).await {
+        eprintln!("writing sidecar of map {} failed: {err}", smap.uuid);
+    }
+}
+
+/// Register every map whose sidecar record and file are stored but which is
+/// missing from the catalog, returning their uuids.
+///
+/// Unreadable sidecar records are skipped.
+pub(crate) async fn rescan(
+    store: &Store,
+    storage: &dyn StorageBackend,
+) -> Result<Vec<String>, RescanError> {
+    let keys: HashSet<String> = storage.list().await?.into_iter().collect();
+
+    let mut restored = Vec::new();
+    for key in keys.iter().filter(|key| key.ends_with(SIDECAR_SUFFIX)) {
+        let bytes: Vec<Bytes> = match storage.get(key).await {
+            Ok(stream) => stream.try_collect().await?,
+            Err(StorageError::NotFound(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let smap: SMap = match serde_json::from_slice(&bytes.concat()) {
+            Ok(smap) => smap,
+            Err(err) => {
+                eprintln!("skipping unreadable sidecar {key}: {err}");
+                continue;
+            }
+        };
+        if keys.contains(&smap.key) && store.adopt(smap.clone()).await? {
+            restored.push(smap.uuid);
+        }
+    }
+    Ok(restored)
+}
@@ -0,0 +1,155 @@
+//! Antivirus scanning of uploaded map files by a clamd daemon, over its
+//! `INSTREAM` protocol.
+
+use std::{fmt, io, path::Path};
+
+use chrono::{DateTime, Utc};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+};
+use utoipa::ToSchema;
+
+/// Bytes sent to clamd per `INSTREAM` chunk.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Antivirus settings.
+#[derive(Args, Debug)]
+pub(crate) struct ScanConfig {
+    /// Address of the clamd daemon scanning uploaded files, as `host:port` or
+    /// the path of its unix socket. Uploads are not scanned if unset.
+    #[arg(long, env = "SMU_CLAMD")]
+    pub(crate) clamd: Option<String>,
+}
+
+/// Antivirus scan of a map file found clean.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub(crate) struct Scan {
+    /// Version of the scanner and of its signature database.
+    #[schema(example = "ClamAV 1.0.5/27305/Mon Jun  3 08:29:26 2024")]
+    pub(crate) engine: String,
+    /// When the file was scanned.
+    #[schema(example = "2024-06-03T10:00:00Z")]
+    pub(crate) scanned_at: DateTime<Utc>,
+}
+
+/// Outcome of a scan.
+pub(crate) enum Verdict {
+    Clean(Scan),
+    /// Infected, with the name of the signature found.
+    Infected(String),
+}
+
+/// Scanner errors.
+#[derive(Debug)]
+pub(crate) enum ScanError {
+    /// clamd could not be reached or the connection failed.
+    Io(io::Error),
+    /// clamd could not scan the file, e.g. over its `StreamMaxLength`.
+    Failed(String),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "antivirus i/o error: {err}"),
+            Self::Failed(reply) => write!(f, "antivirus scan failed: {reply}"),
+        }
+    }
+}
+
+impl From<io::Error> for ScanError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Record of an infected file kept in quarantine.
+#[derive(Serialize)]
+struct Quarantined<'a> {
+    signature: &'a str,
+    quarantined_at: DateTime<Utc>,
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+async fn connect(clamd: &str) -> io::Result<Box<dyn Connection>> {
+    if clamd.starts_with('/') {
+        Ok(Box::new(UnixStream::connect(clamd).await?))
+    } else {
+        Ok(Box::new(TcpStream::connect(clamd).await?))
+    }
+}
+
+/// Send `command` to clamd and return its reply, without the trailing NUL.
+async fn request(
+    clamd: &str,
+    command: &str,
+    content: Option<&mut (dyn AsyncRead + Unpin + Send)>,
+) -> Result<String, ScanError> {
+    let mut connection = connect(clamd).await?;
+    connection
+        .write_all(format!("z{command}\0").as_bytes())
+        .await?;
+    if let Some(content) = content {
+        let mut chunk = vec![0; CHUNK_LEN];
+        loop {
+            let len = content.read(&mut chunk).await?;
+            connection.write_all(&(len as u32).to_be_bytes()).await?;
+            if len == 0 {
+                break;
+            }
+            connection.write_all(&chunk[..len]).await?;
+        }
+    }
+    let mut reply = String::new();
+    connection.read_to_string(&mut reply).await?;
+    Ok(reply.trim_end_matches(['\0', '\n']).to_string())
+}
+
+/// Scan `content` with the clamd daemon at `clamd`.
+pub(crate) async fn scan(
+    clamd: &str,
+    content: &mut (dyn AsyncRead + Unpin + Send),
+) -> Result<Verdict, ScanError> {
+    let engine = request(clamd, "VERSION", None).await?;
+    let reply = request(clamd, "INSTREAM", Some(content)).await?;
+    match reply.strip_prefix("stream: ") {
+        Some("OK") => Ok(Verdict::Clean(Scan {
+            engine,
+            scanned_at: Utc::now(),
+        })),
+        Some(found) if found.ends_with(" FOUND") => Ok(Verdict::Infected(
+            found.trim_end_matches(" FOUND").to_string(),
+        )),
+        _ => Err(ScanError::Failed(reply)),
+    }
+}
+
+/// Keep the infected `content` hashing to `hash` in `dir`, next to a JSON
+/// record of the `signature` found, for later inspection.
+pub(crate) async fn quarantine(
+    dir: &Path,
+    hash: &str,
+    content: &mut (dyn AsyncRead + Unpin + Send),
+    signature: &str,
+) -> io::Result<()> {
+    fs::create_dir_all(dir).await?;
+    let mut file = fs::File::create(dir.join(hash)).await?;
+    tokio::io::copy(content, &mut file).await?;
+    file.sync_all().await?;
+    let record = Quarantined {
+        signature,
+        quarantined_at: Utc::now(),
+    };
+    fs::write(
+        dir.join(format!("{hash}.json")),
+        serde_json::to_vec(&record)?,
+    )
+    .await
+}
@@ -0,0 +1,138 @@
+//! In-memory full-text index over the title, description and tags of active maps.
+//!
+//! The index is built when the store opens and follows the changes made through
+//! it, so maps written by other instances sharing the repository are only found
+//! after a restart.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
+
+use crate::smap::SMap;
+
+/// Weight of a term found in the title.
+const TITLE_WEIGHT: u32 = 3;
+/// Weight of a term found in a tag.
+const TAG_WEIGHT: u32 = 2;
+/// Weight of a term found in the description.
+const DESCRIPTION_WEIGHT: u32 = 1;
+
+/// Inverted index from lowercase terms to the maps using them.
+#[derive(Default)]
+pub(crate) struct SearchIndex {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Weight of each term in each map, by term then uuid.
+    postings: BTreeMap<String, HashMap<String, u32>>,
+    /// Terms indexed for each map, to unindex it.
+    terms: HashMap<String, Vec<String>>,
+}
+
+/// Lowercase alphanumeric words of `text`.
+fn tokens(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+impl SearchIndex {
+    /// Index of the active maps of `smaps`.
+    pub(crate) fn build(smaps: &[SMap]) -> Self {
+        let index = Self::default();
+        for smap in smaps {
+            index.index(smap);
+        }
+        index
+    }
+
+    /// Index `smap` in its current state, replacing any previous one. Trashed
+    /// maps are unindexed.
+    pub(crate) fn index(&self, smap: &SMap) {
+        let mut inner = self.inner.write().unwrap();
+        inner.unindex(&smap.uuid);
+        if smap.deleted_at.is_some() {
+            return;
+        }
+
+        let mut weights: HashMap<String, u32> = HashMap::new();
+        let fields = [(TITLE_WEIGHT, smap.title.as_str())]
+            .into_iter()
+            .chain(smap.tags.iter().map(|tag| (TAG_WEIGHT, tag.as_str())))
+            .chain(
+                smap.description
+                    .iter()
+                    .map(|description| (DESCRIPTION_WEIGHT, description.as_str())),
+            );
+        for (weight, text) in fields {
+            for term in tokens(text) {
+                *weights.entry(term).or_default() += weight;
+            }
+        }
+
+        let terms = weights.keys().cloned().collect();
+        for (term, weight) in weights {
+            inner
+                .postings
+                .entry(term)
+                .or_default()
+                .insert(smap.uuid.clone(), weight);
+        }
+        inner.terms.insert(smap.uuid.clone(), terms);
+    }
+
+    /// Drop the map registered under `uuid` from the index.
+    pub(crate) fn remove(&self, uuid: &str) {
+        self.inner.write().unwrap().unindex(uuid);
+    }
+
+    /// Uuids of the maps matching every word of `query`, best first.
+    ///
+    /// A word matches the indexed terms it is a prefix of. Maps score the
+    /// weight of their matching terms; ties keep uuid order.
+    pub(crate) fn search(&self, query: &str) -> Vec<String> {
+        let inner = self.inner.read().unwrap();
+        let mut scores: Option<HashMap<String, u32>> = None;
+        for word in tokens(query) {
+            let mut matches: HashMap<String, u32> = HashMap::new();
+            for (term, postings) in inner.postings.range(word.clone()..) {
+                if !term.starts_with(&word) {
+                    break;
+                }
+                for (uuid, weight) in postings {
+                    *matches.entry(uuid.clone()).or_default() += weight;
+                }
+            }
+            scores = Some(match scores {
+                None => matches,
+                Some(mut scores) => {
+                    scores.retain(|uuid, _| matches.contains_key(uuid));
+                    for (uuid, score) in &mut scores {
+                        *score += matches[uuid];
+                    }
+                    scores
+                }
+            });
+        }
+
+        let mut ranked: Vec<(String, u32)> = scores.unwrap_or_default().into_iter().collect();
+        ranked.sort_by(|(a, a_score), (b, b_score)| b_score.cmp(a_score).then_with(|| a.cmp(b)));
+        ranked.into_iter().map(|(uuid, _)| uuid).collect()
+    }
+}
+
+impl Inner {
+    fn unindex(&mut self, uuid: &str) {
+        for term in self.terms.remove(uuid).unwrap_or_default() {
+            if let Some(postings) = self.postings.get_mut(&term) {
+                postings.remove(uuid);
+                if postings.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+}
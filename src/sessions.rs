@@ -0,0 +1,515 @@
+//! Chunked upload sessions, a simpler alternative to tus for clients sending a
+//! large file in numbered parts.
+//!
+//! `POST /upload/sessions` opens a session, `PUT /upload/sessions/{id}/parts/{n}`
+//! stores part `n`, replacing any previous one so failed parts can be resent,
+//! and `POST /upload/sessions/{id}/complete` assembles parts 1 to N into the
+//! file of a new map. Sessions are kept below `<data dir>/sessions` until
+//! completed.
+//!
+//! `GET /upload/sessions/{id}/progress` streams the bytes received as
+//! server-sent events, for web clients to show upload progress.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{BodyStream, Path as UrlPath, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, TryStreamExt};
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::watch};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    archive,
+    config::Config,
+    smap::{self, SMap, SMapError, Store},
+    storage::StorageBackend,
+};
+
+/// Highest part number of a session.
+const MAX_PARTS: u32 = 10_000;
+
+/// Name of the session record in its directory.
+const SESSION_FILE: &str = "session.json";
+
+/// Upload session to open.
+#[derive(Deserialize, ToSchema)]
+pub(super) struct NewUploadSession {
+    /// Title of the map registered on completion.
+    #[schema(example = "Tropical Cyclone exposed population")]
+    title: String,
+    /// Media type of the assembled file.
+    #[schema(example = "image/tiff")]
+    content_type: Option<String>,
+}
+
+/// Open upload session.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(super) struct UploadSession {
+    #[schema(example = "5b1f0c7e-2f6e-4c1a-9d0b-6f3a8e2c4d17")]
+    id: String,
+    #[schema(example = "Tropical Cyclone exposed population")]
+    title: String,
+    #[schema(example = "image/tiff")]
+    content_type: Option<String>,
+    /// Owner the map is attributed to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    #[schema(value_type = String, example = "2024-05-20T10:00:00Z")]
+    created_at: DateTime<Utc>,
+}
+
+/// Part stored in a session.
+#[derive(Serialize, ToSchema)]
+pub(super) struct UploadPart {
+    #[schema(example = 1)]
+    number: u32,
+    /// Size of the part in bytes.
+    #[schema(example = 8388608)]
+    size: u64,
+    /// Hex-encoded SHA-256 digest of the part, to check it arrived intact.
+    #[schema(example = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08")]
+    sha256: String,
+}
+
+/// Bytes received by an upload session.
+#[derive(Serialize, ToSchema, Clone, Copy, Default)]
+pub(super) struct UploadProgress {
+    /// Bytes received across parts, stored or being sent.
+    #[schema(example = 25165824)]
+    received: u64,
+    /// Parts stored or being sent.
+    #[schema(example = 3)]
+    parts: u32,
+}
+
+/// Bytes received for each part of a session, published to progress streams.
+struct Progress {
+    parts: HashMap<u32, u64>,
+    sender: watch::Sender<UploadProgress>,
+}
+
+/// Open upload sessions, one directory each.
+pub(crate) struct Sessions {
+    dir: PathBuf,
+    /// Sessions being completed, so a session is not assembled twice.
+    completing: Mutex<HashSet<String>>,
+    /// Progress of the sessions followed since startup.
+    progress: Mutex<HashMap<String, Progress>>,
+}
+
+impl Sessions {
+    /// Keep sessions in `dir`, creating it if missing.
+    pub(crate) fn open(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            completing: Mutex::default(),
+            progress: Mutex::default(),
+        })
+    }
+
+    fn session_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    /// Session opened under `id`, if any.
+    async fn get(&self, id: &str) -> io::Result<Option<UploadSession>> {
+        // Ids are uuids: anything else must not reach the file system.
+        if Uuid::parse_str(id).is_err() {
+            return Ok(None);
+        }
+        match fs::read(self.session_dir(id).join(SESSION_FILE)).await {
+            Ok(session) => Ok(Some(serde_json::from_slice(&session)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Numbers of the parts stored in session `id`, in order.
+    async fn parts(&self, id: &str) -> io::Result<Vec<u32>> {
+        let mut parts = Vec::new();
+        let mut entries = fs::read_dir(self.session_dir(id)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(number) = name.strip_suffix(".part").and_then(|n| n.parse().ok()) {
+                parts.push(number);
+            }
+        }
+        parts.sort_unstable();
+        Ok(parts)
+    }
+
+    /// Follow the progress of session `id`, starting from its stored parts.
+    async fn progress(&self, id: &str) -> io::Result<watch::Receiver<UploadProgress>> {
+        if let Some(progress) = self.progress.lock().unwrap().get(id) {
+            return Ok(progress.sender.subscribe());
+        }
+        let mut parts = HashMap::new();
+        for number in self.parts(id).await? {
+            let path = self.session_dir(id).join(format!("{number}.part"));
+            parts.insert(number, fs::metadata(path).await?.len());
+        }
+
+        let mut progress = self.progress.lock().unwrap();
+        let progress = progress.entry(id.to_string()).or_insert_with(|| {
+            let (sender, _) = watch::channel(UploadProgress::default());
+            Progress { parts, sender }
+        });
+        progress.publish();
+        Ok(progress.sender.subscribe())
+    }
+
+    /// Record `received` bytes for part `number` of session `id`, or none
+    /// held for it, returning the bytes recorded before.
+    fn set_received(&self, id: &str, number: u32, received: Option<u64>) -> Option<u64> {
+        let mut progress = self.progress.lock().unwrap();
+        let progress = progress.get_mut(id)?;
+        let previous = match received {
+            Some(received) => progress.parts.insert(number, received),
+            None => progress.parts.remove(&number),
+        };
+        progress.publish();
+        previous
+    }
+}
+
+impl Progress {
+    fn publish(&self) {
+        self.sender.send_replace(UploadProgress {
+            received: self.parts.values().sum(),
+            parts: self.parts.len() as u32,
+        });
+    }
+}
+
+fn error(status: StatusCode, error: SMapError) -> (StatusCode, Json<SMapError>) {
+    (status, Json(error))
+}
+
+fn not_found(id: &str) -> (StatusCode, Json<SMapError>) {
+    error(
+        StatusCode::NOT_FOUND,
+        SMapError::NotFound(format!("session = {id}")),
+    )
+}
+
+fn storage_error(err: io::Error) -> (StatusCode, Json<SMapError>) {
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        SMapError::Storage(format!("upload session i/o error: {err}")),
+    )
+}
+
+/// Open upload session
+///
+/// Open a session to upload the file of a new map in parts.
+#[utoipa::path(
+    post,
+    path = "/upload/sessions",
+    request_body = NewUploadSession,
+    responses(
+        (status = 201, description = "Session opened", body = UploadSession),
+        (status = 400, description = "Title is blank", body = SMapError),
+        (status = 415, description = "Content type is not accepted", body = SMapError),
+        (status = 500, description = "Session could not be stored", body = SMapError)
+    )
+)]
+pub(super) async fn open_session(
+    State(config): State<Arc<Config>>,
+    State(sessions): State<Arc<Sessions>>,
+    headers: HeaderMap,
+    Json(new): Json<NewUploadSession>,
+) -> Response {
+    if new.title.trim().is_empty() {
+        return error(
+            StatusCode::BAD_REQUEST,
+            SMapError::BadRequest("title must not be blank".to_string()),
+        )
+        .into_response();
+    }
+    if let Err(err) = smap::accept_content_type(&config, new.content_type.as_deref()) {
+        return err.into_response();
+    }
+    let session = UploadSession {
+        id: Uuid::new_v4().to_string(),
+        title: new.title,
+        content_type: new.content_type,
+        owner: smap::caller(&config, &headers).owner(),
+        created_at: Utc::now(),
+    };
+
+    let dir = sessions.session_dir(&session.id);
+    let opened = async {
+        fs::create_dir(&dir).await?;
+        fs::write(dir.join(SESSION_FILE), serde_json::to_vec(&session)?).await
+    };
+    match opened.await {
+        Ok(()) => (StatusCode::CREATED, Json(session)).into_response(),
+        Err(err) => storage_error(err).into_response(),
+    }
+}
+
+/// Upload session part
+///
+/// Store part `n` of the file, replacing the part sent before under the same
+/// number. Parts can be sent in any order and concurrently. A part not matching
+/// the SHA-256 digest of its `Content-Digest` header is refused.
+#[utoipa::path(
+    put,
+    path = "/upload/sessions/{id}/parts/{n}",
+    params(
+        ("id" = String, Path, description = "Session id"),
+        ("n" = u32, Path, description = "Part number, from 1"),
+        ("Content-Digest" = Option<String>, Header, description = "SHA-256 digest of the part, as `sha-256=:<base64>:`")
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Part stored", body = UploadPart),
+        (status = 400, description = "Part number out of range or body interrupted", body = SMapError),
+        (status = 404, description = "Session not found", body = SMapError),
+        (status = 422, description = "Part does not match its Content-Digest", body = SMapError),
+        (status = 500, description = "Part could not be stored", body = SMapError)
+    )
+)]
+pub(super) async fn upload_part(
+    State(sessions): State<Arc<Sessions>>,
+    UrlPath((id, number)): UrlPath<(String, u32)>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Response {
+    let digest = match smap::content_digest(&headers) {
+        Ok(digest) => digest,
+        Err(err) => return err.into_response(),
+    };
+    match store_part(&sessions, &id, number, body, digest).await {
+        Ok(part) => Json(part).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+async fn store_part(
+    sessions: &Sessions,
+    id: &str,
+    number: u32,
+    mut body: BodyStream,
+    digest: Option<String>,
+) -> Result<UploadPart, (StatusCode, Json<SMapError>)> {
+    if !(1..=MAX_PARTS).contains(&number) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            SMapError::BadRequest(format!("part numbers range from 1 to {MAX_PARTS}")),
+        ));
+    }
+    if sessions.get(id).await.map_err(storage_error)?.is_none() {
+        return Err(not_found(id));
+    }
+    sessions.progress(id).await.map_err(storage_error)?;
+    let previous = sessions.set_received(id, number, Some(0));
+
+    // Written aside and renamed, so a part being resent never looks complete.
+    let dir = sessions.session_dir(id);
+    let temp_path = dir.join(format!(".{number}.{}.tmp", Uuid::new_v4()));
+    let mut file = fs::File::create(&temp_path).await.map_err(storage_error)?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut sha256 = String::new();
+    let written = async {
+        while let Some(chunk) = body.try_next().await.map_err(|err| {
+            error(
+                StatusCode::BAD_REQUEST,
+                SMapError::BadRequest(format!("part interrupted: {err}")),
+            )
+        })? {
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            file.write_all(&chunk).await.map_err(storage_error)?;
+            sessions.set_received(id, number, Some(size));
+        }
+        sha256 = format!("{:x}", hasher.finalize());
+        if let Some(expected) = &digest {
+            smap::verify_checksum(&sha256, expected)?;
+        }
+        file.sync_all().await.map_err(storage_error)?;
+        fs::rename(&temp_path, dir.join(format!("{number}.part")))
+            .await
+            .map_err(storage_error)
+    };
+    if let Err(err) = written.await {
+        let _ = fs::remove_file(&temp_path).await;
+        sessions.set_received(id, number, previous);
+        return Err(err);
+    }
+
+    Ok(UploadPart {
+        number,
+        size,
+        sha256,
+    })
+}
+
+/// Complete upload session
+///
+/// Assemble parts 1 to N of the session into the file of a new map, then close
+/// the session.
+#[utoipa::path(
+    post,
+    path = "/upload/sessions/{id}/complete",
+    params(
+        ("id" = String, Path, description = "Session id")
+    ),
+    responses(
+        (status = 201, description = "Static map registered from the parts", body = SMap),
+        (status = 400, description = "No parts, or a part is missing", body = SMapError),
+        (status = 404, description = "Session not found", body = SMapError),
+        (status = 409, description = "Session is already being completed, or map duplicates an existing one", body = SMapError),
+        (status = 413, description = "Assembled image exceeds the pixel limits", body = SMapError),
+        (status = 422, description = "Assembled file is infected, or too malformed to strip its metadata", body = SMapError),
+        (status = 500, description = "Static map file or metadata could not be stored", body = SMapError),
+        (status = 502, description = "Antivirus could not scan the file", body = SMapError),
+        (status = 507, description = "Storage quota exhausted", body = SMapError)
+    )
+)]
+pub(super) async fn complete_session(
+    State(config): State<Arc<Config>>,
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(sessions): State<Arc<Sessions>>,
+    UrlPath(id): UrlPath<String>,
+) -> Response {
+    let session = match sessions.get(&id).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return not_found(&id).into_response(),
+        Err(err) => return storage_error(err).into_response(),
+    };
+    if !sessions.completing.lock().unwrap().insert(id.clone()) {
+        return error(
+            StatusCode::CONFLICT,
+            SMapError::Conflict(format!("session {id} is already being completed")),
+        )
+        .into_response();
+    }
+    let completed = complete(&config, &store, storage.as_ref(), &sessions, session).await;
+    sessions.completing.lock().unwrap().remove(&id);
+    match completed {
+        Ok(smaps) => smap::created(smaps),
+        Err(response) => response,
+    }
+}
+
+async fn complete(
+    config: &Config,
+    store: &Store,
+    storage: &dyn StorageBackend,
+    sessions: &Sessions,
+    session: UploadSession,
+) -> Result<Vec<SMap>, Response> {
+    let dir = sessions.session_dir(&session.id);
+    let parts = sessions
+        .parts(&session.id)
+        .await
+        .map_err(|err| storage_error(err).into_response())?;
+    if parts.is_empty() {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            SMapError::BadRequest("session has no parts".to_string()),
+        )
+        .into_response());
+    }
+    let gap = (1..)
+        .zip(&parts)
+        .find(|&(expected, &number)| expected != number);
+    if let Some((missing, _)) = gap {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            SMapError::BadRequest(format!("part {missing} is missing")),
+        )
+        .into_response());
+    }
+
+    let assembled = async {
+        let mut file = fs::File::from_std(archive::temporary_file()?);
+        for number in &parts {
+            let mut part = fs::File::open(dir.join(format!("{number}.part"))).await?;
+            tokio::io::copy(&mut part, &mut file).await?;
+        }
+        file.flush().await?;
+        Ok(file)
+    };
+    let file = assembled
+        .await
+        .map_err(|err| storage_error(err).into_response())?;
+    let file = smap::store_spooled(config, store, storage, file)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let mut smap = SMap::new(Uuid::new_v4().to_string(), session.title, file);
+    smap.owner = session.owner;
+    let smaps = smap::register_new(config, store, storage, vec![smap]).await?;
+    if let Err(err) = fs::remove_dir_all(&dir).await {
+        eprintln!("failed to remove upload session {}: {err}", session.id);
+    }
+    // Ends the progress streams of the session.
+    sessions.progress.lock().unwrap().remove(&session.id);
+    Ok(smaps)
+}
+
+/// Follow upload session progress
+///
+/// Stream `progress` server-sent events carrying the bytes received by the
+/// session, as parts arrive. The stream ends once the session is completed.
+#[utoipa::path(
+    get,
+    path = "/upload/sessions/{id}/progress",
+    params(
+        ("id" = String, Path, description = "Session id")
+    ),
+    responses(
+        (status = 200, description = "Stream of progress events", body = UploadProgress, content_type = "text/event-stream"),
+        (status = 404, description = "Session not found", body = SMapError),
+        (status = 500, description = "Session could not be read", body = SMapError)
+    )
+)]
+pub(super) async fn session_progress(
+    State(sessions): State<Arc<Sessions>>,
+    UrlPath(id): UrlPath<String>,
+) -> Response {
+    let progress = match sessions.get(&id).await {
+        Ok(Some(_)) => sessions.progress(&id).await,
+        Ok(None) => return not_found(&id).into_response(),
+        Err(err) => Err(err),
+    };
+    let progress = match progress {
+        Ok(progress) => progress,
+        Err(err) => return storage_error(err).into_response(),
+    };
+
+    // Intermediate values are skipped when the client reads slower than parts
+    // arrive: only the latest one matters.
+    let events = stream::unfold((progress, true), |(mut progress, first)| async move {
+        if !first {
+            progress.changed().await.ok()?;
+        }
+        let current = *progress.borrow_and_update();
+        let event = Event::default().event("progress").json_data(current);
+        Some((event, (progress, false)))
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
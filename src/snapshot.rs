@@ -0,0 +1,66 @@
+//! JSON snapshots of the in-memory map catalog, written periodically and on shutdown.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    fs,
+    time::{self, Instant},
+};
+
+use crate::{
+    config::{Config, MetadataKind},
+    db::MetadataError,
+    smap::{SMap, Store},
+};
+
+/// Snapshot file in the data directory.
+pub(crate) fn path(config: &Config) -> PathBuf {
+    config.data_dir.join("smaps.json")
+}
+
+/// Maps saved at `path`, none if there is no snapshot yet.
+pub(crate) async fn load(path: &Path) -> Result<Vec<SMap>, MetadataError> {
+    match fs::read(path).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write `smaps` to `path`.
+///
+/// The snapshot is written to a temporary file first, so a crash never leaves a truncated one.
+pub(crate) async fn write(smaps: &[SMap], path: &Path) -> Result<(), MetadataError> {
+    let bytes = serde_json::to_vec(smaps)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, bytes).await?;
+    fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
+/// [`Store::checkpoint`] every `snapshot_interval` seconds in the background, when the catalog is
+/// kept in a JSON snapshot and the interval is not 0.
+pub(crate) fn spawn(store: Arc<Store>, config: &Config) {
+    if config.metadata != MetadataKind::Json || config.snapshot_interval == 0 {
+        return;
+    }
+
+    let period = Duration::from_secs(config.snapshot_interval);
+    tokio::spawn(async move {
+        let mut ticker = time::interval_at(Instant::now() + period, period);
+        loop {
+            ticker.tick().await;
+            if let Err(err) = store.checkpoint().await {
+                eprintln!("snapshot failed: {err}");
+            }
+        }
+    });
+}
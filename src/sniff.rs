@@ -0,0 +1,65 @@
+//! Detection of the format of map files from their first bytes, so that uploads
+//! are checked against what they hold rather than what clients declare.
+
+use std::io::{BufRead, Cursor, Seek};
+
+use image::{DynamicImage, ImageFormat, ImageReader, ImageResult, Limits};
+
+use crate::config::Config;
+
+/// Leading bytes needed to recognize every known format.
+pub(crate) const SNIFF_LEN: usize = 16;
+
+/// Leading bytes of each known format, with its media type.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    // Classic TIFF and BigTIFF, in both byte orders; GeoTIFFs are TIFFs.
+    (b"II*\0", "image/tiff"),
+    (b"MM\0*", "image/tiff"),
+    (b"II+\0", "image/tiff"),
+    (b"MM\0+", "image/tiff"),
+    (b"\0\0\0\x0cjP  \r\n\x87\n", "image/jp2"),
+    (b"%PDF-", "application/pdf"),
+];
+
+/// Media type of the format of a file starting with `head`, if known.
+pub(crate) fn sniff(head: &[u8]) -> Option<&'static str> {
+    // WebP is a RIFF container: its signature follows the chunk size.
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| head.starts_with(signature))
+        .map(|(_, media_type)| *media_type)
+}
+
+/// Width and height in pixels of the image of `content_type` read by `reader`,
+/// from its header; `None` for other formats or unreadable headers.
+pub(crate) fn dimensions<R: BufRead + Seek>(content_type: &str, reader: R) -> Option<(u32, u32)> {
+    let format = ImageFormat::from_mime_type(content_type)?;
+    ImageReader::with_format(reader, format)
+        .into_dimensions()
+        .ok()
+}
+
+/// Decoding limits of the images allowed by `config`.
+pub(crate) fn limits(config: &Config) -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(config.max_image_side);
+    limits.max_image_height = Some(config.max_image_side);
+    // Up to 8 bytes per pixel, for 16-bit RGBA.
+    limits.max_alloc = Some(config.max_image_pixels.saturating_mul(8));
+    limits
+}
+
+/// Decode the image `bytes`, of a format guessed from their content, within
+/// `limits`.
+pub(crate) fn decode(bytes: &[u8], limits: Limits) -> ImageResult<DynamicImage> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    reader.decode()
+}
@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+use tokio::sync::Semaphore;
+
+use crate::{
+    config::Config, idempotency::IdempotencyKeys, presign::PresignedUploads, quota::Quotas,
+    ratelimit::RateLimiter, sessions::Sessions, smap::Store, storage::StorageBackend, tus::Uploads,
+};
+
+/// Shared state handed to every handler.
+#[derive(Clone, FromRef)]
+pub(crate) struct AppState {
+    pub(crate) config: Arc<Config>,
+    pub(crate) store: Arc<Store>,
+    pub(crate) storage: Arc<dyn StorageBackend>,
+    pub(crate) idempotency: Arc<IdempotencyKeys>,
+    pub(crate) uploads: Arc<Uploads>,
+    pub(crate) sessions: Arc<Sessions>,
+    pub(crate) presigned: Arc<PresignedUploads>,
+    pub(crate) quotas: Arc<Quotas>,
+    pub(crate) rate_limiter: Arc<RateLimiter>,
+    /// Slots of the uploads handled at once.
+    pub(crate) upload_slots: Arc<Semaphore>,
+}
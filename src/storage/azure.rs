@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use clap::Args;
+use object_store::azure::MicrosoftAzureBuilder;
+
+use super::{ObjectStorage, StorageError};
+
+/// Azure Blob Storage settings.
+///
+/// Authenticates with the connection string when given, otherwise with the
+/// managed identity of the host.
+#[derive(Args, Debug)]
+pub(crate) struct AzureConfig {
+    /// Blob container holding uploaded maps.
+    #[arg(
+        id = "azure_container",
+        long = "azure-container",
+        env = "SMU_AZURE_CONTAINER"
+    )]
+    pub(crate) container: Option<String>,
+
+    /// Blob name prefix applied to every stored object.
+    #[arg(
+        id = "azure_prefix",
+        long = "azure-prefix",
+        env = "SMU_AZURE_PREFIX",
+        default_value = ""
+    )]
+    pub(crate) prefix: String,
+
+    /// Storage account connection string.
+    #[arg(
+        id = "azure_connection_string",
+        long = "azure-connection-string",
+        env = "AZURE_STORAGE_CONNECTION_STRING",
+        hide_env_values = true
+    )]
+    pub(crate) connection_string: Option<String>,
+
+    /// Storage account name, required for managed identity.
+    #[arg(
+        id = "azure_account",
+        long = "azure-account",
+        env = "SMU_AZURE_ACCOUNT"
+    )]
+    pub(crate) account: Option<String>,
+
+    /// Client id of a user-assigned managed identity.
+    #[arg(
+        id = "azure_client_id",
+        long = "azure-client-id",
+        env = "SMU_AZURE_CLIENT_ID"
+    )]
+    pub(crate) client_id: Option<String>,
+}
+
+/// Build an Azure Blob container backend.
+pub(crate) fn build(config: &AzureConfig) -> Result<ObjectStorage, StorageError> {
+    let container = config
+        .container
+        .as_deref()
+        .ok_or_else(|| StorageError::Config("missing Azure container".to_string()))?;
+
+    let mut builder = MicrosoftAzureBuilder::new().with_container_name(container);
+
+    if let Some(connection_string) = &config.connection_string {
+        for (name, value) in parse_connection_string(connection_string)? {
+            builder = match name {
+                "AccountName" => builder.with_account(value),
+                "AccountKey" => builder.with_access_key(value),
+                "BlobEndpoint" => builder.with_endpoint(value.to_string()),
+                _ => builder,
+            };
+        }
+    } else {
+        let account = config.account.as_deref().ok_or_else(|| {
+            StorageError::Config("missing Azure connection string or account".to_string())
+        })?;
+        builder = builder.with_account(account);
+        if let Some(client_id) = &config.client_id {
+            builder = builder.with_client_id(client_id);
+        }
+    }
+
+    Ok(ObjectStorage::new(
+        Arc::new(builder.build()?),
+        &config.prefix,
+    ))
+}
+
+/// Split a `Name=value;Name=value` connection string into its settings.
+fn parse_connection_string(value: &str) -> Result<Vec<(&str, &str)>, StorageError> {
+    value
+        .split(';')
+        .filter(|setting| !setting.is_empty())
+        .map(|setting| {
+            setting.split_once('=').ok_or_else(|| {
+                StorageError::Config(format!("malformed connection string setting: {setting}"))
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,84 @@
+use std::{io, sync::Arc};
+
+use axum::async_trait;
+use bytes::Bytes;
+use clap::Args;
+use futures::{stream, StreamExt, TryStreamExt};
+
+use super::{ByteStream, StorageBackend, StorageError};
+
+/// Compression settings.
+#[derive(Args, Debug)]
+pub(crate) struct CompressionConfig {
+    /// Compress stored objects with zstd.
+    #[arg(long, env = "SMU_COMPRESS")]
+    pub(crate) compress: bool,
+
+    /// zstd compression level.
+    #[arg(long, env = "SMU_COMPRESSION_LEVEL", default_value_t = zstd::DEFAULT_COMPRESSION_LEVEL)]
+    pub(crate) compression_level: i32,
+}
+
+/// Compresses objects with zstd before handing them to an inner backend.
+pub(crate) struct CompressedStorage {
+    inner: Arc<dyn StorageBackend>,
+    level: i32,
+}
+
+impl CompressedStorage {
+    /// Wrap `inner` when compression is enabled in `config`, otherwise return it unchanged.
+    pub(crate) fn wrap(
+        inner: Arc<dyn StorageBackend>,
+        config: &CompressionConfig,
+    ) -> Arc<dyn StorageBackend> {
+        if !config.compress {
+            return inner;
+        }
+        Arc::new(Self {
+            inner,
+            level: config.compression_level,
+        })
+    }
+}
+
+/// Run CPU-bound codec work off the async runtime.
+async fn blocking<F>(f: F) -> Result<Vec<u8>, StorageError>
+where
+    F: FnOnce() -> io::Result<Vec<u8>> + Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)??)
+}
+
+#[async_trait]
+impl StorageBackend for CompressedStorage {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError> {
+        let level = self.level;
+        let compressed = blocking(move || zstd::encode_all(bytes.as_ref(), level)).await?;
+        self.inner.put(key, compressed.into()).await
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let chunks: Vec<Bytes> = self.inner.get(key).await?.try_collect().await?;
+        let compressed = chunks.concat();
+        let data = blocking(move || zstd::decode_all(compressed.as_slice())).await?;
+        Ok(stream::iter([Ok(Bytes::from(data))]).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.delete(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        self.inner.exists(key).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        self.inner.list().await
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>, StorageError> {
+        self.inner.free_space().await
+    }
+}
@@ -0,0 +1,120 @@
+use std::{path::PathBuf, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, Generate, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use axum::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use clap::Args;
+use futures::{stream, StreamExt, TryStreamExt};
+
+use super::{ByteStream, StorageBackend, StorageError};
+
+/// Length of the random nonce prepended to every encrypted object.
+const NONCE_LEN: usize = 12;
+
+/// Encryption at rest settings. Objects are stored in plain text when no key is given.
+#[derive(Args, Debug)]
+pub(crate) struct EncryptionConfig {
+    /// Base64-encoded 256-bit AES-GCM key.
+    #[arg(long, env = "SMU_ENCRYPTION_KEY", hide_env_values = true)]
+    pub(crate) encryption_key: Option<String>,
+
+    /// File containing the base64-encoded 256-bit AES-GCM key.
+    #[arg(
+        long,
+        env = "SMU_ENCRYPTION_KEY_FILE",
+        conflicts_with = "encryption_key"
+    )]
+    pub(crate) encryption_key_file: Option<PathBuf>,
+}
+
+impl EncryptionConfig {
+    /// Raw key bytes, if encryption is enabled.
+    fn key(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        let encoded = match (&self.encryption_key, &self.encryption_key_file) {
+            (Some(key), _) => key.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)?,
+            (None, None) => return Ok(None),
+        };
+        STANDARD
+            .decode(encoded.trim())
+            .map(Some)
+            .map_err(|err| StorageError::Config(format!("invalid encryption key: {err}")))
+    }
+}
+
+/// Encrypts objects with AES-256-GCM before handing them to an inner backend.
+pub(crate) struct EncryptedStorage {
+    inner: Arc<dyn StorageBackend>,
+    cipher: Aes256Gcm,
+}
+
+impl EncryptedStorage {
+    /// Wrap `inner` when `config` holds a key, otherwise return it unchanged.
+    pub(crate) fn wrap(
+        inner: Arc<dyn StorageBackend>,
+        config: &EncryptionConfig,
+    ) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        let Some(key) = config.key()? else {
+            return Ok(inner);
+        };
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| {
+            StorageError::Config("encryption key must be 32 bytes long".to_string())
+        })?;
+        Ok(Arc::new(Self { inner, cipher }))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for EncryptedStorage {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError> {
+        let nonce = Nonce::generate();
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, bytes.as_ref())
+            .map_err(|_| StorageError::Crypto(format!("failed to encrypt {key}")))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        self.inner.put(key, sealed.into()).await
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        // GCM authenticates the whole object, so it has to be read in full.
+        let chunks: Vec<Bytes> = self.inner.get(key).await?.try_collect().await?;
+        let sealed = chunks.concat();
+        let corrupted = || StorageError::Crypto(format!("failed to decrypt {key}"));
+
+        if sealed.len() < NONCE_LEN {
+            return Err(corrupted());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_from(nonce).map_err(|_| corrupted())?;
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce, ciphertext)
+            .map_err(|_| corrupted())?;
+
+        Ok(stream::iter([Ok(Bytes::from(plaintext))]).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.delete(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        self.inner.exists(key).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        self.inner.list().await
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>, StorageError> {
+        self.inner.free_space().await
+    }
+}
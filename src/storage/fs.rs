@@ -0,0 +1,200 @@
+use std::{
+    io::{self, ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use axum::async_trait;
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use super::{ByteStream, StorageBackend, StorageError};
+
+/// Depth of the shard directories objects are spread over.
+const SHARD_DEPTH: usize = 2;
+
+/// Stores objects as plain files below a root directory.
+///
+/// Files are sharded by key prefix, e.g. key `abcdef` is stored at `ab/cd/abcdef`.
+pub(crate) struct FileSystemStorage {
+    root: PathBuf,
+}
+
+impl FileSystemStorage {
+    /// Use `root` as storage directory, creating it if missing and moving files
+    /// left by the former flat layout into their shard.
+    pub(crate) fn open(root: &Path) -> Result<Self, StorageError> {
+        std::fs::create_dir_all(root)?;
+        let storage = Self {
+            root: root.to_path_buf(),
+        };
+        storage.migrate_flat_layout()?;
+        Ok(storage)
+    }
+
+    fn migrate_flat_layout(&self) -> io::Result<()> {
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type()?.is_file() || is_temporary(&name) {
+                continue;
+            }
+
+            let path = self.path(&name);
+            if path != entry.path() {
+                std::fs::create_dir_all(path.parent().unwrap_or(&self.root))?;
+                std::fs::rename(entry.path(), path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn shard(&self, key: &str) -> PathBuf {
+        let mut dir = self.root.clone();
+        if is_temporary(key) {
+            return dir;
+        }
+        for depth in 0..SHARD_DEPTH {
+            match key.get(depth * 2..depth * 2 + 2) {
+                Some(prefix) if key.len() > SHARD_DEPTH * 2 => dir.push(prefix),
+                _ => break,
+            }
+        }
+        dir
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.shard(key).join(key)
+    }
+
+    /// Hidden sibling of `key` that a write goes to before being renamed into place.
+    fn temp_path(&self, key: &str) -> PathBuf {
+        self.shard(key)
+            .join(format!(".{key}.{}.tmp", Uuid::new_v4()))
+    }
+}
+
+/// Temporary files and probes are dot-prefixed and never exposed as stored objects.
+fn is_temporary(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+#[async_trait]
+impl StorageBackend for FileSystemStorage {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError> {
+        // Write next to the destination and rename, so a crash never leaves a truncated file.
+        let temp_path = self.temp_path(key);
+        let result = async {
+            fs::create_dir_all(self.shard(key)).await?;
+            let mut file = File::create(&temp_path).await?;
+            file.write_all(&bytes).await?;
+            file.sync_all().await?;
+            fs::rename(&temp_path, self.path(key)).await
+        }
+        .await;
+
+        if let Err(err) = result {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(err.into());
+        }
+        Ok(bytes.len() as u64)
+    }
+
+    async fn put_stream(&self, key: &str, mut stream: ByteStream) -> Result<u64, StorageError> {
+        let temp_path = self.temp_path(key);
+        let result = async {
+            fs::create_dir_all(self.shard(key)).await?;
+            let mut file = File::create(&temp_path).await?;
+            let mut size = 0;
+            while let Some(chunk) = stream.try_next().await? {
+                file.write_all(&chunk).await?;
+                size += chunk.len() as u64;
+            }
+            file.sync_all().await?;
+            fs::rename(&temp_path, self.path(key)).await?;
+            Ok::<_, StorageError>(size)
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+        result
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        match File::open(self.path(key)).await {
+            Ok(file) => Ok(ReaderStream::new(file).map_err(StorageError::from).boxed()),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Err(StorageError::NotFound(key.to_string()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<ByteStream, StorageError> {
+        let mut file = match File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(StorageError::NotFound(key.to_string()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        file.seek(SeekFrom::Start(offset)).await?;
+        let reader = file.take(length);
+        Ok(ReaderStream::new(reader)
+            .map_err(StorageError::from)
+            .boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match fs::remove_file(self.path(key)).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Err(StorageError::NotFound(key.to_string()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        Ok(fs::try_exists(self.path(key)).await?)
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        let root = self.root.clone();
+        let keys = tokio::task::spawn_blocking(move || {
+            let mut keys = Vec::new();
+            for entry in WalkDir::new(root).max_depth(SHARD_DEPTH + 1) {
+                let entry = entry.map_err(io::Error::from)?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if entry.file_type().is_file() && !is_temporary(&name) {
+                    keys.push(name);
+                }
+            }
+            Ok::<_, io::Error>(keys)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        Ok(keys)
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>, StorageError> {
+        let root = self.root.clone();
+        let available = tokio::task::spawn_blocking(move || fs4::available_space(root))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(Some(available))
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use clap::Args;
+use object_store::gcp::GoogleCloudStorageBuilder;
+
+use super::{ObjectStorage, StorageError};
+
+/// Google Cloud Storage settings.
+///
+/// Without an explicit service account the application default credentials
+/// of the host are used.
+#[derive(Args, Debug)]
+pub(crate) struct GcsConfig {
+    /// Bucket holding uploaded maps.
+    #[arg(id = "gcs_bucket", long = "gcs-bucket", env = "SMU_GCS_BUCKET")]
+    pub(crate) bucket: Option<String>,
+
+    /// Object name prefix applied to every stored object.
+    #[arg(
+        id = "gcs_prefix",
+        long = "gcs-prefix",
+        env = "SMU_GCS_PREFIX",
+        default_value = ""
+    )]
+    pub(crate) prefix: String,
+
+    /// Path to a service account JSON key file.
+    #[arg(
+        id = "gcs_service_account",
+        long = "gcs-service-account",
+        env = "GOOGLE_SERVICE_ACCOUNT"
+    )]
+    pub(crate) service_account: Option<String>,
+}
+
+/// Build a Google Cloud Storage bucket backend.
+pub(crate) fn build(config: &GcsConfig) -> Result<ObjectStorage, StorageError> {
+    let bucket = config
+        .bucket
+        .as_deref()
+        .ok_or_else(|| StorageError::Config("missing GCS bucket".to_string()))?;
+
+    let mut builder = GoogleCloudStorageBuilder::from_env().with_bucket_name(bucket);
+    if let Some(path) = &config.service_account {
+        builder = builder.with_service_account_path(path);
+    }
+
+    Ok(ObjectStorage::new(
+        Arc::new(builder.build()?),
+        &config.prefix,
+    ))
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use axum::async_trait;
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use tokio::sync::RwLock;
+
+use super::{ByteStream, StorageBackend, StorageError};
+
+/// Keeps objects in RAM; everything is lost on restart.
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    objects: RwLock<HashMap<String, Bytes>>,
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError> {
+        let size = bytes.len() as u64;
+        self.objects.write().await.insert(key.to_string(), bytes);
+        Ok(size)
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let bytes = self
+            .objects
+            .read()
+            .await
+            .get(key)
+            .cloned()
+            .ok_or_else(|| StorageError::NotFound(key.to_string()))?;
+        Ok(stream::iter([Ok(bytes)]).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.objects
+            .write()
+            .await
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| StorageError::NotFound(key.to_string()))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        Ok(self.objects.read().await.contains_key(key))
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        Ok(self.objects.read().await.keys().cloned().collect())
+    }
+}
@@ -0,0 +1,235 @@
+//! Pluggable storage for uploaded static map files.
+
+use std::{fmt, io, sync::Arc, time::Duration};
+
+use axum::async_trait;
+use bytes::Bytes;
+use futures::{future, stream::BoxStream, StreamExt, TryStreamExt};
+use uuid::Uuid;
+
+use crate::config::{Config, StorageKind};
+
+mod azure;
+mod compressed;
+mod encrypted;
+mod fs;
+mod gcs;
+mod memory;
+mod object;
+mod replicated;
+mod s3;
+mod sftp;
+mod webdav;
+
+pub(crate) use azure::AzureConfig;
+pub(crate) use compressed::{CompressedStorage, CompressionConfig};
+pub(crate) use encrypted::{EncryptedStorage, EncryptionConfig};
+pub(crate) use fs::FileSystemStorage;
+pub(crate) use gcs::GcsConfig;
+pub(crate) use memory::MemoryStorage;
+pub(crate) use object::ObjectStorage;
+pub(crate) use replicated::{ReplicatedStorage, ReplicationConfig};
+pub(crate) use s3::S3Config;
+pub(crate) use sftp::{SftpConfig, SftpStorage};
+pub(crate) use webdav::{WebDavConfig, WebDavStorage};
+
+/// Stream of object bytes returned by [`StorageBackend::get`].
+pub(crate) type ByteStream = BoxStream<'static, Result<Bytes, StorageError>>;
+
+/// Storage operation errors.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub(crate) enum StorageError {
+    /// No object stored under the requested key.
+    NotFound(String),
+    /// Backend misconfiguration detected at startup.
+    Config(String),
+    /// Underlying I/O failure.
+    Io(io::Error),
+    /// Remote object store failure.
+    ObjectStore(object_store::Error),
+    /// HTTP transport failure.
+    Http(reqwest::Error),
+    /// Unexpected response from a remote server.
+    Remote(String),
+    /// Encryption or decryption failure.
+    Crypto(String),
+    /// Health probe read back something else than it wrote.
+    Unhealthy(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(key) => write!(f, "object not found: {key}"),
+            Self::Config(msg) => write!(f, "invalid storage configuration: {msg}"),
+            Self::Io(err) => write!(f, "storage i/o error: {err}"),
+            Self::ObjectStore(err) => write!(f, "object store error: {err}"),
+            Self::Http(err) => write!(f, "storage http error: {err}"),
+            Self::Remote(msg) => write!(f, "storage server error: {msg}"),
+            Self::Crypto(msg) => write!(f, "storage crypto error: {msg}"),
+            Self::Unhealthy(msg) => write!(f, "storage unhealthy: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+impl From<object_store::Error> for StorageError {
+    fn from(err: object_store::Error) -> Self {
+        match err {
+            object_store::Error::NotFound { path, .. } => Self::NotFound(path),
+            err => Self::ObjectStore(err),
+        }
+    }
+}
+
+/// Backend holding the bytes of uploaded maps, addressed by key.
+#[allow(dead_code)]
+#[async_trait]
+pub(crate) trait StorageBackend: Send + Sync {
+    /// Store `bytes` under `key`, replacing any previous object.
+    ///
+    /// Returns the number of bytes the object occupies in the backend.
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError>;
+
+    /// Store the bytes of `stream` under `key`, replacing any previous object.
+    ///
+    /// Backends able to write incrementally override this; the default
+    /// collects the stream in memory and calls [`put`](Self::put).
+    async fn put_stream(&self, key: &str, stream: ByteStream) -> Result<u64, StorageError> {
+        let mut chunks: Vec<Bytes> = stream.try_collect().await?;
+        let bytes = match chunks.len() {
+            1 => chunks.remove(0),
+            _ => Bytes::from(chunks.concat()),
+        };
+        self.put(key, bytes).await
+    }
+
+    /// Stream the object stored under `key`.
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError>;
+
+    /// Stream `length` bytes of the object stored under `key`, from byte `offset`.
+    ///
+    /// Backends able to read from an offset override this; the default skips
+    /// ahead in the stream of [`get`](Self::get), so that wrappers transforming
+    /// objects slice their decoded bytes.
+    async fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<ByteStream, StorageError> {
+        Ok(slice(self.get(key).await?, offset, length))
+    }
+
+    /// Remove the object stored under `key`.
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Check whether an object is stored under `key`.
+    async fn exists(&self, key: &str) -> Result<bool, StorageError>;
+
+    /// Keys of every stored object.
+    async fn list(&self) -> Result<Vec<String>, StorageError>;
+
+    /// URL to `PUT` the object under `key` to directly, valid for `expires_in`,
+    /// if the backend can sign one.
+    ///
+    /// Wrappers transforming objects keep the default, as bytes written directly
+    /// would skip the transformation.
+    async fn presigned_put(
+        &self,
+        _key: &str,
+        _expires_in: Duration,
+    ) -> Result<Option<String>, StorageError> {
+        Ok(None)
+    }
+
+    /// Free space left on the backing volume, if the backend can tell.
+    async fn free_space(&self) -> Result<Option<u64>, StorageError> {
+        Ok(None)
+    }
+
+    /// Write, read back and delete a probe object.
+    async fn health_check(&self) -> Result<(), StorageError> {
+        let key = format!(".health-{}", Uuid::new_v4());
+        let probe = Bytes::from_static(b"smu storage health probe");
+
+        self.put(&key, probe.clone()).await?;
+        let read: Vec<Bytes> = self.get(&key).await?.try_collect().await?;
+        self.delete(&key).await?;
+
+        if read.concat() != probe {
+            return Err(StorageError::Unhealthy(
+                "probe object read back differs".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Keep the `length` bytes of `stream` from byte `offset`, ending it once past them.
+fn slice(stream: ByteStream, offset: u64, length: u64) -> ByteStream {
+    let end = offset.saturating_add(length);
+    stream
+        .scan(0u64, move |position, chunk| {
+            let start = *position;
+            if start >= end {
+                return future::ready(None);
+            }
+            let chunk = chunk.map(|chunk| {
+                let len = chunk.len() as u64;
+                *position += len;
+                let from = offset.saturating_sub(start).min(len) as usize;
+                let to = (end - start).min(len) as usize;
+                chunk.slice(from..to.max(from))
+            });
+            future::ready(Some(chunk))
+        })
+        .try_filter(|chunk| future::ready(!chunk.is_empty()))
+        .boxed()
+}
+
+/// Build the storage backend selected in `config`.
+///
+/// Starts the replication tasks when a replica backend is configured.
+pub(crate) fn from_config(config: &Config) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    let mut backend = build(config.storage, config)?;
+    if let Some(kind) = config.replication.replica_storage {
+        if kind == config.storage {
+            return Err(StorageError::Config(
+                "replica must use a different backend than the primary".to_string(),
+            ));
+        }
+        let replica = build(kind, config)?;
+        backend = ReplicatedStorage::start(backend, replica, &config.replication);
+    }
+    // Compress before encrypting: ciphertext does not compress.
+    let backend = EncryptedStorage::wrap(backend, &config.encryption)?;
+    Ok(CompressedStorage::wrap(backend, &config.compression))
+}
+
+/// Build a single backend of the given `kind`.
+fn build(kind: StorageKind, config: &Config) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    Ok(match kind {
+        StorageKind::Fs => Arc::new(FileSystemStorage::open(&config.data_dir.join("maps"))?),
+        StorageKind::Memory => Arc::new(MemoryStorage::default()),
+        StorageKind::S3 => Arc::new(s3::build(&config.s3)?),
+        StorageKind::Azure => Arc::new(azure::build(&config.azure)?),
+        StorageKind::Gcs => Arc::new(gcs::build(&config.gcs)?),
+        StorageKind::WebDav => Arc::new(WebDavStorage::new(&config.webdav)?),
+        StorageKind::Sftp => Arc::new(SftpStorage::new(&config.sftp)?),
+    })
+}
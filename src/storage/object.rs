@@ -0,0 +1,141 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::async_trait;
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{
+    path::Path,
+    signer::{Method, Signer},
+    GetOptions, GetRange, ObjectStore, ObjectStoreExt, WriteMultipart,
+};
+
+use super::{ByteStream, StorageBackend, StorageError};
+
+/// Parts of a streamed upload sent concurrently.
+const MAX_PARTS_IN_FLIGHT: usize = 4;
+
+/// Stores objects in a cloud object store below a key prefix.
+pub(crate) struct ObjectStorage {
+    client: Arc<dyn ObjectStore>,
+    prefix: Path,
+    /// Signer of direct upload URLs, for stores supporting them.
+    signer: Option<Arc<dyn Signer>>,
+}
+
+impl ObjectStorage {
+    pub(crate) fn new(client: Arc<dyn ObjectStore>, prefix: &str) -> Self {
+        Self {
+            client,
+            prefix: Path::from(prefix),
+            signer: None,
+        }
+    }
+
+    /// Sign direct upload URLs with `signer`.
+    pub(crate) fn with_signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    fn path(&self, key: &str) -> Path {
+        self.prefix.parts().chain(Path::from(key).parts()).collect()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ObjectStorage {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError> {
+        let size = bytes.len() as u64;
+        self.client.put(&self.path(key), bytes.into()).await?;
+        Ok(size)
+    }
+
+    async fn put_stream(&self, key: &str, mut stream: ByteStream) -> Result<u64, StorageError> {
+        // Uploaded in parts, a few of them in flight at once.
+        let upload = self.client.put_multipart(&self.path(key)).await?;
+        let mut writer = WriteMultipart::new(upload);
+        let mut size = 0;
+        let result = async {
+            while let Some(chunk) = stream.try_next().await? {
+                writer.wait_for_capacity(MAX_PARTS_IN_FLIGHT).await?;
+                size += chunk.len() as u64;
+                writer.put(chunk);
+            }
+            Ok::<_, StorageError>(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                writer.finish().await?;
+                Ok(size)
+            }
+            Err(err) => {
+                let _ = writer.abort().await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn presigned_put(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<Option<String>, StorageError> {
+        let Some(signer) = &self.signer else {
+            return Ok(None);
+        };
+        let url = signer
+            .signed_url(Method::PUT, &self.path(key), expires_in)
+            .await?;
+        Ok(Some(url.to_string()))
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let result = self.client.get(&self.path(key)).await?;
+        Ok(result.into_stream().map_err(StorageError::from).boxed())
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<ByteStream, StorageError> {
+        let options = GetOptions {
+            range: Some(GetRange::Bounded(offset..offset.saturating_add(length))),
+            ..GetOptions::default()
+        };
+        let result = self.client.get_opts(&self.path(key), options).await?;
+        Ok(result.into_stream().map_err(StorageError::from).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.client.delete(&self.path(key)).await?;
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        match self.client.head(&self.path(key)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        let objects: Vec<_> = self.client.list(Some(&self.prefix)).try_collect().await?;
+        Ok(objects
+            .iter()
+            .filter_map(|object| {
+                let parts = object.location.prefix_match(&self.prefix)?;
+                Some(
+                    parts
+                        .map(|part| part.as_ref().to_string())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                )
+            })
+            .collect())
+    }
+}
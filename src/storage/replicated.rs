@@ -0,0 +1,165 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use axum::async_trait;
+use bytes::Bytes;
+use clap::Args;
+use futures::TryStreamExt;
+use tokio::{
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+use super::{ByteStream, StorageBackend, StorageError};
+use crate::config::StorageKind;
+
+/// Replication settings.
+#[derive(Args, Debug)]
+pub(crate) struct ReplicationConfig {
+    /// Secondary backend every stored object is asynchronously copied to.
+    #[arg(long, env = "SMU_REPLICA_STORAGE", value_enum)]
+    pub(crate) replica_storage: Option<StorageKind>,
+
+    /// Seconds between reconciliation runs repairing missing replicas, 0 disables them.
+    #[arg(
+        long = "replica-reconcile-secs",
+        env = "SMU_REPLICA_RECONCILE_SECS",
+        default_value_t = 3600
+    )]
+    pub(crate) reconcile_interval: u64,
+}
+
+/// Change to apply on the replica, in the order it happened on the primary.
+enum ReplicaOp {
+    Put(String, Bytes),
+    Delete(String),
+}
+
+/// Serves from a primary backend and mirrors writes to a replica in the background.
+pub(crate) struct ReplicatedStorage {
+    primary: Arc<dyn StorageBackend>,
+    replica: Arc<dyn StorageBackend>,
+    ops: mpsc::UnboundedSender<ReplicaOp>,
+}
+
+impl ReplicatedStorage {
+    /// Pair `primary` with `replica`, starting the replication worker and the
+    /// reconciliation task.
+    pub(crate) fn start(
+        primary: Arc<dyn StorageBackend>,
+        replica: Arc<dyn StorageBackend>,
+        config: &ReplicationConfig,
+    ) -> Arc<Self> {
+        let (ops, mut pending) = mpsc::unbounded_channel();
+        let storage = Arc::new(Self {
+            primary,
+            replica: replica.clone(),
+            ops,
+        });
+
+        tokio::spawn(async move {
+            while let Some(op) = pending.recv().await {
+                let result = match &op {
+                    ReplicaOp::Put(key, bytes) => replica.put(key, bytes.clone()).await.map(|_| ()),
+                    ReplicaOp::Delete(key) => match replica.delete(key).await {
+                        Err(StorageError::NotFound(_)) => Ok(()),
+                        result => result,
+                    },
+                };
+                if let Err(err) = result {
+                    eprintln!("replication failed: {err}");
+                }
+            }
+        });
+
+        if config.reconcile_interval == 0 {
+            return storage;
+        }
+        let period = Duration::from_secs(config.reconcile_interval);
+        let reconciler = storage.clone();
+        tokio::spawn(async move {
+            let mut ticker = time::interval_at(Instant::now() + period, period);
+            loop {
+                ticker.tick().await;
+                match reconciler.reconcile().await {
+                    Ok(0) => {}
+                    Ok(repaired) => println!("reconciliation repaired {repaired} replicas"),
+                    Err(err) => eprintln!("reconciliation failed: {err}"),
+                }
+            }
+        });
+
+        storage
+    }
+
+    /// Copy objects missing from the replica, returning how many were repaired.
+    pub(crate) async fn reconcile(&self) -> Result<usize, StorageError> {
+        let replicated: HashSet<String> = self.replica.list().await?.into_iter().collect();
+
+        let mut repaired = 0;
+        for key in self.primary.list().await? {
+            if replicated.contains(&key) {
+                continue;
+            }
+            let chunks: Vec<Bytes> = match self.primary.get(&key).await {
+                Ok(stream) => stream.try_collect().await?,
+                // Deleted since listing.
+                Err(StorageError::NotFound(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            self.replica.put(&key, chunks.concat().into()).await?;
+            repaired += 1;
+        }
+        Ok(repaired)
+    }
+
+    fn replicate(&self, op: ReplicaOp) {
+        // The worker only stops with the runtime, so sending cannot fail in practice.
+        let _ = self.ops.send(op);
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ReplicatedStorage {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError> {
+        let stored = self.primary.put(key, bytes.clone()).await?;
+        self.replicate(ReplicaOp::Put(key.to_string(), bytes));
+        Ok(stored)
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        match self.primary.get(key).await {
+            Err(StorageError::NotFound(_)) => self.replica.get(key).await,
+            result => result,
+        }
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<ByteStream, StorageError> {
+        match self.primary.get_range(key, offset, length).await {
+            Err(StorageError::NotFound(_)) => self.replica.get_range(key, offset, length).await,
+            result => result,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.primary.delete(key).await?;
+        self.replicate(ReplicaOp::Delete(key.to_string()));
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        self.primary.exists(key).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        self.primary.list().await
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>, StorageError> {
+        self.primary.free_space().await
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use clap::Args;
+use object_store::{aws::AmazonS3Builder, ClientOptions};
+
+use super::{ObjectStorage, StorageError};
+
+/// S3 storage settings. Credentials are taken from the standard `AWS_*` variables.
+#[derive(Args, Debug)]
+pub(crate) struct S3Config {
+    /// Bucket holding uploaded maps.
+    #[arg(id = "s3_bucket", long = "s3-bucket", env = "SMU_S3_BUCKET")]
+    pub(crate) bucket: Option<String>,
+
+    /// Key prefix applied to every stored object.
+    #[arg(
+        id = "s3_prefix",
+        long = "s3-prefix",
+        env = "SMU_S3_PREFIX",
+        default_value = ""
+    )]
+    pub(crate) prefix: String,
+
+    /// Bucket region, defaults to `AWS_REGION` or `us-east-1`.
+    #[arg(id = "s3_region", long = "s3-region", env = "SMU_S3_REGION")]
+    pub(crate) region: Option<String>,
+
+    /// Custom endpoint URL for S3-compatible stores such as MinIO.
+    #[arg(id = "s3_endpoint", long = "s3-endpoint", env = "SMU_S3_ENDPOINT")]
+    pub(crate) endpoint: Option<String>,
+
+    /// Address buckets as `endpoint/bucket` instead of `bucket.endpoint`.
+    #[arg(
+        id = "s3_path_style",
+        long = "s3-path-style",
+        env = "SMU_S3_PATH_STYLE"
+    )]
+    pub(crate) path_style: bool,
+
+    /// Accept invalid TLS certificates from the endpoint.
+    #[arg(
+        id = "s3_skip_tls_verify",
+        long = "s3-skip-tls-verify",
+        env = "SMU_S3_SKIP_TLS_VERIFY"
+    )]
+    pub(crate) skip_tls_verify: bool,
+}
+
+/// Build an S3 bucket backend.
+pub(crate) fn build(config: &S3Config) -> Result<ObjectStorage, StorageError> {
+    let bucket = config
+        .bucket
+        .as_deref()
+        .ok_or_else(|| StorageError::Config("missing S3 bucket".to_string()))?;
+
+    let mut client_options =
+        ClientOptions::new().with_allow_invalid_certificates(config.skip_tls_verify);
+    let mut builder = AmazonS3Builder::from_env()
+        .with_bucket_name(bucket)
+        .with_virtual_hosted_style_request(!config.path_style);
+    if let Some(region) = &config.region {
+        builder = builder.with_region(region);
+    }
+    if let Some(endpoint) = &config.endpoint {
+        client_options = client_options.with_allow_http(endpoint.starts_with("http://"));
+        builder = builder.with_endpoint(endpoint);
+    }
+    builder = builder.with_client_options(client_options);
+
+    // Buckets sign the URLs of direct uploads.
+    let client = Arc::new(builder.build()?);
+    Ok(ObjectStorage::new(client.clone(), &config.prefix).with_signer(client))
+}
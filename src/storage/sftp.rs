@@ -0,0 +1,304 @@
+use std::{path::PathBuf, sync::Arc};
+
+use axum::async_trait;
+use bytes::Bytes;
+use clap::Args;
+use futures::{stream, StreamExt};
+use russh::{
+    client::{self, Handle},
+    keys::{self, PrivateKey, PrivateKeyWithHashAlg, PublicKey, PublicKeyOrCertificate},
+};
+use russh_sftp::{client::SftpSession, protocol::StatusCode};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use uuid::Uuid;
+
+use super::{ByteStream, StorageBackend, StorageError};
+
+/// SFTP storage settings.
+#[derive(Args, Debug)]
+pub(crate) struct SftpConfig {
+    /// Host name of the SFTP server.
+    #[arg(id = "sftp_host", long = "sftp-host", env = "SMU_SFTP_HOST")]
+    pub(crate) host: Option<String>,
+
+    /// Port of the SFTP server.
+    #[arg(
+        id = "sftp_port",
+        long = "sftp-port",
+        env = "SMU_SFTP_PORT",
+        default_value_t = 22
+    )]
+    pub(crate) port: u16,
+
+    /// User to log in as.
+    #[arg(
+        id = "sftp_username",
+        long = "sftp-username",
+        env = "SMU_SFTP_USERNAME"
+    )]
+    pub(crate) username: Option<String>,
+
+    /// Path of the private key used to authenticate.
+    #[arg(id = "sftp_key", long = "sftp-key", env = "SMU_SFTP_KEY")]
+    pub(crate) key: Option<PathBuf>,
+
+    /// Passphrase of the private key, if encrypted.
+    #[arg(
+        id = "sftp_key_passphrase",
+        long = "sftp-key-passphrase",
+        env = "SMU_SFTP_KEY_PASSPHRASE",
+        hide_env_values = true
+    )]
+    pub(crate) key_passphrase: Option<String>,
+
+    /// Remote directory holding uploaded maps.
+    #[arg(
+        id = "sftp_path",
+        long = "sftp-path",
+        env = "SMU_SFTP_PATH",
+        default_value = "."
+    )]
+    pub(crate) path: String,
+
+    /// Expected server public key in OpenSSH format, any key is accepted if unset.
+    #[arg(
+        id = "sftp_host_key",
+        long = "sftp-host-key",
+        env = "SMU_SFTP_HOST_KEY"
+    )]
+    pub(crate) host_key: Option<String>,
+}
+
+/// Checks the server key against the configured one.
+struct HostKeyCheck(Option<PublicKey>);
+
+impl client::Handler for HostKeyCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        Ok(match &self.0 {
+            Some(expected) => server_public_key.public_key().key_data() == expected.key_data(),
+            None => true,
+        })
+    }
+}
+
+/// Open connection, the handle must outlive the SFTP session running over it.
+struct Connection {
+    _handle: Handle<HostKeyCheck>,
+    sftp: SftpSession,
+}
+
+/// Stores objects as files in a directory of an SFTP server.
+///
+/// The connection is opened on first use and reopened after a failure.
+pub(crate) struct SftpStorage {
+    host: String,
+    port: u16,
+    username: String,
+    key: Arc<PrivateKey>,
+    host_key: Option<PublicKey>,
+    path: String,
+    connection: Mutex<Option<Arc<Connection>>>,
+}
+
+impl SftpStorage {
+    pub(crate) fn new(config: &SftpConfig) -> Result<Self, StorageError> {
+        let missing = |what: &str| StorageError::Config(format!("missing SFTP {what}"));
+        let host = config.host.clone().ok_or_else(|| missing("host"))?;
+        let username = config.username.clone().ok_or_else(|| missing("username"))?;
+        let key_path = config.key.as_ref().ok_or_else(|| missing("key"))?;
+
+        let key = keys::load_secret_key(key_path, config.key_passphrase.as_deref())
+            .map_err(|err| StorageError::Config(format!("invalid SFTP key: {err}")))?;
+        let host_key = config
+            .host_key
+            .as_deref()
+            .map(PublicKey::from_openssh)
+            .transpose()
+            .map_err(|err| StorageError::Config(format!("invalid SFTP host key: {err}")))?;
+
+        Ok(Self {
+            host,
+            port: config.port,
+            username,
+            key: Arc::new(key),
+            host_key,
+            path: config.path.trim_end_matches('/').to_string(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}/{key}", self.path)
+    }
+
+    async fn connect(&self) -> Result<Connection, StorageError> {
+        let config = Arc::new(client::Config::default());
+        let check = HostKeyCheck(self.host_key.clone());
+        let mut handle = client::connect(config, (self.host.as_str(), self.port), check)
+            .await
+            .map_err(ssh_error)?;
+
+        let hash_alg = handle
+            .best_supported_rsa_hash()
+            .await
+            .map_err(ssh_error)?
+            .flatten();
+        let auth = handle
+            .authenticate_publickey(
+                &self.username,
+                PrivateKeyWithHashAlg::new(self.key.clone(), hash_alg),
+            )
+            .await
+            .map_err(ssh_error)?;
+        if !auth.success() {
+            return Err(StorageError::Remote(format!(
+                "SFTP authentication failed for {}",
+                self.username
+            )));
+        }
+
+        let channel = handle.channel_open_session().await.map_err(ssh_error)?;
+        channel
+            .request_subsystem(true, "sftp")
+            .await
+            .map_err(ssh_error)?;
+        let sftp = SftpSession::new(channel.into_stream())
+            .await
+            .map_err(sftp_error)?;
+
+        Ok(Connection {
+            _handle: handle,
+            sftp,
+        })
+    }
+
+    /// Shared connection, opened if there is none yet.
+    async fn connection(&self) -> Result<Arc<Connection>, StorageError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+        let opened = Arc::new(self.connect().await?);
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+
+    /// Drop the shared connection after a transport failure, so the next call reconnects.
+    async fn reset(&self, err: &StorageError) {
+        if !matches!(err, StorageError::NotFound(_)) {
+            self.connection.lock().await.take();
+        }
+    }
+}
+
+fn ssh_error(err: russh::Error) -> StorageError {
+    StorageError::Remote(format!("SSH error: {err}"))
+}
+
+fn sftp_error(err: russh_sftp::client::error::Error) -> StorageError {
+    StorageError::Remote(format!("SFTP error: {err}"))
+}
+
+/// Map a missing file to [`StorageError::NotFound`].
+fn file_error(key: &str, err: russh_sftp::client::error::Error) -> StorageError {
+    match err {
+        russh_sftp::client::error::Error::Status(status)
+            if status.status_code == StatusCode::NoSuchFile =>
+        {
+            StorageError::NotFound(key.to_string())
+        }
+        err => sftp_error(err),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SftpStorage {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError> {
+        // Upload to a hidden file and rename it, so readers never see a partial object.
+        let temp_path = self.path(&format!(".{key}.{}.tmp", Uuid::new_v4()));
+        let connection = self.connection().await?;
+        let result = async {
+            let mut file = connection
+                .sftp
+                .create(&temp_path)
+                .await
+                .map_err(sftp_error)?;
+            file.write_all(&bytes).await?;
+            file.shutdown().await?;
+            // SFTP rename fails if the target exists.
+            if let Err(err) = connection.sftp.remove_file(self.path(key)).await {
+                match file_error(key, err) {
+                    StorageError::NotFound(_) => {}
+                    err => return Err(err),
+                }
+            }
+            connection
+                .sftp
+                .rename(&temp_path, self.path(key))
+                .await
+                .map_err(sftp_error)
+        }
+        .await;
+
+        if let Err(err) = result {
+            let _ = connection.sftp.remove_file(&temp_path).await;
+            self.reset(&err).await;
+            return Err(err);
+        }
+        Ok(bytes.len() as u64)
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let connection = self.connection().await?;
+        match connection.sftp.read(self.path(key)).await {
+            Ok(bytes) => Ok(stream::once(async { Ok(Bytes::from(bytes)) }).boxed()),
+            Err(err) => {
+                let err = file_error(key, err);
+                self.reset(&err).await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let connection = self.connection().await?;
+        if let Err(err) = connection.sftp.remove_file(self.path(key)).await {
+            let err = file_error(key, err);
+            self.reset(&err).await;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        let connection = self.connection().await?;
+        match connection.sftp.try_exists(self.path(key)).await {
+            Ok(exists) => Ok(exists),
+            Err(err) => {
+                let err = sftp_error(err);
+                self.reset(&err).await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        let connection = self.connection().await?;
+        match connection.sftp.read_dir(&self.path).await {
+            Ok(entries) => Ok(entries
+                .filter(|entry| entry.file_type().is_file() && !entry.file_name().starts_with('.'))
+                .map(|entry| entry.file_name())
+                .collect()),
+            Err(err) => {
+                let err = sftp_error(err);
+                self.reset(&err).await;
+                Err(err)
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use axum::async_trait;
+use bytes::Bytes;
+use clap::Args;
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+
+use super::{ByteStream, StorageBackend, StorageError};
+
+/// WebDAV storage settings.
+#[derive(Args, Debug)]
+pub(crate) struct WebDavConfig {
+    /// URL of the WebDAV collection holding uploaded maps.
+    #[arg(id = "webdav_url", long = "webdav-url", env = "SMU_WEBDAV_URL")]
+    pub(crate) url: Option<String>,
+
+    /// Basic auth user name.
+    #[arg(
+        id = "webdav_username",
+        long = "webdav-username",
+        env = "SMU_WEBDAV_USERNAME"
+    )]
+    pub(crate) username: Option<String>,
+
+    /// Basic auth password.
+    #[arg(
+        id = "webdav_password",
+        long = "webdav-password",
+        env = "SMU_WEBDAV_PASSWORD",
+        hide_env_values = true
+    )]
+    pub(crate) password: Option<String>,
+}
+
+/// Stores objects as resources of a WebDAV collection.
+pub(crate) struct WebDavStorage {
+    client: Client,
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl WebDavStorage {
+    pub(crate) fn new(config: &WebDavConfig) -> Result<Self, StorageError> {
+        let url = config
+            .url
+            .as_deref()
+            .ok_or_else(|| StorageError::Config("missing WebDAV url".to_string()))?;
+
+        Ok(Self {
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+        })
+    }
+
+    fn request(&self, method: Method, key: &str) -> RequestBuilder {
+        let request = self.client.request(method, format!("{}/{key}", self.url));
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_deref()),
+            None => request,
+        }
+    }
+
+    /// Send `request`, mapping 404 to [`StorageError::NotFound`] and other
+    /// failures to [`StorageError::Remote`].
+    async fn send(&self, request: RequestBuilder, key: &str) -> Result<Response, StorageError> {
+        let response = request.send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::NOT_FOUND => Err(StorageError::NotFound(key.to_string())),
+            status => Err(StorageError::Remote(format!(
+                "WebDAV server returned {status}"
+            ))),
+        }
+    }
+}
+
+/// Resource names listed in a PROPFIND multistatus body.
+fn hrefs(body: &str) -> impl Iterator<Item = String> + '_ {
+    body.split('<').filter_map(|segment| {
+        let (tag, text) = segment.split_once('>')?;
+        let tag = tag.trim_end();
+        let is_href = tag == "href" || tag.ends_with(":href");
+        let name = text.trim().trim_end_matches('/').rsplit('/').next()?;
+        is_href.then(|| percent_decode_str(name).decode_utf8_lossy().into_owned())
+    })
+}
+
+#[async_trait]
+impl StorageBackend for WebDavStorage {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<u64, StorageError> {
+        let size = bytes.len() as u64;
+        self.send(self.request(Method::PUT, key).body(bytes), key)
+            .await?;
+        Ok(size)
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let response = self.send(self.request(Method::GET, key), key).await?;
+        Ok(response.bytes_stream().map_err(StorageError::from).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.send(self.request(Method::DELETE, key), key).await?;
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        match self.send(self.request(Method::HEAD, key), key).await {
+            Ok(_) => Ok(true),
+            Err(StorageError::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        let propfind = Method::from_bytes(b"PROPFIND").expect("valid method");
+        let request = self
+            .request(propfind, "")
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(r#"<?xml version="1.0"?><propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#);
+        let body = self.send(request, "").await?.text().await?;
+
+        // The first entry is the collection itself.
+        Ok(hrefs(&body)
+            .skip(1)
+            .filter(|name| !name.is_empty())
+            .collect())
+    }
+}
@@ -0,0 +1,451 @@
+//! Stripping of embedded metadata from uploaded map images, such as the GPS
+//! position recorded in EXIF by the laptop a map was made on, or the names of
+//! its authors.
+//!
+//! Image data is left as is: JPEG and PNG files lose their metadata segments
+//! and chunks, TIFF files the metadata tags of their IFDs, whose values are
+//! zeroed in place so that georeferencing tags and offsets stay valid.
+
+use std::{collections::HashSet, fmt, io, path::Path};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use serde::Serialize;
+use tokio::fs;
+
+/// Media types metadata is stripped from.
+const STRIPPED_TYPES: &[&str] = &["image/jpeg", "image/png", "image/tiff"];
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// TIFF tags holding metadata, with the name their values are kept under.
+const TIFF_METADATA_TAGS: &[(u16, &str)] = &[
+    (315, "artist"),
+    (316, "host_computer"),
+    (700, "xmp"),
+    (33723, "iptc"),
+    (34377, "photoshop"),
+    (34665, "exif"),
+    (34853, "gps"),
+];
+
+/// TIFF metadata tags pointing to an IFD of their own.
+const TIFF_IFD_TAGS: &[u16] = &[34665, 34853];
+
+/// Metadata stripping settings.
+#[derive(Args, Debug)]
+pub(crate) struct StripConfig {
+    /// Strip EXIF, XMP, IPTC and text metadata from uploaded JPEG, PNG and TIFF
+    /// files before storing them. The EXIF orientation is stripped too.
+    #[arg(long, env = "SMU_STRIP_METADATA")]
+    pub(crate) strip_metadata: bool,
+
+    /// Keep the stripped metadata in `<data dir>/metadata`, as JSON named after
+    /// the digest of the stored file.
+    #[arg(long, env = "SMU_KEEP_STRIPPED_METADATA", requires = "strip_metadata")]
+    pub(crate) keep_stripped_metadata: bool,
+}
+
+/// Metadata stripped from a file.
+#[derive(Serialize)]
+pub(crate) struct Entry {
+    /// Kind of metadata, e.g. `exif`, `xmp` or `text:Author`.
+    name: String,
+    /// Raw bytes of the metadata, base64-encoded.
+    data: String,
+}
+
+impl Entry {
+    fn new(name: impl Into<String>, data: &[u8]) -> Self {
+        Self {
+            name: name.into(),
+            data: BASE64.encode(data),
+        }
+    }
+}
+
+/// File without its metadata.
+pub(crate) struct Stripped {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) entries: Vec<Entry>,
+}
+
+/// File that could not be parsed to strip its metadata.
+#[derive(Debug)]
+pub(crate) struct StripError(String);
+
+impl fmt::Display for StripError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "metadata could not be stripped: {}", self.0)
+    }
+}
+
+/// Record of the metadata stripped from a file.
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Hex-encoded SHA-256 digest of the file as uploaded.
+    uploaded_sha256: &'a str,
+    stripped_at: DateTime<Utc>,
+    entries: &'a [Entry],
+}
+
+/// Whether metadata is stripped from files of `content_type`.
+pub(crate) fn applies(content_type: &str) -> bool {
+    STRIPPED_TYPES.contains(&content_type)
+}
+
+/// `bytes` of `content_type` without their metadata, `None` if they hold none.
+pub(crate) fn strip(content_type: &str, bytes: &[u8]) -> Result<Option<Stripped>, StripError> {
+    match content_type {
+        "image/jpeg" => jpeg(bytes),
+        "image/png" => png(bytes),
+        "image/tiff" => tiff(bytes),
+        _ => Ok(None),
+    }
+}
+
+/// Keep the `entries` stripped from the file hashing to `uploaded`, stored as
+/// the file hashing to `hash`, in `dir`.
+pub(crate) async fn keep(
+    dir: &Path,
+    hash: &str,
+    uploaded: &str,
+    entries: &[Entry],
+) -> io::Result<()> {
+    fs::create_dir_all(dir).await?;
+    let record = Record {
+        uploaded_sha256: uploaded,
+        stripped_at: Utc::now(),
+        entries,
+    };
+    fs::write(
+        dir.join(format!("{hash}.json")),
+        serde_json::to_vec(&record)?,
+    )
+    .await
+}
+
+fn stripped(bytes: Vec<u8>, entries: Vec<Entry>) -> Option<Stripped> {
+    (!entries.is_empty()).then_some(Stripped { bytes, entries })
+}
+
+/// Strip APP1 (EXIF, XMP), APP13 (IPTC) and comment segments.
+fn jpeg(bytes: &[u8]) -> Result<Option<Stripped>, StripError> {
+    let malformed = |reason: &str| StripError(format!("malformed JPEG: {reason}"));
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return Err(malformed("missing start of image"));
+    }
+
+    let mut kept = bytes[..2].to_vec();
+    let mut entries = Vec::new();
+    let mut at = 2;
+    loop {
+        if bytes.get(at) != Some(&0xFF) {
+            return Err(malformed("expected a marker"));
+        }
+        // Markers may be padded with fill bytes.
+        let mut next = at + 1;
+        while bytes.get(next) == Some(&0xFF) {
+            next += 1;
+        }
+        let marker = *bytes.get(next).ok_or_else(|| malformed("truncated"))?;
+        let start = next + 1;
+        match marker {
+            // Scans and what follows them hold no metadata segments.
+            0xDA | 0xD9 => {
+                kept.extend_from_slice(&bytes[at..]);
+                break;
+            }
+            0x01 | 0xD0..=0xD7 => {
+                kept.extend_from_slice(&bytes[at..start]);
+                at = start;
+                continue;
+            }
+            _ => {}
+        }
+
+        let len = bytes
+            .get(start..start + 2)
+            .map(|len| usize::from(u16::from_be_bytes([len[0], len[1]])))
+            .filter(|len| *len >= 2)
+            .ok_or_else(|| malformed("truncated segment"))?;
+        let end = start + len;
+        let payload = bytes
+            .get(start + 2..end)
+            .ok_or_else(|| malformed("truncated segment"))?;
+        let name = match marker {
+            0xE1 if payload.starts_with(b"Exif\0") => Some("exif"),
+            0xE1 if payload.starts_with(b"http://ns.adobe.com/") => Some("xmp"),
+            0xE1 => Some("app1"),
+            0xED => Some("iptc"),
+            0xFE => Some("comment"),
+            _ => None,
+        };
+        match name {
+            Some(name) => entries.push(Entry::new(name, payload)),
+            None => kept.extend_from_slice(&bytes[at..end]),
+        }
+        at = end;
+    }
+    Ok(stripped(kept, entries))
+}
+
+/// Strip `eXIf` and text chunks, XMP included.
+fn png(bytes: &[u8]) -> Result<Option<Stripped>, StripError> {
+    let malformed = |reason: &str| StripError(format!("malformed PNG: {reason}"));
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return Err(malformed("missing signature"));
+    }
+
+    let mut kept = PNG_SIGNATURE.to_vec();
+    let mut entries = Vec::new();
+    let mut at = PNG_SIGNATURE.len();
+    while at < bytes.len() {
+        let header = bytes
+            .get(at..at + 8)
+            .ok_or_else(|| malformed("truncated chunk"))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = &header[4..8];
+        // Length and type, data, then CRC.
+        let end = at
+            .checked_add(len + 12)
+            .filter(|end| *end <= bytes.len())
+            .ok_or_else(|| malformed("truncated chunk"))?;
+        let data = &bytes[at + 8..end - 4];
+        let name = match kind {
+            b"eXIf" => Some("exif".to_string()),
+            b"iTXt" if data.starts_with(b"XML:com.adobe.xmp\0") => Some("xmp".to_string()),
+            b"tEXt" | b"zTXt" | b"iTXt" => {
+                let keyword = data.split(|byte| *byte == 0).next().unwrap_or_default();
+                Some(format!("text:{}", String::from_utf8_lossy(keyword)))
+            }
+            _ => None,
+        };
+        match name {
+            Some(name) => entries.push(Entry::new(name, data)),
+            None => kept.extend_from_slice(&bytes[at..end]),
+        }
+        at = end;
+        if kind == b"IEND" {
+            kept.extend_from_slice(&bytes[at..]);
+            break;
+        }
+    }
+    Ok(stripped(kept, entries))
+}
+
+/// Strip the metadata tags of every IFD, classic TIFF and BigTIFF alike.
+fn tiff(bytes: &[u8]) -> Result<Option<Stripped>, StripError> {
+    let little = match bytes.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return Err(StripError("malformed TIFF: unknown byte order".to_string())),
+    };
+    let mut tiff = Tiff {
+        bytes: bytes.to_vec(),
+        little,
+        big: false,
+    };
+    tiff.big = match tiff.uint(2, 2)? {
+        42 => false,
+        43 => true,
+        version => return Err(tiff.malformed(&format!("unknown version {version}"))),
+    };
+
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    let mut next = tiff.offset(if tiff.big { 8 } else { 4 })?;
+    while next != 0 {
+        if !visited.insert(next) {
+            return Err(tiff.malformed("IFDs loop"));
+        }
+        next = tiff.strip_ifd(next, &mut entries)?;
+    }
+    Ok(stripped(tiff.bytes, entries))
+}
+
+/// Bytes of each value of a TIFF field type.
+fn tiff_type_size(kind: u16) -> Option<usize> {
+    match kind {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 | 16 | 17 | 18 => Some(8),
+        _ => None,
+    }
+}
+
+/// TIFF file being stripped in place.
+struct Tiff {
+    bytes: Vec<u8>,
+    little: bool,
+    /// BigTIFF, with 64-bit counts and offsets.
+    big: bool,
+}
+
+impl Tiff {
+    fn malformed(&self, reason: &str) -> StripError {
+        StripError(format!("malformed TIFF: {reason}"))
+    }
+
+    /// Bytes of counts and offsets, also the room for values in IFD entries.
+    fn word(&self) -> usize {
+        if self.big {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn entry_len(&self) -> usize {
+        if self.big {
+            20
+        } else {
+            12
+        }
+    }
+
+    fn slice(&self, at: usize, len: usize) -> Result<&[u8], StripError> {
+        at.checked_add(len)
+            .and_then(|end| self.bytes.get(at..end))
+            .ok_or_else(|| self.malformed("offset out of bounds"))
+    }
+
+    fn zero(&mut self, at: usize, len: usize) -> Result<(), StripError> {
+        self.slice(at, len)?;
+        self.bytes[at..at + len].fill(0);
+        Ok(())
+    }
+
+    /// Unsigned integer of `len` bytes at `at`, in the byte order of the file.
+    fn uint(&self, at: usize, len: usize) -> Result<u64, StripError> {
+        Ok(self.decode(self.slice(at, len)?))
+    }
+
+    /// Unsigned integer of `bytes`, in the byte order of the file.
+    fn decode(&self, bytes: &[u8]) -> u64 {
+        let fold = |value: u64, byte: &u8| value << 8 | u64::from(*byte);
+        if self.little {
+            bytes.iter().rev().fold(0, fold)
+        } else {
+            bytes.iter().fold(0, fold)
+        }
+    }
+
+    fn offset(&self, at: usize) -> Result<usize, StripError> {
+        usize::try_from(self.uint(at, self.word())?)
+            .map_err(|_| self.malformed("offset out of bounds"))
+    }
+
+    /// Entry count of the IFD at `at` and offset of its first entry.
+    fn table(&self, at: usize) -> Result<(usize, usize), StripError> {
+        let count_len = if self.big { 8 } else { 2 };
+        let count = usize::try_from(self.uint(at, count_len)?)
+            .map_err(|_| self.malformed("IFD out of bounds"))?;
+        Ok((count, at + count_len))
+    }
+
+    /// Remove the metadata tags of the IFD at `at`, adding their values to
+    /// `entries`, and return the offset of the next IFD.
+    fn strip_ifd(&mut self, at: usize, entries: &mut Vec<Entry>) -> Result<usize, StripError> {
+        let (count, table) = self.table(at)?;
+        let entry_len = self.entry_len();
+        let table_end = count
+            .checked_mul(entry_len)
+            .and_then(|len| table.checked_add(len))
+            .ok_or_else(|| self.malformed("IFD out of bounds"))?;
+        let next = self.offset(table_end)?;
+
+        let mut kept = Vec::with_capacity(count);
+        for index in 0..count {
+            let entry = table + index * entry_len;
+            let tag = self.uint(entry, 2)? as u16;
+            let Some((_, name)) = TIFF_METADATA_TAGS.iter().find(|(known, _)| *known == tag) else {
+                kept.push(self.slice(entry, entry_len)?.to_vec());
+                continue;
+            };
+            let (kind, value) = self.take_value(entry)?;
+            if TIFF_IFD_TAGS.contains(&tag) {
+                // Pointers are the first value, of a LONG, IFD or 64-bit type.
+                let pointer = tiff_type_size(kind)
+                    .and_then(|size| value.get(..size))
+                    .filter(|pointer| pointer.len() >= 4)
+                    .ok_or_else(|| self.malformed("invalid IFD pointer"))?;
+                let ifd = usize::try_from(self.decode(pointer))
+                    .map_err(|_| self.malformed("IFD out of bounds"))?;
+                self.take_ifd(ifd, name, entries)?;
+            } else {
+                entries.push(Entry::new(*name, &value));
+            }
+        }
+        if kept.len() == count {
+            return Ok(next);
+        }
+
+        // Entries stay sorted by tag; the freed tail of the table is zeroed.
+        let count_len = table - at;
+        let count_bytes = self.encode(kept.len() as u64, count_len);
+        self.bytes[at..table].copy_from_slice(&count_bytes);
+        let mut end = table;
+        for entry in kept {
+            self.bytes[end..end + entry_len].copy_from_slice(&entry);
+            end += entry_len;
+        }
+        let word = self.word();
+        let next_bytes = self.encode(next as u64, word);
+        self.bytes[end..end + word].copy_from_slice(&next_bytes);
+        self.zero(end + word, table_end - end)?;
+        Ok(next)
+    }
+
+    /// Remove the IFD at `at` a `name` tag points to, adding the value of each
+    /// of its tags to `entries` as `name:tag`.
+    fn take_ifd(
+        &mut self,
+        at: usize,
+        name: &str,
+        entries: &mut Vec<Entry>,
+    ) -> Result<(), StripError> {
+        let (count, table) = self.table(at)?;
+        let entry_len = self.entry_len();
+        for index in 0..count {
+            let entry = table + index * entry_len;
+            let tag = self.uint(entry, 2)?;
+            let (_, value) = self.take_value(entry)?;
+            entries.push(Entry::new(format!("{name}:{tag}"), &value));
+        }
+        let len = table - at + count * entry_len + self.word();
+        self.zero(at, len)
+    }
+
+    /// Type and bytes of the value of the IFD entry at `entry`, zeroed in the
+    /// file when stored out of the entry.
+    fn take_value(&mut self, entry: usize) -> Result<(u16, Vec<u8>), StripError> {
+        let word = self.word();
+        let kind = self.uint(entry + 2, 2)? as u16;
+        let size = tiff_type_size(kind)
+            .ok_or_else(|| self.malformed(&format!("unknown field type {kind}")))?;
+        let len = usize::try_from(self.uint(entry + 4, word)?)
+            .ok()
+            .and_then(|count| count.checked_mul(size))
+            .ok_or_else(|| self.malformed("value out of bounds"))?;
+        let field = entry + 4 + word;
+        if len <= word {
+            return Ok((kind, self.slice(field, len)?.to_vec()));
+        }
+        let offset = self.offset(field)?;
+        let value = self.slice(offset, len)?.to_vec();
+        self.zero(offset, len)?;
+        Ok((kind, value))
+    }
+
+    /// `value` as `len` bytes in the byte order of the file.
+    fn encode(&self, value: u64, len: usize) -> Vec<u8> {
+        if self.little {
+            value.to_le_bytes()[..len].to_vec()
+        } else {
+            value.to_be_bytes()[8 - len..].to_vec()
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Mirroring of the maps of a remote smu instance, e.g. to keep a DR instance warm.
+
+use std::{collections::HashSet, fmt, time::Duration};
+
+use clap::Args;
+use hyper::StatusCode;
+use sha2::{Digest, Sha256};
+use tokio::time::{self, Instant};
+
+use crate::{
+    db::MetadataError,
+    rescan,
+    smap::{self, SMap, SMapError},
+    state::AppState,
+};
+
+/// Sync settings.
+#[derive(Args, Debug)]
+pub(crate) struct SyncConfig {
+    /// Base URL of a remote smu instance whose maps are mirrored locally.
+    #[arg(long, env = "SMU_SYNC_FROM")]
+    pub(crate) sync_from: Option<String>,
+
+    /// Seconds between sync runs.
+    #[arg(
+        long = "sync-interval-secs",
+        env = "SMU_SYNC_INTERVAL_SECS",
+        default_value_t = 300,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub(crate) interval: u64,
+}
+
+/// Sync run errors.
+#[derive(Debug)]
+pub(crate) enum SyncError {
+    /// Remote request failed.
+    Http(reqwest::Error),
+    /// Local metadata store failed.
+    Database(MetadataError),
+    /// Downloaded file does not match the remote hash.
+    HashMismatch(String),
+    /// Downloaded file could not be stored locally.
+    Store(StatusCode, SMapError),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "remote request failed: {err}"),
+            Self::Database(err) => write!(f, "metadata store failed: {err}"),
+            Self::HashMismatch(uuid) => write!(f, "hash mismatch for map {uuid}"),
+            Self::Store(status, err) => write!(f, "storing map failed with {status}: {err:?}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SyncError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+impl From<MetadataError> for SyncError {
+    fn from(err: MetadataError) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Download every map of `remote` missing locally, returning how many were copied.
+pub(crate) async fn pull(
+    client: &reqwest::Client,
+    remote: &str,
+    state: &AppState,
+) -> Result<usize, SyncError> {
+    let remote = remote.trim_end_matches('/');
+    let mut smaps: Vec<SMap> = Vec::new();
+    let mut page = format!("sort=created_at&limit={}", smap::MAX_LIMIT);
+    loop {
+        let response = client
+            .get(format!("{remote}/api/v1/smap?{page}"))
+            .send()
+            .await?
+            .error_for_status()?;
+        let next = response
+            .headers()
+            .get(smap::NEXT_CURSOR.as_str())
+            .and_then(|cursor| cursor.to_str().ok())
+            .map(|cursor| format!("cursor={cursor}&limit={}", smap::MAX_LIMIT));
+        smaps.extend(response.json::<Vec<SMap>>().await?);
+        match next {
+            Some(next) => page = next,
+            None => break,
+        }
+    }
+
+    // Trashed maps count as present, so they are not mirrored again.
+    let local: HashSet<String> = state
+        .store
+        .list_all()
+        .await?
+        .into_iter()
+        .map(|smap| smap.uuid)
+        .collect();
+
+    let mut copied = 0;
+    for remote_smap in smaps {
+        if local.contains(&remote_smap.uuid) {
+            continue;
+        }
+
+        let bytes = client
+            .get(format!("{remote}/api/v1/smap/{}/file", remote_smap.uuid))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        if format!("{:x}", Sha256::digest(&bytes)) != remote_smap.hash {
+            return Err(SyncError::HashMismatch(remote_smap.uuid));
+        }
+
+        let content_type = Some(remote_smap.content_type.as_str());
+        let file = smap::store_file(
+            &state.config,
+            &state.store,
+            state.storage.as_ref(),
+            bytes,
+            content_type,
+        )
+        .await
+        .map_err(|(status, err)| SyncError::Store(status, err.0))?;
+        let mut smap = SMap::new(remote_smap.uuid, remote_smap.title, file);
+        smap.created_at = remote_smap.created_at;
+        smap.owner = remote_smap.owner;
+        smap.expires_at = remote_smap.expires_at;
+        state.store.register(smap.clone()).await?;
+        rescan::record(state.storage.as_ref(), &smap).await;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+/// Periodically [`pull`] from the configured remote, if any.
+pub(crate) fn spawn(state: AppState) {
+    let Some(remote) = state.config.sync.sync_from.clone() else {
+        return;
+    };
+    let period = Duration::from_secs(state.config.sync.interval);
+
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut ticker = time::interval_at(Instant::now(), period);
+        loop {
+            ticker.tick().await;
+            match pull(&client, &remote, &state).await {
+                Ok(0) => {}
+                Ok(copied) => println!("sync copied {copied} maps from {remote}"),
+                Err(err) => eprintln!("sync from {remote} failed: {err}"),
+            }
+        }
+    });
+}
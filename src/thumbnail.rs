@@ -0,0 +1,189 @@
+//! Thumbnails of map images, generated by background jobs after upload so that
+//! galleries can show maps without downloading them in full.
+//!
+//! Thumbnails are JPEG files stored next to the map files, under keys derived
+//! from the digest of the file they were made from: maps sharing a file share
+//! its thumbnails, and replaced files get new ones.
+
+use std::{fmt, io};
+
+use bytes::Bytes;
+use clap::Args;
+use futures::TryStreamExt;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageError, Limits};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use utoipa::ToSchema;
+
+use crate::{
+    smap::SMap,
+    sniff,
+    storage::{StorageBackend, StorageError},
+};
+
+/// Media types thumbnails are made of.
+const THUMBNAILED_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/tiff",
+    "image/webp",
+];
+
+/// JPEG quality of thumbnails, out of 100.
+const QUALITY: u8 = 80;
+
+/// Thumbnail settings.
+#[derive(Args, Debug)]
+pub(crate) struct ThumbnailConfig {
+    /// Do not generate thumbnails of uploaded map images.
+    #[arg(long = "no-thumbnails", env = "SMU_NO_THUMBNAILS")]
+    pub(crate) disabled: bool,
+}
+
+/// Thumbnail sizes.
+#[derive(Deserialize, ToSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Size {
+    /// At most 256 pixels on the longest side.
+    Small,
+    /// At most 1024 pixels on the longest side.
+    Medium,
+}
+
+impl Size {
+    /// Pixels on the longest side of thumbnails of this size.
+    fn edge(self) -> u32 {
+        match self {
+            Self::Small => 256,
+            Self::Medium => 1024,
+        }
+    }
+
+    /// Storage key of the thumbnail of this size of the file hashing to `hash`.
+    fn key(self, hash: &str) -> String {
+        match self {
+            Self::Small => format!("{hash}.small.jpg"),
+            Self::Medium => format!("{hash}.medium.jpg"),
+        }
+    }
+}
+
+/// Storage keys of the thumbnails of a map file.
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq, Debug)]
+pub(crate) struct Thumbnails {
+    /// JPEG at most 256 pixels on the longest side.
+    #[schema(
+        example = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08.small.jpg"
+    )]
+    pub(crate) small: String,
+    /// JPEG at most 1024 pixels on the longest side.
+    #[schema(
+        example = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08.medium.jpg"
+    )]
+    pub(crate) medium: String,
+}
+
+impl Thumbnails {
+    /// Thumbnails of the file hashing to `hash`.
+    pub(crate) fn of(hash: &str) -> Self {
+        Self {
+            small: Size::Small.key(hash),
+            medium: Size::Medium.key(hash),
+        }
+    }
+
+    /// Storage key of the thumbnail of `size`.
+    pub(crate) fn key(&self, size: Size) -> &str {
+        match size {
+            Size::Small => &self.small,
+            Size::Medium => &self.medium,
+        }
+    }
+
+    /// Storage keys of every thumbnail.
+    pub(crate) fn keys(&self) -> [&str; 2] {
+        [&self.small, &self.medium]
+    }
+}
+
+/// Thumbnail generation errors.
+#[derive(Debug)]
+pub(crate) enum ThumbnailError {
+    /// Map files could not be read or thumbnails written.
+    Storage(StorageError),
+    /// Map file could not be decoded or thumbnails encoded.
+    Image(ImageError),
+}
+
+impl fmt::Display for ThumbnailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Storage(err) => write!(f, "{err}"),
+            Self::Image(err) => write!(f, "image error: {err}"),
+        }
+    }
+}
+
+impl From<StorageError> for ThumbnailError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<ImageError> for ThumbnailError {
+    fn from(err: ImageError) -> Self {
+        Self::Image(err)
+    }
+}
+
+/// Whether `smap` is an image lacking thumbnails of its current file.
+pub(crate) fn needs_thumbnails(smap: &SMap) -> bool {
+    THUMBNAILED_TYPES.contains(&smap.content_type.as_str())
+        && smap.thumbnails.as_ref() != Some(&Thumbnails::of(&smap.hash))
+}
+
+/// Encode `image` as a JPEG fitting in `size`, never enlarging it.
+fn encode(image: &DynamicImage, size: Size) -> Result<Bytes, ImageError> {
+    let edge = size.edge();
+    let resized = if image.width() > edge || image.height() > edge {
+        image.resize(edge, edge, FilterType::Triangle)
+    } else {
+        image.clone()
+    };
+    let mut jpeg = Vec::new();
+    // JPEG has no alpha channel.
+    DynamicImage::ImageRgb8(resized.to_rgb8())
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, QUALITY))?;
+    Ok(Bytes::from(jpeg))
+}
+
+/// Decode the file of `smap` within `limits` and store its thumbnails, unless
+/// they already are, returning them.
+pub(crate) async fn generate(
+    storage: &dyn StorageBackend,
+    smap: &SMap,
+    limits: Limits,
+) -> Result<Thumbnails, ThumbnailError> {
+    let thumbnails = Thumbnails::of(&smap.hash);
+    let mut stored = true;
+    for key in thumbnails.keys() {
+        stored &= storage.exists(key).await?;
+    }
+    if stored {
+        return Ok(thumbnails);
+    }
+
+    let bytes: Vec<Bytes> = storage.get(&smap.key).await?.try_collect().await?;
+    let encoded = task::spawn_blocking(move || {
+        let image = sniff::decode(&bytes.concat(), limits)?;
+        Ok::<_, ImageError>([encode(&image, Size::Small)?, encode(&image, Size::Medium)?])
+    })
+    .await
+    .map_err(|err| ImageError::IoError(io::Error::other(err)))??;
+
+    for (key, jpeg) in thumbnails.keys().into_iter().zip(encoded) {
+        storage.put(key, jpeg).await?;
+    }
+    Ok(thumbnails)
+}
@@ -0,0 +1,55 @@
+//! Purging of maps kept in the trash longer than the retention period.
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use tokio::time::{self, Instant};
+
+use crate::{db::MetadataError, smap::Store};
+
+/// Seconds between purge runs.
+const PURGE_INTERVAL: u64 = 3600;
+
+/// Permanently remove maps trashed more than `retention_days` ago, returning their uuids.
+///
+/// Their files are left to garbage collection, as other maps may share them.
+pub(crate) async fn purge(
+    store: &Store,
+    retention_days: u64,
+) -> Result<Vec<String>, MetadataError> {
+    let cutoff = Utc::now() - chrono::Duration::days(retention_days as i64);
+
+    let mut purged = Vec::new();
+    for smap in store.list_all().await? {
+        if smap
+            .deleted_at
+            .is_some_and(|deleted_at| deleted_at < cutoff)
+            && store.remove(&smap.uuid).await?
+        {
+            purged.push(smap.uuid);
+        }
+    }
+    Ok(purged)
+}
+
+/// Run [`purge`] every hour in the background, unless `retention_days` is 0.
+pub(crate) fn spawn(store: Arc<Store>, retention_days: u64) {
+    if retention_days == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let period = Duration::from_secs(PURGE_INTERVAL);
+        let mut ticker = time::interval_at(Instant::now(), period);
+        loop {
+            ticker.tick().await;
+            match purge(&store, retention_days).await {
+                Ok(purged) if !purged.is_empty() => {
+                    println!("trash purge removed {} maps", purged.len())
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("trash purge failed: {err}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,658 @@
+//! Resumable uploads following the tus protocol 1.0.0, with its creation and
+//! checksum extensions, so that interrupted uploads resume instead of restarting.
+//!
+//! `POST /uploads` creates an upload of a declared length, `HEAD` reports how
+//! many of its bytes were received and `PATCH` appends bytes from there. The
+//! complete file is registered as a new map, whose uuid is returned in the
+//! `Smap-Uuid` header. Partial uploads are kept below `<data dir>/uploads`, so
+//! they survive restarts; abandoned ones are not cleaned up.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{BodyStream, Path as UrlPath, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::Args;
+use futures::TryStreamExt;
+use hyper::{
+    header::{HeaderName, CACHE_CONTROL, CONTENT_TYPE, LOCATION},
+    HeaderMap, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::{
+    archive,
+    config::Config,
+    smap::{self, BaseUrl, SMap, SMapError, Store},
+    storage::StorageBackend,
+};
+
+/// Version of the protocol spoken.
+const PROTOCOL_VERSION: &str = "1.0.0";
+
+/// Protocol extensions supported.
+const EXTENSIONS: &str = "creation,checksum";
+
+/// Algorithms accepted in `Upload-Checksum`.
+const CHECKSUM_ALGORITHMS: &str = "sha1,sha256,md5";
+
+/// Media type of the bodies of `PATCH` requests.
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+const TUS_CHECKSUM_ALGORITHM: HeaderName = HeaderName::from_static("tus-checksum-algorithm");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+const UPLOAD_CHECKSUM: HeaderName = HeaderName::from_static("upload-checksum");
+
+/// Header carrying the uuid of the map registered from a complete upload.
+const SMAP_UUID: HeaderName = HeaderName::from_static("smap-uuid");
+
+/// Resumable upload settings.
+#[derive(Args, Debug)]
+pub(crate) struct TusConfig {
+    /// Largest file accepted by resumable uploads, in bytes.
+    #[arg(
+        long = "tus-max-size",
+        env = "SMU_TUS_MAX_SIZE",
+        default_value_t = 4 * 1024 * 1024 * 1024
+    )]
+    pub(crate) max_size: u64,
+}
+
+/// Upload in progress, recorded next to its partial file.
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    /// Declared length of the file.
+    length: u64,
+    /// `Upload-Metadata` header of the creation, reported back by `HEAD`.
+    metadata: Option<String>,
+    title: String,
+    content_type: Option<String>,
+    owner: Option<String>,
+    /// Uuid of the map registered once the file was complete.
+    smap: Option<String>,
+}
+
+/// Partial uploads, each stored as a data file and a JSON info file.
+pub(crate) struct Uploads {
+    dir: PathBuf,
+    /// Uploads being appended to, locked against concurrent `PATCH` requests.
+    busy: Mutex<HashSet<String>>,
+}
+
+/// Exclusive access to an upload, released when dropped.
+struct Lock<'a> {
+    uploads: &'a Uploads,
+    id: String,
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        self.uploads.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Uploads {
+    /// Keep uploads in `dir`, creating it if missing.
+    pub(crate) fn open(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            busy: Mutex::default(),
+        })
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// Upload created under `id`, if any.
+    async fn get(&self, id: &str) -> io::Result<Option<Upload>> {
+        // Ids are uuids: anything else must not reach the file system.
+        if Uuid::parse_str(id).is_err() {
+            return Ok(None);
+        }
+        match fs::read(self.info_path(id)).await {
+            Ok(info) => Ok(Some(serde_json::from_slice(&info)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn save(&self, id: &str, upload: &Upload) -> io::Result<()> {
+        fs::write(self.info_path(id), serde_json::to_vec(upload)?).await
+    }
+
+    /// Bytes of the upload `id` received so far.
+    async fn offset(&self, id: &str, upload: &Upload) -> io::Result<u64> {
+        if upload.smap.is_some() {
+            return Ok(upload.length);
+        }
+        Ok(fs::metadata(self.data_path(id)).await?.len())
+    }
+
+    fn lock(&self, id: &str) -> Option<Lock<'_>> {
+        if !self.busy.lock().unwrap().insert(id.to_string()) {
+            return None;
+        }
+        Some(Lock {
+            uploads: self,
+            id: id.to_string(),
+        })
+    }
+}
+
+/// Digest of an `Upload-Checksum` algorithm.
+enum Checksum {
+    Sha1(sha1_smol::Sha1),
+    Sha256(Sha256),
+    Md5(md5::Context),
+}
+
+impl Checksum {
+    fn new(algorithm: &str) -> Option<Self> {
+        Some(match algorithm {
+            "sha1" => Self::Sha1(sha1_smol::Sha1::new()),
+            "sha256" => Self::Sha256(Sha256::new()),
+            "md5" => Self::Md5(md5::Context::new()),
+            _ => return None,
+        })
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Sha1(sha1) => sha1.update(bytes),
+            Self::Sha256(sha256) => sha256.update(bytes),
+            Self::Md5(md5) => md5.consume(bytes),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Self::Sha1(sha1) => sha1.digest().bytes().to_vec(),
+            Self::Sha256(sha256) => sha256.finalize().to_vec(),
+            Self::Md5(md5) => md5.finalize().0.to_vec(),
+        }
+    }
+}
+
+/// `response` with the protocol version every response carries.
+fn tus(response: impl IntoResponse) -> Response {
+    ([(TUS_RESUMABLE, PROTOCOL_VERSION)], response).into_response()
+}
+
+fn error(status: StatusCode, error: SMapError) -> Response {
+    tus((status, Json(error)))
+}
+
+fn bad_request(message: String) -> Response {
+    error(StatusCode::BAD_REQUEST, SMapError::BadRequest(message))
+}
+
+fn not_found(id: &str) -> Response {
+    error(
+        StatusCode::NOT_FOUND,
+        SMapError::NotFound(format!("upload = {id}")),
+    )
+}
+
+fn storage_error(err: io::Error) -> Response {
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        SMapError::Storage(format!("upload i/o error: {err}")),
+    )
+}
+
+/// 412 response if the request does not speak the supported protocol version.
+fn version_mismatch(headers: &HeaderMap) -> Option<Response> {
+    if headers
+        .get(TUS_RESUMABLE)
+        .is_some_and(|version| version == PROTOCOL_VERSION)
+    {
+        return None;
+    }
+    Some(tus((
+        StatusCode::PRECONDITION_FAILED,
+        [(TUS_VERSION, PROTOCOL_VERSION)],
+    )))
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Pairs of `Upload-Metadata`: comma-separated keys, each followed by a space
+/// and its base64-encoded value unless it has none.
+fn parse_metadata(metadata: &str) -> Result<HashMap<String, String>, String> {
+    let mut pairs = HashMap::new();
+    for pair in metadata
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+        let value = BASE64
+            .decode(value.trim())
+            .ok()
+            .and_then(|value| String::from_utf8(value).ok())
+            .ok_or_else(|| format!("metadata {key:?} is not base64-encoded UTF-8"))?;
+        pairs.insert(key.to_string(), value);
+    }
+    Ok(pairs)
+}
+
+/// Resumable upload capabilities
+///
+/// Report the protocol versions, extensions, checksum algorithms and largest
+/// file size supported.
+#[utoipa::path(
+    options,
+    path = "/uploads",
+    responses(
+        (status = 204, description = "Capabilities in the Tus-* headers")
+    )
+)]
+pub(super) async fn upload_options(State(config): State<Arc<Config>>) -> Response {
+    tus((
+        StatusCode::NO_CONTENT,
+        [
+            (TUS_VERSION, PROTOCOL_VERSION.to_string()),
+            (TUS_EXTENSION, EXTENSIONS.to_string()),
+            (TUS_MAX_SIZE, config.tus.max_size.to_string()),
+            (TUS_CHECKSUM_ALGORITHM, CHECKSUM_ALGORITHMS.to_string()),
+        ],
+    ))
+}
+
+/// Create resumable upload
+///
+/// Create an upload of `Upload-Length` bytes, to send with `PATCH` requests to
+/// the returned `Location`. `Upload-Metadata` must hold a `title` or a
+/// `filename`, and may hold the `filetype` of the file.
+#[utoipa::path(
+    post,
+    path = "/uploads",
+    params(
+        ("Tus-Resumable" = String, Header, description = "Protocol version, 1.0.0"),
+        ("Upload-Length" = u64, Header, description = "Size of the file in bytes"),
+        ("Upload-Metadata" = String, Header, description = "Comma-separated keys and base64 values")
+    ),
+    responses(
+        (status = 201, description = "Upload created at the returned Location"),
+        (status = 400, description = "Missing length or title, or invalid metadata", body = SMapError),
+        (status = 412, description = "Unsupported protocol version"),
+        (status = 413, description = "File exceeds the maximum size", body = SMapError),
+        (status = 415, description = "Metadata filetype is not an accepted content type", body = SMapError),
+        (status = 422, description = "Metadata filename has a blocked or disguised extension", body = SMapError),
+        (status = 500, description = "Upload could not be created", body = SMapError)
+    )
+)]
+pub(super) async fn create_upload(
+    State(config): State<Arc<Config>>,
+    State(uploads): State<Arc<Uploads>>,
+    base: BaseUrl,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = version_mismatch(&headers) {
+        return response;
+    }
+    let Some(length) = header(&headers, &UPLOAD_LENGTH).and_then(|length| length.parse().ok())
+    else {
+        return bad_request("missing or invalid Upload-Length".to_string());
+    };
+    if length > config.tus.max_size {
+        return error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            SMapError::PayloadTooLarge(format!("upload exceeds {} bytes", config.tus.max_size)),
+        );
+    }
+
+    let metadata = header(&headers, &UPLOAD_METADATA).map(str::to_string);
+    let mut pairs = match metadata.as_deref().map(parse_metadata).transpose() {
+        Ok(pairs) => pairs.unwrap_or_default(),
+        Err(message) => return bad_request(message),
+    };
+    let file_name = pairs.remove("filename");
+    if let Some(name) = &file_name {
+        if let Err(err) = smap::accept_file_name(&config, name) {
+            return tus(err);
+        }
+    }
+    let title = pairs
+        .remove("title")
+        .filter(|title| !title.trim().is_empty())
+        .or_else(|| file_name.as_deref().and_then(archive::title_of));
+    let Some(title) = title else {
+        return bad_request("upload metadata needs a title or a filename".to_string());
+    };
+    let content_type = pairs.remove("filetype");
+    if let Err(err) = smap::accept_content_type(&config, content_type.as_deref()) {
+        return tus(err);
+    }
+    let upload = Upload {
+        length,
+        metadata,
+        title,
+        content_type,
+        owner: smap::caller(&config, &headers).owner(),
+        smap: None,
+    };
+
+    let id = Uuid::new_v4().to_string();
+    let created = async {
+        File::create(uploads.data_path(&id)).await?;
+        uploads.save(&id, &upload).await
+    };
+    if let Err(err) = created.await {
+        return storage_error(err);
+    }
+    tus((
+        StatusCode::CREATED,
+        [(LOCATION, base.join(&format!("/uploads/{id}")))],
+    ))
+}
+
+/// Resumable upload offset
+///
+/// Report how many bytes of the upload were received, to resume it from
+/// there, and the uuid of its map once complete.
+#[utoipa::path(
+    head,
+    path = "/uploads/{id}",
+    params(
+        ("id" = String, Path, description = "Upload id"),
+        ("Tus-Resumable" = String, Header, description = "Protocol version, 1.0.0")
+    ),
+    responses(
+        (status = 200, description = "Offset in the Upload-Offset header"),
+        (status = 404, description = "Upload not found"),
+        (status = 412, description = "Unsupported protocol version")
+    )
+)]
+pub(super) async fn head_upload(
+    State(uploads): State<Arc<Uploads>>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = version_mismatch(&headers) {
+        return response;
+    }
+    let upload = match uploads.get(&id).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return not_found(&id),
+        Err(err) => return storage_error(err),
+    };
+    let offset = match uploads.offset(&id, &upload).await {
+        Ok(offset) => offset,
+        Err(err) => return storage_error(err),
+    };
+
+    let mut response = tus((
+        StatusCode::OK,
+        [
+            (UPLOAD_OFFSET, offset.to_string()),
+            (UPLOAD_LENGTH, upload.length.to_string()),
+            (CACHE_CONTROL, "no-store".to_string()),
+        ],
+    ));
+    let extra = [(UPLOAD_METADATA, upload.metadata), (SMAP_UUID, upload.smap)];
+    for (name, value) in extra {
+        if let Some(value) = value.and_then(|value| value.parse().ok()) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Append to resumable upload
+///
+/// Append the body to the upload, from the `Upload-Offset` it was at. With an
+/// `Upload-Checksum` of the body, a mismatching body is discarded. The map is
+/// registered once the file is complete; if that fails, an empty `PATCH` at
+/// the final offset retries it.
+#[utoipa::path(
+    patch,
+    path = "/uploads/{id}",
+    params(
+        ("id" = String, Path, description = "Upload id"),
+        ("Tus-Resumable" = String, Header, description = "Protocol version, 1.0.0"),
+        ("Upload-Offset" = u64, Header, description = "Offset the body starts at"),
+        ("Upload-Checksum" = Option<String>, Header, description = "Algorithm and base64 digest of the body")
+    ),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 204, description = "Body appended, new offset in Upload-Offset and map uuid in Smap-Uuid once complete"),
+        (status = 400, description = "Missing offset or invalid checksum header", body = SMapError),
+        (status = 404, description = "Upload not found", body = SMapError),
+        (status = 409, description = "Offset differs from the received bytes, or map duplicates an existing one", body = SMapError),
+        (status = 412, description = "Unsupported protocol version"),
+        (status = 413, description = "Body exceeds the declared length, or the completed image exceeds the pixel limits", body = SMapError),
+        (status = 415, description = "Body is not application/offset+octet-stream", body = SMapError),
+        (status = 423, description = "Upload is being appended to by another request", body = SMapError),
+        (status = 460, description = "Body does not match its checksum", body = SMapError),
+        (status = 500, description = "Upload or map could not be stored", body = SMapError),
+        (status = 507, description = "Storage quota exhausted", body = SMapError)
+    )
+)]
+pub(super) async fn patch_upload(
+    State(config): State<Arc<Config>>,
+    State(store): State<Arc<Store>>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(uploads): State<Arc<Uploads>>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Response {
+    if let Some(response) = version_mismatch(&headers) {
+        return response;
+    }
+    if header(&headers, &CONTENT_TYPE) != Some(OFFSET_OCTET_STREAM) {
+        return error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            SMapError::UnsupportedMediaType(format!("body must be {OFFSET_OCTET_STREAM}")),
+        );
+    }
+    let Some(offset) = header(&headers, &UPLOAD_OFFSET).and_then(|offset| offset.parse().ok())
+    else {
+        return bad_request("missing or invalid Upload-Offset".to_string());
+    };
+    let checksum = match header(&headers, &UPLOAD_CHECKSUM).map(parse_checksum) {
+        Some(Ok(checksum)) => Some(checksum),
+        Some(Err(message)) => return bad_request(message),
+        None => None,
+    };
+
+    let mut upload = match uploads.get(&id).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return not_found(&id),
+        Err(err) => return storage_error(err),
+    };
+    let Some(_lock) = uploads.lock(&id) else {
+        return error(
+            StatusCode::LOCKED,
+            SMapError::Conflict(format!("upload {id} is being appended to")),
+        );
+    };
+    let received = match uploads.offset(&id, &upload).await {
+        Ok(received) => received,
+        Err(err) => return storage_error(err),
+    };
+    if offset != received {
+        return error(
+            StatusCode::CONFLICT,
+            SMapError::Conflict(format!("upload is at offset {received}, not {offset}")),
+        );
+    }
+
+    let offset = match append(&uploads, &id, &upload, offset, body, checksum).await {
+        Ok(offset) => offset,
+        Err(response) => return response,
+    };
+    if offset == upload.length && upload.smap.is_none() {
+        let registered = register(
+            &config,
+            &store,
+            storage.as_ref(),
+            &uploads,
+            &id,
+            &mut upload,
+        );
+        if let Err(response) = registered.await {
+            return response;
+        }
+    }
+
+    let mut response = tus((
+        StatusCode::NO_CONTENT,
+        [(UPLOAD_OFFSET, offset.to_string())],
+    ));
+    if let Some(uuid) = upload.smap.and_then(|uuid| uuid.parse().ok()) {
+        response.headers_mut().insert(SMAP_UUID, uuid);
+    }
+    response
+}
+
+/// Algorithm and expected digest of an `Upload-Checksum` header.
+fn parse_checksum(checksum: &str) -> Result<(Checksum, Vec<u8>), String> {
+    let (algorithm, digest) = checksum
+        .split_once(' ')
+        .ok_or_else(|| format!("invalid Upload-Checksum {checksum:?}"))?;
+    let Some(algorithm) = Checksum::new(algorithm) else {
+        return Err(format!(
+            "checksum algorithm {algorithm:?} is not one of {CHECKSUM_ALGORITHMS}"
+        ));
+    };
+    let digest = BASE64
+        .decode(digest.trim())
+        .map_err(|err| format!("checksum is not valid base64: {err}"))?;
+    Ok((algorithm, digest))
+}
+
+/// Write `body` to the file of the upload `id` from `offset`, returning the new
+/// offset.
+///
+/// Without a checksum, the bytes received before an interrupted body are kept
+/// for the upload to resume after them; with one, the whole body is discarded
+/// unless it matches.
+async fn append(
+    uploads: &Uploads,
+    id: &str,
+    upload: &Upload,
+    offset: u64,
+    mut body: BodyStream,
+    mut checksum: Option<(Checksum, Vec<u8>)>,
+) -> Result<u64, Response> {
+    let path = uploads.data_path(id);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .await
+        .map_err(storage_error)?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(storage_error)?;
+
+    let verified = checksum.is_some();
+    let mut written = offset;
+    let mut interrupted = false;
+    let mut failure = None;
+    loop {
+        let chunk = match body.try_next().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => {
+                interrupted = true;
+                failure = Some(bad_request(format!("upload interrupted: {err}")));
+                break;
+            }
+        };
+        if written + chunk.len() as u64 > upload.length {
+            failure = Some(error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                SMapError::PayloadTooLarge(format!(
+                    "upload exceeds its length of {}",
+                    upload.length
+                )),
+            ));
+            break;
+        }
+        if let Some((digest, _)) = &mut checksum {
+            digest.update(&chunk);
+        }
+        if let Err(err) = file.write_all(&chunk).await {
+            failure = Some(storage_error(err));
+            break;
+        }
+        written += chunk.len() as u64;
+    }
+    if let Err(err) = file.flush().await {
+        failure.get_or_insert(storage_error(err));
+    }
+    if let Some((digest, expected)) = checksum.filter(|_| failure.is_none()) {
+        if digest.finish() != expected {
+            failure = Some(error(
+                StatusCode::from_u16(460).unwrap(),
+                SMapError::BadRequest("body does not match Upload-Checksum".to_string()),
+            ));
+        }
+    }
+
+    let Some(failure) = failure else {
+        return Ok(written);
+    };
+    let kept = if interrupted && !verified {
+        written
+    } else {
+        offset
+    };
+    file.set_len(kept).await.map_err(storage_error)?;
+    Err(failure)
+}
+
+/// Register the map of the complete upload `id`, recording its uuid in `upload`.
+async fn register(
+    config: &Config,
+    store: &Store,
+    storage: &dyn StorageBackend,
+    uploads: &Uploads,
+    id: &str,
+    upload: &mut Upload,
+) -> Result<(), Response> {
+    let path = uploads.data_path(id);
+    let file = File::open(&path).await.map_err(storage_error)?;
+    let file = smap::store_spooled(config, store, storage, file)
+        .await
+        .map_err(tus)?;
+    let mut smap = SMap::new(Uuid::new_v4().to_string(), upload.title.clone(), file);
+    smap.owner = upload.owner.clone();
+    let smaps = smap::register_new(config, store, storage, vec![smap])
+        .await
+        .map_err(tus)?;
+
+    upload.smap = smaps.into_iter().next().map(|smap| smap.uuid);
+    uploads.save(id, upload).await.map_err(storage_error)?;
+    fs::remove_file(&path).await.map_err(storage_error)
+}
@@ -0,0 +1,107 @@
+//! Write-ahead log of catalog mutations, replayed on startup so a crash between
+//! two snapshots never loses them.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use crate::{
+    db::{Catalog, MetadataError},
+    smap::SMap,
+};
+
+/// Change of the catalog, logged as one JSON line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Mutation {
+    /// A map was registered.
+    Insert { smap: SMap },
+    /// A registered map was replaced.
+    Update { smap: SMap },
+    /// A registered map was removed.
+    Delete { uuid: String },
+}
+
+impl Mutation {
+    /// Whether the mutation changes `smaps`.
+    pub(crate) fn applies_to(&self, smaps: &Catalog) -> bool {
+        match self {
+            Self::Insert { smap } => !smaps.contains_key(&smap.uuid),
+            Self::Update { smap } => smaps.contains_key(&smap.uuid),
+            Self::Delete { uuid } => smaps.contains_key(uuid),
+        }
+    }
+
+    /// Replay the mutation on `smaps`, returning whether it changed anything.
+    pub(crate) fn apply(self, smaps: &mut Catalog) -> bool {
+        if !self.applies_to(smaps) {
+            return false;
+        }
+        match self {
+            Self::Insert { smap } | Self::Update { smap } => {
+                smaps.insert(smap.uuid.clone(), smap);
+            }
+            Self::Delete { uuid } => {
+                smaps.shift_remove(&uuid);
+            }
+        }
+        true
+    }
+}
+
+/// Append-only log file of mutations not covered by the last snapshot.
+pub(crate) struct Wal {
+    file: File,
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it if missing, and return the mutations it holds.
+    ///
+    /// A trailing line left incomplete by a crash is discarded.
+    pub(crate) async fn open(path: &Path) -> Result<(Self, Vec<Mutation>), MetadataError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        let content = fs::read(path).await?;
+        let mut mutations = Vec::new();
+        let mut valid = 0;
+        for line in content.split_inclusive(|&byte| byte == b'\n') {
+            if !line.ends_with(b"\n") {
+                break;
+            }
+            mutations.push(serde_json::from_slice(line)?);
+            valid += line.len();
+        }
+        if valid < content.len() {
+            file.set_len(valid as u64).await?;
+        }
+
+        Ok((Self { file }, mutations))
+    }
+
+    /// Durably append `mutation` to the log.
+    pub(crate) async fn append(&mut self, mutation: &Mutation) -> Result<(), MetadataError> {
+        let mut line = serde_json::to_vec(mutation)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.file.sync_data().await?;
+        Ok(())
+    }
+
+    /// Empty the log once a snapshot covers its mutations.
+    pub(crate) async fn truncate(&mut self) -> Result<(), MetadataError> {
+        self.file.set_len(0).await?;
+        self.file.sync_data().await?;
+        Ok(())
+    }
+}
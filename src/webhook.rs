@@ -0,0 +1,75 @@
+//! Webhooks notified once uploaded maps are processed, so that other services
+//! can act on new maps without polling the listing.
+
+use std::time::Duration;
+
+use clap::Args;
+use reqwest::{Client, Url};
+use serde::Serialize;
+
+use crate::smap::SMap;
+
+/// Webhook settings.
+#[derive(Args, Debug)]
+pub(crate) struct WebhookConfig {
+    /// URLs sent a `POST` with the JSON event `smap.processed` once each
+    /// uploaded map is processed, comma-separated. Failed deliveries are
+    /// retried like processing jobs.
+    #[arg(
+        id = "webhook_urls",
+        long = "webhook-urls",
+        env = "SMU_WEBHOOK_URLS",
+        value_delimiter = ',',
+        value_parser = parse_url,
+        hide_env_values = true
+    )]
+    pub(crate) urls: Vec<String>,
+
+    /// Seconds allowed for a webhook delivery.
+    #[arg(
+        id = "webhook_timeout",
+        long = "webhook-timeout-secs",
+        env = "SMU_WEBHOOK_TIMEOUT_SECS",
+        default_value_t = 10
+    )]
+    pub(crate) timeout: u64,
+}
+
+/// Webhook URL, which must be an absolute HTTP(S) URL.
+fn parse_url(url: &str) -> Result<String, String> {
+    match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(url.to_string()),
+        _ => Err(format!("not an http(s) url: {url}")),
+    }
+}
+
+/// Body of webhook requests.
+#[derive(Serialize)]
+struct Event<'a> {
+    /// Kind of event, `smap.processed`.
+    event: &'static str,
+    /// Processed map, with its processing status.
+    smap: &'a SMap,
+}
+
+/// Client delivering webhooks within the timeout of `config`.
+pub(crate) fn client(config: &WebhookConfig) -> reqwest::Result<Client> {
+    Client::builder()
+        .timeout(Duration::from_secs(config.timeout))
+        .build()
+}
+
+/// Send processed `smap` to webhook `url`, failing on error statuses.
+pub(crate) async fn notify(client: &Client, url: &str, smap: &SMap) -> reqwest::Result<()> {
+    let event = Event {
+        event: "smap.processed",
+        smap,
+    };
+    client
+        .post(url)
+        .json(&event)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}